@@ -1,3 +1,34 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
 fn main() {
-    println!("cargo:rustc-link-lib=dylib=ffi");
+    // Only the backend links with LLVM, and links the runtime into programs
+    if env::var_os("CARGO_FEATURE_BACKEND_LLVM").is_some() {
+        println!("cargo:rustc-link-lib=dylib=ffi");
+        build_runtime();
+    }
+}
+
+/// Build the runtime library `src/core` as a static library in the output directory, and
+/// pass its path to the compiler in `KVASIR_RUNTIME_LIB`, so that the driver can link it into
+/// every program
+fn build_runtime() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    let target_dir = out_dir.join("core");
+    let cargo = env::var("CARGO").unwrap_or("cargo".to_string());
+    let status = Command::new(cargo)
+        .args(&["build", "--release", "--manifest-path", "src/core/Cargo.toml"])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run cargo to build the runtime library, {}", e));
+    if !status.success() {
+        panic!("Failed to build the runtime library")
+    }
+    let lib = target_dir.join("release").join("libkvasir_core.a");
+    println!("cargo:rustc-env=KVASIR_RUNTIME_LIB={}", lib.display());
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/core/Cargo.toml");
+    println!("cargo:rerun-if-changed=src/core/src");
 }
@@ -0,0 +1,65 @@
+/* C interface to the Kvasir compiler, for embedding Kvasir as a scripting language
+ *
+ * Link with the `kvasir` library built by cargo, as well as the Kvasir core library, whose
+ * symbols must be exported from the final executable. The library must be built with the
+ * default `panic = "unwind"`, as compilation errors are caught by unwinding.
+ *
+ * Kvasir functions have a single argument and a return value. They map to C as follows
+ *
+ *     Int8 ... Int64, UInt8 ... UInt64   int8_t ... int64_t, uint8_t ... uint64_t
+ *     Float32, Float64                   float, double
+ *     Bool                               bool
+ *     Nil                                an empty struct
+ *     (Cons a b)                         struct { a car; b cdr; }
+ *     (Ptr a)                            a *
+ *
+ * All functions returning `int` return 0 on success and -1 on failure.
+ */
+
+#ifndef KVASIR_H
+#define KVASIR_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An embedded compiler, and the program it has most recently compiled */
+typedef struct KvasirContext KvasirContext;
+
+/* Create a new context. Free it with `kvasir_context_free` */
+KvasirContext *kvasir_context_new(void);
+
+/* Free the context `ctx`, including any compiled program */
+void kvasir_context_free(KvasirContext *ctx);
+
+/* Register the C function at `addr` as the extern `name` of Kvasir type `type`,
+ * e.g. "(-> Int64 Int64)", callable from programs subsequently compiled in `ctx` */
+int kvasir_register_host_function(KvasirContext *ctx,
+                                  const char *name,
+                                  const char *type,
+                                  const void *addr);
+
+/* JIT compile the program with source code `src` in `ctx`, replacing any previously
+ * compiled program. Errors and warnings are available through `kvasir_diagnostics`.
+ * Functions gotten from a replaced program remain valid, with their old definitions, until
+ * the context is freed */
+int kvasir_compile(KvasirContext *ctx, const char *src);
+
+/* The diagnostics of the last compilation in `ctx`. Valid until the next call to
+ * `kvasir_compile` or `kvasir_context_free` */
+const char *kvasir_diagnostics(const KvasirContext *ctx);
+
+/* Run `main` of the compiled program, and write its exit code to `exit_code`, if not null.
+ * The global variables of the program are initialized by `kvasir_compile`, so functions of
+ * the program may be called without running `main` */
+int kvasir_run_main(KvasirContext *ctx, int *exit_code);
+
+/* The address of the monomorphic, global function `name` of the compiled program, or null
+ * if there is no such function */
+const void *kvasir_get_function(const KvasirContext *ctx, const char *name);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,194 @@
+//! C interface to the compiler, for embedding Kvasir as a scripting language in non-Rust
+//! applications
+//!
+//! The functions are declared in `include/kvasir.h`. A context holds the registered host
+//! functions, and the program and diagnostics of the last compilation.
+//!
+//! ```c
+//! KvasirContext *ctx = kvasir_context_new();
+//! kvasir_register_host_function(ctx, "double", "(-> Int64 Int64)", (const void *)double_it);
+//! if (kvasir_compile(ctx, src) != 0) {
+//!     fputs(kvasir_diagnostics(ctx), stderr);
+//! } else {
+//!     int64_t (*f)(int64_t) = (int64_t (*)(int64_t))kvasir_get_function(ctx, "f");
+//! }
+//! kvasir_context_free(ctx);
+//! ```
+//!
+//! No panic is allowed to unwind across the interface. Internal compiler errors are instead
+//! reported as failures, with the panic message in the diagnostics.
+//!
+//! Both errors in programs and internal compiler errors are caught by unwinding, so the
+//! library must not be built with `panic = "abort"`.
+
+use lib::ice::payload_msg;
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use {jit_str, Diagnostics, HostFunctions, JitModule, Options};
+
+/// An embedded compiler, and the program it has most recently compiled
+pub struct KvasirContext {
+    host: HostFunctions,
+    program: Option<JitModule>,
+    /// Programs replaced by later compilations. They are kept alive until the context is freed,
+    /// so that the functions gotten from them remain valid, with their old definitions
+    replaced: Vec<JitModule>,
+    diagnostics: CString,
+}
+
+impl KvasirContext {
+    fn set_diagnostics(&mut self, s: String) {
+        // Diagnostics never contain NUL, except in quoted source code. Truncate there
+        let s = match s.find('\0') {
+            Some(i) => s[..i].to_string(),
+            None => s,
+        };
+        self.diagnostics = CString::new(s).expect("ICE: NUL in diagnostics")
+    }
+}
+
+/// Run `f`, converting a panic into an error with the panic message
+fn catch_ice<R, F: FnOnce() -> R>(f: F) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|e| format!("Internal compiler error: {}\n", payload_msg(&*e)))
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+/// Create a new context. Free it with `kvasir_context_free`
+#[no_mangle]
+pub extern "C" fn kvasir_context_new() -> *mut KvasirContext {
+    Box::into_raw(Box::new(KvasirContext {
+        host: HostFunctions::new(),
+        program: None,
+        replaced: Vec::new(),
+        diagnostics: CString::default(),
+    }))
+}
+
+/// Free the context `ctx`, including any compiled program
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_context_free(ctx: *mut KvasirContext) {
+    if !ctx.is_null() {
+        let _ = catch_ice(|| drop(Box::from_raw(ctx)));
+    }
+}
+
+/// Register the C function at `addr` as the extern `name` of Kvasir type `typ`, callable from
+/// programs subsequently compiled in `ctx`
+///
+/// Returns 0 on success, and -1 if any argument is null or not valid UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_register_host_function(
+    ctx: *mut KvasirContext,
+    name: *const c_char,
+    typ: *const c_char,
+    addr: *const c_void,
+) -> c_int {
+    match (ctx.as_mut(), str_arg(name), str_arg(typ)) {
+        (Some(ctx), Some(name), Some(typ)) if !addr.is_null() => {
+            ctx.host.register(name, typ, addr);
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// JIT compile the program with source code `src` in `ctx`, replacing any previously compiled
+/// program
+///
+/// Functions gotten from a replaced program remain valid until the context is freed, and keep
+/// the definitions they were compiled with. Functions of the new program only ever call
+/// functions of the new program.
+///
+/// Returns 0 on success, and -1 on failure. In either case, the errors and warnings of the
+/// compilation are available through `kvasir_diagnostics`.
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_compile(ctx: *mut KvasirContext, src: *const c_char) -> c_int {
+    let ctx = match ctx.as_mut() {
+        Some(ctx) => ctx,
+        None => return -1,
+    };
+    if let Some(program) = ctx.program.take() {
+        ctx.replaced.push(program)
+    }
+    let src = match str_arg(src) {
+        Some(src) => src,
+        None => {
+            ctx.set_diagnostics("Error: Source code is null or not valid UTF-8\n".to_string());
+            return -1;
+        }
+    };
+    let result = {
+        let host = &ctx.host;
+        catch_ice(|| jit_str(src, Options::default(), host))
+    };
+    match result {
+        Ok(Ok(program)) => {
+            let Diagnostics(warnings) = program.warnings.clone();
+            ctx.program = Some(program);
+            ctx.set_diagnostics(warnings);
+            0
+        }
+        Ok(Err(Diagnostics(errors))) | Err(errors) => {
+            ctx.set_diagnostics(errors);
+            -1
+        }
+    }
+}
+
+/// Returns the diagnostics of the last compilation in `ctx`, as a NUL-terminated string
+///
+/// The string is owned by the context, and valid until the next call to `kvasir_compile` or
+/// `kvasir_context_free`.
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_diagnostics(ctx: *const KvasirContext) -> *const c_char {
+    match ctx.as_ref() {
+        Some(ctx) => ctx.diagnostics.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Run `main` of the program compiled in `ctx`, and write its exit code to `exit_code`
+///
+/// The global variables of the program are initialized by `kvasir_compile`, so functions of
+/// the program may be called without running `main`. Returns 0 on success, and -1 if no
+/// program is compiled.
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_run_main(ctx: *mut KvasirContext, exit_code: *mut c_int) -> c_int {
+    match ctx.as_ref().and_then(|ctx| ctx.program.as_ref()) {
+        Some(program) => {
+            let code = program.run_main();
+            if let Some(exit_code) = exit_code.as_mut() {
+                *exit_code = code
+            }
+            0
+        }
+        None => -1,
+    }
+}
+
+/// Returns the address of the global function `name` of the program compiled in `ctx`, or null
+/// if there is no such function
+///
+/// The function must be monomorphic. It has the C calling convention, and takes its single
+/// argument and returns its result as described in `include/kvasir.h`.
+#[no_mangle]
+pub unsafe extern "C" fn kvasir_get_function(
+    ctx: *const KvasirContext,
+    name: *const c_char,
+) -> *const c_void {
+    let program = ctx.as_ref().and_then(|ctx| ctx.program.as_ref());
+    match (program, str_arg(name)) {
+        (Some(program), Some(name)) => program.get_function(name).unwrap_or(ptr::null()),
+        _ => ptr::null(),
+    }
+}
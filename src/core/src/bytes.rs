@@ -0,0 +1,20 @@
+//! Byte buffers, as a pair of length and pointer to the data, like byte array literals
+//!
+//! Buffers are not owned by the `Bytes` that refer to them, so slicing is just arithmetic on
+//! the pair, and only copying allocates.
+
+use libc::malloc;
+use std::ptr;
+use _oom;
+
+/// Copy the `len` bytes at `data` to a new buffer on the heap
+#[no_mangle]
+pub unsafe extern "C" fn bytes_copy((len, data): (usize, *const u8)) -> (usize, *mut u8) {
+    // `malloc(0)` may return null, so always allocate at least one byte
+    let copy = malloc(len.max(1)) as *mut u8;
+    if copy.is_null() {
+        _oom(len)
+    }
+    ptr::copy_nonoverlapping(data, copy, len);
+    (len, copy)
+}
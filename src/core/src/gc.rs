@@ -17,8 +17,10 @@
 //!     Maybe we need a way to consider temporary values as root nodes, until a binding is made and they're referd to through there.
 
 use libc;
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use task;
 use std::mem;
 use std::iter;
 
@@ -26,14 +28,14 @@ lazy_static! {
     static ref GC: Mutex<Gc> = Mutex::new(Gc::new());
 }
 
-type ObjHandler = extern "C" fn(obj_ref: *const i8);
+pub type ObjHandler = extern "C" fn(obj_ref: *const i8);
 
-type ObjVisitor = extern "C" fn(obj: *const i8, obj_handler: ObjHandler);
+pub type ObjVisitor = extern "C" fn(obj: *const i8, obj_handler: ObjHandler);
 
 #[no_mangle]
 pub extern "C" fn gc_push_new_scope() {
     let mut gc = GC.lock().unwrap();
-    gc.push_new_scope()
+    gc.stack().push_new_scope()
 }
 
 #[no_mangle]
@@ -45,26 +47,88 @@ pub extern "C" fn gc_alloc_temp(size: usize, obj_visitor: ObjVisitor) -> *mut i8
 #[no_mangle]
 pub extern "C" fn gc_mark_bound(obj: *const i8, obj_visitor: ObjVisitor) {
     let mut gc = GC.lock().unwrap();
-    gc.mark_bound(obj, obj_visitor)
+    gc.stack().mark_bound(obj, obj_visitor)
 }
 
 #[no_mangle]
 pub extern "C" fn gc_mark_bound_and_clear_scope_temps(obj: *const i8, obj_visitor: ObjVisitor) {
     let mut gc = GC.lock().unwrap();
-    gc.mark_bound(obj, obj_visitor);
-    gc.clear_scope_temps()
+    let stack = gc.stack();
+    stack.mark_bound(obj, obj_visitor);
+    stack.clear_scope_temps()
 }
 
 #[no_mangle]
 pub extern "C" fn gc_pop_scope() {
     let mut gc = GC.lock().unwrap();
-    gc.pop_scope()
+    gc.stack().pop_scope()
+}
+
+#[no_mangle]
+pub extern "C" fn gc_push_arena() {
+    let mut gc = GC.lock().unwrap();
+    gc.stack().arenas.push(Arena::new())
+}
+
+#[no_mangle]
+pub extern "C" fn gc_pop_arena() {
+    let mut gc = GC.lock().unwrap();
+    gc.stack()
+        .arenas
+        .pop()
+        .expect("ICE: Popped arena when no arenas");
 }
 
 #[no_mangle]
 pub extern "C" fn gc_move_locals_to_parent_scope_as_temps_and_pop_scope() {
     let mut gc = GC.lock().unwrap();
-    gc.move_locals_to_parent_scope_as_temps_and_pop_scope();
+    gc.stack()
+        .move_locals_to_parent_scope_as_temps_and_pop_scope();
+}
+
+/// Keep the objects referred to by `obj` alive, regardless of the scopes of any thread,
+/// until unpinned
+///
+/// Used for values passed between threads, which may outlive the scope they were bound in.
+#[no_mangle]
+pub extern "C" fn gc_pin(obj: *const i8, obj_visitor: ObjVisitor) {
+    let mut gc = GC.lock().unwrap();
+    for &r in get_obj_refs(obj, obj_visitor) {
+        *gc.pinned.entry(r).or_insert(0) += 1
+    }
+}
+
+/// Undo one `gc_pin` of the objects referred to by `obj`
+#[no_mangle]
+pub extern "C" fn gc_unpin(obj: *const i8, obj_visitor: ObjVisitor) {
+    let mut gc = GC.lock().unwrap();
+    for &r in get_obj_refs(obj, obj_visitor) {
+        gc.unpin(r)
+    }
+}
+
+/// Undo one `gc_pin` of the objects referred to by `obj`, but keep them alive as temporaries
+/// of the current scope
+///
+/// Used for values received from other threads.
+#[no_mangle]
+pub extern "C" fn gc_adopt(obj: *const i8, obj_visitor: ObjVisitor) {
+    let mut gc = GC.lock().unwrap();
+    for &r in get_obj_refs(obj, obj_visitor) {
+        gc.unpin(r);
+        gc.stack().top_scope.temps.insert(r);
+    }
+}
+
+/// Drop the scopes and arenas of the current thread or task, which is about to finish
+pub fn drop_stack() {
+    let mut gc = GC.lock().unwrap();
+    gc.stacks.remove(&stack_id());
+}
+
+/// The id of the stack of the current thread, or of the current task of the thread
+fn stack_id() -> (ThreadId, usize) {
+    (thread::current().id(), task::current_task_id())
 }
 
 type UIntPtr = usize;
@@ -83,6 +147,58 @@ impl Scope {
     }
 }
 
+/// The size of a chunk of an arena. Larger objects get chunks of their own
+const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The alignment of objects allocated in an arena, which is that of `malloc`
+const ARENA_ALIGN: usize = 16;
+
+/// A bump allocator, for `with-arena`
+///
+/// Objects allocated in the arena are not tracked by the GC, but freed together when the
+/// arena is dropped.
+struct Arena {
+    chunks: Vec<UIntPtr>,
+    next: UIntPtr,
+    end: UIntPtr,
+}
+
+impl Arena {
+    fn new() -> Self {
+        Arena {
+            chunks: Vec::new(),
+            next: 0,
+            end: 0,
+        }
+    }
+
+    /// Allocate `size` bytes, or return null if out of memory
+    fn alloc(&mut self, size: usize) -> *mut i8 {
+        let size = (size + ARENA_ALIGN - 1) & !(ARENA_ALIGN - 1);
+        if self.end - self.next < size {
+            let chunk_size = size.max(ARENA_CHUNK_SIZE);
+            let chunk = unsafe { libc::malloc(chunk_size) };
+            if chunk.is_null() {
+                return chunk as *mut i8;
+            }
+            self.next = chunk as UIntPtr;
+            self.chunks.push(self.next);
+            self.end = self.next + chunk_size;
+        }
+        let ptr = self.next;
+        self.next += size;
+        ptr as *mut i8
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for &chunk in &self.chunks {
+            unsafe { libc::free(chunk as *mut _) }
+        }
+    }
+}
+
 static mut GET_OBJ_REFS_REFS: Vec<UIntPtr> = Vec::new();
 
 extern "C" fn push_obj_ref_to_static_vec(obj: *const i8) {
@@ -97,18 +213,20 @@ fn get_obj_refs(obj: *const i8, obj_visitor: ObjVisitor) -> &'static [UIntPtr] {
     }
 }
 
-struct Gc {
-    allocs: BTreeMap<UIntPtr, ObjVisitor>,
+/// The scopes and arenas of a single thread or task
+struct Stack {
     scopes: Vec<Scope>,
     top_scope: Scope,
+    /// The stack of active arenas. Allocations are made in the top arena, if any
+    arenas: Vec<Arena>,
 }
 
-impl Gc {
+impl Stack {
     fn new() -> Self {
-        Gc {
-            allocs: BTreeMap::new(),
+        Stack {
             scopes: Vec::with_capacity(8),
             top_scope: Scope::new(),
+            arenas: Vec::new(),
         }
     }
 
@@ -118,15 +236,6 @@ impl Gc {
         self.scopes.push(prev_top_scope);
     }
 
-    fn alloc_temp(&mut self, size: usize, obj_visitor: ObjVisitor) -> *mut i8 {
-        let ptr = unsafe { libc::malloc(size) };
-        let ptr_u = ptr as UIntPtr;
-        self.allocs.insert(ptr_u, obj_visitor);
-        self.top_scope.temps.insert(ptr_u);
-        self.clean();
-        ptr as *mut i8
-    }
-
     fn mark_bound(&mut self, obj: *const i8, obj_visitor: ObjVisitor) {
         let refs = get_obj_refs(obj, obj_visitor);
         for r in refs {
@@ -160,11 +269,75 @@ impl Gc {
         )
     }
 
-    fn gather_reachable_objects(&self) -> BTreeSet<UIntPtr> {
-        let mut objs: VecDeque<UIntPtr> = self.scopes
+    fn roots<'a>(&'a self) -> impl Iterator<Item = UIntPtr> + 'a {
+        self.scopes
             .iter()
             .chain(iter::once(&self.top_scope))
             .flat_map(|s| s.temps.iter().chain(&s.bounds).cloned())
+    }
+}
+
+/// The heap shared by all threads
+///
+/// Each thread and task has a stack of scopes of its own, and the roots are the objects in
+/// the scopes of all of them, as well as any pinned objects.
+struct Gc {
+    allocs: BTreeMap<UIntPtr, ObjVisitor>,
+    stacks: HashMap<(ThreadId, usize), Stack>,
+    /// Objects kept alive regardless of scopes, with the number of times they've been pinned
+    pinned: BTreeMap<UIntPtr, usize>,
+}
+
+impl Gc {
+    fn new() -> Self {
+        Gc {
+            allocs: BTreeMap::new(),
+            stacks: HashMap::new(),
+            pinned: BTreeMap::new(),
+        }
+    }
+
+    /// The stack of the current thread or task
+    fn stack(&mut self) -> &mut Stack {
+        self.stacks
+            .entry(stack_id())
+            .or_insert_with(Stack::new)
+    }
+
+    fn unpin(&mut self, obj: UIntPtr) {
+        let n = {
+            let n = self.pinned
+                .get_mut(&obj)
+                .expect("ICE: Unpinned object that was not pinned");
+            *n -= 1;
+            *n
+        };
+        if n == 0 {
+            self.pinned.remove(&obj);
+        }
+    }
+
+    fn alloc_temp(&mut self, size: usize, obj_visitor: ObjVisitor) -> *mut i8 {
+        if let Some(arena) = self.stack().arenas.last_mut() {
+            return arena.alloc(size);
+        }
+        let ptr = unsafe { libc::malloc(size) };
+        if ptr.is_null() {
+            // The generated code calls `_oom`. Don't track, or later trace, the null object
+            return ptr as *mut i8;
+        }
+        let ptr_u = ptr as UIntPtr;
+        self.allocs.insert(ptr_u, obj_visitor);
+        self.stack().top_scope.temps.insert(ptr_u);
+        self.clean();
+        ptr as *mut i8
+    }
+
+    fn gather_reachable_objects(&self) -> BTreeSet<UIntPtr> {
+        let mut objs: VecDeque<UIntPtr> = self.stacks
+            .values()
+            .flat_map(Stack::roots)
+            .chain(self.pinned.keys().cloned())
             .collect();
         let mut reachables: BTreeSet<UIntPtr> = objs.iter().cloned().collect();
         while let Some(obj) = objs.pop_front() {
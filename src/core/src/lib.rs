@@ -6,11 +6,20 @@ extern crate libc;
 extern crate rand;
 
 pub mod string;
+pub mod bytes;
 pub mod gc;
+pub mod test;
+pub mod thread;
+pub mod task;
+pub mod net;
+pub mod profile;
 
+use std::cell::RefCell;
+use std::ffi::CStr;
 use std::io::{self, BufRead};
-use std::mem::size_of;
-use libc::malloc;
+use std::mem::{self, size_of};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc::{c_char, malloc};
 use string::*;
 
 unsafe fn on_heap<T>(data: T) -> *mut T {
@@ -56,11 +65,85 @@ pub extern "C" fn print_float64(x: f64) {
     println!("{}", x)
 }
 
+thread_local! {
+    /// The shadow stack of the calls in progress, innermost last. Each frame is described by
+    /// a constant C string of the called function and the position of the call
+    static SHADOW_STACK: RefCell<Vec<*const c_char>> = RefCell::new(Vec::new());
+}
+
+/// Called by generated code before a call, with a description of the frame
+#[no_mangle]
+pub extern "C" fn _frame_push(frame: *const c_char) {
+    SHADOW_STACK.with(|stack| stack.borrow_mut().push(frame))
+}
+
+/// Called by generated code when a call returns
+#[no_mangle]
+pub extern "C" fn _frame_pop() {
+    SHADOW_STACK.with(|stack| stack.borrow_mut().pop());
+}
+
+/// Swap the calls in progress of this thread with `stack`, when switching tasks
+fn swap_shadow_stack(stack: &mut Vec<*const c_char>) {
+    SHADOW_STACK.with(|s| mem::swap(&mut *s.borrow_mut(), stack))
+}
+
+/// Print the calls in progress, innermost first
+fn print_backtrace() {
+    SHADOW_STACK.with(|stack| {
+        let stack = stack.borrow();
+        if stack.is_empty() {
+            println!(
+                "note: Calls are only tracked for backtraces when compiled with \
+                 `--debug-backtrace`"
+            );
+        } else {
+            println!("Backtrace:");
+        }
+        for (i, &frame) in stack.iter().rev().enumerate() {
+            let descr = unsafe { CStr::from_ptr(frame) };
+            println!("{:4}: {}", i, descr.to_string_lossy())
+        }
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _panic(s: KvsString) {
     println!(
         "Kvasir thread panicked with message: {}",
         kvs_string_to_string(s)
     );
+    print_backtrace();
     std::process::exit(1)
 }
+
+/// A handler of failed heap allocations, given the size of the allocation
+pub type OomHandler = extern "C" fn(size: usize);
+
+/// The address of the installed `OomHandler`, or 0 for the default handler
+static OOM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Install `handler` to be called when a heap allocation fails, or restore the default
+/// handler if `handler` is null
+///
+/// The handler may e.g. log, free caches, or exit with a custom status. If it returns, the
+/// default handler is run.
+#[no_mangle]
+pub extern "C" fn set_oom_handler(handler: Option<OomHandler>) {
+    OOM_HANDLER.store(handler.map(|h| h as usize).unwrap_or(0), Ordering::SeqCst)
+}
+
+/// Called by generated code when a heap allocation of `size` bytes fails
+///
+/// Runs the installed handler, if any, and then the default handler, `_panic`.
+#[no_mangle]
+pub extern "C" fn _oom(size: usize) -> ! {
+    let handler = OOM_HANDLER.load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: OomHandler = unsafe { mem::transmute(handler) };
+        handler(size)
+    }
+    let msg = format!("Out of memory, failed to allocate {} bytes", size);
+    unsafe { _panic(string_to_kvs_string(&msg)) };
+    unreachable!("ICE: _panic returned")
+}
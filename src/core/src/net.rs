@@ -0,0 +1,226 @@
+//! Non-blocking TCP sockets, driven by the event loop of the task scheduler
+//!
+//! Sockets are represented by their file descriptors, and errors by negative numbers. An
+//! operation that would block waits for the socket with `task::wait_io`, so that other tasks
+//! may run meanwhile.
+
+use libc::{self, c_int, c_short, sockaddr, socklen_t, POLLIN, POLLOUT};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem::{self, ManuallyDrop};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::str;
+use std::sync::Mutex;
+use string::*;
+use task;
+
+lazy_static! {
+    /// The trailing bytes of an incomplete UTF-8 sequence read from each connection, to be
+    /// completed by the next read
+    static ref PARTIAL_READS: Mutex<HashMap<i64, Vec<u8>>> = Mutex::new(HashMap::new());
+}
+
+/// Retry `op` on the socket `fd` until it doesn't block, waiting for the `poll` events
+/// `events` in between
+fn retry<T, F>(fd: RawFd, events: c_short, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => unsafe {
+                task::wait_io(fd, events)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            r => return r,
+        }
+    }
+}
+
+/// Borrow the socket of file descriptor `fd` as a `T`, without closing it when dropped
+unsafe fn borrow_fd<T: FromRawFd>(fd: i64) -> ManuallyDrop<T> {
+    ManuallyDrop::new(T::from_raw_fd(fd as RawFd))
+}
+
+/// Listen for TCP connections on `addr`:`port`. Returns the file descriptor of the listener,
+/// or -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn tcp_listen((addr, port): (KvsString, u16)) -> i64 {
+    TcpListener::bind((kvs_string_to_string(addr).as_str(), port))
+        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+        .map(|l| l.into_raw_fd() as i64)
+        .unwrap_or(-1)
+}
+
+/// Accept a connection on the listener `fd`. Returns the file descriptor of the connection,
+/// or -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn tcp_accept(fd: i64) -> i64 {
+    let listener = borrow_fd::<TcpListener>(fd);
+    retry(listener.as_raw_fd(), POLLIN, || listener.accept())
+        .and_then(|(s, _)| s.set_nonblocking(true).map(|_| s))
+        .map(|s| s.into_raw_fd() as i64)
+        .unwrap_or(-1)
+}
+
+/// Connect a new non-blocking socket to `addr`, waiting for the connection to be established
+/// with `task::wait_io`
+unsafe fn connect_nonblocking(addr: &SocketAddr) -> io::Result<RawFd> {
+    let (family, sa, sa_len) = match *addr {
+        SocketAddr::V4(ref a) => {
+            let mut sin: libc::sockaddr_in = mem::zeroed();
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            let mut sa: libc::sockaddr_storage = mem::zeroed();
+            *(&mut sa as *mut _ as *mut libc::sockaddr_in) = sin;
+            (libc::AF_INET, sa, mem::size_of::<libc::sockaddr_in>())
+        }
+        SocketAddr::V6(ref a) => {
+            let mut sin6: libc::sockaddr_in6 = mem::zeroed();
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            let mut sa: libc::sockaddr_storage = mem::zeroed();
+            *(&mut sa as *mut _ as *mut libc::sockaddr_in6) = sin6;
+            (libc::AF_INET6, sa, mem::size_of::<libc::sockaddr_in6>())
+        }
+    };
+    let fd = libc::socket(
+        family,
+        libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        0,
+    );
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sa_ptr = &sa as *const _ as *const sockaddr;
+    let r = loop {
+        if libc::connect(fd, sa_ptr, sa_len as socklen_t) == 0 {
+            break Ok(fd);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => (),
+            Some(libc::EINPROGRESS) => {
+                task::wait_io(fd, POLLOUT);
+                let mut err: c_int = 0;
+                let mut err_len = mem::size_of::<c_int>() as socklen_t;
+                let got = libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_ERROR,
+                    &mut err as *mut c_int as *mut _,
+                    &mut err_len,
+                );
+                break if got < 0 {
+                    Err(io::Error::last_os_error())
+                } else if err != 0 {
+                    Err(io::Error::from_raw_os_error(err))
+                } else {
+                    Ok(fd)
+                };
+            }
+            _ => break Err(e),
+        }
+    };
+    if r.is_err() {
+        libc::close(fd);
+    }
+    r
+}
+
+/// Connect to `addr`:`port`, trying each address it resolves to in turn. Returns the file
+/// descriptor of the connection, or -1 on error
+///
+/// Other tasks may run while the connection is being established.
+#[no_mangle]
+pub unsafe extern "C" fn tcp_connect((addr, port): (KvsString, u16)) -> i64 {
+    let addrs = match (kvs_string_to_string(addr).as_str(), port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return -1,
+    };
+    addrs
+        .filter_map(|a| connect_nonblocking(&a).ok())
+        .next()
+        .map(|fd| fd as i64)
+        .unwrap_or(-1)
+}
+
+/// Split `bytes` into the longest prefix of valid UTF-8, with invalid sequences replaced, and
+/// the trailing bytes of an incomplete sequence, if any
+fn split_utf8(bytes: &[u8]) -> (String, Vec<u8>) {
+    let mut s = String::new();
+    let mut rest = bytes;
+    loop {
+        match str::from_utf8(rest) {
+            Ok(valid) => {
+                s.push_str(valid);
+                return (s, Vec::new());
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                s.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                match e.error_len() {
+                    Some(n) => {
+                        s.push('\u{FFFD}');
+                        rest = &after[n..];
+                    }
+                    None => return (s, after.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+/// Read what is available on the connection `fd`, at least one byte unless at the end
+///
+/// Returns the number of bytes read, 0 at the end, or -1 on error, along with the string
+/// read. A UTF-8 sequence split between reads is kept until completed by the next read, and
+/// replaced at the end, so the string may be empty even when bytes were read.
+#[no_mangle]
+pub unsafe extern "C" fn tcp_read(fd: i64) -> (i64, KvsString) {
+    let mut stream = borrow_fd::<TcpStream>(fd);
+    let mut buf = [0u8; 4096];
+    let r = retry(fd as RawFd, POLLIN, || stream.read(&mut buf));
+    let mut partials = PARTIAL_READS.lock().unwrap();
+    let mut bytes = partials.remove(&fd).unwrap_or_default();
+    match r {
+        Ok(0) => (0, string_to_kvs_string(&String::from_utf8_lossy(&bytes))),
+        Ok(n) => {
+            bytes.extend_from_slice(&buf[..n]);
+            let (s, incomplete) = split_utf8(&bytes);
+            if !incomplete.is_empty() {
+                partials.insert(fd, incomplete);
+            }
+            (n as i64, string_to_kvs_string(&s))
+        }
+        Err(_) => (-1, string_to_kvs_string("")),
+    }
+}
+
+/// Write all of `s` to the connection `fd`. Returns the number of bytes written, or -1 on
+/// error
+#[no_mangle]
+pub unsafe extern "C" fn tcp_write((fd, s): (i64, KvsString)) -> i64 {
+    let mut stream = borrow_fd::<TcpStream>(fd);
+    let s = kvs_string_to_string(s);
+    let mut written = 0;
+    while written < s.len() {
+        match retry(fd as RawFd, POLLOUT, || stream.write(&s.as_bytes()[written..])) {
+            Ok(n) => written += n,
+            Err(_) => return -1,
+        }
+    }
+    written as i64
+}
+
+/// Close the socket `fd`, discarding any incomplete UTF-8 sequence read from it
+#[no_mangle]
+pub unsafe extern "C" fn tcp_close(fd: i64) {
+    PARTIAL_READS.lock().unwrap().remove(&fd);
+    drop(TcpStream::from_raw_fd(fd as RawFd))
+}
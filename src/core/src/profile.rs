@@ -0,0 +1,68 @@
+//! Heap usage profiling
+//!
+//! Programs compiled with `--profile-heap` allocate through `gc_alloc_profiled`, which counts
+//! the allocations and bytes of each allocation site. A report of the sites, by bytes
+//! allocated, is printed to stderr when the program exits.
+
+use gc::{gc_alloc_temp, ObjVisitor};
+use libc::{self, c_char};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+#[derive(Default)]
+struct SiteStats {
+    allocs: u64,
+    bytes: u64,
+}
+
+lazy_static! {
+    /// The statistics of each allocation site, by the address of its description
+    static ref PROFILE: Mutex<HashMap<usize, SiteStats>> = Mutex::new(HashMap::new());
+}
+
+static REGISTER_REPORT: Once = ONCE_INIT;
+
+extern "C" fn report() {
+    let profile = PROFILE.lock().unwrap();
+    let mut sites = profile.iter().collect::<Vec<_>>();
+    sites.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes));
+    let total_allocs = sites.iter().map(|&(_, s)| s.allocs).sum::<u64>();
+    let total_bytes = sites.iter().map(|&(_, s)| s.bytes).sum::<u64>();
+    eprintln!(
+        "\nHeap profile: {} bytes in {} allocations",
+        total_bytes, total_allocs
+    );
+    eprintln!("{:>12} {:>10}  site", "bytes", "allocs");
+    for (&site, stats) in sites {
+        let descr = unsafe { CStr::from_ptr(site as *const c_char) };
+        eprintln!(
+            "{:>12} {:>10}  {}",
+            stats.bytes,
+            stats.allocs,
+            descr.to_string_lossy()
+        );
+    }
+}
+
+/// Allocate like `gc_alloc_temp`, and record the allocation under `site`
+///
+/// `site` is a constant, null-terminated description of the allocation site, like
+/// `"examples/fib.kvs:3:8"`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_alloc_profiled(
+    size: usize,
+    obj_visitor: ObjVisitor,
+    site: *const c_char,
+) -> *mut i8 {
+    REGISTER_REPORT.call_once(|| {
+        libc::atexit(report);
+    });
+    {
+        let mut profile = PROFILE.lock().unwrap();
+        let stats = profile.entry(site as usize).or_insert_with(SiteStats::default);
+        stats.allocs += 1;
+        stats.bytes += size as u64;
+    }
+    gc_alloc_temp(size, obj_visitor)
+}
@@ -93,16 +93,72 @@ pub unsafe fn kvs_string_to_string(mut s: KvsString) -> String {
     buf
 }
 
+pub unsafe fn string_to_kvs_string(s: &str) -> KvsString {
+    s.chars()
+        .rev()
+        .fold(KvsString::empty(), |acc, c| KvsString::cons(c, acc))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn str_lit_to_string((len, ptr): (usize, *const u8)) -> KvsString {
     let slice = slice::from_raw_parts(ptr, len);
     let s = str::from_utf8(slice).unwrap();
-    s.chars()
-        .rev()
-        .fold(KvsString::empty(), |acc, c| KvsString::cons(c, acc))
+    string_to_kvs_string(s)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn c_display(s: KvsString) {
     println!("{}", kvs_string_to_string(s))
 }
+
+/// Whether the strings `a` and `b` consist of the same characters
+#[no_mangle]
+pub unsafe extern "C" fn string_eq((mut a, mut b): (KvsString, KvsString)) -> bool {
+    loop {
+        match (a.split_first(), b.split_first()) {
+            (None, None) => return true,
+            (Some((c0, a_)), Some((c1, b_))) if c0 == c1 => {
+                a = a_;
+                b = b_;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Whether the string `a` is lexicographically less than `b`
+#[no_mangle]
+pub unsafe extern "C" fn string_lt((mut a, mut b): (KvsString, KvsString)) -> bool {
+    loop {
+        match (a.split_first(), b.split_first()) {
+            (_, None) => return false,
+            (None, Some(_)) => return true,
+            (Some((c0, a_)), Some((c1, b_))) => if c0 == c1 {
+                a = a_;
+                b = b_;
+            } else {
+                return c0 < c1;
+            },
+        }
+    }
+}
+
+/// The string of the characters of `a` followed by `b`
+///
+/// Both strings are copied, as the cells of the result are not tracked by the GC, which
+/// therefore can't see that a GC allocated `b` would still be referenced through them.
+#[no_mangle]
+pub unsafe extern "C" fn string_append((mut a, mut b): (KvsString, KvsString)) -> KvsString {
+    let mut cs = Vec::new();
+    while let Some((c, a_)) = a.split_first() {
+        cs.push(c);
+        a = a_;
+    }
+    while let Some((c, b_)) = b.split_first() {
+        cs.push(c);
+        b = b_;
+    }
+    cs.into_iter()
+        .rev()
+        .fold(KvsString::empty(), |acc, c| KvsString::cons(c, acc))
+}
@@ -0,0 +1,298 @@
+//! Green threads: lightweight cooperative tasks, spawned with `task-spawn`
+//!
+//! Each task runs on a fiber of its own, with a small stack guarded against overflow by an
+//! inaccessible page, and gives up control with
+//! `task-yield`, or when awaiting another task. The tasks of each OS thread are run in turn by
+//! a scheduler of that thread. Fibers are implemented with the `ucontext` functions of POSIX.
+//!
+//! A task may also wait for a file descriptor to become ready, with `wait_io`. When no task is
+//! ready to run, the scheduler polls the file descriptors of the waiting tasks, as an event
+//! loop.
+
+use libc::{self, c_int, c_short, pollfd, ucontext_t};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use thread::ThreadEntry;
+use gc;
+
+extern "C" {
+    fn getcontext(ucp: *mut ucontext_t) -> c_int;
+    fn makecontext(ucp: *mut ucontext_t, func: extern "C" fn(), argc: c_int, ...);
+    fn swapcontext(oucp: *mut ucontext_t, ucp: *const ucontext_t) -> c_int;
+}
+
+/// The size of the stack of a task, not counting the guard page
+const TASK_STACK_SIZE: usize = 256 * 1024;
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Map a stack of `TASK_STACK_SIZE` bytes, below which a `PROT_NONE` guard page is mapped, so
+/// that an overflow segfaults instead of silently corrupting the memory around it
+///
+/// Returns the base of the mapping, including the guard page, or null if out of memory.
+unsafe fn map_stack() -> *mut libc::c_void {
+    let len = TASK_STACK_SIZE + page_size();
+    let base = libc::mmap(
+        ptr::null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+        -1,
+        0,
+    );
+    if base == libc::MAP_FAILED {
+        ptr::null_mut()
+    } else if libc::mprotect(base, page_size(), libc::PROT_NONE) != 0 {
+        libc::munmap(base, len);
+        ptr::null_mut()
+    } else {
+        base
+    }
+}
+
+unsafe fn unmap_stack(base: *mut libc::c_void) {
+    libc::munmap(base, TASK_STACK_SIZE + page_size());
+}
+
+/// A task, and its result once finished
+pub struct KvsTask {
+    /// A unique, nonzero id
+    id: usize,
+    ctx: ucontext_t,
+    /// The base of the mapping of the stack, starting with the guard page
+    stack: *mut libc::c_void,
+    entry: ThreadEntry,
+    func: *const i8,
+    captures: *const i8,
+    result: *mut i8,
+    result_size: usize,
+    /// Set with release ordering once `result` is written, as the task may be awaited from
+    /// another thread than the one running it
+    done: AtomicBool,
+    /// The file descriptor and `poll` events that the task is waiting for, if any
+    waiting_on: Option<(c_int, c_short)>,
+    /// The calls in progress of the task, while it is not running
+    shadow_stack: Vec<*const libc::c_char>,
+}
+
+struct Scheduler {
+    ready: VecDeque<*mut KvsTask>,
+    /// Tasks waiting for IO
+    waiting: Vec<*mut KvsTask>,
+    /// The running task, or null if the scheduler is
+    current: *mut KvsTask,
+    ctx: ucontext_t,
+    next_id: usize,
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<Scheduler> = RefCell::new(Scheduler {
+        ready: VecDeque::new(),
+        waiting: Vec::new(),
+        current: ptr::null_mut(),
+        ctx: unsafe { mem::zeroed() },
+        next_id: 1,
+    });
+}
+
+/// The id of the running task of this thread, or 0 if no task is running
+pub fn current_task_id() -> usize {
+    SCHEDULER.with(|s| {
+        let current = s.borrow().current;
+        if current.is_null() {
+            0
+        } else {
+            unsafe { (*current).id }
+        }
+    })
+}
+
+/// The entry point of all fibers, which runs the current task to completion
+extern "C" fn task_trampoline() {
+    let t = SCHEDULER.with(|s| s.borrow().current);
+    unsafe {
+        let t = &mut *t;
+        (t.entry)(t.func, t.captures, t.result);
+        gc::drop_stack();
+        t.done.store(true, Ordering::Release);
+    }
+    // Returning resumes the scheduler, via `uc_link`
+}
+
+/// Run the task `t` until it yields or finishes
+unsafe fn run(t: *mut KvsTask) {
+    let sched_ctx = SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        s.current = t;
+        &mut s.ctx as *mut ucontext_t
+    });
+    ::swap_shadow_stack(&mut (*t).shadow_stack);
+    swapcontext(sched_ctx, &(*t).ctx);
+    ::swap_shadow_stack(&mut (*t).shadow_stack);
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        s.current = ptr::null_mut();
+        if (*t).done.load(Ordering::Acquire) {
+            unmap_stack((*t).stack);
+            (*t).stack = ptr::null_mut();
+        } else if (*t).waiting_on.is_some() {
+            s.waiting.push(t)
+        } else {
+            s.ready.push_back(t)
+        }
+    })
+}
+
+/// Poll the file descriptors of the waiting tasks, and `extra` if given, and make the tasks
+/// of ready file descriptors ready to run. Return whether `extra` is ready
+///
+/// If `block`, wait until any file descriptor is ready.
+unsafe fn poll_waiting(extra: Option<(c_int, c_short)>, block: bool) -> bool {
+    let waiting = SCHEDULER.with(|s| mem::replace(&mut s.borrow_mut().waiting, Vec::new()));
+    let mut fds = waiting
+        .iter()
+        .map(|&t| (*t).waiting_on.expect("ICE: waiting task not waiting on IO"))
+        .chain(extra)
+        .map(|(fd, events)| pollfd {
+            fd,
+            events,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    if fds.is_empty() {
+        return false;
+    }
+    let timeout = if block { -1 } else { 0 };
+    while libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) < 0
+        && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+    {}
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        for (&t, fd) in waiting.iter().zip(&fds) {
+            if fd.revents == 0 {
+                s.waiting.push(t)
+            } else {
+                (*t).waiting_on = None;
+                s.ready.push_back(t)
+            }
+        }
+    });
+    extra.is_some() && fds.last().map(|fd| fd.revents != 0).unwrap_or(false)
+}
+
+/// Whether there are no ready tasks, but some waiting for IO, in which case the scheduler
+/// should block until some IO is ready
+fn only_waiting() -> bool {
+    SCHEDULER.with(|s| {
+        let s = s.borrow();
+        s.ready.is_empty() && !s.waiting.is_empty()
+    })
+}
+
+/// Wait for the file descriptor `fd` to become ready for the `poll` events `events`
+///
+/// In a task, let the other tasks run meanwhile. Outside of any task, run the tasks while
+/// waiting.
+pub unsafe fn wait_io(fd: c_int, events: c_short) {
+    let current = SCHEDULER.with(|s| s.borrow().current);
+    if current.is_null() {
+        loop {
+            let block = SCHEDULER.with(|s| s.borrow().ready.is_empty());
+            if poll_waiting(Some((fd, events)), block) {
+                break;
+            }
+            run_round()
+        }
+    } else {
+        (*current).waiting_on = Some((fd, events));
+        let sched_ctx = SCHEDULER.with(|s| &mut s.borrow_mut().ctx as *mut ucontext_t);
+        swapcontext(&mut (*current).ctx, sched_ctx);
+    }
+}
+
+/// Poll for IO, and run each ready task once
+unsafe fn run_round() {
+    poll_waiting(None, only_waiting());
+    let n = SCHEDULER.with(|s| s.borrow().ready.len());
+    for _ in 0..n {
+        if let Some(t) = SCHEDULER.with(|s| s.borrow_mut().ready.pop_front()) {
+            run(t)
+        }
+    }
+}
+
+/// Spawn a task running `entry` with the function pointer and captures of a closure, and a
+/// buffer of `result_size` bytes for the result
+///
+/// The task is not run until the spawner yields or awaits. Like thread handles, task
+/// handles are never freed.
+#[no_mangle]
+pub unsafe extern "C" fn _task_spawn(
+    entry: ThreadEntry,
+    func: *const i8,
+    captures: *const i8,
+    result_size: usize,
+) -> *mut KvsTask {
+    let stack = map_stack();
+    let result = libc::malloc(result_size.max(1)) as *mut i8;
+    if stack.is_null() || result.is_null() {
+        ::_oom(TASK_STACK_SIZE + page_size() + result_size)
+    }
+    let t = Box::into_raw(Box::new(KvsTask {
+        id: SCHEDULER.with(|s| {
+            let mut s = s.borrow_mut();
+            s.next_id += 1;
+            s.next_id - 1
+        }),
+        ctx: mem::zeroed(),
+        stack,
+        entry,
+        func,
+        captures,
+        result,
+        result_size,
+        done: AtomicBool::new(false),
+        waiting_on: None,
+        shadow_stack: Vec::new(),
+    }));
+    getcontext(&mut (*t).ctx);
+    (*t).ctx.uc_stack.ss_sp = (stack as *mut u8).offset(page_size() as isize) as *mut _;
+    (*t).ctx.uc_stack.ss_size = TASK_STACK_SIZE;
+    (*t).ctx.uc_link = SCHEDULER.with(|s| &mut s.borrow_mut().ctx as *mut ucontext_t);
+    makecontext(&mut (*t).ctx, task_trampoline, 0);
+    SCHEDULER.with(|s| s.borrow_mut().ready.push_back(t));
+    t
+}
+
+/// Give up control to the other tasks of this thread
+///
+/// In a task, switch back to the scheduler. Outside of any task, poll for IO and run each
+/// ready task once.
+#[no_mangle]
+pub unsafe extern "C" fn _task_yield() {
+    let current = SCHEDULER.with(|s| s.borrow().current);
+    if current.is_null() {
+        run_round()
+    } else {
+        let sched_ctx = SCHEDULER.with(|s| &mut s.borrow_mut().ctx as *mut ucontext_t);
+        swapcontext(&mut (*current).ctx, sched_ctx);
+    }
+}
+
+/// Yield until the task `t` has finished, and copy its result to `out`
+///
+/// The task may belong to another thread, in which case it is run by the scheduler of that
+/// thread while this one yields.
+#[no_mangle]
+pub unsafe extern "C" fn _task_await(t: *mut KvsTask, out: *mut i8) {
+    while !(*t).done.load(Ordering::Acquire) {
+        _task_yield()
+    }
+    ptr::copy_nonoverlapping((*t).result, out, (*t).result_size)
+}
@@ -0,0 +1,85 @@
+//! Runtime of the test harness generated by `kvasir test`
+//!
+//! Each test is run in a forked child process. A failed assertion panics, which exits the
+//! child with a non-zero status, so the remaining tests still get to run.
+
+use libc;
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::process;
+use std::sync::Mutex;
+
+struct TestResults {
+    passed: usize,
+    failed: Vec<String>,
+}
+
+lazy_static! {
+    static ref RESULTS: Mutex<TestResults> = Mutex::new(TestResults {
+        passed: 0,
+        failed: Vec::new(),
+    });
+}
+
+/// Fork to run the test `name`
+///
+/// Returns `true` in the child, which shall run the test and then call `_test_pass`.
+/// In the parent, wait for the child to exit, record the result, and return `false`.
+#[no_mangle]
+pub unsafe extern "C" fn _test_run(name: *const c_char) -> bool {
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    print!("test {} ... ", name);
+    // Flush before forking, so the buffer isn't printed by both processes
+    io::stdout().flush().ok();
+    match libc::fork() {
+        -1 => {
+            println!("error: failed to fork");
+            process::exit(101)
+        }
+        0 => true,
+        child => {
+            let mut status = 0;
+            libc::waitpid(child, &mut status, 0);
+            let mut results = RESULTS.lock().unwrap();
+            if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                println!("ok");
+                results.passed += 1;
+            } else {
+                println!("FAILED");
+                results.failed.push(name);
+            }
+            false
+        }
+    }
+}
+
+/// Exit the child process of a test that ran to completion
+#[no_mangle]
+pub extern "C" fn _test_pass() {
+    io::stdout().flush().ok();
+    process::exit(0)
+}
+
+/// Print a summary of the test results, and return the exit code of the harness
+#[no_mangle]
+pub extern "C" fn _test_summary() -> i32 {
+    let results = RESULTS.lock().unwrap();
+    if !results.failed.is_empty() {
+        println!("\nfailures:");
+        for name in &results.failed {
+            println!("    {}", name)
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        if results.failed.is_empty() { "ok" } else { "FAILED" },
+        results.passed,
+        results.failed.len()
+    );
+    if results.failed.is_empty() {
+        0
+    } else {
+        1
+    }
+}
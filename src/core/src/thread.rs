@@ -0,0 +1,197 @@
+//! Threads, spawned with `thread-spawn` and joined with `thread-join`, and the channels
+//! and mutexes shared between them
+//!
+//! Values are passed to and from the runtime as pointers to their bytes, which are copied.
+//! Generated code pins any objects referred to by a value while it is held by the runtime.
+
+use libc;
+use std::collections::VecDeque;
+use std::process;
+use std::ptr;
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use gc::{self, ObjVisitor};
+
+/// The entry point of a thread, generated for each `thread-spawn`
+///
+/// Calls the closure of function pointer `func` and captures `captures`, and writes the
+/// result to `out`.
+pub type ThreadEntry = extern "C" fn(func: *const i8, captures: *const i8, out: *mut i8);
+
+/// A spawned thread, and its result once joined
+///
+/// The result is pinned by the thread, and stays pinned for as long as the handle lives, as
+/// it may be joined any number of times.
+pub struct KvsThread {
+    /// The join handle, until joined, and then the address of the result. Locked by joiners,
+    /// as a handle may be joined from several threads at once
+    state: Mutex<(Option<JoinHandle<usize>>, usize)>,
+    result_size: usize,
+    result_visitor: ObjVisitor,
+}
+
+impl KvsThread {
+    /// Wait for the thread to finish, if it hasn't already been joined, and return the
+    /// address of its result
+    fn join(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        if let Some(handle) = state.0.take() {
+            // A panicking Kvasir thread exits the process, so this is a panic in the runtime
+            state.1 = handle.join().unwrap_or_else(|_| process::exit(1));
+        }
+        state.1
+    }
+}
+
+impl Drop for KvsThread {
+    fn drop(&mut self) {
+        let result = self.join();
+        gc::gc_unpin(result as *const i8, self.result_visitor);
+        unsafe { libc::free(result as *mut _) }
+    }
+}
+
+/// Spawn a thread running `entry`, with a buffer of `result_size` bytes for the result, which
+/// refers to objects visited by `result_visitor`
+///
+/// Threads are never detached. The handle is not freed by generated code, as it may be
+/// joined any number of times, but may be freed with `_thread_drop`.
+#[no_mangle]
+pub extern "C" fn _thread_spawn(
+    entry: ThreadEntry,
+    func: *const i8,
+    captures: *const i8,
+    result_size: usize,
+    result_visitor: ObjVisitor,
+) -> *mut KvsThread {
+    // Raw pointers are not `Send`, so pass them as integers
+    let (func, captures) = (func as usize, captures as usize);
+    let handle = thread::spawn(move || {
+        let out = unsafe { libc::malloc(result_size.max(1)) } as *mut i8;
+        if out.is_null() {
+            ::_oom(result_size)
+        }
+        entry(func as *const i8, captures as *const i8, out);
+        gc::drop_stack();
+        out as usize
+    });
+    Box::into_raw(Box::new(KvsThread {
+        state: Mutex::new((Some(handle), 0)),
+        result_size,
+        result_visitor,
+    }))
+}
+
+/// Wait for the thread to finish, if it hasn't already been joined, and copy its result
+/// to `out`
+#[no_mangle]
+pub unsafe extern "C" fn _thread_join(t: *mut KvsThread, out: *mut i8) {
+    let t = &*t;
+    let result = t.join();
+    ptr::copy_nonoverlapping(result as *const i8, out, t.result_size)
+}
+
+/// Free the handle of a thread that will not be joined again, joining it if needed, and
+/// unpin its result
+#[no_mangle]
+pub unsafe extern "C" fn _thread_drop(t: *mut KvsThread) {
+    drop(Box::from_raw(t))
+}
+
+/// Copy `size` bytes from `val` to a new heap buffer, and return its address
+unsafe fn copy_to_heap(val: *const i8, size: usize) -> usize {
+    let buf = libc::malloc(size.max(1)) as *mut i8;
+    if buf.is_null() {
+        ::_oom(size)
+    }
+    ptr::copy_nonoverlapping(val, buf, size);
+    buf as usize
+}
+
+/// An unbounded channel of messages of `elem_size` bytes each
+pub struct KvsChan {
+    queue: Mutex<VecDeque<usize>>,
+    available: Condvar,
+    elem_size: usize,
+}
+
+/// Create a channel. Like thread handles, channels are never freed
+#[no_mangle]
+pub extern "C" fn _chan_new(elem_size: usize) -> *mut KvsChan {
+    Box::into_raw(Box::new(KvsChan {
+        queue: Mutex::new(VecDeque::new()),
+        available: Condvar::new(),
+        elem_size,
+    }))
+}
+
+/// Send a copy of the message pointed to by `val`
+#[no_mangle]
+pub unsafe extern "C" fn _chan_send(c: *mut KvsChan, val: *const i8) {
+    let c = &*c;
+    let msg = copy_to_heap(val, c.elem_size);
+    c.queue.lock().unwrap().push_back(msg);
+    c.available.notify_one()
+}
+
+/// Wait for a message, and write it to `out`
+#[no_mangle]
+pub unsafe extern "C" fn _chan_recv(c: *mut KvsChan, out: *mut i8) {
+    let c = &*c;
+    let mut queue = c.queue.lock().unwrap();
+    let msg = loop {
+        match queue.pop_front() {
+            Some(msg) => break msg,
+            None => queue = c.available.wait(queue).unwrap(),
+        }
+    };
+    ptr::copy_nonoverlapping(msg as *const i8, out, c.elem_size);
+    libc::free(msg as *mut _)
+}
+
+/// A mutex protecting a value of `size` bytes
+///
+/// As the mutex is locked and unlocked in separate calls from generated code, the lock is a
+/// flag guarded by a `Mutex`, rather than a guard of the `Mutex` itself.
+pub struct KvsMutex {
+    locked: Mutex<bool>,
+    unlocked: Condvar,
+    val: usize,
+    size: usize,
+}
+
+/// Create an unlocked mutex protecting a copy of the value pointed to by `val`. Like thread
+/// handles, mutexes are never freed
+#[no_mangle]
+pub unsafe extern "C" fn _mutex_new(size: usize, val: *const i8) -> *mut KvsMutex {
+    Box::into_raw(Box::new(KvsMutex {
+        locked: Mutex::new(false),
+        unlocked: Condvar::new(),
+        val: copy_to_heap(val, size),
+        size,
+    }))
+}
+
+/// Wait for the mutex to be unlocked, lock it, and write its value to `out`
+#[no_mangle]
+pub unsafe extern "C" fn _mutex_lock(m: *mut KvsMutex, out: *mut i8) {
+    let m = &*m;
+    let mut locked = m.locked.lock().unwrap();
+    while *locked {
+        locked = m.unlocked.wait(locked).unwrap();
+    }
+    *locked = true;
+    ptr::copy_nonoverlapping(m.val as *const i8, out, m.size)
+}
+
+/// Replace the value of the locked mutex with the value pointed to by `val`, write the
+/// previous value to `old_out`, and unlock it
+#[no_mangle]
+pub unsafe extern "C" fn _mutex_unlock(m: *mut KvsMutex, val: *const i8, old_out: *mut i8) {
+    let m = &*m;
+    let mut locked = m.locked.lock().unwrap();
+    ptr::copy_nonoverlapping(m.val as *const i8, old_out, m.size);
+    ptr::copy_nonoverlapping(val, m.val as *mut i8, m.size);
+    *locked = false;
+    m.unlocked.notify_one()
+}
@@ -0,0 +1,750 @@
+//! The Kvasir compiler as a library
+//!
+//! `compile_str` compiles a program to LLVM IR without printing to stdout or exiting the
+//! process on errors, so that the compiler can be embedded in build tools, test harnesses,
+//! and the like.
+//!
+//! ```ignore
+//! let src = "(import std)\n(define (main real-world) (cons (new Nil) real-world))";
+//! match kvasir::compile_str(src, kvasir::Options::default()) {
+//!     Ok(m) => println!("{}", m.llvm_ir),
+//!     Err(diagnostics) => println!("{}", diagnostics),
+//! }
+//! ```
+//!
+//! `run_str` instead JIT compiles and runs the program in the current process. Functions of
+//! the host application can be made callable from the program by registering them in
+//! `HostFunctions`.
+//!
+//! ```ignore
+//! extern "C" fn double(x: i64) -> i64 {
+//!     x * 2
+//! }
+//!
+//! let mut host = kvasir::HostFunctions::new();
+//! host.register("double", "(-> Int64 Int64)", double as *const _);
+//! kvasir::run_str(src, kvasir::Options::default(), &host)
+//! ```
+//!
+//! To test the behaviour of single functions without spawning processes, `jit_str` keeps the
+//! program loaded, so that its global functions of primitive parameters and result can be
+//! called with `JitModule::call`.
+//!
+//! ```ignore
+//! let src = "(define: (add a b) (-> Int64 (-> Int64 Int64)) (+ a b))";
+//! let m = kvasir::jit_str(src, kvasir::Options::default(), &kvasir::HostFunctions::new())?;
+//! let args = [JitValue::Int64(1), JitValue::Int64(2)];
+//! assert_eq!(m.call("add", &args), Ok(JitValue::Int64(3)));
+//! ```
+//!
+//! For snapshot tests of the code generator, `functions_ir` returns the LLVM IR of only the
+//! selected functions of a program.
+//!
+//! ```ignore
+//! let irs = kvasir::functions_ir(src, kvasir::Options::default(), &["square"])?;
+//! assert_eq!(irs["square"], include_str!("snapshots/square.ll"));
+//! ```
+//!
+//! With the `testing` feature, `testing` checks that the code generator agrees with constant
+//! folding on the core binops and numeric casts.
+//!
+//! ```ignore
+//! kvasir::testing::check_binop("mul", JitValue::Int8(16), JitValue::Int8(8))?;
+//! ```
+//!
+//! The same functionality is exposed to C in `capi`, and declared in `include/kvasir.h`.
+//!
+//! Errors in the compiled program abort the compilation by unwinding back to the library
+//! function, which returns the captured diagnostics. The library must therefore be built with
+//! `panic = "unwind"`, the default. With `panic = "abort"`, the first error in a program
+//! aborts the whole host process.
+
+#![feature(non_ascii_idents, box_syntax, box_patterns, duration_extras, inner_deref)]
+
+extern crate bitflags;
+#[cfg(feature = "backend-llvm")]
+extern crate cbox;
+#[cfg(feature = "bindgen")]
+extern crate clang_sys;
+extern crate itertools;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+#[cfg(feature = "backend-llvm")]
+extern crate llvm_sys;
+#[macro_use]
+extern crate maplit;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+extern crate stacker;
+extern crate term;
+extern crate toml;
+extern crate unicode_normalization;
+extern crate unicode_xid;
+
+use lib::CanonPathBuf;
+use lib::collections::AddMap;
+use lib::front::{capture_diagnostics, Diagnostic, TypeVarGen};
+use lib::front::inference::infer_types;
+use lib::front::lex::{self, normalize};
+use lib::front::{lint, macros};
+use lib::front::ast::Ast;
+use lib::front::parse::parse_program;
+#[cfg(feature = "backend-llvm")]
+use libc::c_void;
+#[cfg(feature = "backend-llvm")]
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[cfg(feature = "backend-llvm")]
+pub mod capi;
+pub mod lib;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "backend-llvm")]
+pub use lib::back::JitValue;
+
+/// Enum of the different output formats of the compiler
+pub enum Emission {
+    /// Human readable LLVM assembly language code
+    LlvmAsm,
+    /// LLVM bitcode
+    LlvmBc,
+    /// Assembly language code for the target
+    Asm,
+    /// Linkable object code
+    Obj,
+    /// An executable binary
+    Exe,
+    /// A shared library, exporting the functions given by `BuildConfig::exports`
+    Dylib,
+}
+impl<S: AsRef<str> + fmt::Display> From<S> for Emission {
+    fn from(s: S) -> Emission {
+        match s.as_ref() {
+            "llvm-ir" => Emission::LlvmAsm,
+            "llvm-bc" => Emission::LlvmBc,
+            "asm" => Emission::Asm,
+            "obj" => Emission::Obj,
+            "exe" => Emission::Exe,
+            "dylib" => Emission::Dylib,
+            _ => panic!("Unknown emission type `{}`", s),
+        }
+    }
+}
+
+/// Runtime checkers that the program can be instrumented with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitizer {
+    /// AddressSanitizer. Detects out-of-bounds accesses, use after free, and double frees
+    Address,
+    /// UndefinedBehaviorSanitizer. Only the runtime is linked, so undefined behaviour is
+    /// detected in linked C code compiled with `-fsanitize=undefined`, e.g. FFI code
+    Undefined,
+}
+impl Sanitizer {
+    /// The name of the sanitizer, as given to `--sanitize` and `clang -fsanitize`
+    pub fn name(self) -> &'static str {
+        match self {
+            Sanitizer::Address => "address",
+            Sanitizer::Undefined => "undefined",
+        }
+    }
+}
+impl FromStr for Sanitizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "address" => Ok(Sanitizer::Address),
+            "undefined" => Ok(Sanitizer::Undefined),
+            _ => Err(format!("Unknown sanitizer `{}`", s)),
+        }
+    }
+}
+
+/// Kinds of link time optimization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lto {
+    /// Merge all bitcode into a single module, and optimize it as a whole
+    Full,
+    /// Optimize each module in parallel, importing functions from other modules as needed
+    Thin,
+}
+impl FromStr for Lto {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "full" => Ok(Lto::Full),
+            "thin" => Ok(Lto::Thin),
+            _ => Err(format!("Unknown kind of LTO `{}`", s)),
+        }
+    }
+}
+
+/// Configuration of how to build the final output from the AST
+pub struct BuildConfig {
+    pub out_filename: CanonPathBuf,
+    /// Whether `out_filename` was given explicitly, and should be used verbatim
+    /// instead of having its extension replaced according to `emission`
+    pub explicit_out_filename: bool,
+    pub emission: Emission,
+    pub link_libs: Vec<String>,
+    pub lib_paths: Vec<String>,
+    /// Additional arguments to pass verbatim to the linker
+    pub link_args: Vec<String>,
+    /// The target triple to compile for. Defaults to the host
+    pub target: Option<String>,
+    /// The CPU to generate code for, e.g. `skylake` or `native`
+    pub target_cpu: Option<String>,
+    /// Target features to enable or disable, e.g. `+avx2`
+    pub target_features: Vec<String>,
+    /// Whether to generate a test harness, that runs all `def-test`s, as the entry point
+    /// instead of `main`
+    pub test_harness: bool,
+    pub sanitizers: Vec<Sanitizer>,
+    /// Whether to emit bitcode and defer code generation to the linker, which optimizes
+    /// across the program and any libraries compiled with `-flto`. Only affects `Emission::Exe`
+    pub lto: Option<Lto>,
+    /// The global functions to export from a shared library, or from a library compiled with
+    /// `no_main`. Only affects `Emission::Dylib` and `no_main`
+    pub exports: Vec<String>,
+    /// The name of the generated C entry point, which wraps the user-defined `main`.
+    /// Usually `main`, but some platforms expect another entry point
+    pub entry_symbol: String,
+    /// Whether to compile a program without `main` into a library, instead of an
+    /// executable. Exports the functions given by `exports`, or all monomorphic global
+    /// functions if none are given. Not applicable to `Emission::Exe`
+    pub no_main: bool,
+    /// Whether to instrument heap allocations, and have the program print a report of the
+    /// bytes allocated at each allocation site at exit
+    pub profile_heap: bool,
+    /// Whether to keep a shadow stack of the calls in progress, by pushing and popping a frame
+    /// around each call, so that a backtrace can be printed when the program panics
+    pub debug_backtrace: bool,
+    /// The interval in bytes at which stack frames at least as large are probed, so that they
+    /// can not skip past the guard page of the stack. `None` to never probe
+    pub stack_probe_interval: Option<u64>,
+}
+
+/// Options for `compile_str`
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// The name of the source file, as shown in diagnostics. Defaults to `<string>`.
+    /// The file is considered to be in the current directory, which is where imports and
+    /// includes are resolved relative to
+    pub filename: Option<String>,
+    /// Directories to search for imported modules, after the current directory
+    pub src_roots: Vec<PathBuf>,
+    /// The target triple to compile for. Defaults to the host
+    pub target: Option<String>,
+    /// Whether a program compiled by `jit_str` calls its global functions through slots, so
+    /// that they can be replaced by `JitModule::reload`
+    pub hot_reload: bool,
+    /// The levels of the lints. Defaults to the default level of each lint
+    pub lint_levels: lint::Levels,
+    /// The limits on the shape of the source code, beyond which it fails to compile
+    pub lex_limits: lex::Limits,
+    /// Which macro expansions to print with the diagnostics. Defaults to none
+    pub trace_macros: macros::Trace,
+}
+
+/// The rendered errors, warnings, and notes of a compilation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics(pub String);
+
+impl Diagnostics {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The result of a successful compilation
+#[cfg(feature = "backend-llvm")]
+#[derive(Debug, Clone)]
+pub struct CompiledModule {
+    /// The generated module, as human readable LLVM IR
+    pub llvm_ir: String,
+    /// Warnings emitted during compilation
+    pub warnings: Diagnostics,
+}
+
+/// A function of the host application, callable as an extern from programs run by `run_str`
+#[cfg(feature = "backend-llvm")]
+#[derive(Debug, Clone)]
+pub struct HostFunction {
+    pub name: String,
+    /// The Kvasir type of the function, e.g. `(-> Int64 Int64)`
+    pub typ: String,
+    /// Pointer to an `extern "C" fn` matching the type
+    pub addr: *const c_void,
+}
+
+/// The functions of the host application to expose to programs run by `run_str`
+///
+/// The functions are declared as externs in an implicitly imported module, so programs can
+/// call them without any declarations of their own.
+#[cfg(feature = "backend-llvm")]
+#[derive(Debug, Clone, Default)]
+pub struct HostFunctions(Vec<HostFunction>);
+
+#[cfg(feature = "backend-llvm")]
+impl HostFunctions {
+    pub fn new() -> Self {
+        HostFunctions(Vec::new())
+    }
+
+    /// Register the `extern "C" fn` at `addr` as the extern `name` of Kvasir type `typ`
+    pub fn register(&mut self, name: &str, typ: &str, addr: *const c_void) {
+        self.0.push(HostFunction {
+            name: name.to_string(),
+            typ: typ.to_string(),
+            addr,
+        })
+    }
+
+    /// The source code of the module declaring the host functions
+    fn prelude_src(&self) -> String {
+        self.0
+            .iter()
+            .map(|f| format!("(extern {} {})\n", f.name, f.typ))
+            .collect()
+    }
+}
+
+/// Parse and typecheck the program `src`, and pass the AST to `back`
+///
+/// Returns the result of `back`, or `None` if the compilation failed, along with the
+/// diagnostics, which end with the error that failed the compilation.
+///
+/// The sources of `preludes` are implicitly imported into the program, as if they were files
+/// with the paired names.
+fn compile_str_with<R, F>(
+    src: &str,
+    options: &Options,
+    preludes: &[(&str, String)],
+    back: F,
+) -> (Option<R>, Diagnostics)
+where
+    F: FnOnce(&Ast) -> Result<R, Diagnostic>,
+{
+    let virtual_file = |name: &str| {
+        CanonPathBuf::new(".")
+            .map(|dir| dir.join_file_name(OsStr::new(name)))
+            .map_err(|e| Diagnostics(format!("Invalid current directory, {}\n", e)))
+    };
+    let filename = options.filename.as_ref().map(|s| s.as_str()).unwrap_or("<string>");
+    let files = virtual_file(filename).and_then(|file| {
+        preludes
+            .iter()
+            .map(|&(name, _)| virtual_file(name))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|prelude_files| (file, prelude_files))
+    });
+    let (filename, prelude_files) = match files {
+        Ok(files) => files,
+        Err(e) => return (None, e),
+    };
+    let compile = || {
+        let mut type_var_gen = TypeVarGen::new(0);
+        let sources = AddMap::new();
+        sources.add(filename.clone(), normalize(src));
+        for (file, &(_, ref prelude_src)) in prelude_files.iter().zip(preludes) {
+            sources.add(file.clone(), normalize(prelude_src));
+        }
+        let mut ast = parse_program(
+            filename,
+            &sources,
+            &mut type_var_gen,
+            &options.src_roots,
+            &prelude_files,
+        )?;
+        lint::check_denied()?;
+        infer_types(&mut ast, &mut type_var_gen)?;
+        lint::check_denied()?;
+        back(&ast)
+    };
+    let (r, mut diagnostics) = capture_diagnostics(|| {
+        lint::in_compilation(&options.lint_levels, || {
+            lex::in_compilation(options.lex_limits, || {
+                macros::in_compilation(options.trace_macros.clone(), compile)
+            })
+        })
+    });
+    match r {
+        Ok(r) => (Some(r), Diagnostics(diagnostics)),
+        Err(e) => {
+            diagnostics.push_str(&e.0);
+            (None, Diagnostics(diagnostics))
+        }
+    }
+}
+
+/// Parse and typecheck the program with source code `src`, without generating any code, and
+/// return the warnings
+///
+/// Unlike the rest of the compilation functions, this is available without the
+/// `backend-llvm` feature, for tooling that only needs the frontend.
+pub fn check_str(src: &str, options: Options) -> Result<Diagnostics, Diagnostics> {
+    match compile_str_with(src, &options, &[], |_| Ok(())) {
+        (Some(()), warnings) => Ok(warnings),
+        (None, errors) => Err(errors),
+    }
+}
+
+/// Compile the program with source code `src` to LLVM IR
+///
+/// Diagnostics are returned instead of printed, and errors do not exit the process.
+/// Internal compiler errors still panic.
+#[cfg(feature = "backend-llvm")]
+pub fn compile_str(src: &str, options: Options) -> Result<CompiledModule, Diagnostics> {
+    let target = options.target.as_ref().map(|s| s.as_str());
+    match compile_str_with(src, &options, &[], |ast| lib::back::gen_llvm_ir(ast, target)) {
+        (Some(llvm_ir), warnings) => Ok(CompiledModule { llvm_ir, warnings }),
+        (None, errors) => Err(errors),
+    }
+}
+
+/// Compile the program with source code `src`, and return the textual LLVM IR of each of the
+/// functions `names`, by name
+///
+/// This is a hook for snapshot tests of code generation. Compare the IR to a known good
+/// snapshot, instead of only testing the behaviour of the compiled executable.
+#[cfg(feature = "backend-llvm")]
+pub fn functions_ir(
+    src: &str,
+    options: Options,
+    names: &[&str],
+) -> Result<BTreeMap<String, String>, Diagnostics> {
+    match compile_str_with(src, &options, &[], |ast| lib::back::gen_functions_ir(ast, names)) {
+        (Some(irs), Diagnostics(mut diagnostics)) => {
+            let mut found = BTreeMap::new();
+            for (name, ir) in names.iter().zip(irs) {
+                match ir {
+                    Some(ir) => {
+                        found.insert(name.to_string(), ir);
+                    }
+                    None => diagnostics.push_str(&format!(
+                        "Error: Function `{}` not found in the generated module\n",
+                        name
+                    )),
+                }
+            }
+            if found.len() == names.len() {
+                Ok(found)
+            } else {
+                Err(Diagnostics(diagnostics))
+            }
+        }
+        (None, errors) => Err(errors),
+    }
+}
+
+/// A program JIT compiled by `jit_str`
+#[cfg(feature = "backend-llvm")]
+pub struct JitModule {
+    program: lib::back::JitProgram,
+    options: Options,
+    /// Warnings emitted during compilation
+    pub warnings: Diagnostics,
+}
+
+#[cfg(feature = "backend-llvm")]
+impl JitModule {
+    /// Run the program, and return the exit code of `main`
+    pub fn run_main(&self) -> i32 {
+        self.program.run_main()
+    }
+
+    /// Returns the address of the compiled, global function `name`, as an `extern "C" fn`
+    ///
+    /// The global variables are initialized by `jit_str`, so the function may be called
+    /// before `run_main`.
+    pub fn get_function(&self, name: &str) -> Option<*const c_void> {
+        self.program.get_function(name)
+    }
+
+    /// Call the global function `name` with the arguments `args`, and return the result
+    ///
+    /// Only monomorphic functions of primitive parameters and result can be called. A
+    /// function of several parameters is applied to each of `args` in turn. The global
+    /// variables are initialized by `jit_str`, so the function may be called before
+    /// `run_main`.
+    pub fn call(&self, name: &str, args: &[JitValue]) -> Result<JitValue, String> {
+        self.program.call(name, args)
+    }
+
+    /// Call the global function `name` with the arguments `args`, and return the result as
+    /// it would be written in source code, e.g. `(new Just (cons 1 2.5))`
+    ///
+    /// Like `call`, but the result may be of any type. Opaque values, like closures and
+    /// pointers, are shown as their type and address, e.g. `#<(-> Int64 Int64) 0x7f3a...>`.
+    /// If `name` is a global variable, `args` must be empty, and its value is shown.
+    pub fn show(&self, name: &str, args: &[JitValue]) -> Result<String, String> {
+        self.program.show(name, args)
+    }
+
+    /// Replace the global functions of the running program with those of the changed source
+    /// code `src`, and return the warnings
+    ///
+    /// Global variables keep their values. Subsequent calls go to the new functions, also
+    /// from closures and function pointers obtained before the reload. The program must have
+    /// been compiled with `Options::hot_reload`, and the data types, global variables, and
+    /// types of functions must be unchanged. Functions may be added and removed.
+    pub fn reload(&mut self, src: &str, host: &HostFunctions) -> Result<Diagnostics, Diagnostics> {
+        let preludes = [("<host>", host.prelude_src())];
+        let host_addrs = host.0
+            .iter()
+            .map(|f| (f.name.as_str(), f.addr))
+            .collect::<Vec<_>>();
+        let program = &mut self.program;
+        match compile_str_with(src, &self.options, &preludes, |ast| {
+            program.reload(ast, &host_addrs)
+        }) {
+            (Some(()), warnings) => Ok(warnings),
+            (None, errors) => Err(errors),
+        }
+    }
+}
+
+/// JIT compile the program with source code `src` in the current process, and initialize
+/// its global variables
+///
+/// The program need not have a `main`, in which case `run_main` does nothing.
+///
+/// The functions in `host` are callable from the program. Other externs, including the core
+/// library, are resolved by name in the current process, so they must be linked into the
+/// host application with their symbols exported.
+#[cfg(feature = "backend-llvm")]
+pub fn jit_str(
+    src: &str,
+    options: Options,
+    host: &HostFunctions,
+) -> Result<JitModule, Diagnostics> {
+    let preludes = [("<host>", host.prelude_src())];
+    let host_addrs = host.0
+        .iter()
+        .map(|f| (f.name.as_str(), f.addr))
+        .collect::<Vec<_>>();
+    match compile_str_with(src, &options, &preludes, |ast| {
+        lib::back::JitProgram::new(ast, &host_addrs, options.hot_reload)
+    }) {
+        (Some(program), warnings) => Ok(JitModule {
+            program,
+            options,
+            warnings,
+        }),
+        (None, errors) => Err(errors),
+    }
+}
+
+/// JIT compile and run the program with source code `src` in the current process, and return
+/// the exit code of `main`
+///
+/// See `jit_str`.
+#[cfg(feature = "backend-llvm")]
+pub fn run_str(src: &str, options: Options, host: &HostFunctions) -> Result<i32, Diagnostics> {
+    jit_str(src, options, host).map(|m| m.run_main())
+}
+
+#[cfg(all(test, feature = "backend-llvm"))]
+mod test {
+    use super::*;
+
+    /// The declarations of the core binops used in the tests, as in std. Their definitions
+    /// are generated by the code generator
+    const CORE_BINOPS: &str = "(define: (undef _) (-> Nil t) (undef nil))
+(define: (add _) (constrain [(Num t)] (-> (Cons t t) t)) (undef nil))
+(define: (sub _) (constrain [(Num t)] (-> (Cons t t) t)) (undef nil))
+(define: (eq _) (constrain [(Num t)] (-> (Cons t t) Bool)) (undef nil))
+(define: (lt _) (constrain [(Num t)] (-> (Cons t t) Bool)) (undef nil))";
+
+    /// The program `src`, preceded by the declarations of the core binops
+    fn with_core_binops(src: &str) -> String {
+        format!("{}\n{}", CORE_BINOPS, src)
+    }
+
+    fn jit(src: &str) -> JitModule {
+        jit_str(&with_core_binops(src), Options::default(), &HostFunctions::new())
+            .unwrap_or_else(|e| panic!("Failed to compile test program\n{}", e))
+    }
+
+    const ADD_OFFSET: &str = "(define offset (add (cons (: 40 Int64) (: 2 Int64))))
+(define: (add-offset n) (-> Int64 Int64) (add (cons n offset)))
+(define: (add2 a b) (-> Int64 (-> Int64 Int64)) (add (cons a b)))";
+
+    #[test]
+    fn test_call_before_main_sees_initialized_globals() {
+        let m = jit(ADD_OFFSET);
+        assert_eq!(
+            m.call("add-offset", &[JitValue::Int64(1)]),
+            Ok(JitValue::Int64(43))
+        );
+        assert_eq!(m.run_main(), 0);
+        assert_eq!(
+            m.call("add-offset", &[JitValue::Int64(-42)]),
+            Ok(JitValue::Int64(0))
+        );
+    }
+
+    #[test]
+    fn test_call_curried() {
+        let m = jit(ADD_OFFSET);
+        let args = [JitValue::Int64(1), JitValue::Int64(2)];
+        assert_eq!(m.call("add2", &args), Ok(JitValue::Int64(3)));
+    }
+
+    #[test]
+    fn test_call_invalid() {
+        let m = jit(ADD_OFFSET);
+        assert!(m.call("no-such-function", &[]).is_err());
+        assert!(m.call("offset", &[]).is_err());
+        assert!(m.call("add2", &[JitValue::Int64(1)]).is_err());
+        assert!(m.call("add-offset", &[JitValue::Int32(1)]).is_err());
+    }
+
+    #[test]
+    fn test_show_structural() {
+        let m = jit(
+            "(data (Stack a) Bottom (Push a (Stack a)))
+(define pair (cons (: 1 Int64) (cons true 2.5)))
+(define: (some-neg n) (-> Int32 (Maybe Int32))
+  (if (lt (cons n 0)) (new Some n) (new None)))
+(define: (countdown n) (-> UInt8 (Stack UInt8))
+  (if (eq (cons n 0)) (new Bottom) (new Push n (countdown (sub (cons n 1))))))",
+        );
+        assert_eq!(m.show("pair", &[]), Ok("(cons 1 (cons true 2.5))".to_string()));
+        assert_eq!(
+            m.show("some-neg", &[JitValue::Int32(-3)]),
+            Ok("(new Some -3)".to_string())
+        );
+        assert_eq!(
+            m.show("some-neg", &[JitValue::Int32(3)]),
+            Ok("(new None)".to_string())
+        );
+        assert_eq!(
+            m.show("countdown", &[JitValue::UInt8(2)]),
+            Ok("(new Push 2 (new Push 1 (new Bottom)))".to_string())
+        );
+        assert!(m.show("some-neg", &[]).is_err());
+    }
+
+    #[test]
+    fn test_show_opaque() {
+        let m = jit(ADD_OFFSET);
+        assert_eq!(m.show("offset", &[]), Ok("42".to_string()));
+        let m = jit("(define: (adder n) (-> Int64 (-> (Ptr UInt8) Int64)) (lambda (p) n))");
+        let shown = m.show("adder", &[JitValue::Int64(1)]).unwrap();
+        assert!(shown.starts_with("#<(-> (Ptr UInt8) Int64) 0x"), "{}", shown);
+    }
+
+    #[test]
+    fn test_lint_levels_are_per_compilation() {
+        let src = "(define: (f x) (-> Int64 Int64) (let [[y x]] x))";
+        let mut denying = Options::default();
+        denying.lint_levels.set("unused-let-binding", lint::Level::Deny).unwrap();
+        let errors = check_str(src, denying).unwrap_err();
+        assert!(errors.0.contains("[unused-let-binding] is denied"), "{}", errors);
+        // The levels of the previous compilation are not kept
+        let warnings = check_str(src, Options::default()).unwrap();
+        assert!(warnings.0.contains("[unused-let-binding]"), "{}", warnings);
+        assert!(!warnings.0.contains("is denied"), "{}", warnings);
+    }
+
+    #[test]
+    fn test_lex_limits_are_per_compilation() {
+        let src = "(define x (cons 1 (cons 2 3)))";
+        let mut shallow = Options::default();
+        shallow.lex_limits.max_depth = 2;
+        let errors = check_str(src, shallow).unwrap_err();
+        assert!(errors.0.contains("The maximum nesting depth is 2"), "{}", errors);
+        // The limits of the previous compilation are not kept
+        assert_eq!(check_str(src, Options::default()), Ok(Diagnostics::default()));
+    }
+
+    #[test]
+    fn test_macro_trace_is_per_compilation() {
+        let src = "(macro twice [(x) (cons x x)])\n(define x (twice 1))";
+        let mut tracing = Options::default();
+        tracing.trace_macros = macros::Trace::Only("twice".to_string());
+        let trace = check_str(src, tracing).unwrap();
+        assert!(trace.0.contains("twice case 0: (twice 1)\n=> (cons 1 1)"), "{}", trace);
+        // The trace of the previous compilation is not kept
+        assert_eq!(check_str(src, Options::default()), Ok(Diagnostics::default()));
+    }
+
+    #[test]
+    fn test_collection_literals_need_no_import() {
+        let src = "(define: v (LenList Int64) #[1 2 3])
+(define: m (AssocList UInt8 Bool) {1 true 2 false})
+(define: (len xs) (-> (LenList Int64) UIntPtr) (match xs [(LenList n _) n]))";
+        assert_eq!(check_str(src, Options::default()), Ok(Diagnostics::default()));
+    }
+
+    #[test]
+    fn test_match_with_type_params_as_instantiation() {
+        // The parameters of `Swap` are instantiated with type variables of the same names
+        let src = "(data (Swap a b) (Swap a b))
+(define: (swap p) (-> (Swap a b) (Swap b a)) (match p [(Swap x y) (new Swap y x)]))
+(define: (fst p) (-> (Swap a a) a) (match p [(Swap x _) x]))
+(define: (entries m) (-> (AssocList k v) (List (Cons k v))) (match m [(AssocList es) es]))";
+        assert_eq!(check_str(src, Options::default()), Ok(Diagnostics::default()));
+    }
+
+    fn jit_reloadable(src: &str) -> JitModule {
+        let options = Options {
+            hot_reload: true,
+            ..Options::default()
+        };
+        jit_str(&with_core_binops(src), options, &HostFunctions::new())
+            .unwrap_or_else(|e| panic!("Failed to compile test program\n{}", e))
+    }
+
+    #[test]
+    fn test_reload_swaps_functions() {
+        let twice = "(define: (add-offset-twice n) (-> Int64 Int64) (add-offset (add-offset n)))";
+        let mut m = jit_reloadable(&format!("{}\n{}", ADD_OFFSET, twice));
+        assert_eq!(
+            m.call("add-offset-twice", &[JitValue::Int64(1)]),
+            Ok(JitValue::Int64(85))
+        );
+        let changed = ADD_OFFSET.replace("(add (cons n offset))", "(sub (cons n offset))");
+        let changed = with_core_binops(&format!("{}\n{}", changed, twice));
+        let reloaded = m.reload(&changed, &HostFunctions::new());
+        assert_eq!(reloaded, Ok(Diagnostics::default()));
+        // The unchanged `add-offset-twice` calls the new `add-offset` through its slot
+        assert_eq!(
+            m.call("add-offset-twice", &[JitValue::Int64(1)]),
+            Ok(JitValue::Int64(-83))
+        );
+        assert_eq!(m.show("offset", &[]), Ok("42".to_string()));
+    }
+
+    #[test]
+    fn test_reload_rejects_changed_types() {
+        let mut m = jit_reloadable(ADD_OFFSET);
+        let changed = ADD_OFFSET.replace("(-> Int64 Int64)", "(-> Int32 Int64)");
+        let changed = changed.replace("(cons n offset)", "(cons (cast n Int64) offset)");
+        assert!(m.reload(&with_core_binops(&changed), &HostFunctions::new()).is_err());
+        assert_eq!(
+            m.call("add-offset", &[JitValue::Int64(1)]),
+            Ok(JitValue::Int64(43))
+        );
+        let mut m = jit(ADD_OFFSET);
+        assert!(m.reload(&with_core_binops(ADD_OFFSET), &HostFunctions::new()).is_err());
+    }
+}
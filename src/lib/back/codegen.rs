@@ -1,25 +1,50 @@
-use lib::front::{error_exit, exit, SrcPos};
-use lib::front::ast::{self, Expr, Pattern};
-use lib::{map_of, set_of, ErrCode};
+use lib::front::{Diagnostic, SrcPos};
+use lib::front::ast;
+use lib::{grow_stack, ice, map_of, ErrCode};
+use lib::mangle::{demangle, mangle_inst};
 use llvm_sys;
 use llvm_sys::prelude::*;
 use llvm_sys::target::LLVMTargetDataRef;
 use std::{fmt, mem};
 use std::cell::RefCell;
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet};
 use std::str::FromStr;
 use super::llvm::*;
 use super::gc::*;
+use super::trace::*;
+use super::thread::*;
+use super::show::*;
+use super::mir::{self, is_arithm_binop, is_relational_binop};
 use self::CodegenErr::*;
 use itertools::Itertools;
 
+/// The LLVM calling convention of the extern calling convention `cc` on the target `triple`
+fn llvm_call_conv(cc: ast::CallConv, triple: &str) -> u32 {
+    use llvm_sys::LLVMCallConv::*;
+    let is_x86_32_windows = triple.contains("windows")
+        && ["i386", "i486", "i586", "i686", "x86-"]
+            .iter()
+            .any(|arch| triple.starts_with(arch));
+    let llvm_cc = match cc {
+        ast::CallConv::C => LLVMCCallConv,
+        ast::CallConv::Stdcall => LLVMX86StdcallCallConv,
+        ast::CallConv::Fastcall => LLVMX86FastcallCallConv,
+        ast::CallConv::System if is_x86_32_windows => LLVMX86StdcallCallConv,
+        ast::CallConv::System => LLVMCCallConv,
+    };
+    llvm_cc as u32
+}
+
 /// Runtime errors
 #[derive(PartialEq, Eq)]
-enum RuntErr<'s> {
+enum RuntErr<'s, 'a> {
     NonExhaustPatts(SrcPos<'s>),
+    AssertFailed(SrcPos<'s>, &'a str),
+    /// A `cast-checked` of a value out of the range of the target type, given by name
+    CastOutOfRange(SrcPos<'s>, &'a str),
 }
 
-impl<'s> RuntErr<'s> {
+impl<'s, 'a> RuntErr<'s, 'a> {
     fn code(&self) -> ErrCode {
         fn e(n: usize) -> ErrCode {
             ErrCode {
@@ -29,6 +54,8 @@ impl<'s> RuntErr<'s> {
         }
         match *self {
             RuntErr::NonExhaustPatts(..) => e(0),
+            RuntErr::AssertFailed(..) => e(1),
+            RuntErr::CastOutOfRange(..) => e(2),
         }
     }
 
@@ -39,107 +66,26 @@ impl<'s> RuntErr<'s> {
                 code,
                 format!("Non-exhaustive patterns in match. Fell all the way through!"),
             ),
+            RuntErr::AssertFailed(ref pos, msg) => pos.error_string(code, msg),
+            RuntErr::CastOutOfRange(ref pos, typ) => pos.error_string(
+                code,
+                format!("Value out of the range of `{}` in checked cast", typ),
+            ),
         }
     }
 }
 
-type Instantiations<'src> = BTreeSet<(Vec<ast::Type<'src>>, ast::Type<'src>)>;
-type FreeVarInsts<'src> = BTreeMap<&'src str, Instantiations<'src>>;
+/// The size in bytes of the largest ADT variant that is always stored inline
+const MAX_UNBOXED_VARIANT_SIZE: u64 = 64;
+
+/// How many times larger than the smallest variant of its ADT a variant larger than
+/// `MAX_UNBOXED_VARIANT_SIZE` must be to be boxed
+const BOXED_VARIANT_SIZE_RATIO: u64 = 4;
 
 fn type_generic_ptr(ctx: &Context) -> &Type {
     PointerType::new(Type::get::<u8>(ctx))
 }
 
-fn free_vars_in_exprs<'a, 'src: 'a, T>(es: T) -> FreeVarInsts<'src>
-where
-    T: IntoIterator<Item = &'a ast::Expr<'src>>,
-{
-    let mut fvs = BTreeMap::new();
-    for (k, v) in es.into_iter().flat_map(|e2| free_vars_in_expr(e2)) {
-        fvs.entry(k).or_insert(BTreeSet::new()).extend(v)
-    }
-    fvs
-}
-
-/// Returns a map of the free variables in `e`, where each variable name is mapped to the
-/// instantiations of the free variable in `e`
-fn free_vars_in_expr<'src>(e: &ast::Expr<'src>) -> FreeVarInsts<'src> {
-    use self::ast::Expr::*;
-    match *e {
-        Nil(_) | NumLit(_) | StrLit(_) | Bool(_) => FreeVarInsts::new(),
-        Variable(ref v) => {
-            map_of(
-                v.ident.s,
-                set_of((
-                    v.typ.get_inst_args().unwrap_or(&[]).to_vec(),
-                    // Apply instantiation to type to remove wrapping `App`
-                    v.typ.canonicalize(),
-                )),
-            )
-        }
-        App(box ref a) => free_vars_in_exprs([&a.func, &a.arg].iter().cloned()),
-        If(ref i) => free_vars_in_exprs(
-            [&i.predicate, &i.consequent, &i.alternative]
-                .iter()
-                .cloned(),
-        ),
-        Lambda(box ref l) => free_vars_in_lambda(l),
-        Let(box ref l) => {
-            let mut es = vec![&l.body];
-            for binding in l.bindings.bindings() {
-                if binding.sig.is_monomorphic() {
-                    es.push(&binding.val)
-                } else {
-                    es.extend(binding.mono_insts.values())
-                }
-            }
-            let mut fvs = free_vars_in_exprs(es.iter().cloned());
-            for b in l.bindings.bindings() {
-                fvs.remove(b.ident.s);
-            }
-            fvs
-        }
-        TypeAscript(_) => panic!("free_vars_in_expr encountered TypeAscript"),
-        Cons(box ref c) => free_vars_in_exprs([&c.car, &c.cdr].iter().cloned()),
-        Car(box ref c) => free_vars_in_expr(&c.expr),
-        Cdr(box ref c) => free_vars_in_expr(&c.expr),
-        Cast(ref c) => free_vars_in_expr(&c.expr),
-        New(ref n) => free_vars_in_exprs(&n.members),
-        Match(ref m) => free_vars_in_match(m),
-    }
-}
-
-/// Returns a map of the free variables in `e`, where each variable name is mapped to the
-/// instantiations of the free variable in `e`
-fn free_vars_in_lambda<'src>(lam: &ast::Lambda<'src>) -> FreeVarInsts<'src> {
-    let mut free_vars = free_vars_in_expr(&lam.body);
-    free_vars.remove(lam.param_ident.s);
-    free_vars
-}
-
-/// Get free variables of `lam`, but filter out externs
-fn free_vars_in_lambda_filter_globals<'src, 'ctx>(
-    env: &Env<'src, 'ctx>,
-    lam: &ast::Lambda<'src>,
-) -> FreeVarInsts<'src> {
-    free_vars_in_lambda(&lam)
-        .into_iter()
-        .filter(|&(k, _)| env.locals.contains_key(k))
-        .collect::<FreeVarInsts>()
-}
-
-fn free_vars_in_match<'src>(m: &ast::Match<'src>) -> FreeVarInsts<'src> {
-    let mut fvs = free_vars_in_expr(&m.expr);
-    for case in &m.cases {
-        let mut case_fvs = free_vars_in_expr(&case.body);
-        for v in case.patt.variables() {
-            case_fvs.remove(v.ident.s);
-        }
-        fvs.extend(case_fvs);
-    }
-    fvs
-}
-
 enum CodegenErr {
     NumParseErr(String),
     ICE(String),
@@ -160,6 +106,27 @@ impl fmt::Display for CodegenErr {
     }
 }
 
+fn llvm_ordering(o: ast::AtomicOrdering) -> llvm_sys::LLVMAtomicOrdering {
+    use self::ast::AtomicOrdering::*;
+    use llvm_sys::LLVMAtomicOrdering::*;
+    match o {
+        Relaxed => LLVMAtomicOrderingMonotonic,
+        Acquire => LLVMAtomicOrderingAcquire,
+        Release => LLVMAtomicOrderingRelease,
+        AcqRel => LLVMAtomicOrderingAcquireRelease,
+        SeqCst => LLVMAtomicOrderingSequentiallyConsistent,
+    }
+}
+
+/// The ordering of the load in a failed compare-and-swap, which may not have release semantics
+fn llvm_failure_ordering(o: ast::AtomicOrdering) -> llvm_sys::LLVMAtomicOrdering {
+    match o {
+        ast::AtomicOrdering::Release => llvm_sys::LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic,
+        ast::AtomicOrdering::AcqRel => llvm_sys::LLVMAtomicOrdering::LLVMAtomicOrderingAcquire,
+        _ => llvm_ordering(o),
+    }
+}
+
 fn opt_set_name<'ctx>(v: &'ctx Value, name: Option<&str>) -> &'ctx Value {
     if let Some(name_) = name {
         v.set_name(name_);
@@ -173,11 +140,14 @@ fn opt_set_name<'ctx>(v: &'ctx Value, name: Option<&str>) -> &'ctx Value {
 /// it's also used for external linkage. If function is used as a
 /// value, e.g. put in a list together with arbitrary functions, we
 /// have to wrap it in a closure so that it can be called in the same
-/// way as any other function.
+/// way as any other function. The wrapper is only generated for
+/// functions that are used as values, on first such use.
 #[derive(Debug, Clone, Copy)]
 struct GlobFunc<'ctx> {
     func: &'ctx Function,
-    closure: &'ctx Value,
+    /// The slot of the function in the indirection table, through which it's called so that
+    /// it can be replaced when hot reloading, if it's reloadable
+    slot: Option<&'ctx GlobalVariable>,
 }
 
 /// A global variable/function (includes externs)
@@ -185,36 +155,68 @@ struct GlobFunc<'ctx> {
 enum Global<'ctx> {
     Func(GlobFunc<'ctx>),
     Var(&'ctx GlobalVariable),
+    /// A global variable defined in a `lazy` form, read by calling its getter
+    LazyVar(&'ctx Function),
 }
 
-/// A variable in the environment
-///
-/// Either a global variable/function (includes externs), or
-/// a local variable (includes both functions/closures and other
-/// values).
-enum Var<'ctx> {
-    Global(Global<'ctx>),
-    Local(&'ctx Value),
+/// The locals of the function being generated
+#[derive(Debug)]
+struct Locals<'src, 'ctx> {
+    decls: Vec<mir::LocalDecl<'src>>,
+    vals: Vec<Option<&'ctx Value>>,
+}
+
+impl<'src, 'ctx> Locals<'src, 'ctx> {
+    fn new(decls: &[mir::LocalDecl<'src>]) -> Self {
+        Locals {
+            decls: decls.to_vec(),
+            vals: vec![None; decls.len()],
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Env<'src, 'ctx> {
+pub struct Env<'src, 'ctx> {
     globs: BTreeMap<String, BTreeMap<Vec<ast::Type<'src>>, Global<'ctx>>>,
-    locals: BTreeMap<String, Vec<BTreeMap<Vec<ast::Type<'src>>, &'ctx Value>>>,
+    locals: Locals<'src, 'ctx>,
 }
 
 impl<'src, 'ctx> Env<'src, 'ctx> {
     fn new() -> Self {
         Env {
             globs: BTreeMap::new(),
-            locals: BTreeMap::new(),
+            locals: Locals::new(&[]),
         }
     }
 
-    fn get_local(&self, s: &str, ts: &[ast::Type]) -> Option<&'ctx Value> {
-        let scopes = self.locals.get(s)?;
-        let insts = scopes.last()?;
-        insts.get(ts).map(|&x| x)
+    /// Enter a function with the locals `decls`. Returns the locals of the enclosing
+    /// function, to be restored with `leave`
+    fn enter(&mut self, decls: &[mir::LocalDecl<'src>]) -> Locals<'src, 'ctx> {
+        mem::replace(&mut self.locals, Locals::new(decls))
+    }
+
+    fn leave(&mut self, parent_locals: Locals<'src, 'ctx>) {
+        self.locals = parent_locals
+    }
+
+    fn get_local(&self, l: mir::Local) -> &'ctx Value {
+        self.locals.vals[l].unwrap_or_else(|| panic!("ICE: Local %{} used before definition", l))
+    }
+
+    fn set_local(&mut self, l: mir::Local, val: &'ctx Value) {
+        self.locals.vals[l] = Some(val)
+    }
+
+    fn local_name(&self, l: mir::Local) -> Option<&'src str> {
+        self.locals.decls[l].name
+    }
+
+    fn local_type(&self, l: mir::Local) -> &ast::Type<'src> {
+        &self.locals.decls[l].typ
+    }
+
+    fn atom_type(&self, a: &mir::Atom<'src>) -> ast::Type<'src> {
+        a.typ(&self.locals.decls)
     }
 
     fn get_global(&self, s: &str, ts: &[ast::Type]) -> Option<Global<'ctx>> {
@@ -226,12 +228,6 @@ impl<'src, 'ctx> Env<'src, 'ctx> {
         self.get_global(s, &[])
     }
 
-    fn get(&self, s: &str, ts: &[ast::Type]) -> Option<Var<'ctx>> {
-        self.get_local(s, ts)
-            .map(|l| Var::Local(l))
-            .or_else(|| self.get_global(s, ts).map(|g| Var::Global(g)))
-    }
-
     fn add_global(&mut self, id: &str, var: BTreeMap<Vec<ast::Type<'src>>, Global<'ctx>>) {
         self.globs.insert(id.to_string(), var);
     }
@@ -240,41 +236,12 @@ impl<'src, 'ctx> Env<'src, 'ctx> {
         self.add_global(id, map_of(vec![], var))
     }
 
-    fn push_local(&mut self, id: &str, var: BTreeMap<Vec<ast::Type<'src>>, &'ctx Value>) {
-        self.locals
-            .entry(id.to_string())
-            .or_insert(Vec::new())
-            .push(var)
-    }
-
-    fn push_local_mono(&mut self, id: &str, var: &'ctx Value) {
-        self.push_local(id, map_of(vec![], var))
-    }
-
     fn add_global_inst(&mut self, id: &str, inst: Vec<ast::Type<'src>>, val: Global<'ctx>) {
         let insts = self.globs.entry(id.to_string()).or_insert(BTreeMap::new());
         if insts.insert(inst, val).is_some() {
             panic!("ICE: val already exists for inst")
         }
     }
-
-    fn add_local_inst(&mut self, id: &str, inst: Vec<ast::Type<'src>>, val: &'ctx Value) {
-        let v = self.locals.entry(id.to_string()).or_insert(Vec::new());
-        if v.is_empty() {
-            v.push(BTreeMap::new());
-        }
-        if v.last_mut().unwrap().insert(inst, val).is_some() {
-            panic!("ICE: val already exists for inst")
-        }
-    }
-
-    fn pop_local(&mut self, id: &str) -> BTreeMap<Vec<ast::Type<'src>>, &'ctx Value> {
-        self.locals
-            .get_mut(id)
-            .expect("ICE: Var not in env")
-            .pop()
-            .expect("ICE: Popped empty `vars` of env")
-    }
 }
 
 struct NamedTypes<'ctx, 'src> {
@@ -282,48 +249,188 @@ struct NamedTypes<'ctx, 'src> {
     real_world: &'ctx Type,
     adts: BTreeMap<(&'src str, Vec<ast::Type<'src>>), &'ctx Type>,
     adts_inner: BTreeMap<(&'src str, Vec<ast::Type<'src>>), &'ctx Type>,
+    /// The opaque structs of the foreign types declared with `extern-type`
+    extern_types: BTreeMap<&'src str, &'ctx Type>,
 }
 
-type MonoFuncBinding<'src, 'ast> = (&'src str, &'ast [ast::Type<'src>], &'ast ast::Lambda<'src>);
-type MonoVarBinding<'src, 'ast> = (&'src str, &'ast [ast::Type<'src>], &'ast Expr<'src>);
-
-fn separate_func_bindings_mono<'src, 'ast>(
-    bindings: &[&'ast ast::Binding<'src>],
-) -> (
-    Vec<MonoFuncBinding<'src, 'ast>>,
-    Vec<MonoVarBinding<'src, 'ast>>,
-) {
-    // Flatten with regards to mono insts
-    let mut func_binding_insts = Vec::<MonoFuncBinding>::new();
-    let mut var_binding_insts = Vec::<MonoVarBinding>::new();
-    for binding in bindings {
-        let mono_insts = if binding.sig.is_monomorphic() {
-            vec![(binding.ident.s, &[][..], &binding.val)]
-        } else {
-            binding
-                .mono_insts
-                .iter()
-                .map(|(inst_ts, val_inst)| (binding.ident.s, inst_ts.as_slice(), val_inst))
-                .collect()
-        };
-        for (name, inst, val) in mono_insts {
-            match val {
-                ast::Expr::Lambda(ref lam) => func_binding_insts.push((name, inst, lam)),
-                ref e => var_binding_insts.push((name, inst, e)),
-            }
+/// A hash of `bytes` that is stable across compilations, platforms, and versions of the
+/// compiler, for symbol names derived from the contents of constants. 64-bit FNV-1a
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// The base symbol name of the instance `inst` of the global `name`. The source name if
+/// monomorphic, and otherwise mangled as described in `lib::mangle`
+fn inst_symbol(name: &str, inst: &[ast::Type]) -> String {
+    if inst.is_empty() {
+        name.to_string()
+    } else {
+        mangle_inst(name, inst)
+    }
+}
+
+/// Check that the exported function `name` is defined, and is a monomorphic function, so that
+/// it's generated as a single function with the source name as symbol
+fn check_export(ast: &ast::Ast, name: &str) -> Result<(), Diagnostic> {
+    let binding = ast.globals
+        .bindings()
+        .find(|b| b.ident.s == name)
+        .ok_or_else(|| Diagnostic::error(format!("exported function `{}` not found", name)))?;
+    if !binding.sig.is_monomorphic() || binding.sig.body.get_func().is_none() {
+        return Err(binding.pos.diagnostic(format!(
+            "Exported function `{}` must be a monomorphic function, found type `{}`",
+            name,
+            binding.sig.pretty()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether values of type `t` can be passed as is between the host and a JIT compiled program
+fn is_primitive(t: &ast::Type) -> bool {
+    t.is_int() || t.is_uint() || t.is_float() || match t.get_const() {
+        Some("Bool") | Some("Nil") => true,
+        _ => false,
+    }
+}
+
+/// The longest prefix of primitive, curried parameter types of the function type `t`, and the
+/// type of the result of applying the function to them
+///
+/// If `t` is not a function, or its first parameter is not primitive, there are no parameters
+/// and `t` is the result.
+fn primitive_params_and_result<'t, 's>(
+    t: &'t ast::Type<'s>,
+) -> (Vec<&'t ast::Type<'s>>, &'t ast::Type<'s>) {
+    let mut params = Vec::new();
+    let mut t = t;
+    while let Some((arg, ret)) = t.get_func() {
+        if !is_primitive(arg) {
+            break;
         }
+        params.push(arg);
+        t = ret;
+    }
+    (params, t)
+}
+
+/// A thunk generated by `CodeGenerator::gen_call_thunks`
+#[derive(Debug, Clone)]
+pub struct CallThunk {
+    /// The name of the global function or variable called by the thunk
+    pub name: String,
+    /// The symbol of the thunk that writes the result, if it's a function with a primitive
+    /// result
+    pub symbol: Option<String>,
+    /// The symbol of the thunk that writes the result as source code to a host `String`
+    pub show_symbol: String,
+    /// The types of the curried, primitive parameters of the function. Empty for a variable
+    pub params: Vec<String>,
+    /// The result type of the function
+    pub ret: String,
+}
+
+/// The symbols of a program generated by `CodeGenerator::gen_jit`
+#[derive(Debug, Clone, Default)]
+pub struct JitSymbols {
+    pub call_thunks: Vec<CallThunk>,
+    /// The global variables that hold state of the program, i.e. the global variables of the
+    /// source, the flags of lazy globals, and the slots of the reloadable functions
+    pub state: Vec<String>,
+    /// The slot of each reloadable function, and the function
+    pub slots: Vec<(String, String)>,
+}
+
+fn codegen_err(n: usize) -> ErrCode {
+    ErrCode {
+        module: "codegen",
+        number: n,
     }
-    (func_binding_insts, var_binding_insts)
 }
 
-fn is_arithm_binop(op_name: &str) -> bool {
-    let arithm_binops = hashset!{ "add", "sub", "mul", "div" };
-    arithm_binops.contains(op_name)
+/// Whether the name `name` looks like a misspelling of `main` in case or punctuation, like
+/// `Main`, `_main`, or `main'`
+///
+/// Names at a small edit distance from `main`, like `mail` or `man`, are too often
+/// legitimate to suggest.
+fn is_near_main(name: &str) -> bool {
+    name.trim_matches(|c: char| !c.is_alphanumeric())
+        .eq_ignore_ascii_case("main")
+}
+
+/// Find the user-defined `main` of `ast`, and check that it is of type `expect` or
+/// `expect_with_args`
+///
+/// Returns whether `main` is of type `expect_with_args`. Errors if `main` is not found, listing
+/// the bindings of the main module with names similar to `main`.
+fn check_main(
+    ast: &ast::Ast,
+    expect: &ast::Type,
+    expect_with_args: &ast::Type,
+) -> Result<bool, Diagnostic> {
+    let main = match ast.globals.bindings().find(|b| b.ident.s == "main") {
+        Some(main) => main,
+        None => {
+            let module = match ast.main_file {
+                Some(ref f) => format!(" in the main module `{}`", f.display()),
+                None => String::new(),
+            };
+            let mut err = Diagnostic::error_code(
+                codegen_err(0),
+                format!("`main` function not found{}", module),
+            );
+            let in_main_module = |b: &&ast::Binding| match ast.main_file {
+                Some(ref f) => b.pos.filename() == f.as_path(),
+                None => true,
+            };
+            for b in ast.globals
+                .bindings()
+                .filter(in_main_module)
+                .filter(|b| is_near_main(b.ident.s))
+            {
+                err = err.help(
+                    &b.ident.pos,
+                    format!("`{}` is similar. Did you mean `main`?", b.ident.s),
+                )
+            }
+            return Err(err.note(
+                "The entry point of a program is defined like \
+                 `(define (main real-world) ...)`. To compile a library without an entry \
+                 point, use `--no-main`",
+            ));
+        }
+    };
+    let takes_args = main.sig.body == *expect_with_args;
+    if main.sig.body != *expect && !takes_args {
+        let error_msg = format!(
+            "`main` has the wrong type. Expected type `{}` or `{}`, found type `{}`",
+            expect.pretty(),
+            expect_with_args.pretty(),
+            main.sig.pretty()
+        );
+        let pos = main.sig_pos.as_ref().unwrap_or(&main.ident.pos);
+        let mut err = Diagnostic(pos.error_string(codegen_err(1), error_msg));
+        if !main.sig.is_monomorphic() {
+            err = err.help(
+                pos,
+                "Try adding type annotations to enforce correct type during type-checking.\n\
+                 E.g. `(define: main (IO Nil) ...)`",
+            );
+        }
+        return Err(err);
+    }
+    Ok(takes_args)
 }
 
-fn is_relational_binop(op_name: &str) -> bool {
-    let relational_binops = hashset!{ "eq", "lt" };
-    relational_binops.contains(op_name)
+/// A scope pushed on the GC stack of the runtime, that is popped when leaving the scope
+#[derive(Clone, Copy)]
+enum GcScope {
+    /// The scope of the bindings of a `let`
+    Let,
+    /// The arena of a `with-arena`
+    Arena,
 }
 
 /// A codegenerator that visits all nodes in the AST, wherein it builds expressions
@@ -334,9 +441,49 @@ pub struct CodeGenerator<'ctx, 'src> {
     /// The function currently being built
     current_func: RefCell<Option<&'ctx Function>>,
     current_block: RefCell<Option<&'ctx BasicBlock>>,
+    /// The header block and the phi nodes of the variables of each enclosing loop in the
+    /// current function, and the depth of `gc_scopes` at the loop, innermost last
+    loops: Vec<(&'ctx BasicBlock, Vec<&'ctx Value>, usize)>,
+    /// The GC scopes pushed by the enclosing `let`s and `with-arena`s in the current
+    /// function, innermost last
+    gc_scopes: Vec<GcScope>,
     named_types: NamedTypes<'ctx, 'src>,
     adts: ast::Adts<'src>,
     gc: Gc<'ctx, 'src>,
+    shadow_stack: ShadowStack<'ctx>,
+    threads: Threads<'ctx>,
+    show: Show<'ctx, 'src>,
+    /// The lambdas without captures that have been lifted to global functions
+    lifted_lambdas: Vec<(mir::Func<'src>, GlobFunc<'ctx>)>,
+    /// The constant data of each distinct string literal
+    str_lits: RefCell<BTreeMap<String, &'ctx GlobalVariable>>,
+    /// The symbol table. The interned global of each symbol, by name
+    symbols: BTreeMap<&'src str, &'ctx Value>,
+    /// The closure wrappers of the global functions that are used as values, by function name
+    closure_wrappers: BTreeMap<String, &'ctx Value>,
+    /// Whether to record heap allocations in a profile, by allocation site
+    profile_heap: bool,
+    /// The location of the innermost expression being generated, to which allocations are
+    /// attributed in the heap profile
+    alloc_site: RefCell<Option<String>>,
+    /// The interval in bytes at which to probe large stack frames, or `None` to not probe
+    stack_probe_interval: Option<u64>,
+    /// The number of symbols that have been named from each base name
+    symbol_counts: RefCell<BTreeMap<String, usize>>,
+    /// Whether all global functions get external linkage, and not only the exported ones, so
+    /// that the host of a JIT compiled program can look them up by name
+    export_all: bool,
+    /// Whether global functions are called through slots in an indirection table, so that
+    /// they can be replaced when hot reloading
+    hot_reload: bool,
+    /// The symbols of the state globals that are declared instead of defined, to be linked to
+    /// those of an earlier version of the program when hot reloading
+    linked_state: BTreeSet<String>,
+    /// The symbols of the global variables that hold state of the program. See
+    /// `add_state_global`
+    state_globals: Vec<String>,
+    /// The symbol of the slot of each reloadable function, and of the function
+    slots: Vec<(String, String)>,
 }
 
 impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
@@ -345,26 +492,90 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         builder: &'ctx Builder,
         module: &'ctx Module,
         adts: ast::Adts<'src>,
+        extern_types: &BTreeMap<&'src str, SrcPos<'src>>,
+        profile_heap: bool,
+        debug_backtrace: bool,
+        stack_probe_interval: Option<u64>,
     ) -> Self {
         let named_types = NamedTypes {
             real_world: StructType::new_named(ctx, "RealWorld", &[], false),
             nil: StructType::new_named(ctx, "Nil", &[], false),
             adts: BTreeMap::new(),
             adts_inner: BTreeMap::new(),
+            extern_types: extern_types
+                .keys()
+                .map(|&name| {
+                    let t: &Type = StructType::new_opaque(ctx, name);
+                    (name, t)
+                })
+                .collect(),
         };
         let gc = Gc::new(ctx, module, builder);
+        let shadow_stack = ShadowStack::new(ctx, module, builder, debug_backtrace);
+        let threads = Threads::new(ctx, module, builder);
+        let show = Show::new(ctx, module, builder);
         CodeGenerator {
             ctx,
             module,
             builder,
             current_func: RefCell::new(None),
             current_block: RefCell::new(None),
+            loops: Vec::new(),
+            gc_scopes: Vec::new(),
             named_types,
             adts,
             gc,
+            shadow_stack,
+            threads,
+            show,
+            lifted_lambdas: Vec::new(),
+            str_lits: RefCell::new(BTreeMap::new()),
+            symbols: BTreeMap::new(),
+            closure_wrappers: BTreeMap::new(),
+            profile_heap,
+            alloc_site: RefCell::new(None),
+            stack_probe_interval,
+            symbol_counts: RefCell::new(BTreeMap::new()),
+            export_all: false,
+            hot_reload: false,
+            linked_state: BTreeSet::new(),
+            state_globals: Vec::new(),
+            slots: Vec::new(),
         }
     }
 
+    /// Get a name for a new symbol of the base name `base`, that is not taken in the module
+    ///
+    /// The first symbol of a base gets the base itself as name, and the following get the
+    /// suffix `.N`, where `N` counts the symbols of the base. So unlike the implicit renaming
+    /// done by LLVM on collision, the name of a symbol doesn't depend on the order of
+    /// generation of unrelated symbols.
+    fn unique_symbol(&self, base: &str) -> String {
+        let mut counts = self.symbol_counts.borrow_mut();
+        let count = counts.entry(base.to_string()).or_insert(0);
+        loop {
+            let name = if *count == 0 {
+                base.to_string()
+            } else {
+                format!("{}.{}", base, count)
+            };
+            *count += 1;
+            if self.module.get_function(&name).is_none() && self.module.get_global(&name).is_none()
+            {
+                return name;
+            }
+        }
+    }
+
+    /// Get a name for a new internal symbol of the base name `base`, generated for the code
+    /// at `pos`
+    ///
+    /// The name includes the file and line of `pos`, e.g. `lambda_main_f@foo.kvs:12`, so that
+    /// generated functions can be told apart in the output of a profiler.
+    fn internal_symbol(&self, base: &str, pos: &SrcPos) -> String {
+        self.unique_symbol(&format!("{}@{}", base, pos.short_location()))
+    }
+
     fn new_nil_val(&self) -> &'ctx Value {
         Value::new_undef(self.named_types.nil)
     }
@@ -442,11 +653,21 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             ast::Type::Const("Float64", _) => Type::get::<f64>(self.ctx),
             ast::Type::Const("Nil", _) => self.named_types.nil,
             ast::Type::Const("RealWorld", _) => self.named_types.real_world,
+            // A pointer to the interned length and name of the symbol
+            ast::Type::Const("Symbol", _) => PointerType::new(StructType::new(
+                self.ctx,
+                &[self.gen_int_ptr_type_(), type_generic_ptr(self.ctx)],
+                false,
+            )),
             // It's not a builtin type, which means it has to be a user-defined
             // algebraic data type, unless bug in typechecker.
             ast::Type::Const(name, _) if self.adts.defs.contains_key(name) => {
                 self.get_or_gen_adt_by_name_and_inst(name, &[])
             }
+            // An opaque foreign type. Only ever used behind a pointer
+            ast::Type::Const(name, _) if self.named_types.extern_types.contains_key(name) => {
+                self.named_types.extern_types[name]
+            }
             ast::Type::App(box ast::TypeFunc::Const(s), ref ts) => match s {
                 "->" => {
                     let fp = PointerType::new(self.gen_func_type(&ts[0], &ts[1]));
@@ -459,6 +680,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     false,
                 ),
                 "Ptr" => PointerType::new(self.gen_type(&ts[0])),
+                // A handle to a thread, channel, or mutex in the runtime
+                "Thread" | "Chan" | "Mutex" | "Task" => type_generic_ptr(self.ctx),
                 // It's not a builtin type function, which means it
                 // has to be a user-defined algebraic data type,
                 // unless bug in typechecker.
@@ -477,41 +700,24 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         }
     }
 
-    /// Generate the LLVM Type of an algebraic data type
+    /// Define the LLVM Type of an algebraic data type, created opaque
     ///
     /// ADTs are equivalent to tagged unions, and are represented as a pair of a 16 bit tag,
-    /// and the type of the largest variant.
-    fn gen_adt(&mut self, adt: &ast::AdtDef<'src>, inst: &[ast::Type<'src>]) -> &'ctx Type {
-        let tag_type = Type::get::<u16>(self.ctx);
-        let largest_type = self.gen_largest_adt_variant_type(adt, inst);
-        StructType::new_named(self.ctx, adt.name.s, &[tag_type, largest_type], false)
-    }
-
-    /// When ADT is recursive, it needs to be kept behind pointer
-    /// as to not become infinitely large
-    fn populate_recursive_adt(
+    /// and the type of the largest variant, as stored. See `gen_adt_variant_types`.
+    ///
+    /// The type is created opaque before being defined, as a boxed variant may refer to the
+    /// ADT itself.
+    fn populate_adt(
         &mut self,
         adt: &ast::AdtDef<'src>,
         inst: &[ast::Type<'src>],
-        inner_struct_type: &'ctx StructType,
+        struct_type: &'ctx StructType,
     ) {
         let tag_type = Type::get::<u16>(self.ctx);
-        let variant_types = adt.variants
-            .iter()
-            .map(|v| {
-                let t = self.adts
-                    .type_with_inst_of_variant(v, inst)
-                    .expect("ICE: get_variant_type_with_inst failed in populate_recursive_adt");
-                self.gen_type(&t)
-            })
-            .collect::<Vec<_>>();
-        let largest_type = variant_types
-            .into_iter()
-            .max_by_key(|t| self.size_of_(t))
-            .unwrap_or(self.named_types.nil);
-        inner_struct_type
+        let largest_type = self.gen_largest_adt_variant_type(adt, inst);
+        struct_type
             .set_elements(&[tag_type, largest_type], false)
-            .expect("ICE: non-opaque struct in populate_recursive_adt");
+            .expect("ICE: non-opaque struct in populate_adt");
     }
 
     fn get_or_gen_adt_by_name_and_inst(
@@ -532,6 +738,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     )
                 })
                 .clone();
+            // When ADT is recursive, it needs to be kept behind pointer
+            // as to not become infinitely large
             if self.adts.adt_is_recursive(&adt) {
                 let inner = StructType::new_opaque(self.ctx, &format!("{}_in", name));
                 self.named_types
@@ -539,24 +747,23 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     .insert((name, inst.to_vec()), inner);
                 let ptr = PointerType::new(inner);
                 self.named_types.adts.insert((name, inst.to_vec()), ptr);
-                self.populate_recursive_adt(&adt, inst, inner);
+                self.populate_adt(&adt, inst, inner);
             } else {
-                let t = self.gen_adt(&adt, inst);
+                let t = StructType::new_opaque(self.ctx, name);
                 self.named_types.adts.insert((name, inst.to_vec()), t);
+                self.populate_adt(&adt, inst, t);
             }
             self.get_or_gen_adt_by_name_and_inst(name, inst)
         }
     }
 
-    /// Returns the type of the free variables when captured
-    fn captures_type_of_free_vars(&mut self, free_vars: &FreeVarInsts<'src>) -> &'ctx Type {
-        let mut captures_types = Vec::new();
-        for (_, insts) in free_vars {
-            for &(_, ref typ) in insts {
-                captures_types.push(typ);
-            }
-        }
-        self.gen_struct_type(captures_types)
+    /// Returns the type of the locals `captures` when captured
+    fn captures_type(&mut self, env: &Env<'src, 'ctx>, captures: &[mir::Local]) -> &'ctx Type {
+        let captures_types = captures
+            .iter()
+            .map(|&l| env.local_type(l).clone())
+            .collect::<Vec<_>>();
+        self.gen_struct_type(&captures_types)
     }
 
     fn gen_struct_type<'a, I>(&mut self, types: I) -> &'ctx Type
@@ -590,7 +797,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     /// Generate a dummy closure value that wraps a call to a plain function
     fn gen_wrapping_closure(
         &mut self,
-        func: &'ctx Function,
+        func: GlobFunc<'ctx>,
         id: &str,
         func_type: &ast::Type<'src>,
     ) -> &'ctx Value {
@@ -599,33 +806,65 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             .unwrap_or_else(|| panic!("ICE: Invalid function type `{}`", func_type));
         let (arg_type, ret_type) = (self.gen_type(at), self.gen_type(rt));
         let clos_typ = FunctionType::new(ret_type, &[type_generic_ptr(self.ctx), arg_type]);
-        let closure_func = self.module
-            .add_function(&format!("closure_func_{}", id), &clos_typ);
+        let closure_func_name = self.unique_symbol(&format!("closure_func_{}", id));
+        let closure_func = self.module.add_function(&closure_func_name, &clos_typ);
+        closure_func.set_linkage(Linkage::Internal);
         let entry = closure_func.append("entry");
         self.builder.position_at_end(entry);
         closure_func[0].set_name("DUMMY-CAPTURES");
         let param = &*closure_func[1];
-        let r = self.builder.build_call(func, &[param]);
+        let r = self.build_call_glob(func, &[param]);
         self.builder.build_ret(r);
         let closure_val = Value::new_struct(
             self.ctx,
             &[closure_func, Value::new_null(type_generic_ptr(self.ctx))],
             false,
         );
+        let closure_name = self.unique_symbol(&format!("closure_{}", id));
         let closure = self.module
-            .add_global_const_variable(&format!("closure_{}", id), closure_val);
+            .add_global_const_variable(&closure_name, closure_val);
+        closure.set_linkage(Linkage::Internal);
+        closure
+    }
+
+    /// Get the closure that wraps the global function `func`, of type `func_type`, generating
+    /// the wrapper on first use
+    fn get_or_gen_closure_wrapper(
+        &mut self,
+        func: GlobFunc<'ctx>,
+        func_type: &ast::Type<'src>,
+    ) -> &'ctx Value {
+        let id = func.func
+            .get_name()
+            .expect("ICE: global function has no name")
+            .to_string();
+        if let Some(&closure) = self.closure_wrappers.get(&id) {
+            return closure;
+        }
+        let closure = self.gen_wrapping_closure(func, &id, func_type);
+        if let Some(block) = *self.current_block.borrow() {
+            self.builder.position_at_end(block);
+        }
+        self.closure_wrappers.insert(id, closure);
         closure
     }
 
-    /// Generate an external function declaration and matching closure-wrapping
+    /// Generate an external function declaration
+    /// The target triple of the module, or of the host if none is set
+    fn target_triple(&self) -> String {
+        match self.module.get_target() {
+            "" => default_triple(),
+            t => t.to_string(),
+        }
+    }
+
     fn gen_extern_func(&mut self, id: &str, typ: &ast::Type<'src>) -> GlobFunc<'ctx> {
         assert!(
             self.current_block.borrow().is_none(),
             "ICE: External function declarations may only be generated first"
         );
         let func = self.gen_func_decl(id, typ);
-        let closure = self.gen_wrapping_closure(func, id, typ);
-        GlobFunc { func, closure }
+        GlobFunc { func, slot: None }
     }
 
     /// Generates a simple binop function of the instruction built by `build_instr`
@@ -635,67 +874,78 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         typ: &ast::Type<'src>,
         build_instr: fn(&'ctx Builder, &'ctx Value, &'ctx Value) -> &'ctx Value,
     ) -> GlobFunc<'ctx> {
-        let func = self.gen_func_decl(func_name, typ);
+        let (at, rt) = typ.get_func()
+            .unwrap_or_else(|| panic!("ICE: Invalid function type `{}`", typ));
+        let (arg_type, ret_type) = (self.gen_type(at), self.gen_type(rt));
+        let func = self.module.add_function(
+            &self.unique_symbol(func_name),
+            FunctionType::new(ret_type, &[arg_type]),
+        );
         let entry = func.append("entry");
         self.builder.position_at_end(entry);
         let a = self.builder.build_extract_value(&*func[0], 0);
         let b = self.builder.build_extract_value(&*func[0], 1);
         let r = build_instr(self.builder, a, b);
         self.builder.build_ret(r);
-        let closure = self.gen_wrapping_closure(func, func_name, typ);
-        GlobFunc { func, closure }
+        // Binops are generated on demand, possibly in the middle of another function
+        if let Some(block) = *self.current_block.borrow() {
+            self.builder.position_at_end(block);
+        }
+        GlobFunc { func, slot: None }
     }
 
-    fn gen_core_funcs(&mut self, env: &mut Env<'src, 'ctx>) {
+    /// Get the core binop `op_name` of the numeric type `typ`, e.g. `add-Int64`, generating
+    /// the function on first use
+    ///
+    /// Returns the name of the function in the environment.
+    fn get_or_gen_core_binop(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        op_name: &str,
+        typ: &ast::Type<'src>,
+    ) -> String {
         type BinopBuilder<'ctx> = fn(&'ctx Builder, &'ctx Value, &'ctx Value) -> &'ctx Value;
-        assert!(
-            self.current_block.borrow().is_none(),
-            "ICE: Core functions may only be generated before main"
-        );
+        let type_name = typ.get_const()
+            .unwrap_or_else(|| panic!("ICE: binop of non-numeric type {}", typ));
+        let func_name = format!("{}-{}", op_name, type_name);
+        if env.get_global_mono(&func_name).is_some() {
+            return func_name;
+        }
+        let (build_op, op_type): (BinopBuilder<'ctx>, _) = match op_name {
+            "add" => (Builder::build_add, ast::Type::new_binop(typ.clone())),
+            "sub" => (Builder::build_sub, ast::Type::new_binop(typ.clone())),
+            "mul" => (Builder::build_mul, ast::Type::new_binop(typ.clone())),
+            "div" if typ.is_int() => (Builder::build_sdiv, ast::Type::new_binop(typ.clone())),
+            "div" if typ.is_uint() => (Builder::build_udiv, ast::Type::new_binop(typ.clone())),
+            "div" => (Builder::build_fdiv, ast::Type::new_binop(typ.clone())),
+            "eq" => (
+                Builder::build_eq,
+                ast::Type::new_relational_binop(typ.clone()),
+            ),
+            "lt" => (
+                Builder::build_lt,
+                ast::Type::new_relational_binop(typ.clone()),
+            ),
+            _ => panic!("ICE: unknown core binop `{}`", op_name),
+        };
+        let func = self.gen_binop_func(&func_name, &op_type, build_op);
+        env.add_global_mono(&func_name, Global::Func(func));
+        func_name
+    }
 
-        // Generate arithmeric, relational, and logic binops, e.g. addition
-        let int_types = ["Int8", "Int16", "Int32", "Int64"];
-        let uint_types = ["UInt8", "UInt16", "UInt32", "UInt64"];
-        let float_types = ["Float32", "Float64"];
-        let arithm_binops = [
-            ("add", Builder::build_add as BinopBuilder<'ctx>),
-            ("sub", Builder::build_sub),
-            ("mul", Builder::build_mul),
-        ];
-        let int_arithm_binops = [("div", Builder::build_sdiv as BinopBuilder<'ctx>)];
-        let uint_arithm_binops = [("div", Builder::build_udiv as BinopBuilder<'ctx>)];
-        let float_arithm_binops = [("div", Builder::build_fdiv as BinopBuilder<'ctx>)];
-        let relational_binops = [
-            ("eq", Builder::build_eq as BinopBuilder<'ctx>),
-            ("lt", Builder::build_lt),
-        ];
-        let num_classes_with_extras = [
-            (&int_types[..], &int_arithm_binops[..]),
-            (&uint_types[..], &uint_arithm_binops[..]),
-            (&float_types[..], &float_arithm_binops[..]),
-        ];
-        for &(num_class, extras) in &num_classes_with_extras {
-            for type_name in num_class {
-                let arithms_with_div = arithm_binops
-                    .iter()
-                    .chain(extras)
-                    .cloned()
-                    .collect::<Vec<_>>();
-                let typ = ast::Type::Const(type_name, None);
-                let binop_type = ast::Type::new_binop(typ.clone());
-                let relational_binop_type = ast::Type::new_relational_binop(typ);
-                let ops_with_type = [
-                    (&arithms_with_div[..], binop_type),
-                    (&relational_binops[..], relational_binop_type),
-                ];
-                for &(ops, ref op_type) in &ops_with_type {
-                    for &(op_name, build_op) in ops {
-                        let func_name = format!("{}-{}", op_name, type_name);
-                        let func = self.gen_binop_func(&func_name, op_type, build_op);
-                        env.add_global_mono(&func_name, Global::Func(func))
-                    }
-                }
+    /// Get the closure that wraps the core binop function `func_name`, of type `func_type`
+    fn gen_core_binop_closure(
+        &mut self,
+        env: &Env<'src, 'ctx>,
+        func_name: &str,
+        func_type: &ast::Type<'src>,
+    ) -> &'ctx Value {
+        match env.get_global_mono(func_name) {
+            Some(Global::Func(glob)) => {
+                let closure = self.get_or_gen_closure_wrapper(glob, func_type);
+                self.builder.build_load(closure)
             }
+            _ => panic!("ICE: core binop `{}` not generated", func_name),
         }
     }
 
@@ -703,28 +953,36 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         &mut self,
         env: &mut Env<'src, 'ctx>,
         externs: &BTreeMap<&'src str, ast::ExternDecl<'src>>,
-    ) {
+    ) -> Result<(), Diagnostic> {
+        let triple = self.target_triple();
         for (id, decl) in externs.iter() {
             // TODO: External non-function variable declarations?
             if decl.typ.get_func().is_none() {
-                decl.pos
-                    .error_exit("Non-function externs not yet implemented!")
+                return Err(decl.pos
+                    .diagnostic("Non-function externs not yet implemented!"));
             }
-            let func = self.gen_extern_func(id, &decl.typ);
+            let func = self.gen_extern_func(decl.symbol(&triple), &decl.typ);
+            func.func.set_call_conv(llvm_call_conv(decl.call_conv, &triple));
             env.add_global_mono(id, Global::Func(func))
         }
+        Ok(())
     }
 
-    fn parse_gen_lit<I>(&self, lit: &str, typ: &ast::Type<'src>, pos: &SrcPos<'src>) -> &'ctx Value
+    fn parse_gen_lit<I>(
+        &self,
+        lit: &str,
+        typ: &ast::Type<'src>,
+        pos: &SrcPos<'src>,
+    ) -> Result<&'ctx Value, Diagnostic>
     where
         I: Compile<'ctx> + FromStr,
     {
         lit.parse::<I>()
             .map(|n| n.compile(self.ctx))
-            .unwrap_or_else(|_| pos.error_exit(CodegenErr::num_parse_err(typ)))
+            .map_err(|_| pos.diagnostic(CodegenErr::num_parse_err(typ)))
     }
 
-    fn gen_num(&mut self, num: &ast::NumLit) -> &'ctx Value {
+    fn gen_num(&mut self, num: &ast::NumLit) -> Result<&'ctx Value, Diagnostic> {
         let parser = match num.typ {
             ast::Type::Const("Int8", _) => CodeGenerator::parse_gen_lit::<i8>,
             ast::Type::Const("Int16", _) => CodeGenerator::parse_gen_lit::<i16>,
@@ -739,15 +997,31 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             ast::Type::Const("Bool", _) => CodeGenerator::parse_gen_lit::<bool>,
             ast::Type::Const("Float32", _) => CodeGenerator::parse_gen_lit::<f32>,
             ast::Type::Const("Float64", _) => CodeGenerator::parse_gen_lit::<f64>,
-            _ => num.pos
-                .error_exit(ICE("type of numeric literal is not numeric".into())),
+            _ => {
+                return Err(num.pos
+                    .diagnostic(ICE("type of numeric literal is not numeric".into())))
+            }
         };
         parser(self, &num.lit, &num.typ, &num.pos)
     }
 
-    fn gen_str_(&self, env: &mut Env<'src, 'ctx>, s: &str) -> &'ctx Value {
+    /// The global constant of the string literal `s`, shared by all occurrences of `s`
+    ///
+    /// The constant is named by the hash of its contents, so that adding or removing an
+    /// unrelated literal doesn't rename it.
+    fn str_lit_const(&self, s: &str) -> &'ctx GlobalVariable {
+        if let Some(&str_const) = self.str_lits.borrow().get(s) {
+            return str_const;
+        }
         let str_lit_ll = Value::new_string(self.ctx, s, true);
-        let str_const = self.module.add_global_const_variable("str_lit", str_lit_ll);
+        let name = self.unique_symbol(&format!("str_lit.{:016x}", content_hash(s.as_bytes())));
+        let str_const = self.module.add_global_const_variable(&name, str_lit_ll);
+        self.str_lits.borrow_mut().insert(s.to_string(), str_const);
+        str_const
+    }
+
+    fn gen_str_(&self, env: &mut Env<'src, 'ctx>, s: &str) -> &'ctx Value {
+        let str_const = self.str_lit_const(s);
         let str_ptr = self.builder.build_gep(
             str_const,
             &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
@@ -755,71 +1029,188 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         let s = self.build_struct(&[s.len().compile(self.ctx), str_ptr]);
         s.set_name("str-lit");
         let r = match env.get_global_mono("str_lit_to_string") {
-            Some(Global::Func(glob)) => self.builder.build_call(glob.func, &[s]),
+            Some(Global::Func(glob)) => self.build_call_glob(glob, &[s]),
             _ => panic!("ICE: No global function str_lit_to_string found"),
         };
         r.set_name("str");
         r
     }
 
-    fn gen_str(&self, env: &mut Env<'src, 'ctx>, lit: &'ast ast::StrLit<'src>) -> &'ctx Value {
-        self.gen_str_(env, &lit.lit)
+    /// Generate a string literal that is converted to a `String` only on its first
+    /// evaluation, after which the result is pinned and reused
+    ///
+    /// Strings are immutable, so the conversion can be shared by all evaluations.
+    fn gen_cached_str(&mut self, env: &mut Env<'src, 'ctx>, s: &str) -> &'ctx Value {
+        use llvm_sys::LLVMAtomicOrdering::*;
+        let string_type = self.gen_type(&ast::TYPE_STRING);
+        let string_visitor = self.gen_obj_visitor(&ast::TYPE_STRING)
+            .unwrap_or(self.gc.nop_obj_visitor);
+        let hash = content_hash(s.as_bytes());
+        let cache = self.module.add_global_variable(
+            &self.unique_symbol(&format!("str_cache.{:016x}", hash)),
+            Value::new_null(string_type),
+        );
+        let is_cached = self.module.add_global_variable(
+            &self.unique_symbol(&format!("str_is_cached.{:016x}", hash)),
+            0u8.compile(self.ctx),
+        );
+        let parent_func = self.current_func.borrow().unwrap();
+        let convert_br = parent_func.append("str_convert");
+        let next_br = parent_func.append("str_cached");
+        let flag = self.builder
+            .build_atomic_load(is_cached, LLVMAtomicOrderingAcquire, 1);
+        let not_cached = self.builder.build_is_null(flag);
+        self.builder.build_cond_br(not_cached, convert_br, next_br);
+
+        self.builder.position_at_end(convert_br);
+        *self.current_block.borrow_mut() = Some(convert_br);
+        let converted = self.gen_str_(env, s);
+        self.builder.build_store(converted, cache);
+        self.gc.build_pin(cache, string_visitor);
+        self.builder.build_atomic_store(
+            1u8.compile(self.ctx),
+            is_cached,
+            LLVMAtomicOrderingRelease,
+            1,
+        );
+        self.builder.build_br(next_br);
+
+        self.builder.position_at_end(next_br);
+        *self.current_block.borrow_mut() = Some(next_br);
+        let r = self.builder.build_load(cache);
+        r.set_name("str");
+        r
+    }
+
+    /// Generate a string literal
+    ///
+    /// In loops, the conversion of the literal is hoisted out of the iterations by caching.
+    fn gen_str(&mut self, env: &mut Env<'src, 'ctx>, lit: &str) -> &'ctx Value {
+        if self.loops.is_empty() {
+            self.gen_str_(env, lit)
+        } else {
+            self.gen_cached_str(env, lit)
+        }
+    }
+
+    /// Generate a byte array literal as a pair of length and pointer to the constant data
+    fn gen_bytes(&self, bytes: &[u8]) -> &'ctx Value {
+        let bytes_ll = Value::new_bytes(self.ctx, bytes);
+        let name = self.unique_symbol(&format!("bytes_lit.{:016x}", content_hash(bytes)));
+        let bytes_const = self.module.add_global_const_variable(&name, bytes_ll);
+        let bytes_ptr = self.builder.build_gep(
+            bytes_const,
+            &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+        );
+        let r = self.build_struct(&[bytes.len().compile(self.ctx), bytes_ptr]);
+        r.set_name("bytes-lit");
+        r
+    }
+
+    /// Generate a symbol literal as a pointer to the global of the symbol
+    ///
+    /// All occurrences of a symbol refer to the same global, so symbols are equal if and only
+    /// if the pointers are equal.
+    fn gen_sym(&mut self, name: &'src str) -> &'ctx Value {
+        if let Some(&sym) = self.symbols.get(name) {
+            return sym;
+        }
+        let name_const = self.str_lit_const(name);
+        let name_ptr = self.builder.build_gep(
+            name_const,
+            &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+        );
+        let sym_ll = Value::new_struct(
+            self.ctx,
+            &[name.len().compile(self.ctx), name_ptr],
+            false,
+        );
+        let sym_name = self.unique_symbol(&format!("sym.{}", name));
+        let sym = self.module.add_global_const_variable(&sym_name, sym_ll);
+        self.symbols.insert(name, sym);
+        sym
+    }
+
+    /// Generate IR for an atom
+    fn gen_atom(
+        &mut self,
+        env: &Env<'src, 'ctx>,
+        atom: &'ast mir::Atom<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        Ok(match *atom {
+            mir::Atom::Local(l) => env.get_local(l),
+            // Represent Nil as the empty struct, unit
+            mir::Atom::Nil => self.new_nil_val(),
+            mir::Atom::Num(ref n) => self.gen_num(n)?,
+            mir::Atom::Bool(b) => b.compile(self.ctx),
+            mir::Atom::Sym(name) => self.gen_sym(name),
+        })
     }
 
-    /// Generate IR for a variable used as an r-value
-    fn gen_variable(&mut self, env: &mut Env<'src, 'ctx>, var: &'ast ast::Variable) -> &'ctx Value {
-        let inst = var.typ.get_inst_args().unwrap_or(&[]);
-        let type_canon = var.typ.canonicalize();
-        match env.get(var.ident.s, inst) {
+    /// Generate IR for the instantiation `inst` of the global `name`, of type `typ`, used as
+    /// an r-value
+    fn gen_global(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        name: &str,
+        inst: &[ast::Type<'src>],
+        typ: &ast::Type<'src>,
+    ) -> &'ctx Value {
+        match env.get_global(name, inst) {
             // NOTE: Ugly hack to fix generic codegen for some binops
-            Some(Var::Global(_)) if is_arithm_binop(var.ident.s) => {
-                let maybe_op_typ = type_canon.get_cons_binop().map(|t| t.var_to_int64());
-                let op_typ = maybe_op_typ
-                    .unwrap_or_else(|| panic!("ICE: binop has bad type {}", type_canon));
+            Some(_) if is_arithm_binop(name) => {
+                let maybe_op_typ = typ.get_cons_binop().map(|t| t.var_to_int64());
+                let op_typ =
+                    maybe_op_typ.unwrap_or_else(|| panic!("ICE: binop has bad type {}", typ));
                 assert!(
                     op_typ.is_int() || op_typ.is_uint() || op_typ.is_float(),
                     "ICE: binop has bad type {}",
-                    type_canon
+                    typ
                 );
-                let typ = ast::Type::new_binop(op_typ.clone());
-                let f = format!("{}-{}", var.ident.s, op_typ.get_const().unwrap());
-                let mut var2 = var.clone();
-                var2.typ = typ;
-                var2.ident.s = &f;
-                self.gen_variable(env, &var2)
-            }
-            Some(Var::Global(_)) if is_relational_binop(var.ident.s) => {
-                let maybe_op_typ = type_canon
-                    .get_cons_relational_binop()
+                let binop_typ = ast::Type::new_binop(op_typ.clone());
+                let f = self.get_or_gen_core_binop(env, name, &op_typ);
+                self.gen_core_binop_closure(env, &f, &binop_typ)
+            }
+            Some(_) if is_relational_binop(name) => {
+                let maybe_op_typ = typ.get_cons_relational_binop()
                     .map(|t| t.var_to_int64());
                 let op_typ = maybe_op_typ.unwrap_or_else(|| {
-                    panic!("ICE: binary relational op has bad type {}", type_canon)
+                    panic!("ICE: binary relational op has bad type {}", typ)
                 });
                 assert!(
                     op_typ.is_int() || op_typ.is_uint() || op_typ.is_float(),
                     "ICE: relational binop has bad type {}",
-                    type_canon
+                    typ
                 );
-                let typ = ast::Type::new_relational_binop(op_typ.clone());
-                let f = format!("{}-{}", var.ident.s, op_typ.get_const().unwrap());
-                let mut var2 = var.clone();
-                var2.typ = typ;
-                var2.ident.s = &f;
-                self.gen_variable(env, &var2)
-            }
-            Some(Var::Global(Global::Func(glob))) => self.builder.build_load(glob.closure),
-            Some(Var::Global(Global::Var(var))) => self.builder.build_load(var),
-            Some(Var::Local(val)) => val,
+                let binop_typ = ast::Type::new_relational_binop(op_typ.clone());
+                let f = self.get_or_gen_core_binop(env, name, &op_typ);
+                self.gen_core_binop_closure(env, &f, &binop_typ)
+            }
+            Some(Global::Func(glob)) => {
+                let closure = self.get_or_gen_closure_wrapper(glob, typ);
+                self.builder.build_load(closure)
+            }
+            Some(Global::Var(var)) => self.builder.build_load(var),
+            Some(Global::LazyVar(getter)) => self.builder.build_call(getter, &[]),
             // Undefined variables are caught during type check/inference
             None => panic!(
-                "ICE: Undefined variable at codegen: `{}`, inst `{:?}`\nglobals: {:?}\nlocals: {:?}",
-                var.ident.s, inst, env.globs.get(var.ident.s), env.locals.get(var.ident.s).and_then(|l| l.last())
+                "ICE: Undefined variable at codegen: `{}`, inst `{:?}`\nglobals: {:?}",
+                name,
+                inst,
+                env.globs.get(name)
             ),
         }
     }
 
-    fn gen_if(&mut self, env: &mut Env<'src, 'ctx>, cond: &'ast ast::If<'src>) -> &'ctx Value {
-        let pred = self.gen_expr(env, &cond.predicate, None);
+    fn gen_if(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        pred: &'ast mir::Atom<'src>,
+        conseq: &'ast mir::Block<'src>,
+        alt: &'ast mir::Block<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let pred = self.gen_atom(env, pred)?;
         let parent_func = self.current_func.borrow().unwrap();
         let then_br = parent_func.append("cond_then");
         let else_br = parent_func.append("cond_else");
@@ -829,21 +1220,26 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
 
         self.builder.position_at_end(then_br);
         *self.current_block.borrow_mut() = Some(then_br);
-        let then_val = self.gen_expr(env, &cond.consequent, None);
+        let then_val = self.gen_block(env, conseq, typ)?;
         let then_last_block = self.current_block.borrow().unwrap();
         phi_nodes.push((then_val, then_last_block));
         self.builder.build_br(next_br);
 
         self.builder.position_at_end(else_br);
         *self.current_block.borrow_mut() = Some(else_br);
-        let else_val = self.gen_expr(env, &cond.alternative, None);
+        let else_val = self.gen_block(env, alt, typ)?;
         let else_last_block = self.current_block.borrow().unwrap();
         phi_nodes.push((else_val, else_last_block));
         self.builder.build_br(next_br);
 
         self.builder.position_at_end(next_br);
         *self.current_block.borrow_mut() = Some(next_br);
-        self.builder.build_phi(then_val.get_type(), &phi_nodes)
+        // Nil has a single value, so there's nothing to select between, e.g. for `when`
+        if *typ == *ast::TYPE_NIL {
+            Ok(self.new_nil_val())
+        } else {
+            Ok(self.builder.build_phi(then_val.get_type(), &phi_nodes))
+        }
     }
 
     /// Build the application of a function to an argument
@@ -856,27 +1252,74 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         self.builder.build_call(func, &[captures_ptr, arg])
     }
 
+    /// Build a call of the global function `g` to the arguments `args`
+    ///
+    /// A reloadable function is called through its slot, which is loaded atomically, as it
+    /// may be swapped by another thread when hot reloading.
+    fn build_call_glob(&self, g: GlobFunc<'ctx>, args: &[&'ctx Value]) -> &'ctx Value {
+        use llvm_sys::LLVMAtomicOrdering::*;
+        match g.slot {
+            Some(slot) => {
+                let align = Self::ptr_size_bytes(self.module) as u32;
+                let func_val = self.builder
+                    .build_atomic_load(slot, LLVMAtomicOrderingMonotonic, align);
+                func_val.set_name("func");
+                let func = Function::from_super(func_val)
+                    .expect("ICE: Failed to cast slot to &Function");
+                self.builder.build_call(func, args)
+            }
+            None => self.builder.build_call(g.func, args),
+        }
+    }
+
     // TODO: Tail call optimization
-    /// Generates IR code for a function application.
-    fn gen_app(&mut self, env: &mut Env<'src, 'ctx>, app: &'ast ast::App<'src>) -> &'ctx Value {
-        let typ = app.func.get_type();
-        let inst = typ.get_inst_args().unwrap_or(&[]);
-        let arg = self.gen_expr(env, &app.arg, Some("app-arg"));
-        // If it's a direct application of an global function: call it
-        // as a function, otherwise treat it normally (call it as a closure)
-        let maybe_glob = app.func
-            .as_var()
-            .and_then(|v| env.get(v.ident.s, inst).map(|x| (v.ident.s, x)));
-        if let Some((name, Var::Global(Global::Func(g)))) = maybe_glob {
-            if !is_arithm_binop(name) && !is_relational_binop(name) {
-                return self.builder.build_call(g.func, &[arg]);
-            }
-        }
-        let func = self.gen_expr(env, &app.func, Some("app-func"));
-        self.build_app(func, arg)
-    }
-
-    /// Build a call for the function/closure of name `name`, given the argument as a compiled value
+    /// Generates IR code for a direct call of the instantiation `inst` of the global
+    /// function `name`
+    fn gen_call(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        name: &str,
+        inst: &[ast::Type<'src>],
+        arg: &'ast mir::Atom<'src>,
+        pos: &SrcPos<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let arg = self.gen_atom(env, arg)?;
+        let g = match env.get_global(name, inst) {
+            Some(Global::Func(g)) => g,
+            _ => panic!("ICE: No global function `{}` to call, inst `{:?}`", name, inst),
+        };
+        self.shadow_stack.build_push_frame(name, pos);
+        let r = self.build_call_glob(g, &[arg]);
+        self.shadow_stack.build_pop_frame();
+        Ok(r)
+    }
+
+    /// Generates IR code for the application of a closure
+    ///
+    /// A frame named `frame` is pushed on the shadow stack for the call, if any.
+    fn gen_app(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        func: &'ast mir::Atom<'src>,
+        arg: &'ast mir::Atom<'src>,
+        frame: Option<&str>,
+        pos: &SrcPos<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let arg = self.gen_atom(env, arg)?;
+        let func = self.gen_atom(env, func)?;
+        Ok(match frame {
+            None => self.build_app(func, arg),
+            Some(name) => {
+                self.shadow_stack.build_push_frame(name, pos);
+                let r = self.build_app(func, arg);
+                self.shadow_stack.build_pop_frame();
+                r
+            }
+        })
+    }
+
+    /// Build a call for the global function/closure of name `name`, given the argument as a
+    /// compiled value
     ///
     /// Global/external functions are called without the closure overhead.
     fn build_call_named(
@@ -886,10 +1329,10 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         inst: &[ast::Type<'src>],
         arg: &'ctx Value,
     ) -> &'ctx Value {
-        match env.get(name, inst) {
-            Some(Var::Global(Global::Func(g))) => self.builder.build_call(g.func, &[arg]),
-            Some(Var::Global(Global::Var(g))) => self.build_app(self.builder.build_load(g), arg),
-            Some(Var::Local(v)) => self.build_app(v, arg),
+        match env.get_global(name, inst) {
+            Some(Global::Func(g)) => self.build_call_glob(g, &[arg]),
+            Some(Global::Var(g)) => self.build_app(self.builder.build_load(g), arg),
+            Some(Global::LazyVar(g)) => self.build_app(self.builder.build_call(g, &[]), arg),
             None => panic!("ICE: No function `{}` defined or declared", name),
         }
     }
@@ -916,28 +1359,38 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 .collect::<String>()
         );
         let func = self.module
-            .add_function(&visitor_name, self.gc.obj_visitor_type);
+            .add_function(&self.unique_symbol(&visitor_name), self.gc.obj_visitor_type);
+        func.set_linkage(Linkage::Internal);
         let entry = func.append("entry");
         self.builder.position_at_end(entry);
         let adt_generic = &*func[0];
-        let tag_type = Type::get::<u16>(self.ctx);
-        let inner_type_generic = Type::get::<u8>(self.ctx);
-        let adt_type = PointerType::new(StructType::new(
-            self.ctx,
-            &[tag_type, inner_type_generic],
-            false,
-        ));
+        // The offset of the inner value depends on its alignment, so use the actual type
+        let adt_def = self.adts.defs[name].clone();
+        let adt_type_direct = self.get_or_gen_adt_by_name_and_inst(adt_def.name.s, inst);
+        let adt_type = if self.adts.adt_is_recursive(&adt_def) {
+            let inner_type = self.named_types
+                .adts_inner
+                .get(&(adt_def.name.s, inst.to_vec()))
+                .cloned()
+                .expect("ICE: No adts_inner type in gen_adt_obj_visitor");
+            PointerType::new(inner_type)
+        } else {
+            PointerType::new(adt_type_direct)
+        };
         let adt = self.builder.build_bit_cast(adt_generic, adt_type);
         adt.set_name("adt");
         let tag = self.build_load_car(adt);
         tag.set_name("tag");
-        let inner = self.builder.build_gep_struct(self.ctx, adt, 1);
+        let inner_direct = self.builder.build_gep_struct(self.ctx, adt, 1);
+        let inner = self.builder
+            .build_bit_cast(inner_direct, type_generic_ptr(self.ctx));
         inner.set_name("inner");
         let obj_handler = &*func[1];
         obj_handler.set_name("obj_handler");
+        let stored_types = self.gen_adt_variant_types(&adt_def, inst);
         // Switch over variants.
         let mut cases: Vec<(&Value, &BasicBlock)> = vec![];
-        let variants = self.adts.defs[name]
+        let variants = adt_def
             .variants
             .iter()
             .cloned()
@@ -950,7 +1403,21 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     "ICE: No type with inst `{:?}` of variant `{}`",
                     inst, v.name
                 ));
-            if let Some(visitor) = self.gen_obj_visitor(&t) {
+            if stored_types[i].1 {
+                // A boxed variant is an object of its own, which is visited by the
+                // visitor it was allocated with
+                let block = func.append(&format!("variant_{}", i));
+                self.builder.position_at_end(block);
+                let box_ptr = self.builder
+                    .build_bit_cast(inner, PointerType::new(type_generic_ptr(self.ctx)));
+                let boxed = self.builder.build_load(box_ptr);
+                boxed.set_name("boxed");
+                let handler = Function::from_super(obj_handler)
+                    .expect("ICE: obj_handler was not a function in gen_adt_obj_visitor");
+                self.builder.build_call(handler, &[boxed]);
+                let i_ll = (i as u16).compile(self.ctx);
+                cases.push((i_ll, block))
+            } else if let Some(visitor) = self.gen_obj_visitor(&t) {
                 let block = func.append(&format!("variant_{}", i));
                 self.builder.position_at_end(block);
                 self.builder.build_call(visitor, &[inner, obj_handler]);
@@ -980,7 +1447,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             None
         } else {
             let name = format!("obj_visitor_cons_{}_{}", car_typ, cdr_typ);
-            let func = self.module.add_function(&name, self.gc.obj_visitor_type);
+            let func = self.module
+                .add_function(&self.unique_symbol(&name), self.gc.obj_visitor_type);
+            func.set_linkage(Linkage::Internal);
             let entry = func.append("entry");
             self.builder.position_at_end(entry);
             let cons = &*func[0];
@@ -1007,7 +1476,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     fn gen_ptr_obj_visitor(&mut self, inner_typ: &ast::Type<'src>) -> Option<&'ctx Function> {
         self.gen_obj_visitor(inner_typ).map(|inner_vis| {
             let name = format!("obj_visitor_ptr_{}", inner_typ);
-            let func = self.module.add_function(&name, self.gc.obj_visitor_type);
+            let func = self.module
+                .add_function(&self.unique_symbol(&name), self.gc.obj_visitor_type);
+            func.set_linkage(Linkage::Internal);
             let entry = func.append("entry");
             self.builder.position_at_end(entry);
             let ptr = &*func[0];
@@ -1025,7 +1496,6 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     }
 
     fn gen_captures_obj_visitor(&mut self, types: Vec<ast::Type<'src>>) -> Option<&'ctx Function> {
-        println!("gen_captures_obj_visitor: types: {:?}", types);
         if let Some(visitor) = self.gc.captures_obj_visitors.get(&types) {
             return visitor.clone();
         }
@@ -1043,7 +1513,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 .intersperse("_".to_string())
                 .collect::<String>();
             let name = format!("obj_visitor_captures_{{{}}}", types_s);
-            let func = self.module.add_function(&name, self.gc.obj_visitor_type);
+            let func = self.module
+                .add_function(&self.unique_symbol(&name), self.gc.obj_visitor_type);
+            func.set_linkage(Linkage::Internal);
             let entry = func.append("entry");
             self.builder.position_at_end(entry);
             let captures_generic = &*func[0];
@@ -1098,6 +1570,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     "->" => Some(self.gc.closure_obj_visitor),
                     "Cons" => self.gen_cons_obj_visitor(&ts[0], &ts[1]),
                     "Ptr" => self.gen_ptr_obj_visitor(&ts[0]),
+                    // Values held by the runtime are pinned
+                    "Thread" | "Chan" | "Mutex" | "Task" => None,
                     name if self.adts.defs.contains_key(name) => {
                         Some(if self.adts.adt_of_name_is_recursive(name) {
                             self.gc.handle_self_obj_visitor
@@ -1119,6 +1593,43 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         }
     }
 
+    /// Generate a call to the heap allocator to allocate `size` bytes of heap space for an
+    /// object visited by `obj_visitor`
+    ///
+    /// If the allocator returns null, the out-of-memory handler is called, which does not
+    /// return.
+    fn build_gc_alloc(&self, size: usize, obj_visitor: &'ctx Function) -> &'ctx Value {
+        let ptr = match *self.alloc_site.borrow() {
+            Some(ref location) if self.profile_heap => {
+                let site_ll = Value::new_string(self.ctx, location, false);
+                let site_name = format!("alloc_site.{:016x}", content_hash(location.as_bytes()));
+                let site_const = self.module
+                    .add_global_const_variable(&self.unique_symbol(&site_name), site_ll);
+                let site_ptr = self.builder.build_gep(
+                    site_const,
+                    &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+                );
+                self.gc.build_alloc_profiled(size, obj_visitor, site_ptr)
+            }
+            _ => self.gc.build_alloc_temp(size, obj_visitor),
+        };
+        // `malloc` may return null for zero-sized allocations, which are never dereferenced
+        if size > 0 {
+            let parent_func = self.current_func.borrow().unwrap();
+            let oom_br = parent_func.append("alloc_oom");
+            let next_br = parent_func.append("alloc_next");
+            let is_null = self.builder.build_is_null(ptr);
+            self.builder.build_cond_br(is_null, oom_br, next_br);
+
+            self.builder.position_at_end(oom_br);
+            self.gc.build_oom(size);
+
+            self.builder.position_at_end(next_br);
+            *self.current_block.borrow_mut() = Some(next_br);
+        }
+        ptr
+    }
+
     /// Generate a call to the heap allocator to allocate heap space
     /// for a value of type `typ` as a generic pointer.
     ///
@@ -1128,7 +1639,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         let typ_c = self.gen_type(typ);
         let type_size = self.size_of_(typ_c);
         let obj_visitor = self.gen_obj_visitor(typ).unwrap_or(self.gc.nop_obj_visitor);
-        let ptr = self.gc.build_alloc_temp(type_size as usize, obj_visitor);
+        let ptr = self.build_gc_alloc(type_size as usize, obj_visitor);
         ptr.set_name("alloc-ptr");
         ptr
     }
@@ -1148,7 +1659,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     ///
     /// When storing a valid value later, don't forget to update the object visitor.
     fn gen_gc_alloc_undef(&self, size: usize) -> &'ctx Value {
-        self.gc.build_alloc_temp(size, self.gc.nop_obj_visitor)
+        self.build_gc_alloc(size, self.gc.nop_obj_visitor)
     }
 
     fn gen_gc_alloc_adt(
@@ -1158,8 +1669,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         inner_type: &'ctx Type,
     ) -> &'ctx Value {
         let visitor = self.gen_adt_obj_visitor(name, inst);
-        self.gc
-            .build_alloc_temp(self.size_of_(inner_type) as usize, visitor)
+        self.build_gc_alloc(self.size_of_(inner_type) as usize, visitor)
     }
 
     /// Build a sequence of instructions that create a struct of type
@@ -1197,6 +1707,49 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         self.builder.build_extract_value(cons, 1)
     }
 
+    /// Build a stack slot of type `typ` in the entry block of the current function
+    ///
+    /// An alloca in any other block, e.g. the body of a loop, grows the stack each time it's
+    /// executed, while the allocas of the entry block make up the fixed size frame of the
+    /// function.
+    fn build_entry_alloca(&self, typ: &'ctx Type) -> &'ctx Value {
+        let block = self.builder
+            .get_insert_block()
+            .expect("ICE: builder not positioned in build_entry_alloca");
+        let entry = block
+            .get_parent()
+            .and_then(|func| func.get_entry())
+            .expect("ICE: no entry block in build_entry_alloca");
+        match entry.get_first() {
+            Some(first) => self.builder.position_before(first),
+            None => self.builder.position_at_end(entry),
+        }
+        let slot = self.builder.build_alloca(typ);
+        self.builder.position_at_end(block);
+        slot
+    }
+
+    /// Have each function defined in the module probe its stack frame in the prologue, if
+    /// the frame is at least as large as the stack probe interval
+    ///
+    /// The whole frame is probed, including spills and the slots of many small values, one
+    /// page at a time from the top down, so that a large frame faults on the guard page
+    /// instead of skipping past it into other memory. The probing is done by
+    /// `__rust_probestack` of the core library, as for Rust code. LLVM only implements
+    /// probing on x86 and x86-64, and ignores the attributes on other targets.
+    pub fn gen_stack_probe_attributes(&self) {
+        let interval = match self.stack_probe_interval {
+            Some(interval) if interval > 0 => interval,
+            _ => return,
+        };
+        for func in &*self.module {
+            if !func.is_declaration() {
+                func.add_string_attribute("probe-stack", "__rust_probestack");
+                func.add_string_attribute("stack-probe-size", &interval.to_string());
+            }
+        }
+    }
+
     /// Bitcast arbitrary (i.e. potentially aggregate) value in register to other type
     pub fn build_cast(&self, val: &'ctx Value, typ: &'ctx Type) -> &'ctx Value {
         let val_type = val.get_type();
@@ -1206,7 +1759,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             val_type, self.size_of_(val_type),
             typ, self.size_of_(typ)
         );
-        let target_stack = self.builder.build_alloca(typ);
+        let target_stack = self.build_entry_alloca(typ);
         target_stack.set_name("build-cast_target-stack");
         let val_ptr_type = PointerType::new(val_type);
         let val_stack = self.builder.build_bit_cast(target_stack, val_ptr_type);
@@ -1222,7 +1775,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         if t.is_pointer() {
             val
         } else {
-            let ptr = self.builder.build_alloca(t);
+            let ptr = self.build_entry_alloca(t);
             self.builder.build_store(val, ptr);
             ptr
         }
@@ -1246,134 +1799,132 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     /// A closure is represented as a structure of the environment it captures, and
     /// a function to pass this environment to, together with the argument, when the closure
     /// is applied to an argument.
+    ///
+    /// The environment is the record of `group_captures`, which may be a superset of the
+    /// captures of `func`, when the record is shared by several closures.
     fn gen_closure_func(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        free_vars: &FreeVarInsts<'src>,
-        lam: &'ast ast::Lambda<'src>,
-        name: &str,
-    ) -> &'ctx Function {
+        group_captures: &[mir::Local],
+        func: &'ast mir::Func<'src>,
+    ) -> Result<&'ctx Function, Diagnostic> {
         let parent_name = self.current_func
             .borrow()
-            .and_then(|f| f.get_name().map(str::to_string))
+            .and_then(|f| f.get_name().map(|s| demangle(s).unwrap_or(s.to_string())))
             .unwrap_or("global".to_string());
-        let lambda_name = format!("lambda_{}_{}", parent_name, name);
-        let func = self.gen_closure_func_decl(lambda_name, &lam.typ);
-        let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func));
-        let entry = func.append("entry");
+        let lambda_name =
+            self.internal_symbol(&format!("lambda_{}_{}", parent_name, func.name), &func.pos);
+        let func_ll = self.gen_closure_func_decl(lambda_name, &func.typ);
+        func_ll.set_linkage(Linkage::Internal);
+        let captures_ptr_type = PointerType::new(self.captures_type(env, group_captures));
+        let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func_ll));
+        let entry = func_ll.append("entry");
         let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
         self.builder.position_at_end(entry);
         self.gc.build_push_new_scope();
-        let captures_ptr_type = PointerType::new(self.captures_type_of_free_vars(free_vars));
-        let captures_ptr_generic = &*func[0];
+        let parent_gc_scopes = mem::replace(&mut self.gc_scopes, Vec::new());
+        let parent_locals = env.enter(&func.body.locals);
+        let captures_ptr_generic = &*func_ll[0];
         captures_ptr_generic.set_name("captures_generic");
         let captures_ptr = self.builder
             .build_bit_cast(captures_ptr_generic, captures_ptr_type);
         captures_ptr.set_name("captures");
-        let param = &*func[1];
-        param.set_name(lam.param_ident.s);
+        let param = &*func_ll[1];
+        opt_set_name(param, env.local_name(func.param));
+        env.set_local(func.param, param);
         let param_ptr = self.build_ptr_to_val(param);
-        let param_typ = lam.typ.get_func().unwrap().0;
+        let (param_typ, ret_typ) = func.typ.get_func().unwrap();
         let param_obj_visitor = self.gen_obj_visitor(param_typ)
             .unwrap_or(self.gc.nop_obj_visitor);
         self.gc.build_mark_bound(param_ptr, param_obj_visitor);
 
-        // Create function local environment of only parameter + captures
-        let mut local_env = map_of(lam.param_ident.s.to_string(), vec![map_of(vec![], param)]);
-
-        // Extract individual free variable captures from captures pointer and add to local env
-        let mut i: u32 = 0;
-        for (&fv_id, fv_insts) in free_vars.iter() {
-            local_env
-                .entry(fv_id.to_string())
-                .or_insert(vec![BTreeMap::new()]);
-            for (fv_inst, _) in fv_insts.iter().cloned() {
-                let fv_ptr = self.builder.build_gep_struct(self.ctx, captures_ptr, i);
-                fv_ptr.set_name(&format!("capture_{}", fv_id));
-                let fv_loaded = self.builder.build_load(fv_ptr);
-                fv_loaded.set_name(fv_id);
-                local_env
-                    .get_mut(fv_id)
-                    .expect("ICE: fv_id not in local_env")
-                    .last_mut()
-                    .unwrap()
-                    .insert(fv_inst, fv_loaded);
-                i += 1;
-            }
-        }
-        let old_locals = mem::replace(&mut env.locals, local_env);
-
-        let body = self.gen_expr(env, &lam.body, None);
+        // Extract the captures of this function from the shared captures
+        for &(outer, inner) in &func.captures {
+            let i = group_captures
+                .binary_search(&outer)
+                .expect("ICE: Capture not in captures of group");
+            let name = env.local_name(inner).unwrap_or("_");
+            let fv_ptr = self.builder
+                .build_gep_struct(self.ctx, captures_ptr, i as u32);
+            fv_ptr.set_name(&format!("capture_{}", name));
+            let fv_loaded = self.builder.build_load(fv_ptr);
+            fv_loaded.set_name(name);
+            env.set_local(inner, fv_loaded);
+        }
+
+        let body = self.gen_block(env, &func.body.block, ret_typ)?;
         if body.get_name().is_none() {
             body.set_name("return-val")
         }
         self.gc.build_pop_scope();
         let body_ptr = self.build_ptr_to_val(body);
-        let body_obj_visitor = self.gen_obj_visitor(lam.body.get_type())
+        let body_obj_visitor = self.gen_obj_visitor(ret_typ)
             .unwrap_or(self.gc.nop_obj_visitor);
         self.gc.build_mark_bound(body_ptr, body_obj_visitor);
         self.builder.build_ret(body);
 
         // Restore state of code generator
-        env.locals = old_locals;
+        env.leave(parent_locals);
+        self.gc_scopes = parent_gc_scopes;
         *self.current_func.borrow_mut() = parent_func;
         *self.current_block.borrow_mut() = parent_block;
         self.builder
             .position_at_end(self.current_block.borrow().expect("ICE: no current_block"));
 
-        func
+        Ok(func_ll)
     }
 
-    /// Generate the LLVM representation of a lambda expression, but with the contents
-    /// of the closure capture left as allocated, but undefined, space
-    ///
-    /// Can be used when generating bindings with recursive references
-    fn gen_closure_without_captures(
+    /// Allocate space for the captures `captures` of one or more closures, left undefined
+    /// until filled in by `build_insert_closure_captures`
+    fn build_alloc_undef_captures(
         &mut self,
-        env: &mut Env<'src, 'ctx>,
-        lam: &'ast ast::Lambda<'src>,
+        env: &Env<'src, 'ctx>,
+        captures: &[mir::Local],
         name: &str,
-    ) -> (&'ctx Value, FreeVarInsts<'src>) {
-        let free_vars = free_vars_in_lambda_filter_globals(&env, &lam);
-        let func_ptr = self.gen_closure_func(env, &free_vars, lam, name);
-        let captures_type = self.captures_type_of_free_vars(&free_vars);
+    ) -> &'ctx Value {
+        let captures_type = self.captures_type(env, captures);
         // TODO: First, allocate w nop obj visitor, then update visitor when "defining" the memory later.
-        let undef_heap_captures_generic = self.gc.build_alloc_temp(
+        let undef_heap_captures_generic = self.build_gc_alloc(
             self.size_of_(captures_type) as usize,
             self.gc.nop_obj_visitor,
         );
         undef_heap_captures_generic.set_name(&format!("{}-undef-capts-generic", name));
-        let closure = self.build_struct(&[func_ptr, undef_heap_captures_generic]);
-        closure.set_name(&format!("{}-clos", name));
-        (closure, free_vars)
+        undef_heap_captures_generic
+    }
+
+    /// Generate the LLVM representation of a closure of `func`, with the captures
+    /// `group_captures` at the allocated, but possibly undefined, space `captures_generic`
+    fn gen_closure(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        group_captures: &[mir::Local],
+        captures_generic: &'ctx Value,
+        func: &'ast mir::Func<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let func_ptr = self.gen_closure_func(env, group_captures, func)?;
+        let closure = self.build_struct(&[func_ptr, captures_generic]);
+        closure.set_name(&format!("{}-clos", func.name));
+        Ok(closure)
     }
 
-    /// Generate a struct of the captured free variables `free_vars`
-    /// of some lambda from the environment `env`
+    /// Generate a struct of the captured locals `captures` of some closures from the
+    /// environment `env`
     ///
     /// Returns a LLVM structure of each captured variable
     fn gen_closure_env_capture(
         &mut self,
-        env: &mut Env<'src, 'ctx>,
-        free_vars: &FreeVarInsts<'src>,
+        env: &Env<'src, 'ctx>,
+        captures: &[mir::Local],
         name: &str,
     ) -> (&'ctx Value, &'ctx Function) {
-        let mut captures_vals = Vec::new();
-        let mut captures_types = Vec::new();
-        for (&fv, insts) in free_vars {
-            for &(ref inst, ref typ) in insts {
-                let val = env.get_local(fv, inst).unwrap_or_else(|| {
-                    panic!(
-                        "ICE: Free var not found in env\n\
-                         var: {}, inst: {:?}\n\
-                         env: {:?}",
-                        fv, inst, env
-                    )
-                });
-                captures_vals.push(val);
-                captures_types.push(typ.clone());
-            }
-        }
+        let captures_vals = captures
+            .iter()
+            .map(|&l| env.get_local(l))
+            .collect::<Vec<_>>();
+        let captures_types = captures
+            .iter()
+            .map(|&l| env.local_type(l).clone())
+            .collect();
         let captures = self.build_struct(&captures_vals);
         captures.set_name(&format!("{}-capts", name));
         let captures_obj_visitor = self.gen_captures_obj_visitor(captures_types)
@@ -1381,17 +1932,15 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         (captures, captures_obj_visitor)
     }
 
-    /// Insert the captured environment for a closure, into a closure
-    /// that was created without environment capture.
+    /// Store the captured environment `captures` in the allocated, but undefined, space
+    /// `target_captures_generic_ptr` of one or more closures
     fn build_insert_closure_captures(
         &mut self,
-        closure: &Value,
+        target_captures_generic_ptr: &Value,
         captures: &Value,
         captures_obj_visitor: &Function,
         name: &str,
     ) {
-        let target_captures_generic_ptr = self.builder.build_extract_value(closure, 1);
-        target_captures_generic_ptr.set_name(&format!("{}-clos-capts-generic-ptr", name));
         let target_captures_ptr = self.builder.build_bit_cast(
             target_captures_generic_ptr,
             PointerType::new(captures.get_type()),
@@ -1402,136 +1951,208 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             .build_update_obj_visitor(target_captures_generic_ptr, captures_obj_visitor);
     }
 
-    /// Generate the LLVM representation of a lambda expression
-    fn gen_lambda(
+    /// Generate a function without captures as a plain global function, and return the
+    /// closure that wraps it
+    ///
+    /// The function does not depend on the function it occurs in, so the definition is shared
+    /// by all instances of the enclosing function where the lambda is instantiated the same.
+    fn gen_lifted_lambda(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        lam: &'ast ast::Lambda<'src>,
-        name: &str,
-    ) -> &'ctx Value {
-        let (closure, free_vars) = self.gen_closure_without_captures(env, lam, name);
-        let (captures, captures_obj_visitor) = self.gen_closure_env_capture(env, &free_vars, name);
-        self.build_insert_closure_captures(closure, captures, captures_obj_visitor, name);
-        closure
+        func: &'ast mir::Func<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let lifted = self.lifted_lambdas
+            .iter()
+            .find(|&&(ref f, _)| f.pos == func.pos && f == func)
+            .map(|&(_, glob)| glob);
+        let glob = match lifted {
+            Some(glob) => glob,
+            None => {
+                let id = self.internal_symbol(&format!("lifted_{}", func.name), &func.pos);
+                let (at, rt) = func.typ
+                    .get_func()
+                    .unwrap_or_else(|| panic!("ICE: Invalid function type `{}`", func.typ));
+                let (arg_type, ret_type) = (self.gen_type(at), self.gen_type(rt));
+                let func_ll = self.module
+                    .add_function(&id, FunctionType::new(ret_type, &[arg_type]));
+                func_ll.set_linkage(Linkage::Internal);
+                self.gen_func_def(env, func_ll, func)?;
+                let glob = GlobFunc {
+                    func: func_ll,
+                    slot: None,
+                };
+                self.lifted_lambdas.push((func.clone(), glob));
+                glob
+            }
+        };
+        let closure_ptr = self.get_or_gen_closure_wrapper(glob, &func.typ);
+        let closure = self.builder.build_load(closure_ptr);
+        closure.set_name(&format!("{}-clos", func.name));
+        Ok(closure)
     }
 
-    /// Generate LLVM definitions for the variable/function bindings `bs`
-    ///
-    /// Assumes that the variable bindings in `bs` are in reverse
-    /// topologically order for the relation: "depends on".
-    fn gen_let_bindings(
+    /// Generate LLVM definitions for the functions of a binding group
+    fn gen_let_rec(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        bindings: &[&'ast ast::Binding<'src>],
-    ) {
+        r: &'ast mir::LetRec<'src>,
+    ) -> Result<(), Diagnostic> {
         // To solve the problem of recursive references in closure
         // captures, e.g. two mutually recursive functions that need
-        // to capture each other: First create closures where captures
-        // are left as allocated, but undefined space. Second, fill in
-        // captures with all closures with pointers available to refer
-        // to.
-        let empty_vec = vec![];
-        // Flatten with regards to mono insts
-        let mut bindings_insts: Vec<(_, &Vec<ast::Type>, _)> = Vec::new();
-        for binding in bindings {
-            env.push_local(binding.ident.s, BTreeMap::new());
-            if binding.sig.is_monomorphic() {
-                bindings_insts.push((binding.ident.s, &empty_vec, &binding.val));
+        // to capture each other: First create closures that share a
+        // single captures record, left as allocated, but undefined
+        // space. Second, fill in the record when all closures are
+        // available to refer to.
+        //
+        // Functions without captures are lifted. The rest share the union of their captures
+        let mut closures = Vec::new();
+        let mut group_captures = BTreeSet::new();
+        for &(local, ref func) in &r.funcs {
+            if func.captures.is_empty() {
+                let closure = self.gen_lifted_lambda(env, func)?;
+                env.set_local(local, closure);
             } else {
-                for (inst_ts, val_inst) in &binding.mono_insts {
-                    bindings_insts.push((binding.ident.s, &inst_ts, val_inst));
-                }
+                group_captures.extend(func.captures.iter().map(|&(outer, _)| outer));
+                closures.push((local, func));
             }
         }
-        // Declare functions to allow recursive defs
-        let mut lambdas_free_vars = VecDeque::new();
-        for &(name, inst, val) in &bindings_insts {
-            match *val {
-                ast::Expr::Lambda(ref lam) => {
-                    let (closure, free_vars) = self.gen_closure_without_captures(env, lam, name);
-                    env.add_local_inst(name, inst.clone(), closure);
-                    lambdas_free_vars.push_back(free_vars);
-                }
-                _ => (),
-            }
-        }
-        // Define functions and variables
-        for &(name, inst, val) in &bindings_insts {
-            match val {
-                &ast::Expr::Lambda(_) => {
-                    let closure = env.get_local(name, &inst)
-                        .expect("ICE: variable dissapeared");
-                    let free_vars = lambdas_free_vars.pop_front().unwrap();
-                    let (captures, captures_obj_visitor) =
-                        self.gen_closure_env_capture(env, &free_vars, name);
-                    self.build_insert_closure_captures(
-                        closure,
-                        captures,
-                        captures_obj_visitor,
-                        name,
-                    );
-                }
-                expr => {
-                    let var = self.gen_expr(env, expr, Some(name));
-                    var.set_name(name);
-                    env.add_local_inst(name, inst.clone(), var);
-                    let ptr = self.build_ptr_to_val(var);
-                    let var_obj_visitor = self.gen_obj_visitor(expr.get_type())
-                        .unwrap_or(self.gc.nop_obj_visitor);
-                    self.gc
-                        .build_mark_bound_and_clear_scope_temps(ptr, var_obj_visitor);
-                }
-            }
+        let group_captures = group_captures.into_iter().collect::<Vec<_>>();
+        let group_name = closures.iter().map(|&(_, func)| func.name).join("-");
+        let captures_generic = if closures.is_empty() {
+            None
+        } else {
+            Some(self.build_alloc_undef_captures(env, &group_captures, &group_name))
+        };
+        for &(local, func) in &closures {
+            let closure =
+                self.gen_closure(env, &group_captures, captures_generic.unwrap(), func)?;
+            env.set_local(local, closure);
+        }
+        // Define the variables that may be captured, and then fill in the shared captures
+        for stmt in &r.vals {
+            self.gen_stmt(env, stmt)?;
+        }
+        if let Some(captures_generic) = captures_generic {
+            let (captures, captures_obj_visitor) =
+                self.gen_closure_env_capture(env, &group_captures, &group_name);
+            self.build_insert_closure_captures(
+                captures_generic,
+                captures,
+                captures_obj_visitor,
+                &group_name,
+            );
         }
+        Ok(())
     }
 
-    /// Generate LLVM IR for a `let` special form
-    fn gen_let(&mut self, env: &mut Env<'src, 'ctx>, l: &'ast ast::Let<'src>) -> &'ctx Value {
+    /// Generate LLVM IR for the body of a `let`, in a new GC scope
+    fn gen_scope(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        block: &'ast mir::Block<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
         self.gc.build_push_new_scope();
-        let bindings = l.bindings.bindings().rev().collect::<Vec<_>>();
-        self.gen_let_bindings(env, &bindings);
-        let v = self.gen_expr(env, &l.body, None);
+        self.gc_scopes.push(GcScope::Let);
+        let v = self.gen_block(env, block, typ)?;
+        self.gc_scopes.pop();
         self.gc
             .build_move_locals_to_parent_scope_as_temps_and_pop_scope();
-        for b in bindings {
-            env.pop_local(b.ident.s);
-        }
-        v
+        Ok(v)
     }
 
     /// Generate LLVM IR for the construction of a `cons` pair
-    fn gen_cons(&mut self, env: &mut Env<'src, 'ctx>, cons: &'ast ast::Cons<'src>) -> &'ctx Value {
-        let members = [
-            self.gen_expr(env, &cons.car, Some("car")),
-            self.gen_expr(env, &cons.cdr, Some("cdr")),
-        ];
-        self.build_struct(&members)
-    }
-
-    /// Generate LLVM IR for the extraction of the first element of a `cons` pair
-    fn gen_car(&mut self, env: &mut Env<'src, 'ctx>, c: &'ast ast::Car<'src>) -> &'ctx Value {
-        let cons = self.gen_expr(env, &c.expr, None);
-        let r = self.builder.build_extract_value(cons, 0);
-        r.set_name("car");
-        r
+    fn gen_cons(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        car: &'ast mir::Atom<'src>,
+        cdr: &'ast mir::Atom<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let members = [self.gen_atom(env, car)?, self.gen_atom(env, cdr)?];
+        Ok(self.build_struct(&members))
     }
 
-    /// Generate LLVM IR for the extraction of the second element of a `cons` pair
-    fn gen_cdr(&mut self, env: &mut Env<'src, 'ctx>, c: &'ast ast::Cdr<'src>) -> &'ctx Value {
-        let cons = self.gen_expr(env, &c.expr, None);
-        let r = self.builder.build_extract_value(cons, 1);
-        r.set_name("cdr");
-        r
+    /// Generate LLVM IR for the extraction of the member `i` of the `cons` pair `pair`
+    fn gen_pair_member(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        pair: &'ast mir::Atom<'src>,
+        i: u32,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let cons = self.gen_atom(env, pair)?;
+        let r = self.builder.build_extract_value(cons, i as usize);
+        r.set_name(if i == 0 { "car" } else { "cdr" });
+        Ok(r)
+    }
+
+    /// Generate LLVM IR for the load of a member of the pairs in memory at `ptr`
+    ///
+    /// Only the member is loaded, and not the whole pair.
+    fn gen_load(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        ptr: &'ast mir::Atom<'src>,
+        path: &[u32],
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let mut member_ptr = self.gen_atom(env, ptr)?;
+        for &i in path {
+            member_ptr = self.builder.build_gep_struct(self.ctx, member_ptr, i);
+        }
+        let r = self.builder.build_load(member_ptr);
+        r.set_name(if path.last() == Some(&0) { "car" } else { "cdr" });
+        Ok(r)
     }
 
     /// Generate LLVM IR for the cast of an expression to a type
-    fn gen_cast(&mut self, env: &mut Env<'src, 'ctx>, c: &'ast ast::Cast<'src>) -> &'ctx Value {
+    fn gen_cast(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        c: &'ast mir::Cast<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
         let ptr_size = self.ptr_size_bits_();
-        let from_type = c.expr.get_type();
-        let to_type = &c.typ;
+        let from_type = &c.from;
+        let to_type = &c.to;
+        if to_type.get_ptr().is_some() && !c.in_unsafe {
+            return Err(c.pos.diagnostic(format!(
+                "Unsafe cast\nCasting to the pointer `{}` is unsafe, and may only be done \
+                 inside an `unsafe` form",
+                to_type.pretty()
+            )));
+        }
         let to_type_ll = self.gen_type(to_type);
-        let from_expr = self.gen_expr(env, &c.expr, None);
-        let res = if let Some(from_size) = from_type.int_size(ptr_size) {
+        let from_expr = self.gen_atom(env, &c.val)?;
+        let to_bool = to_type.get_const() == Some("Bool");
+        let is_addr = |t: &ast::Type| match t.get_const() {
+            Some("IntPtr") | Some("UIntPtr") => true,
+            _ => false,
+        };
+        let res = if from_type.get_ptr().is_some() {
+            // Casting from pointer
+            if to_type.get_ptr().is_some() {
+                // to pointer of another type
+                Some(self.builder.build_bit_cast(from_expr, to_type_ll))
+            } else if is_addr(to_type) {
+                // to the address, as a pointer sized integer
+                Some(self.builder.build_ptr_to_int(from_expr, to_type_ll))
+            } else {
+                None
+            }
+        } else if to_type.get_ptr().is_some() {
+            // Casting to pointer, from an address as a pointer sized integer
+            if is_addr(from_type) {
+                Some(self.builder.build_int_to_ptr(from_expr, to_type_ll))
+            } else {
+                None
+            }
+        } else if from_type.get_const() == Some("Bool") {
+            // Casting from boolean
+            if to_type.int_size(ptr_size).or(to_type.uint_size(ptr_size)).is_some() {
+                // to 1 or 0 of some integer type
+                Some(self.builder.build_zext(from_expr, to_type_ll))
+            } else {
+                None
+            }
+        } else if let Some(from_size) = from_type.int_size(ptr_size) {
             // Casting from signed integer
             if let Some(to_size) = to_type.int_size(ptr_size).or(to_type.uint_size(ptr_size)) {
                 // to some integer type
@@ -1542,6 +2163,10 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 } else {
                     Some(from_expr)
                 }
+            } else if to_bool {
+                // to boolean, of whether nonzero
+                let zero = Value::new_null(from_expr.get_type());
+                Some(self.builder.build_neq(from_expr, zero))
             } else if to_type.is_float() {
                 // to some float type
                 Some(self.builder.build_si_to_fp(from_expr, to_type_ll))
@@ -1559,6 +2184,10 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 } else {
                     Some(from_expr)
                 }
+            } else if to_bool {
+                // to boolean, of whether nonzero
+                let zero = Value::new_null(from_expr.get_type());
+                Some(self.builder.build_neq(from_expr, zero))
             } else if to_type.is_float() {
                 // to some float type
                 Some(self.builder.build_ui_to_fp(from_expr, to_type_ll))
@@ -1570,24 +2199,166 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             if to_type.is_float() {
                 // to some float type
                 Some(self.builder.build_fpcast(from_expr, to_type_ll))
-            } else if to_type.is_int() {
+            } else if let Some(to_size) = to_type.int_size(ptr_size) {
                 // to some signed integer type
-                Some(self.builder.build_fp_to_si(from_expr, to_type_ll))
-            } else if to_type.is_uint() {
+                Some(self.build_float_to_int_sat(from_expr, from_type, to_type_ll, true, to_size))
+            } else if let Some(to_size) = to_type.uint_size(ptr_size) {
                 // to some unsigned integer type
-                Some(self.builder.build_fp_to_ui(from_expr, to_type_ll))
+                Some(self.build_float_to_int_sat(from_expr, from_type, to_type_ll, false, to_size))
             } else {
                 None
             }
         } else {
             None
         };
-        res.unwrap_or_else(|| {
-            c.pos.error_exit(format!(
-                "Invalid cast\nCannot cast from {} to {}",
-                from_type, to_type
+        let r = res.ok_or_else(|| {
+            c.pos.diagnostic(format!(
+                "Invalid cast\nCannot cast from `{}` to `{}`",
+                from_type.pretty(),
+                to_type.pretty()
             ))
-        })
+        })?;
+        if c.checked {
+            if let Some(ok) = self.build_cast_is_lossless(from_expr, r, from_type, to_type) {
+                let typ = to_type.pretty();
+                self.build_check(env, ok, &RuntErr::CastOutOfRange(c.pos.clone(), &typ));
+            }
+        }
+        Ok(r)
+    }
+
+    /// Build the comparisons of the float `x` of type `from_type` with the bounds of the
+    /// integer type of signedness `signed` and size `size`, `(>= x MIN)` and `(< x (+ MAX 1))`.
+    /// Both are false for NaN
+    fn build_float_int_bounds(
+        &self,
+        x: &'ctx Value,
+        from_type: &ast::Type<'src>,
+        signed: bool,
+        size: usize,
+    ) -> (&'ctx Value, &'ctx Value) {
+        let (lo, hi) = if signed {
+            (-(2f64.powi(size as i32 - 1)), 2f64.powi(size as i32 - 1))
+        } else {
+            (0.0, 2f64.powi(size as i32))
+        };
+        let (lo_ll, hi_ll) = if from_type.get_const() == Some("Float32") {
+            ((lo as f32).compile(self.ctx), (hi as f32).compile(self.ctx))
+        } else {
+            (lo.compile(self.ctx), hi.compile(self.ctx))
+        };
+        (self.builder.build_gteq(x, lo_ll), self.builder.build_lt(x, hi_ll))
+    }
+
+    /// Build the conversion of the float `x` of type `from_type` to the integer type
+    /// `to_type_ll`, of signedness `signed` and size `size`
+    ///
+    /// Out of range values saturate to the bounds of the integer type, and NaN is converted
+    /// to 0, instead of the poison value of a plain `fptosi` or `fptoui`.
+    fn build_float_to_int_sat(
+        &self,
+        x: &'ctx Value,
+        from_type: &ast::Type<'src>,
+        to_type_ll: &'ctx Type,
+        signed: bool,
+        size: usize,
+    ) -> &'ctx Value {
+        let (ge_min, lt_max) = self.build_float_int_bounds(x, from_type, signed, size);
+        let (v, min, max) = if signed {
+            (
+                self.builder.build_fp_to_si(x, to_type_ll),
+                Value::new_int(to_type_ll, 1 << (size - 1)),
+                Value::new_int(to_type_ll, (1 << (size - 1)) - 1),
+            )
+        } else {
+            (
+                self.builder.build_fp_to_ui(x, to_type_ll),
+                Value::new_int(to_type_ll, 0),
+                Value::new_int(to_type_ll, u64::max_value()),
+            )
+        };
+        let r = self.builder.build_select(ge_min, v, min);
+        let r = self.builder.build_select(lt_max, r, max);
+        let is_num = self.builder.build_eq(x, x);
+        self.builder.build_select(is_num, r, Value::new_null(to_type_ll))
+    }
+
+    /// Build the check of a `cast-checked` of `from` of type `from_type` to `to` of type
+    /// `to_type`, of whether the value is in the range of `to_type`
+    ///
+    /// Returns `None` if the cast can not fail, i.e. if the target type is a float.
+    fn build_cast_is_lossless(
+        &self,
+        from: &'ctx Value,
+        to: &'ctx Value,
+        from_type: &ast::Type<'src>,
+        to_type: &ast::Type<'src>,
+    ) -> Option<&'ctx Value> {
+        let ptr_size = self.ptr_size_bits_();
+        let int_size = |t: &ast::Type| {
+            t.int_size(ptr_size)
+                .map(|s| (true, s))
+                .or(t.uint_size(ptr_size).map(|s| (false, s)))
+        };
+        match (int_size(from_type), int_size(to_type)) {
+            (Some((from_signed, from_size)), Some((to_signed, to_size))) => {
+                // The value is preserved if casting back gives the original, and the sign is
+                // the same
+                let from_type_ll = from.get_type();
+                let back = if to_size < from_size && to_signed {
+                    self.builder.build_sext(to, from_type_ll)
+                } else if to_size < from_size {
+                    self.builder.build_zext(to, from_type_ll)
+                } else if to_size > from_size {
+                    self.builder.build_trunc(to, from_type_ll)
+                } else {
+                    to
+                };
+                let same = self.builder.build_eq(back, from);
+                match (from_signed, to_signed) {
+                    (true, false) => {
+                        let nonneg = self.builder.build_gteq(from, Value::new_null(from_type_ll));
+                        Some(self.builder.build_and(same, nonneg))
+                    }
+                    (false, true) => {
+                        let nonneg = self.builder.build_gteq(to, Value::new_null(to.get_type()));
+                        Some(self.builder.build_and(same, nonneg))
+                    }
+                    _ => Some(same),
+                }
+            }
+            (None, Some((to_signed, to_size))) if from_type.is_float() => {
+                let (ge_min, lt_max) =
+                    self.build_float_int_bounds(from, from_type, to_signed, to_size);
+                Some(self.builder.build_and(ge_min, lt_max))
+            }
+            _ => None,
+        }
+    }
+
+    /// Generate a reinterpretation of the bits of a value as another type
+    ///
+    /// The types must be of the same size, which is only known here, after monomorphization
+    fn gen_transmute(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        t: &'ast mir::Transmute<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let from_type_ll = self.gen_type(&t.from);
+        let to_type_ll = self.gen_type(&t.to);
+        let (from_size, to_size) = (self.size_of_(from_type_ll), self.size_of_(to_type_ll));
+        if from_size != to_size {
+            return Err(t.pos.diagnostic(format!(
+                "Invalid transmute\nCannot transmute between types of different sizes. `{}` is \
+                 {} bytes, but `{}` is {} bytes",
+                t.from.pretty(),
+                from_size,
+                t.to.pretty(),
+                to_size
+            )));
+        }
+        let from_expr = self.gen_atom(env, &t.val)?;
+        Ok(self.build_cast(from_expr, to_type_ll))
     }
 
     fn build_of_variant(&mut self, val: &'ctx Value, variant: &str) -> &'ctx Value {
@@ -1607,7 +2378,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         r
     }
 
-    fn build_as_variant(
+    /// A pointer to the contents of `wrapped`, as the variant `variant`
+    fn build_as_variant_ptr(
         &mut self,
         wrapped: &'ctx Value,
         variant: &'src str,
@@ -1621,7 +2393,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             // If ADT is recursive, it's also behind a pointer
             wrapped
         } else {
-            let wrapped_stack = self.builder.build_alloca(wrapped.get_type());
+            let wrapped_stack = self.build_entry_alloca(wrapped.get_type());
             self.builder.build_store(wrapped, wrapped_stack);
             wrapped_stack
         };
@@ -1633,54 +2405,128 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             .type_with_inst_of_variant_with_name(variant, inst)
             .expect("ICE: No type_of_variant in gen_as_varian");
         let unwrapped_type = self.gen_type(&variant_type);
-        let unwrapped_ptr = self.builder.build_bit_cast(
-            unwrapped_ptr_of_largest_member_type,
-            PointerType::new(unwrapped_type),
-        );
+        let unwrapped_ptr = if self.variant_is_boxed(variant, inst) {
+            // The variant is on the heap, and the pointer to it is stored
+            let box_ptr = self.builder.build_bit_cast(
+                unwrapped_ptr_of_largest_member_type,
+                PointerType::new(PointerType::new(unwrapped_type)),
+            );
+            box_ptr.set_name("as-variant_box-ptr");
+            self.builder.build_load(box_ptr)
+        } else {
+            self.builder.build_bit_cast(
+                unwrapped_ptr_of_largest_member_type,
+                PointerType::new(unwrapped_type),
+            )
+        };
         unwrapped_ptr.set_name("as-variant_unwrapped-ptr");
-        let r = self.builder.build_load(unwrapped_ptr);
-        r.set_name("as-variant_unwrapped");
-        r
+        unwrapped_ptr
     }
 
-    fn gen_tuple(&mut self, env: &mut Env<'src, 'ctx>, es: &[Expr<'src>]) -> &'ctx Value {
+    fn gen_tuple(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        es: &'ast [mir::Atom<'src>],
+    ) -> Result<&'ctx Value, Diagnostic> {
         if let Some((last, init)) = es.split_last() {
-            let last_val = self.gen_expr(env, last, None);
-            last_val.set_name("gen-tuple_last");
-            init.iter().rev().fold(last_val, |acc, e| {
-                let members = [self.gen_expr(env, e, Some("gen-tuple_car")), acc];
+            let last_val = self.gen_atom(env, last)?;
+            init.iter().rev().fold(Ok(last_val), |acc, e| {
+                let acc = acc?;
+                let members = [self.gen_atom(env, e)?, acc];
                 let r = self.build_struct(&members);
                 r.set_name("gen-tuple_cons");
-                r
+                Ok(r)
             })
         } else {
-            self.new_nil_val()
+            Ok(self.new_nil_val())
         }
     }
 
-    fn gen_largest_adt_variant_type(
+    /// The types of the variants of an ADT with instantiation `inst`, as stored in the ADT,
+    /// and whether each variant is boxed
+    ///
+    /// A variant is boxed, i.e. stored as a pointer to the variant on the heap, if it's larger
+    /// than `MAX_UNBOXED_VARIANT_SIZE`, and `BOXED_VARIANT_SIZE_RATIO` times larger than the
+    /// smallest variant. As all values of the ADT are as large as the largest stored variant,
+    /// the common small variants then don't pay for the size of a rare huge one. A variant
+    /// annotated `boxed` or `unboxed` is boxed or not regardless of size.
+    fn gen_adt_variant_types(
         &mut self,
         adt: &ast::AdtDef<'src>,
         inst: &[ast::Type<'src>],
-    ) -> &'ctx Type {
+    ) -> Vec<(&'ctx Type, bool)> {
         let variant_types = adt.variants
             .iter()
             .map(|v| {
                 let t = self.adts
                     .type_with_inst_of_variant(v, inst)
-                    .expect("ICE: get_variant_type_with_inst failed in populate_recursive_adt");
-                self.gen_type(&t)
+                    .expect("ICE: type_with_inst_of_variant failed in gen_adt_variant_types");
+                (self.gen_type(&t), v.boxing)
+            })
+            .collect::<Vec<_>>();
+        // Variants annotated `boxed` are not sized, as they may contain the ADT being defined
+        let sizes = variant_types
+            .iter()
+            .map(|&(t, boxing)| match boxing {
+                ast::Boxing::Boxed => None,
+                _ => Some(self.size_of_(t)),
             })
             .collect::<Vec<_>>();
+        let smallest = sizes.iter().filter_map(|&size| size).min().unwrap_or(0);
         variant_types
             .into_iter()
+            .zip(sizes)
+            .map(|((t, boxing), size)| {
+                // Divide instead of multiplying `smallest`, which could overflow
+                let is_boxed = match (boxing, size) {
+                    (ast::Boxing::Auto, Some(size)) => {
+                        size > MAX_UNBOXED_VARIANT_SIZE
+                            && size / BOXED_VARIANT_SIZE_RATIO > smallest
+                    }
+                    (ast::Boxing::Unboxed, _) => false,
+                    _ => true,
+                };
+                if is_boxed {
+                    let boxed: &'ctx Type = PointerType::new(t);
+                    (boxed, true)
+                } else {
+                    (t, false)
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the variant `variant` of an ADT with instantiation `inst` is boxed
+    fn variant_is_boxed(&mut self, variant: &str, inst: &[ast::Type<'src>]) -> bool {
+        let adt = self.adts
+            .parent_adt_of_variant(variant)
+            .expect("ICE: No parent_adt_of_variant in variant_is_boxed")
+            .clone();
+        let i = adt.variant_index(variant)
+            .expect("ICE: No variant_index in variant_is_boxed");
+        self.gen_adt_variant_types(&adt, inst)[i].1
+    }
+
+    fn gen_largest_adt_variant_type(
+        &mut self,
+        adt: &ast::AdtDef<'src>,
+        inst: &[ast::Type<'src>],
+    ) -> &'ctx Type {
+        self.gen_adt_variant_types(adt, inst)
+            .into_iter()
+            .map(|(t, _)| t)
             .max_by_key(|t| self.size_of_(t))
             .unwrap_or(self.named_types.nil)
     }
 
-    fn gen_new(&mut self, env: &mut Env<'src, 'ctx>, n: &'ast ast::New<'src>) -> &'ctx Value {
+    fn gen_new(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        variant: &'src str,
+        members: &'ast [mir::Atom<'src>],
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
         // { tag: i16, data: LARGEST-TYPE }
-        let variant = n.constr.s;
         let adt = self.adts
             .parent_adt_of_variant(variant)
             .expect("ICE: No parent_adt_of_variant in gen_new")
@@ -1688,14 +2534,25 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         let i = adt.variant_index(variant)
             .expect("ICE: No variant_index in gen_new");
         let tag = (i as u16).compile(self.ctx);
-        let adt_inst = n.typ.get_adt_inst_args().unwrap_or(&[]);
+        let adt_inst = typ.get_adt_inst_args().unwrap_or(&[]);
         let largest_type = self.gen_largest_adt_variant_type(&adt, adt_inst);
-        let unwrapped = self.gen_tuple(env, &n.members);
+        let unwrapped = self.gen_tuple(env, members)?;
         unwrapped.set_name("gen-new_unwrapped");
+        let unwrapped = if self.variant_is_boxed(variant, adt_inst) {
+            let variant_type = self.adts
+                .type_with_inst_of_variant_with_name(variant, adt_inst)
+                .expect("ICE: No type_with_inst_of_variant_with_name in gen_new");
+            let boxed = self.gen_gc_alloc_type(&variant_type);
+            boxed.set_name("gen-new_boxed");
+            self.builder.build_store(unwrapped, boxed);
+            boxed
+        } else {
+            unwrapped
+        };
         let unwrapped_largest = self.build_cast(unwrapped, largest_type);
         unwrapped_largest.set_name("gen-new_unwrapped-larg");
 
-        if self.adts.adt_is_recursive(&adt) {
+        Ok(if self.adts.adt_is_recursive(&adt) {
             let adt_inner_type = self.named_types
                 .adts_inner
                 .get(&(adt.name.s, adt_inst.to_vec()))
@@ -1719,40 +2576,55 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             let wrapped_largest = self.build_struct_of_type(&[tag, unwrapped_largest], adt_type);
             wrapped_largest.set_name("gen-new_wrapped-larg");
             wrapped_largest
-        }
+        })
     }
 
     fn gen_match_case_(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        bindings: &mut BTreeMap<&'src str, (&'ast SrcPos<'src>, &'ctx Value)>,
         matchee: &'ctx Value,
         matchee_adt_inst: &[ast::Type<'src>],
-        patt: &'ast Pattern<'src>,
+        patt: &'ast mir::Pattern<'src>,
         body_type: &'ctx Type,
         next_branch: &'ctx BasicBlock,
-    ) {
+    ) -> Result<(), Diagnostic> {
         match *patt {
-            Pattern::Nil(_) => (),
-            Pattern::NumLit(ref lit) => {
-                let n = self.gen_num(lit);
-                let eq = self.builder.build_eq(matchee, n);
+            mir::Pattern::Wild => (),
+            mir::Pattern::Num(_) | mir::Pattern::Bool(_) => {
+                let lit = match *patt {
+                    mir::Pattern::Num(ref lit) => self.gen_num(lit)?,
+                    mir::Pattern::Bool(b) => b.compile(self.ctx),
+                    _ => unreachable!(),
+                };
+                let eq = self.builder.build_eq(matchee, lit);
                 let parent_func = self.current_func.borrow().unwrap();
                 let then_br = parent_func.append("cond_then");
                 self.builder.build_cond_br(eq, then_br, next_branch);
                 self.builder.position_at_end(then_br);
                 *self.current_block.borrow_mut() = Some(then_br);
             }
-            Pattern::StrLit(_) => unimplemented!(),
-            Pattern::Variable(ref var) => {
-                if var.ident.s != "_" {
-                    if let Some((_, _)) = bindings.insert(var.ident.s, (&var.ident.pos, matchee)) {
-                        unimplemented!("multiple occurences of identifier")
-                    }
-                }
+            // Two comparisons, `lo <= matchee` and `matchee <= hi`
+            mir::Pattern::Range(ref lo_lit, ref hi_lit) => {
+                let (lo, hi) = (self.gen_num(lo_lit)?, self.gen_num(hi_lit)?);
+                let lteq: fn(&'ctx Builder, &'ctx Value, &'ctx Value) -> &'ctx Value =
+                    if lo_lit.typ.is_uint() {
+                        Builder::build_ulteq
+                    } else {
+                        Builder::build_lteq
+                    };
+                let parent_func = self.current_func.borrow().unwrap();
+                let above_lo = parent_func.append("range_above_lo");
+                self.builder
+                    .build_cond_br(lteq(self.builder, lo, matchee), above_lo, next_branch);
+                self.builder.position_at_end(above_lo);
+                let then_br = parent_func.append("cond_then");
+                self.builder
+                    .build_cond_br(lteq(self.builder, matchee, hi), then_br, next_branch);
+                self.builder.position_at_end(then_br);
+                *self.current_block.borrow_mut() = Some(then_br);
             }
-            Pattern::Deconstr(ref deconst) => {
-                let variant = deconst.constr.s;
+            mir::Pattern::Bind(local) => env.set_local(local, matchee),
+            mir::Pattern::Deconstr(variant, ref subpatts) => {
                 let variant_member_types = self.adts
                     .members_with_inst_of_variant_with_name(variant, matchee_adt_inst)
                     .unwrap();
@@ -1762,38 +2634,38 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 self.builder.build_cond_br(of_variant, then_br, next_branch);
                 self.builder.position_at_end(then_br);
                 *self.current_block.borrow_mut() = Some(then_br);
-                if let Some((last_sub, subs)) = deconst.subpatts.split_last() {
+                if let Some((last_sub, subs)) = subpatts.split_last() {
                     let (last_member_t, member_ts) = variant_member_types.split_last().unwrap();
-                    let inner = self.build_as_variant(matchee, variant, matchee_adt_inst);
+                    // Load only the members, instead of the whole variant, as it may be large
+                    let inner = self.build_as_variant_ptr(matchee, variant, matchee_adt_inst);
                     let mut remaining = inner;
                     for (sub, member_t) in subs.into_iter().zip(member_ts) {
-                        let sub_matchee = self.build_extract_car(remaining);
+                        let sub_matchee = self.build_load_car(remaining);
                         let sub_matchee_adt_inst = member_t.get_adt_inst_args().unwrap_or(&[]);
                         self.gen_match_case_(
                             env,
-                            bindings,
                             sub_matchee,
                             sub_matchee_adt_inst,
                             sub,
                             body_type,
                             next_branch,
-                        );
-                        remaining = self.build_extract_cdr(remaining);
+                        )?;
+                        remaining = self.builder.build_gep_struct(self.ctx, remaining, 1);
                     }
-                    let sub_matchee = remaining;
+                    let sub_matchee = self.builder.build_load(remaining);
                     let sub_matchee_adt_inst = last_member_t.get_adt_inst_args().unwrap_or(&[]);
                     self.gen_match_case_(
                         env,
-                        bindings,
                         sub_matchee,
                         sub_matchee_adt_inst,
                         last_sub,
                         body_type,
                         next_branch,
-                    );
+                    )?;
                 }
             }
         }
+        Ok(())
     }
 
     fn gen_match_case(
@@ -1801,33 +2673,33 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         env: &mut Env<'src, 'ctx>,
         matchee: &'ctx Value,
         matchee_adt_inst: &[ast::Type<'src>],
-        case: &ast::Case<'src>,
+        case: &'ast mir::Case<'src>,
+        typ: &ast::Type<'src>,
         next_branch: &'ctx BasicBlock,
-    ) -> &'ctx Value {
-        let mut patt_bindings = BTreeMap::new();
-        let body_type = self.gen_type(case.body.get_type());
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let body_type = self.gen_type(typ);
         self.gen_match_case_(
             env,
-            &mut patt_bindings,
             matchee,
             matchee_adt_inst,
             &case.patt,
             body_type,
             next_branch,
-        );
-        for (var, &(_, val)) in &patt_bindings {
-            env.push_local_mono(var, val);
-        }
-        let r = self.gen_expr(env, &case.body, Some("case_body"));
-        for (var, _) in patt_bindings {
-            env.pop_local(var);
-        }
-        r
+        )?;
+        let r = self.gen_block(env, &case.body, typ)?;
+        r.set_name("case_body");
+        Ok(r)
     }
 
-    fn gen_match(&mut self, env: &mut Env<'src, 'ctx>, m: &'ast ast::Match<'src>) -> &'ctx Value {
-        let expr = self.gen_expr(env, &m.expr, Some("matchee"));
-        let expr_adt_inst = m.expr.get_type().get_adt_inst_args().unwrap_or(&[]);
+    fn gen_match(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        m: &'ast mir::Match<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let expr = self.gen_atom(env, &m.matchee)?;
+        let expr_type = env.atom_type(&m.matchee);
+        let expr_adt_inst = expr_type.get_adt_inst_args().unwrap_or(&[]);
         let parent_func = self.current_func.borrow().unwrap();
 
         let case_blocks = m.cases
@@ -1847,7 +2719,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             self.builder.position_at_end(block);
             *self.current_block.borrow_mut() = Some(block);
             let next_block = it.peek().map(|&&(_, b)| b).unwrap_or(default_block);
-            let case_val = self.gen_match_case(env, expr, expr_adt_inst, &case, next_block);
+            let case_val =
+                self.gen_match_case(env, expr, expr_adt_inst, case, typ, next_block)?;
             // The block jumped from to `final_block` on successful match.
             // I.e., the one to use in the phi node
             let case_last_block = self.current_block.borrow().unwrap();
@@ -1860,105 +2733,724 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         *self.current_block.borrow_mut() = Some(default_block);
         self.build_panic(env, &RuntErr::NonExhaustPatts(m.pos.clone()).to_string());
         self.builder.build_br(final_block);
-        let ret_type = self.gen_type(&m.typ);
+        let ret_type = self.gen_type(typ);
         case_phi_nodes.push((Value::new_undef(ret_type), default_block));
 
         self.builder.position_at_end(final_block);
         *self.current_block.borrow_mut() = Some(final_block);
-        self.builder
-            .build_phi(self.gen_type(&m.typ), &case_phi_nodes)
+        Ok(self.builder.build_phi(ret_type, &case_phi_nodes))
+    }
+
+    /// Build a check that panics with the error `err` if `cond` is false
+    fn build_check(&self, env: &mut Env<'src, 'ctx>, cond: &'ctx Value, err: &RuntErr) {
+        let parent_func = self.current_func.borrow().unwrap();
+        let fail_br = parent_func.append("check_fail");
+        let next_br = parent_func.append("check_next");
+        self.builder.build_cond_br(cond, next_br, fail_br);
+
+        self.builder.position_at_end(fail_br);
+        *self.current_block.borrow_mut() = Some(fail_br);
+        self.build_panic(env, &err.to_string());
+        self.builder.build_br(next_br);
+
+        self.builder.position_at_end(next_br);
+        *self.current_block.borrow_mut() = Some(next_br);
     }
 
-    /// Generate llvm code for an expression and return its llvm Value.
-    fn gen_expr(
+    /// Generate a loop, with the variables as phi nodes in a header block that `recur`s in the
+    /// body branch back to
+    fn gen_loop(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        expr: &'ast Expr<'src>,
-        name: Option<&str>,
-    ) -> &'ctx Value {
-        match *expr {
-            // Represent Nil as the empty struct, unit
-            Expr::Nil(_) => self.new_nil_val(),
-            Expr::NumLit(ref n) => self.gen_num(n),
-            Expr::StrLit(ref s) => self.gen_str(env, s),
-            Expr::Bool(ref b) => b.val.compile(self.ctx),
-            Expr::Variable(ref var) => self.gen_variable(env, var),
-            Expr::App(ref app) => opt_set_name(self.gen_app(env, app), name),
-            Expr::If(ref cond) => opt_set_name(self.gen_if(env, cond), name),
-            Expr::Lambda(ref lam) => self.gen_lambda(env, lam, name.unwrap_or("lam")),
-            Expr::Let(ref l) => opt_set_name(self.gen_let(env, l), name),
-            // All type ascriptions should be replaced at this stage
-            Expr::TypeAscript(_) => unreachable!(),
-            Expr::Cons(ref c) => opt_set_name(self.gen_cons(env, c), name),
-            Expr::Car(ref c) => opt_set_name(self.gen_car(env, c), name),
-            Expr::Cdr(ref c) => opt_set_name(self.gen_cdr(env, c), name),
-            Expr::Cast(ref c) => opt_set_name(self.gen_cast(env, c), name),
-            Expr::New(ref n) => opt_set_name(self.gen_new(env, n), name),
-            Expr::Match(ref m) => opt_set_name(self.gen_match(env, m), name),
-        }
-    }
-
-    fn gen_glob_var_decl(&mut self, name: &str, typ: &ast::Type<'src>) -> &'ctx GlobalVariable {
-        let undef = Value::new_undef(self.gen_type(typ));
-        self.module.add_global_variable(name, undef)
+        l: &'ast mir::Loop<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let inits = l.vars
+            .iter()
+            .map(|&(_, ref init)| self.gen_atom(env, init))
+            .collect::<Result<Vec<_>, _>>()?;
+        let entry_br = self.current_block.borrow().unwrap();
+        let parent_func = self.current_func.borrow().unwrap();
+        let header_br = parent_func.append("loop_header");
+        self.builder.build_br(header_br);
+
+        self.builder.position_at_end(header_br);
+        *self.current_block.borrow_mut() = Some(header_br);
+        let mut phis = Vec::new();
+        for (&(var, _), init) in l.vars.iter().zip(inits) {
+            let phi = self.builder.build_phi(init.get_type(), &[(init, entry_br)]);
+            opt_set_name(phi, env.local_name(var));
+            env.set_local(var, phi);
+            phis.push(phi);
+        }
+        let gc_depth = self.gc_scopes.len();
+        self.loops.push((header_br, phis, gc_depth));
+        let v = self.gen_block(env, &l.body, typ)?;
+        self.loops.pop();
+        Ok(v)
     }
 
-    /// Generate uninitialized declarations for all global
-    /// variables.
+    /// Generate a jump back to the header of the innermost loop
     ///
-    /// As they may be initialized by executing arbitrary
-    /// functions, they must (for now) be initialized at runtime. The
-    /// initialization happens in a wrapper around `main`.
-    fn gen_glob_var_decls(
+    /// The GC scopes pushed inside the loop are popped before the jump, keeping the arguments
+    /// alive as temporaries of the scope of the loop. The parser only accepts a `recur` in tail
+    /// position of its loop, so the code following it is unreachable, and the value is
+    /// undefined
+    fn gen_recur(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        var_bindings: &[MonoVarBinding<'src, 'ast>],
-    ) {
-        for (name, inst, val) in var_bindings {
-            let var = self.gen_glob_var_decl(name, val.get_type());
-            env.add_global_inst(name, inst.to_vec(), Global::Var(var));
+        args: &'ast [mir::Atom<'src>],
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        let args = args.iter()
+            .map(|arg| self.gen_atom(env, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (header_br, phis, gc_depth) = self.loops
+            .last()
+            .cloned()
+            .expect("ICE: recur outside of loop in gen_recur");
+        for scope in self.gc_scopes[gc_depth..].iter().rev() {
+            match *scope {
+                GcScope::Let => self.gc
+                    .build_move_locals_to_parent_scope_as_temps_and_pop_scope(),
+                GcScope::Arena => self.gc.build_pop_arena(),
+            }
+        }
+        let current_br = self.current_block.borrow().unwrap();
+        for (phi, arg) in phis.iter().zip(args) {
+            phi.add_incoming(arg, current_br);
         }
+        self.builder.build_br(header_br);
+
+        let parent_func = self.current_func.borrow().unwrap();
+        let next_br = parent_func.append("recur_next");
+        self.builder.position_at_end(next_br);
+        *self.current_block.borrow_mut() = Some(next_br);
+        Ok(Value::new_undef(self.gen_type(typ)))
     }
 
-    fn gen_glob_var_inits(
+    /// Generate a primitive operation on raw pointers
+    fn gen_ptr_op(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        var_bindings: &[MonoVarBinding<'src, 'ast>],
-    ) {
-        for (name, inst, expr) in var_bindings {
-            let glob = env.get_global(name, inst)
-                .expect("ICE: Global variable declaration dissapeared");
-            let glob_var = match glob {
-                Global::Var(v) => v,
-                _ => panic!("ICE: Global var to init was not a global var"),
-            };
-            let v = self.gen_expr(env, expr, None);
-            self.builder.build_store(v, glob_var);
+        p: &'ast mir::PtrOp<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        use self::ast::PtrOpKind::*;
+        // Type-checking only catches this when the pointee is known before monomorphization
+        match p.kind {
+            Null | Eq => (),
+            _ => if let Some(&ast::Type::Const(name, _)) = env.atom_type(&p.args[0]).get_ptr() {
+                if self.named_types.extern_types.contains_key(name) {
+                    return Err(Diagnostic(p.pos.error_string(
+                        codegen_err(2),
+                        format!(
+                            "Can't operate on the pointee of a pointer to the foreign type `{}`",
+                            name
+                        ),
+                    )));
+                }
+            },
         }
+        let args = p.args
+            .iter()
+            .map(|arg| self.gen_atom(env, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(match p.kind {
+            Read => self.builder.build_load(args[0]),
+            Write => {
+                self.builder.build_store(args[1], args[0]);
+                self.new_nil_val()
+            }
+            Offset => self.builder.build_gep(args[0], &[args[1]]),
+            Null => Value::new_null(self.gen_type(typ)),
+            Eq => self.builder.build_eq(args[0], args[1]),
+            ReadVolatile => self.builder.build_volatile_load(args[0]),
+            WriteVolatile => {
+                self.builder.build_volatile_store(args[1], args[0]);
+                self.new_nil_val()
+            }
+            AtomicLoad | AtomicStore | AtomicFetchAdd | AtomicCas => {
+                self.gen_atomic_op(p, &args)?
+            }
+        })
     }
 
-    fn gen_func_def(
+    /// Generate an atomic operation on the value pointed to by the first argument
+    fn gen_atomic_op(
         &mut self,
-        env: &mut Env<'src, 'ctx>,
-        func: &'ctx Function,
-        lam: &ast::Lambda<'src>,
-    ) {
-        let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func));
-        let entry = func.append("entry");
-        let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
+        p: &'ast mir::PtrOp<'src>,
+        args: &[&'ctx Value],
+    ) -> Result<&'ctx Value, Diagnostic> {
+        use self::ast::PtrOpKind::*;
+        let ordering = p.ordering.expect("ICE: atomic op without ordering");
+        let elem_type = PointerType::from_super(args[0].get_type())
+            .expect("ICE: atomic op on non-pointer")
+            .get_element();
+        let is_int = elem_type.is_integer();
+        if !(is_int || elem_type.is_float() || elem_type.is_pointer()) {
+            return Err(p.pos.diagnostic(
+                "Invalid atomic operation\n\
+                 Atomic operations can only be done on integers, floats, and pointers",
+            ));
+        }
+        let align = self.size_of_(elem_type) as u32;
+        Ok(match p.kind {
+            AtomicLoad => {
+                self.builder
+                    .build_atomic_load(args[0], llvm_ordering(ordering), align)
+            }
+            AtomicStore => {
+                self.builder
+                    .build_atomic_store(args[1], args[0], llvm_ordering(ordering), align);
+                self.new_nil_val()
+            }
+            AtomicFetchAdd => {
+                if !is_int {
+                    return Err(p.pos.diagnostic(
+                        "Invalid atomic operation\n\
+                         `atomic-fetch-add` can only be done on integers",
+                    ));
+                }
+                self.builder
+                    .build_atomic_fetch_add(args[0], args[1], llvm_ordering(ordering))
+            }
+            // `cmpxchg` is only defined for integers and pointers, so floats are compared and
+            // exchanged as integers of the same bits
+            AtomicCas => {
+                let (ptr, expected, new) = if elem_type.is_float() {
+                    let bits_type = if align == 4 {
+                        Type::get::<i32>(self.ctx)
+                    } else {
+                        Type::get::<i64>(self.ctx)
+                    };
+                    (
+                        self.builder
+                            .build_bit_cast(args[0], PointerType::new(bits_type)),
+                        self.builder.build_bit_cast(args[1], bits_type),
+                        self.builder.build_bit_cast(args[2], bits_type),
+                    )
+                } else {
+                    (args[0], args[1], args[2])
+                };
+                let r = self.builder.build_cmpxchg(
+                    ptr,
+                    expected,
+                    new,
+                    llvm_ordering(ordering),
+                    llvm_failure_ordering(ordering),
+                );
+                let prev_raw = self.builder.build_extract_value(r, 0);
+                let prev = if elem_type.is_float() {
+                    self.builder.build_bit_cast(prev_raw, elem_type)
+                } else {
+                    prev_raw
+                };
+                let succeeded = self.builder.build_extract_value(r, 1);
+                self.build_struct(&[prev, succeeded])
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Generate the entry point of a thread that runs an IO action of type `io_type`
+    ///
+    /// The action is applied to a fresh `RealWorld`, as the thread has its own thread of
+    /// effects. The result is written to the `out` buffer given by the runtime, and pinned,
+    /// as it must outlive the thread. The captures of the closure, pinned by the spawner,
+    /// are unpinned.
+    fn gen_thread_entry(&mut self, io_type: &'ast ast::Type<'src>) -> &'ctx Function {
+        let (world_type, io_ret_type) = io_type
+            .get_func()
+            .expect("ICE: thread action is not a function");
+        let result_type = io_type.get_io().expect("ICE: thread action is not IO");
+        let closure_type = self.gen_type(io_type);
+        let result_type_ll = self.gen_type(result_type);
+        let func_ptr_type = PointerType::new(self.gen_func_type(world_type, io_ret_type));
+        let result_visitor = self.gen_obj_visitor(result_type)
+            .unwrap_or(self.gc.nop_obj_visitor);
+        let func = self.module
+            .add_function(&self.unique_symbol("thread_entry"), self.threads.entry_type);
+        let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func));
+        let entry = func.append("entry");
+        let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
+        self.builder.position_at_end(entry);
+        let (func_generic, captures, out) = (&*func[0], &*func[1], &*func[2]);
+        func_generic.set_name("func");
+        captures.set_name("captures");
+        out.set_name("out");
+        let closure = self.build_struct_of_type(
+            &[
+                self.builder.build_bit_cast(func_generic, func_ptr_type),
+                captures,
+            ],
+            closure_type,
+        );
+        let io_ret = self.build_app(closure, self.new_real_world_val());
+        let result = self.builder.build_extract_value(io_ret, 0);
+        let out_typed = self.builder
+            .build_bit_cast(out, PointerType::new(result_type_ll));
+        self.builder.build_store(result, out_typed);
+        self.gc.build_pin(out, result_visitor);
+        let closure_ptr = self.build_ptr_to_val(closure);
+        self.gc
+            .build_unpin(closure_ptr, self.gc.closure_obj_visitor);
+        self.builder.build_ret_void();
+
+        *self.current_func.borrow_mut() = parent_func;
+        *self.current_block.borrow_mut() = parent_block;
+        self.builder
+            .position_at_end(self.current_block.borrow().expect("ICE: no current_block"));
+        func
+    }
+
+    /// Store `val` in a new stack slot, and return a generic pointer to it
+    fn build_generic_ptr_to_val(&self, val: &'ctx Value) -> &'ctx Value {
+        let ptr = self.build_entry_alloca(val.get_type());
+        self.builder.build_store(val, ptr);
+        self.builder.build_bit_cast(ptr, type_generic_ptr(self.ctx))
+    }
+
+    /// Build a call of `build_call` with a generic pointer to a new stack slot of type `typ`,
+    /// and return the value written to the slot
+    fn build_read_out<F>(&mut self, typ: &'ast ast::Type<'src>, build_call: F) -> &'ctx Value
+    where
+        F: FnOnce(&Self, &'ctx Value),
+    {
+        let typ_ll = self.gen_type(typ);
+        let out = self.build_entry_alloca(typ_ll);
+        let out_generic = self.builder
+            .build_bit_cast(out, type_generic_ptr(self.ctx));
+        build_call(self, out_generic);
+        self.builder.build_load(out)
+    }
+
+    /// Generate a primitive operation on threads, green threads, channels, or mutexes
+    ///
+    /// Values held by the runtime, like messages in a channel or the value of a mutex, may
+    /// outlive the scope they were created in, so the objects they refer to are pinned
+    /// until taken out of the runtime again.
+    fn gen_thread_op(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        t: &'ast mir::ThreadOp<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        use self::ast::ThreadOpKind::*;
+        let args = t.args
+            .iter()
+            .map(|arg| self.gen_atom(env, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arg_types = t.args
+            .iter()
+            .map(|arg| env.atom_type(arg))
+            .collect::<Vec<_>>();
+        let elem_type = match t.kind {
+            Spawn | TaskSpawn => arg_types[0].get_io().expect("ICE: action not IO"),
+            Join | ChanRecv | MutexLock | TaskAwait | TaskYield => typ,
+            ChanNew => &typ.get_adt_inst_args().expect("ICE: chan has no elem type")[0],
+            ChanSend | MutexUnlock => &arg_types[1],
+            MutexNew => &arg_types[0],
+        };
+        let elem_type_ll = self.gen_type(elem_type);
+        let elem_size = self.size_of_(elem_type_ll) as usize;
+        let elem_visitor = self.gen_obj_visitor(elem_type)
+            .unwrap_or(self.gc.nop_obj_visitor);
+        Ok(match t.kind {
+            Spawn | TaskSpawn => {
+                let entry = self.gen_thread_entry(&arg_types[0]);
+                // Keep the captures alive until the thread has applied the closure
+                let closure_ptr = self.build_ptr_to_val(args[0]);
+                self.gc
+                    .build_pin(closure_ptr, self.gc.closure_obj_visitor);
+                let func = self.builder.build_extract_value(args[0], 0);
+                let func_generic = self.builder
+                    .build_bit_cast(func, type_generic_ptr(self.ctx));
+                let captures = self.builder.build_extract_value(args[0], 1);
+                if t.kind == Spawn {
+                    self.threads.build_spawn(
+                        entry,
+                        func_generic,
+                        captures,
+                        elem_size,
+                        elem_visitor,
+                    )
+                } else {
+                    self.threads
+                        .build_task_spawn(entry, func_generic, captures, elem_size)
+                }
+            }
+            TaskAwait => self.build_read_out(elem_type, |this, out| {
+                this.threads.build_task_await(args[0], out)
+            }),
+            TaskYield => {
+                self.threads.build_task_yield();
+                self.new_nil_val()
+            }
+            Join => self.build_read_out(elem_type, |this, out| {
+                this.threads.build_join(args[0], out)
+            }),
+            ChanNew => self.threads.build_chan_new(elem_size),
+            ChanSend => {
+                self.gc
+                    .build_pin(self.build_ptr_to_val(args[1]), elem_visitor);
+                let val = self.build_generic_ptr_to_val(args[1]);
+                self.threads.build_chan_send(args[0], val);
+                self.new_nil_val()
+            }
+            ChanRecv => {
+                let v = self.build_read_out(elem_type, |this, out| {
+                    this.threads.build_chan_recv(args[0], out)
+                });
+                self.gc.build_adopt(self.build_ptr_to_val(v), elem_visitor);
+                v
+            }
+            MutexNew => {
+                self.gc
+                    .build_pin(self.build_ptr_to_val(args[0]), elem_visitor);
+                let val = self.build_generic_ptr_to_val(args[0]);
+                self.threads.build_mutex_new(elem_size, val)
+            }
+            // The value stays pinned by the mutex until replaced on unlock
+            MutexLock => self.build_read_out(elem_type, |this, out| {
+                this.threads.build_mutex_lock(args[0], out)
+            }),
+            MutexUnlock => {
+                self.gc
+                    .build_pin(self.build_ptr_to_val(args[1]), elem_visitor);
+                let val = self.build_generic_ptr_to_val(args[1]);
+                let old = self.build_read_out(elem_type, |this, old_out| {
+                    this.threads.build_mutex_unlock(args[0], val, old_out)
+                });
+                self.gc.build_unpin(self.build_ptr_to_val(old), elem_visitor);
+                self.new_nil_val()
+            }
+        })
+    }
+
+    /// Generate the body of an arena region, with all heap allocations made in a new arena,
+    /// which is freed after the body has been evaluated
+    fn gen_with_arena(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        block: &'ast mir::Block<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        self.gc.build_push_arena();
+        self.gc_scopes.push(GcScope::Arena);
+        let v = self.gen_block(env, block, typ)?;
+        self.gc_scopes.pop();
+        self.gc.build_pop_arena();
+        Ok(v)
+    }
+
+    /// Generate llvm code for the statements of a block, and return the llvm Value of its
+    /// tail, of type `typ`
+    fn gen_block(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        block: &'ast mir::Block<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        grow_stack(|| {
+            for stmt in &block.stmts {
+                self.gen_stmt(env, stmt)?;
+            }
+            match block.tail {
+                mir::Tail::Return(ref atom) => self.gen_atom(env, atom),
+                mir::Tail::Recur(ref args) => self.gen_recur(env, args, typ),
+            }
+        })
+    }
+
+    /// Generate llvm code for a statement
+    fn gen_stmt(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        stmt: &'ast mir::Stmt<'src>,
+    ) -> Result<(), Diagnostic> {
+        match *stmt {
+            mir::Stmt::Let(local, ref rhs, ref pos) => {
+                let parent_site = if self.profile_heap {
+                    self.alloc_site.replace(Some(pos.location()))
+                } else {
+                    None
+                };
+                let typ = env.local_type(local).clone();
+                let v = self.gen_rhs(env, rhs, &typ)?;
+                if let Some(name) = env.local_name(local) {
+                    v.set_name(name);
+                }
+                env.set_local(local, v);
+                if self.profile_heap {
+                    *self.alloc_site.borrow_mut() = parent_site;
+                }
+            }
+            mir::Stmt::LetRec(ref r) => {
+                let parent_site = if self.profile_heap {
+                    self.alloc_site.replace(Some(r.pos.location()))
+                } else {
+                    None
+                };
+                self.gen_let_rec(env, r)?;
+                if self.profile_heap {
+                    *self.alloc_site.borrow_mut() = parent_site;
+                }
+            }
+            mir::Stmt::Root(local) => {
+                let ptr = self.build_ptr_to_val(env.get_local(local));
+                let typ = env.local_type(local).clone();
+                let obj_visitor = self.gen_obj_visitor(&typ)
+                    .unwrap_or(self.gc.nop_obj_visitor);
+                self.gc
+                    .build_mark_bound_and_clear_scope_temps(ptr, obj_visitor);
+            }
+            mir::Stmt::Assert(ref cond, ref msg, ref pos) => {
+                let cond = self.gen_atom(env, cond)?;
+                self.build_check(env, cond, &RuntErr::AssertFailed(pos.clone(), msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate llvm code for the right hand side of a `let` statement, of type `typ`
+    fn gen_rhs(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        rhs: &'ast mir::Rhs<'src>,
+        typ: &ast::Type<'src>,
+    ) -> Result<&'ctx Value, Diagnostic> {
+        use self::mir::Rhs::*;
+        Ok(match *rhs {
+            Atom(ref a) => self.gen_atom(env, a)?,
+            Global(name, ref inst) => self.gen_global(env, name, inst, typ),
+            Str(ref s) => self.gen_str(env, s),
+            Bytes(ref b) => self.gen_bytes(b),
+            Call {
+                name,
+                ref inst,
+                ref arg,
+                ref pos,
+            } => self.gen_call(env, name, inst, arg, pos)?,
+            App {
+                ref func,
+                ref arg,
+                frame,
+                ref pos,
+            } => self.gen_app(env, func, arg, frame, pos)?,
+            Cons(ref car, ref cdr) => self.gen_cons(env, car, cdr)?,
+            Car(ref pair) => self.gen_pair_member(env, pair, 0)?,
+            Cdr(ref pair) => self.gen_pair_member(env, pair, 1)?,
+            Load(ref ptr, ref path) => self.gen_load(env, ptr, path)?,
+            Cast(ref c) => self.gen_cast(env, c)?,
+            Transmute(ref t) => self.gen_transmute(env, t)?,
+            New(variant, ref members) => self.gen_new(env, variant, members, typ)?,
+            PtrOp(ref p) => self.gen_ptr_op(env, p, typ)?,
+            ThreadOp(ref t) => self.gen_thread_op(env, t, typ)?,
+            If(ref pred, ref conseq, ref alt) => self.gen_if(env, pred, conseq, alt, typ)?,
+            Match(ref m) => self.gen_match(env, m, typ)?,
+            Loop(ref l) => self.gen_loop(env, l, typ)?,
+            Scope(ref block) => self.gen_scope(env, block, typ)?,
+            WithArena(ref block) => self.gen_with_arena(env, block, typ)?,
+        })
+    }
+
+    fn gen_glob_var_decl(
+        &mut self,
+        name: &str,
+        inst: &[ast::Type<'src>],
+        typ: &ast::Type<'src>,
+    ) -> &'ctx GlobalVariable {
+        let undef = Value::new_undef(self.gen_type(typ));
+        let symbol = self.unique_symbol(&inst_symbol(name, inst));
+        self.add_state_global(&symbol, undef)
+    }
+
+    /// Add a global variable of the symbol `symbol` and initial value `init`, that holds
+    /// state of the program, e.g. the value of a global variable of the source
+    ///
+    /// If the symbol is in `linked_state`, the variable is only declared, to be linked to the
+    /// variable of an earlier version of the program when hot reloading.
+    fn add_state_global(&mut self, symbol: &str, init: &'ctx Value) -> &'ctx GlobalVariable {
+        self.state_globals.push(symbol.to_string());
+        if self.linked_state.contains(symbol) {
+            self.module.add_global(symbol, init.get_type())
+        } else {
+            self.module.add_global_variable(symbol, init)
+        }
+    }
+
+    /// Generate uninitialized declarations for all global
+    /// variables.
+    ///
+    /// As they may be initialized by executing arbitrary
+    /// functions, they must (for now) be initialized at runtime. The
+    /// initialization happens in a wrapper around `main`.
+    fn gen_glob_var_decls(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        vars: &[mir::GlobalVar<'src>],
+    ) {
+        for v in vars {
+            let var = self.gen_glob_var_decl(v.name, &v.inst, &v.typ);
+            env.add_global_inst(v.name, v.inst.clone(), Global::Var(var));
+        }
+    }
+
+    /// Generate the globals defined with `def-const` as LLVM constants
+    ///
+    /// Their values have been folded to literals, so unlike other global variables, they're
+    /// initialized at compile time.
+    fn gen_glob_consts(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        consts: &[mir::GlobalConst<'src>],
+    ) -> Result<(), Diagnostic> {
+        for c in consts {
+            let init = self.gen_atom(env, &c.val)?;
+            let symbol = self.unique_symbol(&inst_symbol(c.name, &c.inst));
+            let var = self.module.add_global_const_variable(&symbol, init);
+            env.add_global_inst(c.name, c.inst.clone(), Global::Var(var));
+        }
+        Ok(())
+    }
+
+    /// Generate declarations for the globals defined in `lazy` forms
+    ///
+    /// Each lazy global gets a variable for the value, a flag for whether it's been
+    /// initialized, and a getter that initializes the variable on the first call. Reads of the
+    /// global are calls to the getter. Returns the getters, variables, and flags, in the order
+    /// of `lazies`.
+    fn gen_lazy_glob_decls(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        lazies: &[mir::GlobalVar<'src>],
+    ) -> Vec<(&'ctx Function, &'ctx GlobalVariable, &'ctx GlobalVariable)> {
+        let mut decls = Vec::new();
+        for v in lazies {
+            let var = self.gen_glob_var_decl(v.name, &v.inst, &v.typ);
+            let base = inst_symbol(v.name, &v.inst);
+            let init_symbol = self.unique_symbol(&format!("{}.init", base));
+            let is_init = self.add_state_global(&init_symbol, 0u8.compile(self.ctx));
+            let typ = self.gen_type(&v.typ);
+            let get_symbol = self.unique_symbol(&format!("{}.get", base));
+            let getter: &Function =
+                self.module.add_function(&get_symbol, FunctionType::new(typ, &[]));
+            env.add_global_inst(v.name, v.inst.clone(), Global::LazyVar(getter));
+            decls.push((getter, var, is_init));
+        }
+        decls
+    }
+
+    /// Generate the definitions of the getters of lazy globals, which initialize and pin the
+    /// global on the first call
+    ///
+    /// The flag is set with release ordering after the value is stored, so a thread that sees
+    /// it set also sees the value. The initialization is not locked however, so a lazy global
+    /// first read by several threads at once may be initialized more than once.
+    fn gen_lazy_glob_getters(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        lazies: &'ast [mir::GlobalVar<'src>],
+        decls: &[(&'ctx Function, &'ctx GlobalVariable, &'ctx GlobalVariable)],
+    ) -> Result<(), Diagnostic> {
+        use llvm_sys::LLVMAtomicOrdering::*;
+        for (lazy, &(getter, var, is_init)) in lazies.iter().zip(decls) {
+            let visitor = self.gen_obj_visitor(&lazy.typ)
+                .unwrap_or(self.gc.nop_obj_visitor);
+            let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(getter));
+            let entry = getter.append("entry");
+            let init_br = getter.append("init");
+            let done_br = getter.append("done");
+            let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
+            let parent_locals = env.enter(&lazy.init.locals);
+
+            self.builder.position_at_end(entry);
+            let flag = self.builder
+                .build_atomic_load(is_init, LLVMAtomicOrderingAcquire, 1);
+            let not_initialized = self.builder.build_is_null(flag);
+            self.builder.build_cond_br(not_initialized, init_br, done_br);
+
+            self.builder.position_at_end(init_br);
+            *self.current_block.borrow_mut() = Some(init_br);
+            let v = ice::in_binding(
+                lazy.name,
+                || lazy.init.to_string(),
+                || self.gen_block(env, &lazy.init.block, &lazy.typ),
+            )?;
+            self.builder.build_store(v, var);
+            // Otherwise only a temporary of the scope of the first read keeps it alive
+            self.gc.build_pin(var, visitor);
+            self.builder.build_atomic_store(
+                1u8.compile(self.ctx),
+                is_init,
+                LLVMAtomicOrderingRelease,
+                1,
+            );
+            self.builder.build_ret(v);
+
+            self.builder.position_at_end(done_br);
+            let v = self.builder.build_load(var);
+            self.builder.build_ret(v);
+
+            env.leave(parent_locals);
+            *self.current_func.borrow_mut() = parent_func;
+            *self.current_block.borrow_mut() = parent_block;
+            if let Some(block) = *self.current_block.borrow() {
+                self.builder.position_at_end(block);
+            }
+        }
+        Ok(())
+    }
+
+    fn gen_glob_var_inits(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        vars: &'ast [mir::GlobalVar<'src>],
+    ) -> Result<(), Diagnostic> {
+        for v in vars {
+            let glob = env.get_global(v.name, &v.inst)
+                .expect("ICE: Global variable declaration dissapeared");
+            let glob_var = match glob {
+                Global::Var(v) => v,
+                _ => panic!("ICE: Global var to init was not a global var"),
+            };
+            let parent_locals = env.enter(&v.init.locals);
+            let val = ice::in_binding(
+                v.name,
+                || v.init.to_string(),
+                || self.gen_block(env, &v.init.block, &v.typ),
+            )?;
+            env.leave(parent_locals);
+            self.builder.build_store(val, glob_var);
+        }
+        Ok(())
+    }
+
+    fn gen_func_def(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        func_ll: &'ctx Function,
+        func: &'ast mir::Func<'src>,
+    ) -> Result<(), Diagnostic> {
+        let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func_ll));
+        let entry = func_ll.append("entry");
+        let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
         self.builder.position_at_end(entry);
-        let param = &*func[0];
-        param.set_name(lam.param_ident.s);
-        let local_env = map_of(lam.param_ident.s.to_string(), vec![map_of(vec![], param)]);
-        let old_locals = mem::replace(&mut env.locals, local_env);
-        let r = self.gen_expr(env, &lam.body, None);
+        let parent_locals = env.enter(&func.body.locals);
+        let param = &*func_ll[0];
+        opt_set_name(param, env.local_name(func.param));
+        env.set_local(func.param, param);
+        let (_, ret_typ) = func.typ.get_func().unwrap();
+        let r = self.gen_block(env, &func.body.block, ret_typ)?;
         self.builder.build_ret(r);
-        env.locals = old_locals;
+        env.leave(parent_locals);
         *self.current_func.borrow_mut() = parent_func;
         *self.current_block.borrow_mut() = parent_block;
         if let Some(block) = *self.current_block.borrow() {
             self.builder.position_at_end(block);
         }
+        Ok(())
     }
 
     /// For global functions, generates plain function definitions and
@@ -1968,23 +3460,105 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     /// plain function with an additional param for dummy closure
     /// environment. This is used so that global functions and local
     /// closures can be represented the same way, size-wise and all.
+    ///
+    /// Only the functions in `exports` get external linkage, unless `export_all`.
     fn gen_glob_funcs(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        bindings: &[MonoFuncBinding<'src, 'ast>],
-    ) {
+        glob_funcs: &'ast [mir::GlobalFunc<'src>],
+        exports: &BTreeSet<&'src str>,
+    ) -> Result<(), Diagnostic> {
         let mut funcs = Vec::new();
-        for (name, inst, lam) in bindings {
-            let func = self.gen_func_decl(name, &lam.typ);
-            let closure = self.gen_wrapping_closure(func, name, &lam.typ);
-            let glob_func = GlobFunc { func, closure };
+        for f in glob_funcs {
+            let symbol = self.unique_symbol(&inst_symbol(f.name, &f.inst));
+            let func = self.gen_func_decl(&symbol, &f.func.typ);
+            if !self.export_all && !exports.contains(f.name) {
+                func.set_linkage(Linkage::Internal);
+            }
+            let slot = if self.hot_reload {
+                let slot_symbol = format!("_kvs_slot.{}", symbol);
+                self.slots.push((slot_symbol.clone(), symbol.clone()));
+                Some(self.add_state_global(&slot_symbol, func))
+            } else {
+                None
+            };
+            let glob_func = GlobFunc { func, slot };
             funcs.push(&*func);
-            env.add_global_inst(name, inst.to_vec(), Global::Func(glob_func));
+            env.add_global_inst(f.name, f.inst.clone(), Global::Func(glob_func));
         }
-        for ((name, _, lam), func) in bindings.into_iter().zip(funcs) {
-            println!("gen_func_def: name: {}", name);
-            self.gen_func_def(env, func, lam);
+        for (f, func) in glob_funcs.iter().zip(funcs) {
+            ice::in_binding(
+                f.name,
+                || f.func.to_string(),
+                || self.gen_func_def(env, func, &f.func),
+            )?;
         }
+        Ok(())
+    }
+
+    /// Generate the externs of `ast`, the globals of its lowering `mir`, and the entry point
+    /// `entry_name` of type `entry_type`, e.g. `main`
+    ///
+    /// The builder is left positioned in the entry point, after the initialization of global
+    /// variables.
+    fn gen_entry_and_globals(
+        &mut self,
+        ast: &ast::Ast<'src>,
+        mir: &'ast mir::Module<'src>,
+        entry_name: &str,
+        entry_type: &'ctx FunctionType,
+    ) -> Result<Env<'src, 'ctx>, Diagnostic> {
+        for name in &ast.exports {
+            check_export(ast, name)?;
+        }
+        let mut env = Env::new();
+        // Create wrapping, entry-point `main` function. Must be
+        // declared before the user-defined main so that it gets the
+        // correct name.
+        let main_wrapper = self.module.add_function(entry_name, entry_type);
+
+        self.gen_extern_decls(&mut env, &ast.externs)?;
+        self.gen_glob_consts(&mut env, &mir.consts)?;
+        self.gen_glob_var_decls(&mut env, &mir.vars);
+        let lazy_decls = self.gen_lazy_glob_decls(&mut env, &mir.lazies);
+        self.gen_glob_funcs(&mut env, &mir.funcs, &ast.exports)?;
+        self.gen_lazy_glob_getters(&mut env, &mir.lazies, &lazy_decls)?;
+
+        // Populate the outer, wrapping `main` with glob var
+        // initialization.
+        let entry = main_wrapper.append("entry");
+        self.builder.position_at_end(entry);
+        *self.current_func.borrow_mut() = Some(main_wrapper);
+        *self.current_block.borrow_mut() = Some(entry);
+        self.gen_glob_var_inits(&mut env, &mir.vars)?;
+        Ok(env)
+    }
+
+    /// The type of the C entry point `main`, `int main(int argc, char **argv)`
+    fn c_main_type(&self) -> &'ctx FunctionType {
+        FunctionType::new(
+            Type::get::<i32>(self.ctx),
+            &[
+                Type::get::<i32>(self.ctx),
+                PointerType::new(type_generic_ptr(self.ctx)),
+            ],
+        )
+    }
+
+    /// Assert that `main` exists and is monomorphic, and of either type
+    /// `(-> RealWorld (Cons Nil RealWorld))`, or
+    /// `(-> (Cons Int32 (Ptr (Ptr UInt8))) (-> RealWorld (Cons Int32 RealWorld)))` to take
+    /// the command line arguments and return the exit code. Returns whether it takes them
+    fn check_user_main(&self, ast: &ast::Ast<'src>) -> Result<bool, Diagnostic> {
+        let expect = ast::Type::new_io(ast::TYPE_NIL.clone());
+        let expect_with_args = ast::Type::new_func(
+            ast::Type::new_cons(
+                ast::Type::Const("Int32", None),
+                ast::Type::new_ptr(ast::Type::new_ptr(ast::Type::Const("UInt8", None))),
+            ),
+            ast::Type::new_io(ast::Type::Const("Int32", None)),
+        );
+        check_main(ast, &expect, &expect_with_args)
     }
 
     /// Generate LLVM IR for the executable application defined in `module`.
@@ -2011,62 +3585,493 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     ///     (main')))
     /// ```
     /// where `main'` is the user defined `main`, and `main` is a simple, C-abi compatible function.
-    pub fn gen_executable(&mut self, ast: &ast::Ast<'src>) {
-        // Assert that `main` exists and is monomorphic of type `(-> Nil Nil)`
-        {
-            let main = ast.globals
-                .bindings()
-                .find(|b| b.ident.s == "main")
-                .unwrap_or_else(|| error_exit("main function not found"));
-            let expect = ast::Type::new_io(ast::TYPE_NIL.clone());
-            if main.sig.body != expect {
-                let error_msg = format!(
-                    "main function has wrong type. Expected type `{}`, found type `{}`",
-                    expect, main.sig
-                );
-                if main.sig.is_monomorphic() {
-                    main.pos.error_exit(error_msg)
-                } else {
-                    main.pos.print_error(ErrCode::undefined(), error_msg);
-                    main.pos.print_help(
-                        "Try adding type annotations to enforce correct type \
-                         during type-checking.\n\
-                         E.g. `(define: main (-> RealWorld (Cons Nil RealWorld)) ...)`",
-                    );
-                    exit()
-                }
-            }
+    ///
+    /// The C entry point is named `entry_name`, e.g. `main`.
+    pub fn gen_executable(
+        &mut self,
+        ast: &ast::Ast<'src>,
+        mir: &'ast mir::Module<'src>,
+        entry_name: &str,
+    ) -> Result<Env<'src, 'ctx>, Diagnostic> {
+        let takes_args = self.check_user_main(ast)?;
+        let c_main_type = self.c_main_type();
+        let env = self.gen_entry_and_globals(ast, mir, entry_name, c_main_type)?;
+        self.gen_user_main_call(&env, takes_args);
+        Ok(env)
+    }
+
+    /// Generate a call to the user-defined `main` from the C entry point being generated, and
+    /// return its exit code
+    fn gen_user_main_call(&mut self, env: &Env<'src, 'ctx>, takes_args: bool) {
+        if takes_args {
+            let main_wrapper = self.current_func.borrow().unwrap();
+            let args = self.build_struct(&[&*main_wrapper[0], &*main_wrapper[1]]);
+            args.set_name("args");
+            let io = self.build_call_named_mono(env, "main", args);
+            let r = self.build_app(io, self.new_real_world_val());
+            let exit_code = self.builder.build_extract_value(r, 0);
+            self.builder.build_ret(exit_code);
+        } else {
+            self.build_call_named_mono(env, "main", self.new_real_world_val());
+            self.builder.build_ret(0i32.compile(self.ctx));
         }
+    }
 
-        // Create wrapping, entry-point `main` function. Must be
-        // declared before the user-defined main so that it gets the
-        // correct name.
-        let outer_main_type =
-            FunctionType::new(Type::get::<i32>(self.ctx), &[self.named_types.nil]);
-        let main_wrapper = self.module.add_function("main", &outer_main_type);
+    /// Generate a program to be JIT compiled, with the entry points `_kvs_jit_init` and
+    /// `main`, and return the call thunks and state globals of the program
+    ///
+    /// `_kvs_jit_init` initializes the global variables, and is run when the program is
+    /// loaded, so that global functions may be called before, or without, `main`. `main`
+    /// calls the user-defined `main` if there is one, and otherwise does nothing. All global
+    /// functions keep external linkage, as the JIT only exposes global symbols to
+    /// `get_function`.
+    ///
+    /// If `hot_reload`, global functions are called through slots. The state globals of
+    /// `linked_state` are only declared, to be linked to those of the version of the program
+    /// being reloaded, in which case `_kvs_jit_init` must not be run.
+    pub fn gen_jit(
+        &mut self,
+        ast: &ast::Ast<'src>,
+        mir: &'ast mir::Module<'src>,
+        hot_reload: bool,
+        linked_state: BTreeSet<String>,
+    ) -> Result<JitSymbols, Diagnostic> {
+        self.export_all = true;
+        self.hot_reload = hot_reload;
+        self.linked_state = linked_state;
+        // Declared before the user-defined `main`, so that it gets the name
+        let c_main_type = self.c_main_type();
+        let main_wrapper = self.module.add_function("main", c_main_type);
+        let has_main = ast.globals.bindings().any(|b| b.ident.s == "main");
+        let takes_args = has_main && self.check_user_main(ast)?;
+        let init_type = FunctionType::new(Type::get::<()>(self.ctx), &[]);
+        let env = self.gen_entry_and_globals(ast, mir, "_kvs_jit_init", init_type)?;
+        self.builder.build_ret_void();
 
-        let mut env = Env::new();
+        let entry = main_wrapper.append("entry");
+        self.builder.position_at_end(entry);
+        *self.current_func.borrow_mut() = Some(main_wrapper);
+        *self.current_block.borrow_mut() = Some(entry);
+        if has_main {
+            self.gen_user_main_call(&env, takes_args)
+        } else {
+            self.builder.build_ret(0i32.compile(self.ctx));
+        }
+        Ok(JitSymbols {
+            call_thunks: self.gen_call_thunks(&env, ast),
+            state: self.state_globals.clone(),
+            slots: self.slots.clone(),
+        })
+    }
 
-        self.gen_core_funcs(&mut env);
-        self.gen_extern_decls(&mut env, &ast.externs);
-        let glob_bindings = ast.globals.bindings().rev().collect::<Vec<_>>();
-        for binding in &glob_bindings {
-            env.globs
-                .insert(binding.ident.s.to_string(), BTreeMap::new());
+    /// Generate call thunks for each monomorphic global of `ast`, so that the host can call
+    /// the functions, and show the results, through a single C type, `void (i8 *args, i8 *out)`
+    ///
+    /// A thunk applies the function to each of its curried primitive parameters in turn, read
+    /// from consecutive 8-byte slots of `args`. If the result is primitive, one thunk writes
+    /// it to `out`. Another thunk writes the result to the host `String` `out`, as it would be
+    /// written in source code, and is also generated for global variables.
+    pub fn gen_call_thunks(
+        &mut self,
+        env: &Env<'src, 'ctx>,
+        ast: &ast::Ast<'src>,
+    ) -> Vec<CallThunk> {
+        let mut thunks = Vec::new();
+        for binding in ast.globals.bindings() {
+            if !binding.sig.is_monomorphic() {
+                continue;
+            }
+            let (params, ret) = primitive_params_and_result(&binding.sig.body);
+            // A global function is only a value through its closure wrapper
+            if params.is_empty() && binding.sig.body.get_func().is_some() {
+                continue;
+            }
+            let name = binding.ident.s;
+            let symbol = if !params.is_empty() && is_primitive(ret) {
+                let symbol = self.unique_symbol(&format!("_kvs_call.{}", name));
+                let (r, out) = self.build_thunk_application(env, &symbol, name, &params);
+                let ret_ptr_type = PointerType::new(self.gen_type(ret));
+                self.builder
+                    .build_store(r, self.builder.build_bit_cast(out, ret_ptr_type));
+                self.builder.build_ret_void();
+                Some(symbol)
+            } else {
+                None
+            };
+            // Generated first, as it moves the builder
+            let show = self.gen_show(ret);
+            let show_symbol = self.unique_symbol(&format!("_kvs_show_call.{}", name));
+            let (r, out) = self.build_thunk_application(env, &show_symbol, name, &params);
+            let r_ptr = self.build_entry_alloca(r.get_type());
+            self.builder.build_store(r, r_ptr);
+            let r_generic = self.builder
+                .build_bit_cast(r_ptr, type_generic_ptr(self.ctx));
+            self.builder.build_call(show, &[out, r_generic]);
+            self.builder.build_ret_void();
+            thunks.push(CallThunk {
+                name: name.to_string(),
+                symbol,
+                show_symbol,
+                params: params.iter().map(|t| t.to_string()).collect(),
+                ret: ret.to_string(),
+            });
         }
-        let (glob_func_bindings, glob_var_bindings) = separate_func_bindings_mono(&glob_bindings);
-        self.gen_glob_var_decls(&mut env, &glob_var_bindings);
-        self.gen_glob_funcs(&mut env, &glob_func_bindings);
+        *self.current_func.borrow_mut() = None;
+        *self.current_block.borrow_mut() = None;
+        thunks
+    }
 
-        // Populate the outer, wrapping `main` with glob var
-        // initialization and calling of user-defined `main`.
-        let entry = main_wrapper.append("entry");
+    /// Begin a thunk of the symbol `symbol` and type `void (i8 *args, i8 *out)`, that applies
+    /// the global `name` to its curried parameters of types `params`, read from `args`
+    ///
+    /// If there are no parameters, the value of the global variable `name` is the result.
+    /// Returns the result and `out`, with the builder left positioned in the thunk.
+    fn build_thunk_application(
+        &mut self,
+        env: &Env<'src, 'ctx>,
+        symbol: &str,
+        name: &str,
+        params: &[&ast::Type<'src>],
+    ) -> (&'ctx Value, &'ctx Value) {
+        let thunk_type = FunctionType::new(
+            Type::get::<()>(self.ctx),
+            &[type_generic_ptr(self.ctx), type_generic_ptr(self.ctx)],
+        );
+        let thunk = self.module.add_function(symbol, thunk_type);
+        let entry = thunk.append("entry");
         self.builder.position_at_end(entry);
-        *self.current_func.borrow_mut() = Some(main_wrapper);
+        *self.current_func.borrow_mut() = Some(thunk);
         *self.current_block.borrow_mut() = Some(entry);
-        self.gen_glob_var_inits(&mut env, &glob_var_bindings);
-        self.build_call_named_mono(&mut env, "main", self.new_real_world_val());
+        let (args, out) = (&*thunk[0], &*thunk[1]);
+        args.set_name("args");
+        out.set_name("out");
+        let mut r = None;
+        for (i, param) in params.iter().enumerate() {
+            let slot = self.builder.build_gep(args, &[(8 * i).compile(self.ctx)]);
+            let param_ptr_type = PointerType::new(self.gen_type(param));
+            let arg = self.builder
+                .build_load(self.builder.build_bit_cast(slot, param_ptr_type));
+            r = Some(match r {
+                None => self.build_call_named_mono(env, name, arg),
+                Some(closure) => self.build_app(closure, arg),
+            });
+        }
+        let r = r.unwrap_or_else(|| match env.get_global_mono(name) {
+            Some(Global::Var(g)) => self.builder.build_load(g),
+            Some(Global::LazyVar(g)) => self.builder.build_call(g, &[]),
+            _ => panic!("ICE: No global variable `{}` in build_thunk_application", name),
+        });
+        (r, out)
+    }
+
+    /// Generate a function that writes a value of type `typ` to a host `String`, as it would
+    /// be written in source code
+    ///
+    /// The function is of type `void (i8 *out, i8 *val)`, where `out` is the `String`, and
+    /// `val` points to the value. Pairs are written as `(cons A B)`, and variants as
+    /// `(new Variant MEMBERS...)`. Opaque values, like closures and pointers, are written as
+    /// their type and address, e.g. `#<(-> Int64 Int64) 0x7f3a...>`.
+    fn gen_show(&mut self, typ: &ast::Type<'src>) -> &'ctx Function {
+        let typ = typ.canonicalize();
+        if let Some(&func) = self.show.funcs.get(&typ) {
+            return func;
+        }
+        let func = self.module
+            .add_function(&self.unique_symbol(&format!("show.{}", typ)), self.show.func_type);
+        func.set_linkage(Linkage::Internal);
+        // Registered before the body is generated, as a value of a recursive ADT contains
+        // values of its own type
+        self.show.funcs.insert(typ.clone(), func);
+        let entry = func.append("entry");
+        let (out, val) = (&*func[0], &*func[1]);
+        out.set_name("out");
+        val.set_name("val");
+        match typ {
+            ast::Type::Const(name, _) if self.adts.defs.contains_key(name) => {
+                self.gen_show_adt(func, entry, name, &[])
+            }
+            ast::Type::App(box ast::TypeFunc::Const(name), ref ts)
+                if self.adts.defs.contains_key(name) =>
+            {
+                self.gen_show_adt(func, entry, name, ts)
+            }
+            ast::Type::App(box ast::TypeFunc::Const("Cons"), ref ts) => {
+                let show_car = self.gen_show(&ts[0]);
+                let show_cdr = self.gen_show(&ts[1]);
+                self.builder.position_at_end(entry);
+                let pair_type = PointerType::new(self.gen_type(&typ));
+                let pair = self.builder.build_bit_cast(val, pair_type);
+                let car = self.builder.build_gep_struct(self.ctx, pair, 0);
+                let cdr = self.builder.build_gep_struct(self.ctx, pair, 1);
+                self.show.build_show_str(out, "(cons ");
+                self.builder.build_call(
+                    show_car,
+                    &[out, self.builder.build_bit_cast(car, type_generic_ptr(self.ctx))],
+                );
+                self.show.build_show_str(out, " ");
+                self.builder.build_call(
+                    show_cdr,
+                    &[out, self.builder.build_bit_cast(cdr, type_generic_ptr(self.ctx))],
+                );
+                self.show.build_show_str(out, ")");
+            }
+            _ => {
+                self.builder.position_at_end(entry);
+                let val_type = PointerType::new(self.gen_type(&typ));
+                let val = self.builder.build_bit_cast(val, val_type);
+                self.build_show_leaf(&typ, out, val)
+            }
+        }
+        self.builder.build_ret_void();
+        if let Some(block) = *self.current_block.borrow() {
+            self.builder.position_at_end(block);
+        }
+        func
+    }
+
+    /// Build a write of the value at `val` of the primitive or opaque type `typ` to `out`
+    fn build_show_leaf(&self, typ: &ast::Type<'src>, out: &'ctx Value, val: &'ctx Value) {
+        match *typ {
+            ast::Type::Const("Nil", _) => self.show.build_show_str(out, "nil"),
+            ast::Type::Const("RealWorld", _) => self.show.build_show_str(out, "#<RealWorld>"),
+            ast::Type::Const("Bool", _) => {
+                self.show.build_show_bool(out, self.builder.build_load(val))
+            }
+            ast::Type::Const("Float32", _) => {
+                self.show.build_show_float32(out, self.builder.build_load(val))
+            }
+            ast::Type::Const("Float64", _) => {
+                self.show.build_show_float64(out, self.builder.build_load(val))
+            }
+            _ if typ.is_int() => self.show.build_show_int(out, self.builder.build_load(val)),
+            _ if typ.is_uint() => self.show.build_show_uint(out, self.builder.build_load(val)),
+            // A pointer to the interned length and name
+            ast::Type::Const("Symbol", _) => {
+                let sym = self.builder.build_load(val);
+                let len = self.build_load_car(sym);
+                let name = self.builder
+                    .build_load(self.builder.build_gep_struct(self.ctx, sym, 1));
+                self.show.build_show_str(out, "'");
+                self.show.build_show_str_dyn(out, name, len);
+            }
+            // A closure is written as the address of its function
+            ast::Type::App(box ast::TypeFunc::Const("->"), _) => {
+                self.show.build_show_str(out, &format!("#<{} ", typ.pretty()));
+                self.show.build_show_addr(out, self.build_load_car(val));
+                self.show.build_show_str(out, ">");
+            }
+            // A pointer, or a handle to a thread, channel, mutex, or task in the runtime
+            _ => {
+                self.show.build_show_str(out, &format!("#<{} ", typ.pretty()));
+                self.show.build_show_addr(out, self.builder.build_load(val));
+                self.show.build_show_str(out, ">");
+            }
+        }
+    }
+
+    /// Generate the body of the show function `func` of the ADT `name` with instantiation
+    /// `inst`, leaving the builder positioned after the variant has been written
+    fn gen_show_adt(
+        &mut self,
+        func: &'ctx Function,
+        entry: &'ctx BasicBlock,
+        name: &'src str,
+        inst: &[ast::Type<'src>],
+    ) {
+        let adt_def = self.adts.defs[name].clone();
+        let stored_types = self.gen_adt_variant_types(&adt_def, inst);
+        // The show functions of the members are generated first, as that moves the builder
+        let mut variants = Vec::new();
+        for v in &adt_def.variants {
+            let members = self.adts
+                .members_with_inst_of_variant(v, inst)
+                .expect("ICE: No members_with_inst_of_variant in gen_show_adt");
+            let mut shows = Vec::new();
+            for member in &members {
+                shows.push(self.gen_show(member));
+            }
+            variants.push((v.name.s, members, shows));
+        }
+        let (out, val) = (&*func[0], &*func[1]);
+        self.builder.position_at_end(entry);
+        // A recursive ADT is behind a pointer
+        let adt_type = self.get_or_gen_adt_by_name_and_inst(name, inst);
+        let adt_ptr = self.builder.build_bit_cast(val, PointerType::new(adt_type));
+        let adt = if self.adts.adt_is_recursive(&adt_def) {
+            self.builder.build_load(adt_ptr)
+        } else {
+            adt_ptr
+        };
+        adt.set_name("adt");
+        let tag = self.build_load_car(adt);
+        tag.set_name("tag");
+        let inner = self.builder.build_gep_struct(self.ctx, adt, 1);
+        inner.set_name("inner");
+        let done = func.append("done");
+        let mut cases: Vec<(&Value, &BasicBlock)> = vec![];
+        for (i, (variant, members, shows)) in variants.into_iter().enumerate() {
+            let block = func.append(&format!("variant_{}", i));
+            self.builder.position_at_end(block);
+            let members_ptr = if stored_types[i].1 {
+                let box_ptr = self.builder
+                    .build_bit_cast(inner, PointerType::new(type_generic_ptr(self.ctx)));
+                self.builder.build_load(box_ptr)
+            } else {
+                self.builder.build_bit_cast(inner, type_generic_ptr(self.ctx))
+            };
+            if members.is_empty() {
+                self.show.build_show_str(out, &format!("(new {})", variant));
+            } else {
+                self.show.build_show_str(out, &format!("(new {}", variant));
+                self.build_show_members(out, members_ptr, &members, &shows);
+                self.show.build_show_str(out, ")");
+            }
+            self.builder.build_br(done);
+            cases.push(((i as u16).compile(self.ctx), block));
+        }
+        let default = func.append("default");
+        self.builder.position_at_end(default);
+        self.builder.build_unreachable();
+        self.builder.position_at_end(entry);
+        self.builder.build_switch(tag, default, &cases);
+        self.builder.position_at_end(done);
+    }
+
+    /// Build a write of the members at `members_ptr` of a variant, each preceded by a space,
+    /// to `out`. The members are stored as nested pairs, like the arguments of a function
+    fn build_show_members(
+        &mut self,
+        out: &'ctx Value,
+        members_ptr: &'ctx Value,
+        members: &[ast::Type<'src>],
+        shows: &[&'ctx Function],
+    ) {
+        let mut rest = members_ptr;
+        for (i, &show) in shows.iter().enumerate() {
+            self.show.build_show_str(out, " ");
+            if i + 1 == members.len() {
+                self.builder.build_call(show, &[out, rest]);
+            } else {
+                let rest_type = self.gen_type(&ast::Type::new_tuple(&members[i..]));
+                let pair = self.builder
+                    .build_bit_cast(rest, PointerType::new(rest_type));
+                let member = self.builder.build_gep_struct(self.ctx, pair, 0);
+                let member = self.builder
+                    .build_bit_cast(member, type_generic_ptr(self.ctx));
+                self.builder.build_call(show, &[out, member]);
+                let cdr = self.builder.build_gep_struct(self.ctx, pair, 1);
+                rest = self.builder.build_bit_cast(cdr, type_generic_ptr(self.ctx));
+            }
+        }
+    }
+
+    /// Generate a test harness that runs each test of `ast`, instead of the user-defined `main`
+    ///
+    /// Each test is run in a child process by `_test_run` of the core library, so that a failed
+    /// assertion only aborts its own test. The exit code is that of `_test_summary`.
+    pub fn gen_test_harness(
+        &mut self,
+        ast: &ast::Ast<'src>,
+        mir: &'ast mir::Module<'src>,
+        entry_name: &str,
+    ) -> Result<(), Diagnostic> {
+        let run_test_type = FunctionType::new(
+            Type::get::<bool>(self.ctx),
+            &[type_generic_ptr(self.ctx)],
+        );
+        let run_test = self.module.add_function("_test_run", run_test_type);
+        let pass_test = self.module
+            .add_function("_test_pass", FunctionType::new(Type::get::<()>(self.ctx), &[]));
+        let summary = self.module
+            .add_function("_test_summary", FunctionType::new(Type::get::<i32>(self.ctx), &[]));
+
+        let c_main_type = self.c_main_type();
+        let env = self.gen_entry_and_globals(ast, mir, entry_name, c_main_type)?;
+        let main_wrapper = self.current_func.borrow().unwrap();
+        for test in &ast.tests {
+            let name_lit = Value::new_string(self.ctx, test.name.s, false);
+            let name_const = self.module.add_global_const_variable(
+                &self.unique_symbol(&format!("test_name.{}", test.name.s)),
+                name_lit,
+            );
+            let name_ptr = self.builder.build_gep(
+                name_const,
+                &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+            );
+            let is_child = self.builder.build_call(run_test, &[name_ptr]);
+            let run_br = main_wrapper.append("test_run");
+            let next_br = main_wrapper.append("test_next");
+            self.builder.build_cond_br(is_child, run_br, next_br);
+
+            self.builder.position_at_end(run_br);
+            *self.current_block.borrow_mut() = Some(run_br);
+            self.build_call_named_mono(&env, test.name.s, self.new_nil_val());
+            self.builder.build_call(pass_test, &[]);
+            self.builder.build_br(next_br);
+
+            self.builder.position_at_end(next_br);
+            *self.current_block.borrow_mut() = Some(next_br);
+        }
+        let exit_code = self.builder.build_call(summary, &[]);
+        self.builder.build_ret(exit_code);
+        Ok(())
+    }
+
+    /// Generate a library, exporting the global functions `exports` of `ast`
+    ///
+    /// Each exported function gets a wrapper with external linkage, which initializes the
+    /// global variables of the library on the first call, and then calls the function.
+    /// All other definitions get internal linkage. Initialization is not synchronized, so the
+    /// first call must not race with other calls.
+    pub fn gen_library(
+        &mut self,
+        ast: &ast::Ast<'src>,
+        mir: &'ast mir::Module<'src>,
+        exports: &[String],
+    ) -> Result<(), Diagnostic> {
+        for name in exports {
+            check_export(ast, name)?;
+        }
+
+        let init_type = FunctionType::new(Type::get::<i32>(self.ctx), &[self.named_types.nil]);
+        let env = self.gen_entry_and_globals(ast, mir, "_kvs_init", init_type)?;
         self.builder.build_ret(0i32.compile(self.ctx));
+        let init = self.current_func.borrow().unwrap();
+
+        for func in &*self.module {
+            if !func.is_declaration() {
+                func.set_linkage(Linkage::Internal)
+            }
+        }
+        let initialized = self.module
+            .add_global_variable("_kvs_initialized", false.compile(self.ctx));
+        initialized.set_linkage(Linkage::Internal);
+
+        for name in exports {
+            let func = match env.get_global_mono(name) {
+                Some(Global::Func(glob_func)) => glob_func.func,
+                _ => panic!("ICE: exported function `{}` not generated", name),
+            };
+            func.set_name(&format!("{}.kvs", name));
+            let wrapper = self.module.add_function(name, func.get_signature());
+            let entry = wrapper.append("entry");
+            let init_br = wrapper.append("init");
+            let call_br = wrapper.append("call");
+
+            self.builder.position_at_end(entry);
+            let is_initialized = self.builder.build_load(initialized);
+            self.builder.build_cond_br(is_initialized, call_br, init_br);
+
+            self.builder.position_at_end(init_br);
+            self.builder.build_call(init, &[self.new_nil_val()]);
+            self.builder.build_store(true.compile(self.ctx), initialized);
+            self.builder.build_br(call_br);
+
+            self.builder.position_at_end(call_br);
+            let result = self.builder.build_call(func, &[&*wrapper[0]]);
+            self.builder.build_ret(result);
+        }
+        Ok(())
     }
 }
 
@@ -2147,3 +4152,18 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
 // while calculating g().
 //
 // In my case, g() is not calculated until after h(f()).
+
+#[cfg(test)]
+mod test {
+    use super::is_near_main;
+
+    #[test]
+    fn test_is_near_main() {
+        for name in &["Main", "MAIN", "_main", "main_", "main'"] {
+            assert!(is_near_main(name), "{}", name);
+        }
+        for name in &["pain", "mail", "man", "domain", "mains"] {
+            assert!(!is_near_main(name), "{}", name);
+        }
+    }
+}
@@ -13,11 +13,18 @@ pub struct Gc<'ctx, 'src> {
     pub captures_obj_visitors: BTreeMap<Vec<ast::Type<'src>>, Option<&'ctx Function>>,
     push_new_scope: &'ctx Function,
     alloc_temp: &'ctx Function,
+    alloc_profiled: &'ctx Function,
+    oom: &'ctx Function,
     mark_bound: &'ctx Function,
     mark_bound_and_clear_scope_temps: &'ctx Function,
     pop_scope: &'ctx Function,
+    push_arena: &'ctx Function,
+    pop_arena: &'ctx Function,
     update_obj_visitor: &'ctx Function,
     move_locals_to_parent_scope_as_temps_and_pop_scope: &'ctx Function,
+    pin: &'ctx Function,
+    unpin: &'ctx Function,
+    adopt: &'ctx Function,
     pub closure_obj_visitor: &'ctx Function,
     pub handle_self_obj_visitor: &'ctx Function,
     pub nop_obj_visitor: &'ctx Function,
@@ -38,6 +45,15 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
         let obj_visitor_type = t_obj_visitor;
         let alloc_type = FunctionType::new(t_ptr_i8, &[t_usize, t_obj_visitor]);
         let alloc_temp = module.add_function("gc_alloc", alloc_type);
+        // extern "C" fn gc_alloc_profiled(size: usize, obj_visitor: ObjVisitor,
+        //                                 site: *const c_char) -> *mut i8;
+        let alloc_profiled = module.add_function(
+            "gc_alloc_profiled",
+            FunctionType::new(t_ptr_i8, &[t_usize, t_obj_visitor, t_ptr_i8]),
+        );
+        // extern "C" fn _oom(size: usize) -> !;
+        let oom = module.add_function("_oom", FunctionType::new(t_void, &[t_usize]));
+        oom.add_attribute("noreturn");
         let closure_obj_visitor =
             Gc::gen_closure_obj_visitor(ctx, module, builder, obj_visitor_type);
         let handle_self_obj_visitor =
@@ -51,6 +67,8 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
         let pop_scope = module.add_function("gc_pop_scope", FunctionType::new(t_void, &[]));
         let push_new_scope =
             module.add_function("gc_push_new_scope", FunctionType::new(t_void, &[]));
+        let push_arena = module.add_function("gc_push_arena", FunctionType::new(t_void, &[]));
+        let pop_arena = module.add_function("gc_pop_arena", FunctionType::new(t_void, &[]));
         let update_obj_visitor = module.add_function(
             "gc_update_obj_visitor",
             FunctionType::new(t_void, &[t_ptr_i8, t_obj_visitor]),
@@ -59,10 +77,15 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
             "gc_move_locals_to_parent_scope_as_temps_and_pop_scope",
             FunctionType::new(t_void, &[]),
         );
+        let pin = module.add_function("gc_pin", mark_bound_type);
+        let unpin = module.add_function("gc_unpin", mark_bound_type);
+        let adopt = module.add_function("gc_adopt", mark_bound_type);
         Gc {
             ctx,
             builder,
             alloc_temp,
+            alloc_profiled,
+            oom,
             closure_obj_visitor,
             obj_visitor_type,
             adt_obj_visitors: BTreeMap::new(),
@@ -73,9 +96,14 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
             nop_obj_visitor,
             obj_visitors: BTreeMap::new(),
             pop_scope,
+            push_arena,
+            pop_arena,
             push_new_scope,
             update_obj_visitor,
             move_locals_to_parent_scope_as_temps_and_pop_scope,
+            pin,
+            unpin,
+            adopt,
         }
     }
 
@@ -137,6 +165,26 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
             .build_call(self.alloc_temp, &[size.compile(self.ctx), obj_visitor])
     }
 
+    /// Build an allocation like `build_alloc_temp`, that is also recorded in the heap profile
+    /// under the allocation site described by the C string `site`
+    pub fn build_alloc_profiled(
+        &self,
+        size: usize,
+        obj_visitor: &'ctx Function,
+        site: &'ctx Value,
+    ) -> &'ctx Value {
+        self.builder.build_call(
+            self.alloc_profiled,
+            &[size.compile(self.ctx), obj_visitor, site],
+        )
+    }
+
+    /// Build a call to the out-of-memory handler, for a failed allocation of `size` bytes
+    pub fn build_oom(&self, size: usize) {
+        self.builder.build_call(self.oom, &[size.compile(self.ctx)]);
+        self.builder.build_unreachable();
+    }
+
     pub fn build_push_new_scope(&self) {
         self.builder.build_call(self.push_new_scope, &[]);
     }
@@ -146,10 +194,33 @@ impl<'ctx, 'src> Gc<'ctx, 'src> {
             .build_call(self.mark_bound, &[obj, obj_visitor]);
     }
 
+    /// Build a pin of the objects referred to by `obj`, keeping them alive until unpinned
+    pub fn build_pin(&self, obj: &Value, obj_visitor: &Function) {
+        self.builder.build_call(self.pin, &[obj, obj_visitor]);
+    }
+
+    pub fn build_unpin(&self, obj: &Value, obj_visitor: &Function) {
+        self.builder.build_call(self.unpin, &[obj, obj_visitor]);
+    }
+
+    /// Build an unpin of the objects referred to by `obj`, which are kept alive as
+    /// temporaries of the current scope instead
+    pub fn build_adopt(&self, obj: &Value, obj_visitor: &Function) {
+        self.builder.build_call(self.adopt, &[obj, obj_visitor]);
+    }
+
     pub fn build_pop_scope(&self) {
         self.builder.build_call(self.pop_scope, &[]);
     }
 
+    pub fn build_push_arena(&self) {
+        self.builder.build_call(self.push_arena, &[]);
+    }
+
+    pub fn build_pop_arena(&self) {
+        self.builder.build_call(self.pop_arena, &[]);
+    }
+
     pub fn build_update_obj_visitor(&self, captures_ptr: &Value, obj_visitor: &Function) {
         self.builder
             .build_call(self.update_obj_visitor, &[captures_ptr, obj_visitor]);
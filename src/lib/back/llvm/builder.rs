@@ -1,6 +1,7 @@
 use libc::{c_char, c_uint};
 use llvm_sys::prelude::{LLVMBuilderRef, LLVMValueRef};
-use llvm_sys::{core, LLVMBuilder, LLVMIntPredicate, LLVMRealPredicate};
+use llvm_sys::{core, LLVMAtomicOrdering, LLVMAtomicRMWBinOp, LLVMBuilder, LLVMIntPredicate,
+               LLVMRealPredicate};
 use cbox::CSemiBox;
 use std::marker::PhantomData;
 use super::block::BasicBlock;
@@ -8,9 +9,20 @@ use super::context::Context;
 use super::types::Type;
 use super::value::{Function, Predicate, Value};
 use super::compile::Compile;
+use super::util;
 
 static NULL_NAME: [c_char; 1] = [0];
 
+/// Give the call instruction `call` the calling convention of the callee `func`, if it's a
+/// function of the module rather than a function pointer. The conventions must match, or
+/// the behaviour of the call is undefined
+unsafe fn inherit_call_conv(call: LLVMValueRef, func: &Function) {
+    let func_ref: LLVMValueRef = func.into();
+    if !core::LLVMIsAFunction(func_ref).is_null() {
+        core::LLVMSetInstructionCallConv(call, core::LLVMGetFunctionCallConv(func_ref))
+    }
+}
+
 /// This provides a uniform API for creating instructions and inserting them into a basic block.
 pub struct Builder(PhantomData<[u8]>);
 native_ref!(&Builder = LLVMBuilderRef);
@@ -50,6 +62,14 @@ impl Builder {
     pub fn position_at_end(&self, block: &BasicBlock) {
         unsafe { core::LLVMPositionBuilderAtEnd(self.into(), block.into()) }
     }
+    /// Position the builder before the instruction `instr`.
+    pub fn position_before(&self, instr: &Value) {
+        unsafe { core::LLVMPositionBuilderBefore(self.into(), instr.into()) }
+    }
+    /// Return the basic block the builder is positioned in, or `None` if it is not positioned.
+    pub fn get_insert_block(&self) -> Option<&BasicBlock> {
+        unsafe { util::ptr_to_null(core::LLVMGetInsertBlock(self.into())) }
+    }
     /// Build an instruction that returns from the function with void.
     pub fn build_ret_void(&self) -> &Value {
         unsafe { core::LLVMBuildRetVoid(self.into()) }.into()
@@ -89,6 +109,104 @@ impl Builder {
     pub fn build_store(&self, val: &Value, ptr: &Value) -> &Value {
         unsafe { core::LLVMBuildStore(self.into(), val.into(), ptr.into()) }.into()
     }
+    /// Build a load of the value pointed to by `ptr` that may not be elided or reordered
+    /// with other volatile operations.
+    pub fn build_volatile_load(&self, ptr: &Value) -> &Value {
+        let load = self.build_load(ptr);
+        unsafe { core::LLVMSetVolatile(load.into(), 1) };
+        load
+    }
+    /// Build a store of `val` in `ptr` that may not be elided or reordered with other
+    /// volatile operations.
+    pub fn build_volatile_store(&self, val: &Value, ptr: &Value) -> &Value {
+        let store = self.build_store(val, ptr);
+        unsafe { core::LLVMSetVolatile(store.into(), 1) };
+        store
+    }
+    /// Build an atomic load of the value pointed to by `ptr`.
+    ///
+    /// Atomic loads must have an explicit alignment, given in bytes by `align`.
+    pub fn build_atomic_load(
+        &self,
+        ptr: &Value,
+        ordering: LLVMAtomicOrdering,
+        align: u32,
+    ) -> &Value {
+        let load = self.build_load(ptr);
+        unsafe {
+            core::LLVMSetOrdering(load.into(), ordering);
+            core::LLVMSetAlignment(load.into(), align);
+        }
+        load
+    }
+    /// Build an atomic store of `val` in `ptr`.
+    ///
+    /// Atomic stores must have an explicit alignment, given in bytes by `align`.
+    pub fn build_atomic_store(
+        &self,
+        val: &Value,
+        ptr: &Value,
+        ordering: LLVMAtomicOrdering,
+        align: u32,
+    ) -> &Value {
+        let store = self.build_store(val, ptr);
+        unsafe {
+            core::LLVMSetOrdering(store.into(), ordering);
+            core::LLVMSetAlignment(store.into(), align);
+        }
+        store
+    }
+    /// Build an instruction that atomically adds the integer `val` to the integer pointed to by
+    /// `ptr`, and returns the previous value.
+    pub fn build_atomic_fetch_add(
+        &self,
+        ptr: &Value,
+        val: &Value,
+        ordering: LLVMAtomicOrdering,
+    ) -> &Value {
+        unsafe {
+            core::LLVMBuildAtomicRMW(
+                self.into(),
+                LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpAdd,
+                ptr.into(),
+                val.into(),
+                ordering,
+                0,
+            )
+        }.into()
+    }
+    /// Build an instruction that atomically replaces the value pointed to by `ptr` with `new`,
+    /// if it is equal to `cmp`.
+    ///
+    /// Returns a pair of the previous value and whether the exchange was made.
+    pub fn build_cmpxchg(
+        &self,
+        ptr: &Value,
+        cmp: &Value,
+        new: &Value,
+        success_ordering: LLVMAtomicOrdering,
+        failure_ordering: LLVMAtomicOrdering,
+    ) -> &Value {
+        unsafe {
+            core::LLVMBuildAtomicCmpXchg(
+                self.into(),
+                ptr.into(),
+                cmp.into(),
+                new.into(),
+                success_ordering,
+                failure_ordering,
+                0,
+            )
+        }.into()
+    }
+    /// Build an instruction that marks the current block as unreachable.
+    pub fn build_unreachable(&self) -> &Value {
+        unsafe { core::LLVMBuildUnreachable(self.into()) }.into()
+    }
+    /// Build an instruction that checks whether the pointer `val` is null.
+    pub fn build_is_null(&self, val: &Value) -> &Value {
+        unsafe { core::LLVMBuildIsNull(self.into(), val.into(), NULL_NAME.as_ptr()) }.into()
+    }
     /// Build an instruction that branches to the block `dest`.
     pub fn build_br(&self, dest: &BasicBlock) -> &Value {
         unsafe { core::LLVMBuildBr(self.into(), dest.into()).into() }
@@ -118,6 +236,7 @@ impl Builder {
                 NULL_NAME.as_ptr(),
             );
             core::LLVMSetTailCall(call, 0);
+            inherit_call_conv(call, func);
             call.into()
         }
     }
@@ -134,6 +253,7 @@ impl Builder {
                 NULL_NAME.as_ptr(),
             );
             core::LLVMSetTailCall(call, 1);
+            inherit_call_conv(call, func);
             call.into()
         }
     }
@@ -344,7 +464,8 @@ impl Builder {
     pub fn build_cmp(&self, a: &Value, b: &Value, pred: Predicate) -> &Value {
         let (at, bt) = (a.get_type(), b.get_type());
         assert_eq!(at, bt);
-        if at.is_integer() {
+        // Pointers are compared like integers, by address
+        if at.is_integer() || at.is_pointer() {
             let pred = match pred {
                 Predicate::Equal => LLVMIntPredicate::LLVMIntEQ,
                 Predicate::NotEqual => LLVMIntPredicate::LLVMIntNE,
@@ -391,4 +512,16 @@ impl Builder {
     pub fn build_lteq(&self, a: &Value, b: &Value) -> &Value {
         self.build_cmp(a, b, Predicate::LessThanOrEqual)
     }
+    /// Build `a <= b` of unsigned integers. `build_lteq` compares integers as signed
+    pub fn build_ulteq(&self, a: &Value, b: &Value) -> &Value {
+        unsafe {
+            core::LLVMBuildICmp(
+                self.into(),
+                LLVMIntPredicate::LLVMIntULE,
+                a.into(),
+                b.into(),
+                NULL_NAME.as_ptr(),
+            )
+        }.into()
+    }
 }
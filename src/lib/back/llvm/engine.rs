@@ -1,4 +1,4 @@
-use libc::{c_int, c_uint, c_ulonglong};
+use libc::{c_int, c_uint, c_ulonglong, c_void};
 use llvm_sys::{core, target};
 use llvm_sys::execution_engine as engine;
 use llvm_sys::execution_engine::*;
@@ -79,6 +79,13 @@ where
     unsafe fn get_global<T>(&'a self, global: &'a Value) -> &'a T {
         mem::transmute(engine::LLVMGetPointerToGlobal(self.into(), global.into()))
     }
+    /// Map the global value given to the address `addr`, instead of resolving it by name.
+    ///
+    /// This is marked as unsafe because the value at `addr` cannot be guaranteed to be of the
+    /// same type as the global value.
+    unsafe fn add_global_mapping(&'a self, global: &'a Value, addr: *const c_void) {
+        engine::LLVMAddGlobalMapping(self.into(), global.into(), addr as *mut c_void)
+    }
     /// Returns a pointer to the global value with the name given.
     ///
     /// This is marked as unsafe because the type cannot be guranteed to be the same as the
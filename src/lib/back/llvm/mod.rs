@@ -28,9 +28,9 @@ pub use self::compile::Compile;
 pub use self::context::{Context, GetContext};
 pub use self::engine::{JitEngine, JitOptions, Interpreter, ExecutionEngine, GenericValue,
                        GenericValueCast};
-pub use self::module::{AddressSpace, Module, Functions};
+pub use self::module::{AddressSpace, CompileOptions, FileType, Module, Functions};
 pub use self::object::{ObjectFile, Symbol, Symbols};
-pub use self::target::{TargetData, Target};
+pub use self::target::{default_triple, TargetData, Target};
 pub use self::types::*;
 pub use self::value::{Alias, Arg, Value, Function, GlobalValue, GlobalVariable, Linkage, Predicate};
 pub use self::util::Sub;
@@ -22,6 +22,35 @@ use super::value::{Alias, Function, GlobalValue, GlobalVariable, Value};
 use super::types::Type;
 use super::util;
 
+/// The kind of file for `Module::compile` to output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Obj,
+    Asm,
+    /// LLVM bitcode, after the passes have run. For link time optimization
+    Bitcode,
+}
+
+impl Default for FileType {
+    fn default() -> Self {
+        FileType::Obj
+    }
+}
+
+/// Options for `Module::compile`
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions<'a> {
+    pub opt_level: usize,
+    /// Names of passes to transform the module with before compilation, e.g. `asan`
+    pub passes: &'a [&'a str],
+    /// The CPU to generate code for, e.g. `skylake` or `native`. Defaults to a generic CPU of
+    /// the target
+    pub cpu: Option<&'a str>,
+    /// Target features to enable or disable, e.g. `+avx2` or `-neon`
+    pub features: &'a [String],
+    pub file_type: FileType,
+}
+
 /// Represents a single compilation unit of code.
 ///
 /// This is attached to the lifetime of the context that constructs it, but is owned by the `CSemiBox`.
@@ -226,24 +255,55 @@ impl Module {
         }
     }
 
-    /// Compile the module into an object file at the given location.
+    /// Compile the module into an object file, assembly, or bitcode, at the given location.
     ///
-    /// Note that this uses the LLVM tool `llc` to do this, which may or may not be
+    /// Note that this uses the LLVM tools `llc` and `opt` to do this, which may or may not be
     /// installed on the user's machine.
-    pub fn compile(&self, path: &Path, opt_level: usize) -> IoResult<Child> {
+    pub fn compile(&self, path: &Path, options: &CompileOptions) -> IoResult<Child> {
         let dir = env::temp_dir();
         let path = path.to_str().unwrap();
         let mod_path = dir.join("module.bc");
         let mod_path = mod_path.to_str().unwrap();
         try!(self.write_bitcode(mod_path));
-        Command::new("llc")
-            .arg(&format!("-O={}", opt_level))
-            .arg("-filetype=obj")
-            .arg("-relocation-model=pic")
-            .arg("-o")
-            .arg(path)
-            .arg(mod_path)
-            .spawn()
+        if options.file_type == FileType::Bitcode {
+            return Command::new("opt")
+                .args(options.passes.iter().map(|pass| format!("-{}", pass)))
+                .arg("-o")
+                .arg(path)
+                .arg(mod_path)
+                .spawn();
+        }
+        if !options.passes.is_empty() {
+            let status = try!(
+                Command::new("opt")
+                    .args(options.passes.iter().map(|pass| format!("-{}", pass)))
+                    .arg("-o")
+                    .arg(mod_path)
+                    .arg(mod_path)
+                    .status()
+            );
+            if !status.success() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("`opt` failed to run passes, {}", status),
+                ));
+            }
+        }
+        let mut llc = Command::new("llc");
+        llc.arg(&format!("-O={}", options.opt_level))
+            .arg(if options.file_type == FileType::Asm {
+                "-filetype=asm"
+            } else {
+                "-filetype=obj"
+            })
+            .arg("-relocation-model=pic");
+        if let Some(cpu) = options.cpu {
+            llc.arg(&format!("-mcpu={}", cpu));
+        }
+        if !options.features.is_empty() {
+            llc.arg(&format!("-mattr={}", options.features.join(",")));
+        }
+        llc.arg("-o").arg(path).arg(mod_path).spawn()
     }
 
     /// Link a module into this module, returning an error string if an error occurs.
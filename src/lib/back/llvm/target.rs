@@ -1,4 +1,5 @@
 use libc::{c_char, c_uint};
+use llvm_sys::core;
 use llvm_sys::target_machine::{self, LLVMTargetRef};
 use llvm_sys::target::{self, LLVMTargetDataRef, LLVMOpaqueTargetData};
 use cbox::{CBox, DisposeRef};
@@ -65,6 +66,16 @@ impl DisposeRef for TargetData {
     }
 }
 
+/// Returns the target triple of the host, which LLVM targets by default
+pub fn default_triple() -> String {
+    unsafe {
+        let triple = target_machine::LLVMGetDefaultTargetTriple();
+        let s = util::to_str(triple).to_string();
+        core::LLVMDisposeMessage(triple);
+        s
+    }
+}
+
 pub struct Target(PhantomData<[u8]>);
 native_ref!(&Target = LLVMTargetRef);
 impl Target {
@@ -1,6 +1,6 @@
 use libc::{c_char, c_int, c_uint};
 use llvm_sys::prelude::LLVMValueRef;
-use llvm_sys::{core, LLVMLinkage};
+use llvm_sys::{core, LLVMAttributeFunctionIndex, LLVMLinkage};
 use std::ffi::CString;
 use std::{fmt, mem};
 use std::ops::{Deref, Index};
@@ -64,6 +64,14 @@ impl Value {
             core::LLVMConstStringInContext(context.into(), ptr, len, rust_style as c_int).into()
         }
     }
+    /// Create a new constant array of the bytes given, without a null terminator.
+    pub fn new_bytes<'a>(context: &'a Context, bytes: &[u8]) -> &'a Value {
+        unsafe {
+            let ptr = bytes.as_ptr() as *const c_char;
+            let len = bytes.len() as c_uint;
+            core::LLVMConstStringInContext(context.into(), ptr, len, 1).into()
+        }
+    }
     /// Create a new constant undefined value of the given type.
     pub fn new_undef<'a>(ty: &'a Type) -> &'a Value {
         unsafe { core::LLVMGetUndef(ty.into()).into() }
@@ -72,6 +80,10 @@ impl Value {
     pub fn new_null<'a>(ty: &'a Type) -> &'a Value {
         unsafe { core::LLVMConstNull(ty.into()).into() }
     }
+    /// Create a new constant integer of the given integer type, from the low bits of `n`.
+    pub fn new_int<'a>(ty: &'a Type, n: u64) -> &'a Value {
+        unsafe { core::LLVMConstInt(ty.into(), n, 0).into() }
+    }
     /// Returns the name of this value, or `None` if it lacks a name
     pub fn get_name(&self) -> Option<&str> {
         unsafe {
@@ -79,6 +91,10 @@ impl Value {
             util::to_null_str(c_name as *mut i8)
         }
     }
+    /// Add the incoming value `val` from the block `block` to this phi node
+    pub fn add_incoming(&self, val: &Value, block: &BasicBlock) {
+        unsafe { core::LLVMAddIncoming(self.into(), &mut val.into(), &mut block.into(), 1) }
+    }
     /// Sets the name of this value
     pub fn set_name(&self, name: &str) {
         let c_name = CString::new(name).unwrap();
@@ -209,6 +225,36 @@ impl Function {
             core::LLVMGetElementType(ty).into()
         }
     }
+    /// Returns true if this function has no body in this module, i.e. is only declared.
+    pub fn is_declaration(&self) -> bool {
+        unsafe { core::LLVMIsDeclaration(self.into()) != 0 }
+    }
+    /// Set the calling convention of this function, as numbered in `llvm::CallingConv`.
+    pub fn set_call_conv(&self, cc: c_uint) {
+        unsafe { core::LLVMSetFunctionCallConv(self.into(), cc) }
+    }
+    /// Add the enum attribute with the name given, e.g. `sanitize_address`, to this function.
+    pub fn add_attribute(&self, name: &str) {
+        unsafe {
+            let kind =
+                core::LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len());
+            let attr = core::LLVMCreateEnumAttribute(self.get_context().into(), kind, 0);
+            core::LLVMAddAttributeAtIndex(self.into(), LLVMAttributeFunctionIndex, attr)
+        }
+    }
+    /// Add the string attribute `key`=`value`, e.g. `probe-stack`, to this function.
+    pub fn add_string_attribute(&self, key: &str, value: &str) {
+        unsafe {
+            let attr = core::LLVMCreateStringAttribute(
+                self.get_context().into(),
+                key.as_ptr() as *const c_char,
+                key.len() as c_uint,
+                value.as_ptr() as *const c_char,
+                value.len() as c_uint,
+            );
+            core::LLVMAddAttributeAtIndex(self.into(), LLVMAttributeFunctionIndex, attr)
+        }
+    }
 }
 impl GetContext for Function {
     fn get_context(&self) -> &Context {
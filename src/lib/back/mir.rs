@@ -0,0 +1,1000 @@
+//! The mid-level intermediate representation, in A-normal form
+//!
+//! The typed and monomorphized AST is lowered to MIR before code generation. Every
+//! intermediate value is bound to a local, so the order of evaluation is explicit, as are the
+//! captures of closures, and the roots of the scopes of the garbage collector. Control flow is
+//! still structured. The branches of `if` and `match`, and the bodies of `let`s and `loop`s,
+//! are nested blocks, that end in either a return of a value or a `recur`.
+
+use lib::front::SrcPos;
+use lib::front::ast::{self, Expr, Type};
+use lib::{grow_stack, ice};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::iter::once;
+use itertools::Itertools;
+
+fn spaces(n: usize) -> String {
+    " ".repeat(n)
+}
+
+pub fn is_arithm_binop(op_name: &str) -> bool {
+    let arithm_binops = hashset!{ "add", "sub", "mul", "div" };
+    arithm_binops.contains(op_name)
+}
+
+pub fn is_relational_binop(op_name: &str) -> bool {
+    let relational_binops = hashset!{ "eq", "lt" };
+    relational_binops.contains(op_name)
+}
+
+/// A local variable of a function, by index in the locals of the function
+pub type Local = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalDecl<'s> {
+    /// The name of the variable in the source, or `None` for a temporary
+    pub name: Option<&'s str>,
+    pub typ: Type<'s>,
+}
+
+/// An operand, that is evaluated without side effects
+#[derive(Clone, Debug, PartialEq)]
+pub enum Atom<'s> {
+    Local(Local),
+    Nil,
+    Num(ast::NumLit<'s>),
+    Bool(bool),
+    Sym(&'s str),
+}
+
+impl<'s> Atom<'s> {
+    pub fn typ(&self, locals: &[LocalDecl<'s>]) -> Type<'s> {
+        match *self {
+            Atom::Local(l) => locals[l].typ.clone(),
+            Atom::Nil => ast::TYPE_NIL.clone(),
+            Atom::Num(ref n) => n.typ.clone(),
+            Atom::Bool(_) => ast::TYPE_BOOL.clone(),
+            Atom::Sym(_) => ast::TYPE_SYMBOL.clone(),
+        }
+    }
+}
+
+impl<'s> Display for Atom<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Atom::Local(l) => write!(f, "%{}", l),
+            Atom::Nil => write!(f, "nil"),
+            Atom::Num(ref n) => write!(f, "{}", n),
+            Atom::Bool(b) => write!(f, "{}", b),
+            Atom::Sym(s) => write!(f, "'{}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cast<'s> {
+    pub val: Atom<'s>,
+    pub from: Type<'s>,
+    pub to: Type<'s>,
+    pub checked: bool,
+    pub in_unsafe: bool,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transmute<'s> {
+    pub val: Atom<'s>,
+    pub from: Type<'s>,
+    pub to: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PtrOp<'s> {
+    pub kind: ast::PtrOpKind,
+    pub args: Vec<Atom<'s>>,
+    pub ordering: Option<ast::AtomicOrdering>,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadOp<'s> {
+    pub kind: ast::ThreadOpKind,
+    pub args: Vec<Atom<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern<'s> {
+    Wild,
+    /// Bind the matchee to the local
+    Bind(Local),
+    Num(ast::NumLit<'s>),
+    Bool(bool),
+    /// The numbers from the first up to and including the second
+    Range(ast::NumLit<'s>, ast::NumLit<'s>),
+    Deconstr(&'s str, Vec<Pattern<'s>>),
+}
+
+impl<'s> Display for Pattern<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pattern::Wild => write!(f, "_"),
+            Pattern::Bind(l) => write!(f, "%{}", l),
+            Pattern::Num(ref n) => write!(f, "{}", n),
+            Pattern::Bool(b) => write!(f, "{}", b),
+            Pattern::Range(ref lo, ref hi) => write!(f, "(range {} {})", lo, hi),
+            Pattern::Deconstr(variant, ref subpatts) => {
+                write!(f, "({} {})", variant, subpatts.iter().join(" "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case<'s> {
+    pub patt: Pattern<'s>,
+    pub body: Block<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match<'s> {
+    pub matchee: Atom<'s>,
+    pub cases: Vec<Case<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loop<'s> {
+    /// The variables of the loop, and their initial values
+    pub vars: Vec<(Local, Atom<'s>)>,
+    pub body: Block<'s>,
+}
+
+/// The right hand side of a `Stmt::Let`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rhs<'s> {
+    Atom(Atom<'s>),
+    /// The instantiation `inst` of a global variable or function, as a value
+    Global(&'s str, Vec<Type<'s>>),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// A direct call of the instantiation `inst` of a global or external function
+    Call {
+        name: &'s str,
+        inst: Vec<Type<'s>>,
+        arg: Atom<'s>,
+        pos: SrcPos<'s>,
+    },
+    /// An application of a closure. `frame` is the name of the frame pushed on the shadow
+    /// stack for the call, or `None` for the core binops
+    App {
+        func: Atom<'s>,
+        arg: Atom<'s>,
+        frame: Option<&'s str>,
+        pos: SrcPos<'s>,
+    },
+    Cons(Atom<'s>, Atom<'s>),
+    Car(Atom<'s>),
+    Cdr(Atom<'s>),
+    /// A load of only the member at the path of `car`s, 0, and `cdr`s, 1, of the pairs
+    /// pointed to, instead of the whole value
+    Load(Atom<'s>, Vec<u32>),
+    Cast(Cast<'s>),
+    Transmute(Transmute<'s>),
+    New(&'s str, Vec<Atom<'s>>),
+    PtrOp(PtrOp<'s>),
+    ThreadOp(ThreadOp<'s>),
+    If(Atom<'s>, Block<'s>, Block<'s>),
+    Match(Match<'s>),
+    Loop(Loop<'s>),
+    /// The bindings and body of a `let`, in a new scope of the GC
+    Scope(Block<'s>),
+    WithArena(Block<'s>),
+}
+
+impl<'s> Rhs<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        let global = |name: &str, inst: &[Type]| {
+            if inst.is_empty() {
+                name.to_string()
+            } else {
+                format!("(inst {} {})", name, inst.iter().join(" "))
+            }
+        };
+        match *self {
+            Rhs::Atom(ref a) => a.to_string(),
+            Rhs::Global(name, ref inst) => global(name, inst),
+            Rhs::Str(ref s) => format!("{:?}", s),
+            Rhs::Bytes(ref bs) => format!("#bytes[{}]", bs.len()),
+            Rhs::Call {
+                name,
+                ref inst,
+                ref arg,
+                ..
+            } => format!("(call {} {})", global(name, inst), arg),
+            Rhs::App {
+                ref func, ref arg, ..
+            } => format!("({} {})", func, arg),
+            Rhs::Cons(ref car, ref cdr) => format!("(cons {} {})", car, cdr),
+            Rhs::Car(ref pair) => format!("(car {})", pair),
+            Rhs::Cdr(ref pair) => format!("(cdr {})", pair),
+            Rhs::Load(ref ptr, ref path) => format!("(load {} [{}])", ptr, path.iter().join(" ")),
+            Rhs::Cast(ref c) => format!(
+                "({} {} {})",
+                if c.checked { "cast-checked" } else { "cast" },
+                c.val,
+                c.to
+            ),
+            Rhs::Transmute(ref t) => format!("(unsafe-transmute {} {})", t.val, t.to),
+            Rhs::New(variant, ref members) => once(format!("(new {}", variant))
+                .chain(members.iter().map(|m| m.to_string()))
+                .join(" ") + ")",
+            Rhs::PtrOp(ref p) => once(format!("({}", p.kind.name()))
+                .chain(p.args.iter().map(|a| a.to_string()))
+                .chain(p.ordering.map(|o| o.name().to_string()))
+                .join(" ") + ")",
+            Rhs::ThreadOp(ref t) => once(format!("({}", t.kind.name()))
+                .chain(t.args.iter().map(|a| a.to_string()))
+                .join(" ") + ")",
+            Rhs::If(ref pred, ref conseq, ref alt) => format!(
+                "(if {}\n{}{}\n{}{})",
+                pred,
+                spaces(n + 4),
+                conseq.to_string_indent(n + 4),
+                spaces(n + 4),
+                alt.to_string_indent(n + 4)
+            ),
+            Rhs::Match(ref m) => {
+                let cases = m.cases.iter().map(|c| {
+                    format!(
+                        "\n{}[{}\n{}{}]",
+                        spaces(n + 2),
+                        c.patt,
+                        spaces(n + 3),
+                        c.body.to_string_indent(n + 3)
+                    )
+                });
+                format!("(match {}{})", m.matchee, cases.collect::<String>())
+            }
+            Rhs::Loop(ref l) => format!(
+                "(loop [{}]\n{}{})",
+                l.vars
+                    .iter()
+                    .map(|&(var, ref init)| format!("[%{} {}]", var, init))
+                    .join(" "),
+                spaces(n + 2),
+                l.body.to_string_indent(n + 2)
+            ),
+            Rhs::Scope(ref b) => format!("(scope\n{}{})", spaces(n + 2), b.to_string_indent(n + 2)),
+            Rhs::WithArena(ref b) => format!(
+                "(with-arena\n{}{})",
+                spaces(n + 2),
+                b.to_string_indent(n + 2)
+            ),
+        }
+    }
+}
+
+/// The definitions of the functions of a binding group
+///
+/// Functions without captures are lifted to global functions. The rest are closures that
+/// share a single record of the union of their captures, which is allocated before the
+/// closures are created, but only filled in after `vals`, the variables of the group up to
+/// the last closure, have been defined. That way the closures may capture each other, and the
+/// variables of the group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LetRec<'s> {
+    pub funcs: Vec<(Local, Func<'s>)>,
+    pub vals: Vec<Stmt<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt<'s> {
+    /// Bind the value of the right hand side to the local. Heap allocations made by the right
+    /// hand side are attributed to the position
+    Let(Local, Rhs<'s>, SrcPos<'s>),
+    LetRec(LetRec<'s>),
+    /// Mark the bound local as a root of the current GC scope, and clear the temporaries of
+    /// the scope
+    Root(Local),
+    /// Panic with the message and position if the condition is false
+    Assert(Atom<'s>, String, SrcPos<'s>),
+}
+
+impl<'s> Stmt<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        match *self {
+            Stmt::Let(local, ref rhs, _) => {
+                let head = format!("(let %{} ", local);
+                format!("{}{})", head, rhs.to_string_indent(n + head.len()))
+            }
+            Stmt::LetRec(ref r) => {
+                let mut parts = r.funcs
+                    .iter()
+                    .map(|&(local, ref func)| {
+                        let head = format!("[%{} ", local);
+                        format!("{}{}]", head, func.to_string_indent(n + 2 + head.len()))
+                    })
+                    .chain(r.vals.iter().map(|s| s.to_string_indent(n + 2)));
+                format!(
+                    "(letrec\n{}{})",
+                    spaces(n + 2),
+                    parts.join(&format!("\n{}", spaces(n + 2)))
+                )
+            }
+            Stmt::Root(local) => format!("(root %{})", local),
+            Stmt::Assert(ref cond, _, _) => format!("(assert {})", cond),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tail<'s> {
+    Return(Atom<'s>),
+    /// Jump back to the header of the innermost loop, with the arguments as the new values of
+    /// the variables of the loop
+    Recur(Vec<Atom<'s>>),
+}
+
+impl<'s> Display for Tail<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tail::Return(ref a) => write!(f, "(return {})", a),
+            Tail::Recur(ref args) => write!(f, "(recur {})", args.iter().join(" ")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block<'s> {
+    pub stmts: Vec<Stmt<'s>>,
+    pub tail: Tail<'s>,
+}
+
+impl<'s> Block<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        self.stmts
+            .iter()
+            .map(|s| s.to_string_indent(n))
+            .chain(once(self.tail.to_string()))
+            .join(&format!("\n{}", spaces(n)))
+    }
+}
+
+/// The code of a function, or of the initialization of a global variable
+#[derive(Clone, Debug, PartialEq)]
+pub struct Body<'s> {
+    pub locals: Vec<LocalDecl<'s>>,
+    pub block: Block<'s>,
+}
+
+impl<'s> Body<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        self.locals
+            .iter()
+            .enumerate()
+            .map(|(i, l)| format!("(local %{} {} {})", i, l.name.unwrap_or("_"), l.typ))
+            .chain(once(self.block.to_string_indent(n)))
+            .join(&format!("\n{}", spaces(n)))
+    }
+}
+
+impl<'s> Display for Body<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_indent(0))
+    }
+}
+
+/// A function, with locals of its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct Func<'s> {
+    pub name: &'s str,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+    pub param: Local,
+    /// The locals of the enclosing function that are captured, ordered by index, and the
+    /// locals of this function they're bound to
+    pub captures: Vec<(Local, Local)>,
+    pub body: Body<'s>,
+}
+
+impl<'s> Func<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        format!(
+            "(fn {} [%{}] (captures {})\n{}{})",
+            self.name,
+            self.param,
+            self.captures
+                .iter()
+                .map(|&(outer, inner)| format!("[%{} %{}]", inner, outer))
+                .join(" "),
+            spaces(n + 2),
+            self.body.to_string_indent(n + 2)
+        )
+    }
+}
+
+impl<'s> Display for Func<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_indent(0))
+    }
+}
+
+/// The instantiation `inst` of a global function
+#[derive(Clone, Debug)]
+pub struct GlobalFunc<'s> {
+    pub name: &'s str,
+    pub inst: Vec<Type<'s>>,
+    pub func: Func<'s>,
+}
+
+/// The instantiation `inst` of a global defined with `def-const`, folded to a literal
+#[derive(Clone, Debug)]
+pub struct GlobalConst<'s> {
+    pub name: &'s str,
+    pub inst: Vec<Type<'s>>,
+    pub val: Atom<'s>,
+}
+
+/// The instantiation `inst` of a global variable, initialized at runtime
+#[derive(Clone, Debug)]
+pub struct GlobalVar<'s> {
+    pub name: &'s str,
+    pub inst: Vec<Type<'s>>,
+    pub typ: Type<'s>,
+    pub init: Body<'s>,
+}
+
+/// The globals of a program, each in the order of the globals of the AST
+#[derive(Clone, Debug, Default)]
+pub struct Module<'s> {
+    pub funcs: Vec<GlobalFunc<'s>>,
+    pub consts: Vec<GlobalConst<'s>>,
+    pub vars: Vec<GlobalVar<'s>>,
+    /// The global variables defined in `lazy` forms, initialized on the first read
+    pub lazies: Vec<GlobalVar<'s>>,
+}
+
+/// The monomorphic instances of `binding`, with the instantiation of each
+fn mono_insts<'a, 's>(binding: &'a ast::Binding<'s>) -> Vec<(Vec<Type<'s>>, &'a Expr<'s>)> {
+    if binding.sig.is_monomorphic() {
+        vec![(vec![], &binding.val)]
+    } else {
+        binding
+            .mono_insts
+            .iter()
+            .map(|(inst, val)| (inst.clone(), val))
+            .collect()
+    }
+}
+
+/// A function being lowered
+struct Frame<'s> {
+    locals: Vec<LocalDecl<'s>>,
+    /// The scopes of each variable name, innermost last, with the local of each instantiation
+    scopes: BTreeMap<&'s str, Vec<BTreeMap<Vec<Type<'s>>, Local>>>,
+    /// The locals of the enclosing function that are captured, and the locals of this
+    /// function they're bound to
+    captures: BTreeMap<Local, Local>,
+    /// The statements of each block being lowered, innermost last
+    blocks: Vec<Vec<Stmt<'s>>>,
+}
+
+impl<'s> Frame<'s> {
+    fn new() -> Self {
+        Frame {
+            locals: Vec::new(),
+            scopes: BTreeMap::new(),
+            captures: BTreeMap::new(),
+            blocks: Vec::new(),
+        }
+    }
+}
+
+struct Lowerer<'s> {
+    /// The names of the global and external functions, which are called directly
+    glob_funcs: BTreeSet<&'s str>,
+    /// The functions being lowered, innermost last
+    frames: Vec<Frame<'s>>,
+}
+
+impl<'s> Lowerer<'s> {
+    fn frame(&mut self) -> &mut Frame<'s> {
+        self.frames.last_mut().expect("ICE: No function being lowered")
+    }
+
+    fn new_local(&mut self, name: Option<&'s str>, typ: &Type<'s>) -> Local {
+        let locals = &mut self.frame().locals;
+        locals.push(LocalDecl {
+            name,
+            typ: typ.canonicalize(),
+        });
+        locals.len() - 1
+    }
+
+    fn push_scope(&mut self, name: &'s str) {
+        self.frame()
+            .scopes
+            .entry(name)
+            .or_insert(Vec::new())
+            .push(BTreeMap::new())
+    }
+
+    fn pop_scope(&mut self, name: &str) {
+        self.frame()
+            .scopes
+            .get_mut(name)
+            .and_then(|scopes| scopes.pop())
+            .expect("ICE: Popped empty scope in lowering");
+    }
+
+    /// Bind the instantiation `inst` of `name` to `local`, in the innermost scope of `name`
+    fn bind(&mut self, name: &str, inst: Vec<Type<'s>>, local: Local) {
+        let insts = self.frame()
+            .scopes
+            .get_mut(name)
+            .and_then(|scopes| scopes.last_mut())
+            .expect("ICE: Bound variable without scope in lowering");
+        if insts.insert(inst, local).is_some() {
+            panic!("ICE: val already exists for inst")
+        }
+    }
+
+    /// The local that the instantiation `inst` of `name` refers to in the function of
+    /// `frames[depth]`, or `None` if it's global
+    ///
+    /// A local of an enclosing function is captured by each function in between.
+    fn lookup_in(&mut self, depth: usize, name: &str, inst: &[Type<'s>]) -> Option<Local> {
+        let local = self.frames[depth]
+            .scopes
+            .get(name)
+            .and_then(|scopes| scopes.last())
+            .and_then(|insts| insts.get(inst))
+            .cloned();
+        if local.is_some() || depth == 0 {
+            return local;
+        }
+        let outer = self.lookup_in(depth - 1, name, inst)?;
+        if let Some(&inner) = self.frames[depth].captures.get(&outer) {
+            return Some(inner);
+        }
+        let decl = self.frames[depth - 1].locals[outer].clone();
+        let frame = &mut self.frames[depth];
+        frame.locals.push(decl);
+        let inner = frame.locals.len() - 1;
+        frame.captures.insert(outer, inner);
+        Some(inner)
+    }
+
+    fn lookup(&mut self, name: &str, inst: &[Type<'s>]) -> Option<Local> {
+        let depth = self.frames.len() - 1;
+        self.lookup_in(depth, name, inst)
+    }
+
+    fn emit(&mut self, stmt: Stmt<'s>) {
+        self.frame()
+            .blocks
+            .last_mut()
+            .expect("ICE: No block being lowered")
+            .push(stmt)
+    }
+
+    fn lower_block(&mut self, e: &Expr<'s>) -> Block<'s> {
+        self.frame().blocks.push(Vec::new());
+        let tail = self.lower_tail(e);
+        let stmts = self.frame().blocks.pop().unwrap();
+        Block { stmts, tail }
+    }
+
+    fn lower_tail(&mut self, e: &Expr<'s>) -> Tail<'s> {
+        match *e {
+            Expr::Recur(ref r) => Tail::Recur(r.args.iter().map(|a| self.lower_atom(a)).collect()),
+            Expr::Begin(ref b) => {
+                let (last, effects) = b.exprs.split_last().expect("ICE: empty begin");
+                for effect in effects {
+                    self.lower_atom(effect);
+                }
+                self.lower_tail(last)
+            }
+            _ => Tail::Return(self.lower_atom(e)),
+        }
+    }
+
+    /// Lower `e` to an atom, binding it to a temporary if it's not one already
+    fn lower_atom(&mut self, e: &Expr<'s>) -> Atom<'s> {
+        match self.lower_rhs(e) {
+            Rhs::Atom(a) => a,
+            rhs => {
+                let tmp = self.new_local(None, e.get_type());
+                self.emit(Stmt::Let(tmp, rhs, e.pos().clone()));
+                Atom::Local(tmp)
+            }
+        }
+    }
+
+    /// A body without parameters, at the top level
+    fn lower_body(&mut self, e: &Expr<'s>) -> Body<'s> {
+        self.frames.push(Frame::new());
+        let block = self.lower_block(e);
+        let frame = self.frames.pop().unwrap();
+        Body {
+            locals: frame.locals,
+            block,
+        }
+    }
+
+    fn lower_lambda(&mut self, lam: &ast::Lambda<'s>, name: &'s str) -> Func<'s> {
+        self.frames.push(Frame::new());
+        let (param_type, _) = lam.typ
+            .get_func()
+            .unwrap_or_else(|| panic!("ICE: Invalid function type `{}`", lam.typ));
+        let param = self.new_local(Some(lam.param_ident.s), param_type);
+        self.push_scope(lam.param_ident.s);
+        self.bind(lam.param_ident.s, vec![], param);
+        let block = self.lower_block(&lam.body);
+        let frame = self.frames.pop().unwrap();
+        Func {
+            name,
+            typ: lam.typ.clone(),
+            pos: lam.pos.clone(),
+            param,
+            captures: frame.captures.into_iter().collect(),
+            body: Body {
+                locals: frame.locals,
+                block,
+            },
+        }
+    }
+
+    fn lower_variable(&mut self, var: &ast::Variable<'s>) -> Rhs<'s> {
+        let inst = var.typ.get_inst_args().unwrap_or(&[]);
+        match self.lookup(var.ident.s, inst) {
+            Some(local) => Rhs::Atom(Atom::Local(local)),
+            None => Rhs::Global(var.ident.s, inst.to_vec()),
+        }
+    }
+
+    fn lower_app(&mut self, app: &ast::App<'s>) -> Rhs<'s> {
+        let arg = self.lower_atom(&app.arg);
+        let inst = app.func.get_type().get_inst_args().unwrap_or(&[]);
+        let maybe_name = app.func.as_var().map(|v| v.ident.s);
+        if let Some(name) = maybe_name {
+            let is_binop = is_arithm_binop(name) || is_relational_binop(name);
+            if !is_binop && self.glob_funcs.contains(name) && self.lookup(name, inst).is_none() {
+                return Rhs::Call {
+                    name,
+                    inst: inst.to_vec(),
+                    arg,
+                    pos: app.pos.clone(),
+                };
+            }
+        }
+        let func = self.lower_atom(&app.func);
+        let frame = match maybe_name {
+            Some(name) if is_arithm_binop(name) || is_relational_binop(name) => None,
+            maybe_name => Some(maybe_name.unwrap_or("<closure>")),
+        };
+        Rhs::App {
+            func,
+            arg,
+            frame,
+            pos: app.pos.clone(),
+        }
+    }
+
+    /// Lower a `car` or `cdr` of a `ptr-read` to the pointer and the path to the member, or
+    /// return `None` if `e` is not such a place in memory
+    fn lower_place(&mut self, e: &Expr<'s>) -> Option<(Atom<'s>, Vec<u32>)> {
+        match *e {
+            Expr::PtrOp(ref p) if p.kind == ast::PtrOpKind::Read => {
+                Some((self.lower_atom(&p.args[0]), Vec::new()))
+            }
+            Expr::Car(ref c) => self.lower_place(&c.expr).map(|(ptr, mut path)| {
+                path.push(0);
+                (ptr, path)
+            }),
+            Expr::Cdr(ref c) => self.lower_place(&c.expr).map(|(ptr, mut path)| {
+                path.push(1);
+                (ptr, path)
+            }),
+            _ => None,
+        }
+    }
+
+    fn lower_let(&mut self, l: &ast::Let<'s>) -> Block<'s> {
+        self.frame().blocks.push(Vec::new());
+        for group in l.bindings.groups().rev() {
+            let mut bindings = group.bindings().collect::<Vec<_>>();
+            bindings.reverse();
+            self.lower_let_group(&bindings, &l.pos);
+        }
+        let tail = self.lower_tail(&l.body);
+        for b in l.bindings.bindings() {
+            self.pop_scope(b.ident.s);
+        }
+        let stmts = self.frame().blocks.pop().unwrap();
+        Block { stmts, tail }
+    }
+
+    /// Lower the bindings of a binding group, in reverse topological order for the relation
+    /// "depends on"
+    fn lower_let_group(&mut self, bindings: &[&ast::Binding<'s>], pos: &SrcPos<'s>) {
+        let mut binding_insts = Vec::new();
+        for binding in bindings {
+            let name = binding.ident.s;
+            self.push_scope(name);
+            for (inst, val) in mono_insts(binding) {
+                let local = self.new_local(Some(name), val.get_type());
+                self.bind(name, inst, local);
+                binding_insts.push((name, local, val));
+            }
+        }
+        let funcs = binding_insts
+            .iter()
+            .filter_map(|&(name, local, val)| match *val {
+                Expr::Lambda(ref lam) => Some((local, self.lower_lambda(lam, name))),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let last_closure = binding_insts.iter().rposition(|&(_, local, _)| {
+            funcs
+                .iter()
+                .any(|&(l, ref func)| l == local && !func.captures.is_empty())
+        });
+        let (before, after) = binding_insts.split_at(last_closure.map_or(0, |i| i + 1));
+        self.frame().blocks.push(Vec::new());
+        for &(_, local, val) in before {
+            self.lower_binding_val(local, val);
+        }
+        let vals = self.frame().blocks.pop().unwrap();
+        if !funcs.is_empty() {
+            self.emit(Stmt::LetRec(LetRec {
+                funcs,
+                vals,
+                pos: pos.clone(),
+            }));
+        }
+        for &(_, local, val) in after {
+            self.lower_binding_val(local, val);
+        }
+    }
+
+    fn lower_binding_val(&mut self, local: Local, val: &Expr<'s>) {
+        if let Expr::Lambda(_) = *val {
+            return;
+        }
+        let rhs = self.lower_rhs(val);
+        self.emit(Stmt::Let(local, rhs, val.pos().clone()));
+        self.emit(Stmt::Root(local));
+    }
+
+    /// Lower a pattern, binding its variables in new scopes, the names of which are added to
+    /// `names`
+    fn lower_pattern(&mut self, patt: &ast::Pattern<'s>, names: &mut Vec<&'s str>) -> Pattern<'s> {
+        match *patt {
+            ast::Pattern::Nil(_) => Pattern::Wild,
+            ast::Pattern::NumLit(ref n) => Pattern::Num(n.clone()),
+            ast::Pattern::Range(ref r) => Pattern::Range(r.lo.clone(), r.hi.clone()),
+            ast::Pattern::StrLit(_) => unimplemented!(),
+            ast::Pattern::Bool(ref b) => Pattern::Bool(b.val),
+            ast::Pattern::Variable(ref var) if var.ident.s == "_" => Pattern::Wild,
+            ast::Pattern::Variable(ref var) => {
+                if names.contains(&var.ident.s) {
+                    unimplemented!("multiple occurences of identifier")
+                }
+                names.push(var.ident.s);
+                let local = self.new_local(Some(var.ident.s), &var.typ);
+                self.push_scope(var.ident.s);
+                self.bind(var.ident.s, vec![], local);
+                Pattern::Bind(local)
+            }
+            ast::Pattern::Deconstr(ref d) => Pattern::Deconstr(
+                d.constr.s,
+                d.subpatts
+                    .iter()
+                    .map(|sub| self.lower_pattern(sub, names))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn lower_match(&mut self, m: &ast::Match<'s>) -> Rhs<'s> {
+        let matchee = self.lower_atom(&m.expr);
+        let cases = m.cases
+            .iter()
+            .map(|case| {
+                let mut names = Vec::new();
+                let patt = self.lower_pattern(&case.patt, &mut names);
+                let body = self.lower_block(&case.body);
+                for name in names {
+                    self.pop_scope(name);
+                }
+                Case { patt, body }
+            })
+            .collect();
+        Rhs::Match(Match {
+            matchee,
+            cases,
+            pos: m.pos.clone(),
+        })
+    }
+
+    fn lower_loop(&mut self, l: &ast::Loop<'s>) -> Rhs<'s> {
+        let inits = l.inits
+            .iter()
+            .map(|init| self.lower_atom(init))
+            .collect::<Vec<_>>();
+        let vars = l.vars
+            .iter()
+            .zip(inits)
+            .map(|(var, init)| {
+                let local = self.new_local(Some(var.ident.s), &var.typ);
+                self.push_scope(var.ident.s);
+                self.bind(var.ident.s, vec![], local);
+                (local, init)
+            })
+            .collect();
+        let body = self.lower_block(&l.body);
+        for var in &l.vars {
+            self.pop_scope(var.ident.s);
+        }
+        Rhs::Loop(Loop { vars, body })
+    }
+
+    fn lower_rhs(&mut self, e: &Expr<'s>) -> Rhs<'s> {
+        grow_stack(|| match *e {
+            Expr::Nil(_) => Rhs::Atom(Atom::Nil),
+            Expr::NumLit(ref n) => Rhs::Atom(Atom::Num(n.clone())),
+            Expr::StrLit(ref s) => Rhs::Str(s.lit.to_string()),
+            Expr::BytesLit(ref b) => Rhs::Bytes(b.bytes.clone()),
+            Expr::SymLit(ref s) => Rhs::Atom(Atom::Sym(s.name)),
+            Expr::Bool(ref b) => Rhs::Atom(Atom::Bool(b.val)),
+            Expr::Variable(ref var) => self.lower_variable(var),
+            Expr::App(ref app) => self.lower_app(app),
+            Expr::If(ref cond) => {
+                let pred = self.lower_atom(&cond.predicate);
+                let conseq = self.lower_block(&cond.consequent);
+                let alt = self.lower_block(&cond.alternative);
+                Rhs::If(pred, conseq, alt)
+            }
+            Expr::Lambda(ref lam) => {
+                let local = self.new_local(None, &lam.typ);
+                let func = self.lower_lambda(lam, "lam");
+                self.emit(Stmt::LetRec(LetRec {
+                    funcs: vec![(local, func)],
+                    vals: Vec::new(),
+                    pos: lam.pos.clone(),
+                }));
+                Rhs::Atom(Atom::Local(local))
+            }
+            Expr::Let(ref l) => Rhs::Scope(self.lower_let(l)),
+            // All type ascriptions should be replaced at this stage
+            Expr::TypeAscript(_) => unreachable!(),
+            Expr::Cons(ref c) => {
+                let car = self.lower_atom(&c.car);
+                let cdr = self.lower_atom(&c.cdr);
+                Rhs::Cons(car, cdr)
+            }
+            Expr::Car(ref c) => match self.lower_place(&c.expr) {
+                Some((ptr, mut path)) => {
+                    path.push(0);
+                    Rhs::Load(ptr, path)
+                }
+                None => Rhs::Car(self.lower_atom(&c.expr)),
+            },
+            Expr::Cdr(ref c) => match self.lower_place(&c.expr) {
+                Some((ptr, mut path)) => {
+                    path.push(1);
+                    Rhs::Load(ptr, path)
+                }
+                None => Rhs::Cdr(self.lower_atom(&c.expr)),
+            },
+            Expr::Cast(ref c) => Rhs::Cast(Cast {
+                val: self.lower_atom(&c.expr),
+                from: c.expr.get_type().clone(),
+                to: c.typ.clone(),
+                checked: c.checked,
+                in_unsafe: c.in_unsafe,
+                pos: c.pos.clone(),
+            }),
+            Expr::Transmute(ref t) => Rhs::Transmute(Transmute {
+                val: self.lower_atom(&t.expr),
+                from: t.expr.get_type().clone(),
+                to: t.typ.clone(),
+                pos: t.pos.clone(),
+            }),
+            // The members are evaluated last to first
+            Expr::New(ref n) => {
+                let mut members = n.members
+                    .iter()
+                    .rev()
+                    .map(|m| self.lower_atom(m))
+                    .collect::<Vec<_>>();
+                members.reverse();
+                Rhs::New(n.constr.s, members)
+            }
+            Expr::Match(ref m) => self.lower_match(m),
+            Expr::Assert(ref a) => {
+                let cond = self.lower_atom(&a.cond);
+                self.emit(Stmt::Assert(cond, a.msg.clone(), a.pos.clone()));
+                Rhs::Atom(Atom::Nil)
+            }
+            Expr::WithArena(ref w) => Rhs::WithArena(self.lower_block(&w.body)),
+            Expr::Loop(ref l) => self.lower_loop(l),
+            Expr::Recur(_) => panic!("ICE: `recur` not in tail position in lowering"),
+            Expr::Begin(ref b) => {
+                let (last, effects) = b.exprs.split_last().expect("ICE: empty begin");
+                for effect in effects {
+                    self.lower_atom(effect);
+                }
+                self.lower_rhs(last)
+            }
+            Expr::PtrOp(ref p) => Rhs::PtrOp(PtrOp {
+                kind: p.kind,
+                args: p.args.iter().map(|a| self.lower_atom(a)).collect(),
+                ordering: p.ordering,
+                pos: p.pos.clone(),
+            }),
+            Expr::ThreadOp(ref t) => Rhs::ThreadOp(ThreadOp {
+                kind: t.kind,
+                args: t.args.iter().map(|a| self.lower_atom(a)).collect(),
+                pos: t.pos.clone(),
+            }),
+        })
+    }
+}
+
+/// Lower the globals of the typed and monomorphized program `ast` to MIR
+pub fn lower<'s>(ast: &ast::Ast<'s>) -> Module<'s> {
+    let glob_bindings = ast.globals.bindings().rev().collect::<Vec<_>>();
+    let mut glob_funcs = ast.externs.keys().cloned().collect::<BTreeSet<_>>();
+    for binding in &glob_bindings {
+        if mono_insts(binding).iter().any(|&(_, val)| match *val {
+            Expr::Lambda(_) => true,
+            _ => false,
+        }) {
+            glob_funcs.insert(binding.ident.s);
+        }
+    }
+    let mut lowerer = Lowerer {
+        glob_funcs,
+        frames: Vec::new(),
+    };
+    let mut module = Module::default();
+    for binding in glob_bindings {
+        let name = binding.ident.s;
+        for (inst, val) in mono_insts(binding) {
+            ice::in_binding(name, || val.to_string(), || match *val {
+                Expr::Lambda(ref lam) => {
+                    let func = lowerer.lower_lambda(lam, name);
+                    module.funcs.push(GlobalFunc { name, inst, func })
+                }
+                _ if ast.consts.contains(name) => {
+                    let val = match *val {
+                        Expr::NumLit(ref n) => Atom::Num(n.clone()),
+                        Expr::Bool(ref b) => Atom::Bool(b.val),
+                        _ => panic!("ICE: Value of constant `{}` is not a literal", name),
+                    };
+                    module.consts.push(GlobalConst { name, inst, val })
+                }
+                _ => {
+                    let var = GlobalVar {
+                        name,
+                        inst,
+                        typ: val.get_type().clone(),
+                        init: lowerer.lower_body(val),
+                    };
+                    if ast.lazy_globals.contains(name) {
+                        module.lazies.push(var)
+                    } else {
+                        module.vars.push(var)
+                    }
+                }
+            })
+        }
+    }
+    module
+}
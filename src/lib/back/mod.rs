@@ -1,56 +1,753 @@
-use self::llvm::{Builder, Context, Module};
+use self::llvm::{Builder, CompileOptions, Context, ExecutionEngine, FileType, JitEngine,
+                 JitOptions, Module};
 use self::codegen::*;
-use Emission;
-use lib::{time_action, CanonPathBuf};
-use lib::front::ast;
-use std::fs;
+use {BuildConfig, Emission, Lto, Sanitizer};
+use lib::{ice, time_action};
+use lib::front::{ast, Diagnostic};
+use libc::c_void;
+use llvm_sys::{core, execution_engine};
+use llvm_sys::execution_engine::LLVMExecutionEngineRef;
+use llvm_sys::prelude::{LLVMContextRef, LLVMModuleRef};
+use std::{fmt, fs, mem, ptr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ffi::CString;
 use std::io::Write;
 use std::process::Command;
 use std::env::current_dir;
+use std::env::consts::DLL_EXTENSION;
 
 mod llvm;
 mod codegen;
+mod mir;
 mod gc;
+mod trace;
+mod thread;
+mod show;
 
-pub fn compile(
-    ast: &ast::Ast,
-    out_filename: CanonPathBuf,
-    explicit_filename: bool,
-    emission: Emission,
-    user_link_libs: &[String],
-    lib_paths: &[String],
-) {
-    let context = Context::new();
-    let builder = Builder::new(&context);
-    let module = Module::new("main", &context);
+/// The path of the runtime library, which provides `_panic`, the string type, the garbage
+/// collector, etc. to every program. Built by the build script from `src/core`
+const RUNTIME_LIB: &str = env!("KVASIR_RUNTIME_LIB");
 
-    let mut codegenerator = CodeGenerator::new(&context, &builder, &module, ast.adts.clone());
-    time_action(
-        || codegenerator.gen_executable(&ast),
-        |t| println!("    Generated LLVM code in {}s", t),
+/// The entry point of a generated module
+enum Entry<'a> {
+    /// The C entry point of the given name, usually `main`, which initializes the globals and
+    /// calls the user-defined `main`
+    Main(&'a str),
+    /// The C entry point of the given name, usually `main`, which initializes the globals and
+    /// runs the tests of the program
+    TestHarness(&'a str),
+    /// No `main`. The globals are initialized on the first call to any of the exported
+    /// functions
+    Library(&'a [String]),
+    /// `_kvs_jit_init`, which initializes the globals, `main`, which calls the user-defined
+    /// `main` if there is one, and call thunks of the globals for `JitProgram::call` and `show`
+    ///
+    /// If `hot_reload`, global functions are called through slots, and the state globals of
+    /// `linked_state` are linked to those of an earlier version. See `CodeGenerator::gen_jit`
+    Jit {
+        hot_reload: bool,
+        linked_state: &'a BTreeSet<String>,
+    },
+}
+
+/// Generate and verify the LLVM module of the program `ast`, with the entry point `entry`
+///
+/// Returns the call thunks and state globals, which are only generated for `Entry::Jit`, or the
+/// error of the program that made it impossible to generate.
+fn gen_module<'ctx, 'src>(
+    context: &'ctx Context,
+    builder: &'ctx Builder,
+    module: &'ctx Module,
+    ast: &ast::Ast<'src>,
+    entry: Entry,
+    profile_heap: bool,
+    debug_backtrace: bool,
+    stack_probe_interval: Option<u64>,
+    verbose: bool,
+) -> Result<JitSymbols, Diagnostic> {
+    let mut codegenerator = CodeGenerator::new(
+        context,
+        builder,
+        module,
+        ast.adts.clone(),
+        &ast.extern_types,
+        profile_heap,
+        debug_backtrace,
+        stack_probe_interval,
+    );
+    let mir = time_action(
+        || mir::lower(ast),
+        |t| {
+            if verbose {
+                println!("    Lowered AST to MIR in {}s", t)
+            }
+        },
     );
+    let jit_symbols = time_action(
+        || {
+            ice::in_codegen(
+                || format!("{:?}", module),
+                || match entry {
+                    Entry::Main(name) => {
+                        codegenerator.gen_executable(ast, &mir, name)?;
+                        Ok(JitSymbols::default())
+                    }
+                    Entry::TestHarness(name) => {
+                        codegenerator.gen_test_harness(ast, &mir, name)?;
+                        Ok(JitSymbols::default())
+                    }
+                    Entry::Library(exports) => {
+                        codegenerator.gen_library(ast, &mir, exports)?;
+                        Ok(JitSymbols::default())
+                    }
+                    Entry::Jit {
+                        hot_reload,
+                        linked_state,
+                    } => codegenerator.gen_jit(ast, &mir, hot_reload, linked_state.clone()),
+                },
+            )
+        },
+        |t| {
+            if verbose {
+                println!("    Generated LLVM code in {}s", t)
+            }
+        },
+    )?;
+    codegenerator.gen_stack_probe_attributes();
 
     time_action(
         || {
-            codegenerator.module.verify().unwrap_or_else(|e| {
-                panic!(
-                    "Verifying module failed\nmodule: {:?}\nerror: {}",
-                    codegenerator.module, e
-                )
+            module.verify().unwrap_or_else(|e| {
+                panic!("Verifying module failed\nmodule: {:?}\nerror: {}", module, e)
             })
         },
-        |t| println!("    Verified LLVM module in {}s", t),
+        |t| {
+            if verbose {
+                println!("    Verified LLVM module in {}s", t)
+            }
+        },
+    );
+    Ok(jit_symbols)
+}
+
+/// Generate the LLVM IR of the executable program `ast`, for the target triple `target`
+pub fn gen_llvm_ir(ast: &ast::Ast, target: Option<&str>) -> Result<String, Diagnostic> {
+    let context = Context::new();
+    let builder = Builder::new(&context);
+    let module = Module::new("main", &context);
+    if let Some(target) = target {
+        module.set_target(target);
+    }
+    gen_module(&context, &builder, &module, ast, Entry::Main("main"), false, false, None, false)?;
+    Ok(format!("{:?}", &*module))
+}
+
+/// Generate the LLVM module of the executable program `ast`, and return the textual IR of each
+/// of the functions `names`, or `None` if there is no such function in the module
+///
+/// Functions are named as in the module, e.g. a global function has the name of its binding,
+/// an instance of a polymorphic global function has the name of its binding and type arguments
+/// mangled as described in `lib::mangle`, and the anonymous function of its closure wrapper,
+/// if it is used as a value, is `closure_func_NAME`. Symbols are named deterministically, so
+/// the same program always generates the same IR.
+pub fn gen_functions_ir(
+    ast: &ast::Ast,
+    names: &[&str],
+) -> Result<Vec<Option<String>>, Diagnostic> {
+    let context = Context::new();
+    let builder = Builder::new(&context);
+    let module = Module::new("main", &context);
+    gen_module(&context, &builder, &module, ast, Entry::Main("main"), false, false, None, false)?;
+    Ok(names
+        .iter()
+        .map(|name| module.get_function(name).map(|f| format!("{:?}", f)))
+        .collect())
+}
+
+/// The default interval in bytes at which to probe large stack frames on the target `target`,
+/// or the host if `None`
+///
+/// The interval is the size of the guard page below the stack. Targets without guard pages,
+/// like WebAssembly and bare metal, are not probed.
+pub fn default_stack_probe_interval(target: Option<&str>) -> Option<u64> {
+    match target {
+        Some(t) if t.starts_with("wasm") || t.contains("-none") => None,
+        Some(t) if t.starts_with("aarch64") && t.contains("-apple-") => Some(16384),
+        _ => Some(4096),
+    }
+}
+
+/// The names of the global functions of `ast` that can be exported from a library, i.e. the
+/// monomorphic ones
+fn exportable_funcs(ast: &ast::Ast) -> Vec<String> {
+    ast.globals
+        .bindings()
+        .filter(|b| b.sig.is_monomorphic() && b.sig.body.get_func().is_some())
+        .filter(|b| !ast.adts.variant_exists(b.ident.s))
+        .map(|b| b.ident.s.to_string())
+        .collect()
+}
+
+/// A JIT compiled program in the current process
+///
+/// The program is kept alive until dropped, so that its functions may be called after `main`
+/// has been run.
+pub struct JitProgram {
+    /// The versions of the program, the latest last. Earlier versions are kept alive after a
+    /// reload, as frames of their functions may still be on the stack
+    versions: Vec<JitVersion>,
+    /// The call thunks of the latest version, by function name
+    call_thunks: BTreeMap<String, CallThunk>,
+    /// Whether global functions are called through slots, so that they can be reloaded
+    hot_reload: bool,
+    /// The address of each state global, by symbol, to which reloaded versions are linked
+    state: BTreeMap<String, usize>,
+    /// Whether each global is a function, and its type, by name
+    globals: BTreeMap<String, (bool, String)>,
+    /// The definition of each data type, by name
+    adts: BTreeMap<String, String>,
+}
+
+/// A version of a JIT compiled program, in a context, module, and engine of its own
+struct JitVersion {
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    engine: LLVMExecutionEngineRef,
+}
+
+impl JitVersion {
+    /// JIT compile the program `ast`, without initializing it
+    ///
+    /// The state globals in `linked_state` are only declared, and are linked to the paired
+    /// addresses. See `JitProgram::new` for `hot_reload` and `host_funcs`.
+    fn new(
+        ast: &ast::Ast,
+        host_funcs: &[(&str, *const c_void)],
+        hot_reload: bool,
+        linked_state: &BTreeMap<String, usize>,
+    ) -> Result<(Self, JitSymbols), Diagnostic> {
+        // The LLVM objects are owned by `version` from creation, so that they are disposed
+        // of even if code generation fails or unwinds
+        let mut version = JitVersion {
+            context: unsafe { core::LLVMContextCreate() },
+            module: ptr::null_mut(),
+            engine: ptr::null_mut(),
+        };
+        let context: &'static Context = version.context.into();
+        let module: &'static Module = {
+            let m = Module::new("main", context);
+            version.module = (&*m).into();
+            mem::forget(m);
+            version.module.into()
+        };
+        let symbols = {
+            let builder = Builder::new(context);
+            let linked_state = linked_state.keys().cloned().collect();
+            let entry = Entry::Jit {
+                hot_reload,
+                linked_state: &linked_state,
+            };
+            gen_module(context, &builder, module, ast, entry, false, false, None, false)?
+        };
+        let engine = JitEngine::new(module, JitOptions { opt_level: 0 })
+            .map_err(|e| Diagnostic::error(format!("Failed to create JIT engine, {}", e)))?;
+        for &(name, addr) in host_funcs.iter().chain(&show::host_funcs()) {
+            if let Some(func) = module.get_function(name) {
+                unsafe { engine.add_global_mapping(func, addr) }
+            }
+        }
+        for (symbol, &addr) in linked_state {
+            if let Some(global) = module.get_global(symbol) {
+                unsafe { engine.add_global_mapping(global, addr as *const c_void) }
+            }
+        }
+        // The engine takes ownership of the module
+        version.engine = (&*engine).into();
+        mem::forget(engine);
+        Ok((version, symbols))
+    }
+
+    fn engine(&self) -> &JitEngine {
+        self.engine.into()
+    }
+
+    /// Returns the address of the global function or variable `symbol`
+    fn get_address(&self, symbol: &str) -> Option<usize> {
+        let c_name = CString::new(symbol).ok()?;
+        match unsafe { execution_engine::LLVMGetGlobalValueAddress(self.engine, c_name.as_ptr()) } {
+            0 => None,
+            addr => Some(addr as usize),
+        }
+    }
+}
+
+impl Drop for JitVersion {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.engine.is_null() {
+                execution_engine::LLVMDisposeExecutionEngine(self.engine)
+            } else if !self.module.is_null() {
+                core::LLVMDisposeModule(self.module)
+            }
+            core::LLVMContextDispose(self.context)
+        }
+    }
+}
+
+/// Whether each global of `ast` is a function, and its type, by name
+fn globals_of(ast: &ast::Ast) -> BTreeMap<String, (bool, String)> {
+    ast.globals
+        .bindings()
+        .map(|b| {
+            let is_func = match b.val {
+                ast::Expr::Lambda(_) => true,
+                _ => false,
+            };
+            (b.ident.s.to_string(), (is_func, b.sig.to_string()))
+        })
+        .collect()
+}
+
+/// The definition of each data type of `ast`, by name
+fn adts_of(ast: &ast::Ast) -> BTreeMap<String, String> {
+    ast.adts
+        .defs
+        .iter()
+        .map(|(name, def)| (name.to_string(), def.to_string()))
+        .collect()
+}
+
+/// A primitive value passed to or returned from a function of a JIT compiled program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JitValue {
+    Nil,
+    Bool(bool),
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    IntPtr(isize),
+    UInt8(u8),
+    UInt16(u16),
+    UInt32(u32),
+    UInt64(u64),
+    UIntPtr(usize),
+    Float32(f32),
+    Float64(f64),
+}
+
+impl JitValue {
+    /// The name of the Kvasir type of the value
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            JitValue::Nil => "Nil",
+            JitValue::Bool(_) => "Bool",
+            JitValue::Int8(_) => "Int8",
+            JitValue::Int16(_) => "Int16",
+            JitValue::Int32(_) => "Int32",
+            JitValue::Int64(_) => "Int64",
+            JitValue::IntPtr(_) => "IntPtr",
+            JitValue::UInt8(_) => "UInt8",
+            JitValue::UInt16(_) => "UInt16",
+            JitValue::UInt32(_) => "UInt32",
+            JitValue::UInt64(_) => "UInt64",
+            JitValue::UIntPtr(_) => "UIntPtr",
+            JitValue::Float32(_) => "Float32",
+            JitValue::Float64(_) => "Float64",
+        }
+    }
+
+    /// Write the value to the 8-byte slot `slot`, as the JIT compiled program reads it
+    unsafe fn write_to(&self, slot: *mut u64) {
+        match *self {
+            JitValue::Nil => (),
+            JitValue::Bool(x) => ptr::write(slot as *mut bool, x),
+            JitValue::Int8(x) => ptr::write(slot as *mut i8, x),
+            JitValue::Int16(x) => ptr::write(slot as *mut i16, x),
+            JitValue::Int32(x) => ptr::write(slot as *mut i32, x),
+            JitValue::Int64(x) => ptr::write(slot as *mut i64, x),
+            JitValue::IntPtr(x) => ptr::write(slot as *mut isize, x),
+            JitValue::UInt8(x) => ptr::write(slot as *mut u8, x),
+            JitValue::UInt16(x) => ptr::write(slot as *mut u16, x),
+            JitValue::UInt32(x) => ptr::write(slot as *mut u32, x),
+            JitValue::UInt64(x) => ptr::write(slot as *mut u64, x),
+            JitValue::UIntPtr(x) => ptr::write(slot as *mut usize, x),
+            JitValue::Float32(x) => ptr::write(slot as *mut f32, x),
+            JitValue::Float64(x) => ptr::write(slot as *mut f64, x),
+        }
+    }
+
+    /// Read a value of the Kvasir type `typ` from the 8-byte slot `slot`, as written by the
+    /// JIT compiled program
+    unsafe fn read_from(typ: &str, slot: *const u64) -> Self {
+        match typ {
+            "Nil" => JitValue::Nil,
+            "Bool" => JitValue::Bool(ptr::read(slot as *const u8) != 0),
+            "Int8" => JitValue::Int8(ptr::read(slot as *const i8)),
+            "Int16" => JitValue::Int16(ptr::read(slot as *const i16)),
+            "Int32" => JitValue::Int32(ptr::read(slot as *const i32)),
+            "Int64" => JitValue::Int64(ptr::read(slot as *const i64)),
+            "IntPtr" => JitValue::IntPtr(ptr::read(slot as *const isize)),
+            "UInt8" => JitValue::UInt8(ptr::read(slot as *const u8)),
+            "UInt16" => JitValue::UInt16(ptr::read(slot as *const u16)),
+            "UInt32" => JitValue::UInt32(ptr::read(slot as *const u32)),
+            "UInt64" => JitValue::UInt64(ptr::read(slot as *const u64)),
+            "UIntPtr" => JitValue::UIntPtr(ptr::read(slot as *const usize)),
+            "Float32" => JitValue::Float32(ptr::read(slot as *const f32)),
+            "Float64" => JitValue::Float64(ptr::read(slot as *const f64)),
+            _ => panic!("ICE: JitValue::read_from: `{}` is not a primitive type", typ),
+        }
+    }
+}
+
+/// Prints the value as it would be written as a literal in Kvasir source code
+impl fmt::Display for JitValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JitValue::Nil => write!(f, "nil"),
+            JitValue::Bool(x) => write!(f, "{}", x),
+            JitValue::Int8(x) => write!(f, "{}", x),
+            JitValue::Int16(x) => write!(f, "{}", x),
+            JitValue::Int32(x) => write!(f, "{}", x),
+            JitValue::Int64(x) => write!(f, "{}", x),
+            JitValue::IntPtr(x) => write!(f, "{}", x),
+            JitValue::UInt8(x) => write!(f, "{}", x),
+            JitValue::UInt16(x) => write!(f, "{}", x),
+            JitValue::UInt32(x) => write!(f, "{}", x),
+            JitValue::UInt64(x) => write!(f, "{}", x),
+            JitValue::UIntPtr(x) => write!(f, "{}", x),
+            // `Debug` always includes the decimal point, so that the literal reads as a float
+            JitValue::Float32(x) => write!(f, "{:?}", x),
+            JitValue::Float64(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl JitProgram {
+    /// JIT compile the program `ast`, and initialize its global variables
+    ///
+    /// If the program has no `main`, the generated `main` does nothing.
+    ///
+    /// Calls to the external functions in `host_funcs` are mapped to the paired addresses.
+    /// Other external symbols, e.g. those of the core library, are resolved in the current
+    /// process, so they must have been linked in and exported.
+    ///
+    /// If `hot_reload`, global functions are called through slots in an indirection table,
+    /// so that they can be replaced by `reload`.
+    pub fn new(
+        ast: &ast::Ast,
+        host_funcs: &[(&str, *const c_void)],
+        hot_reload: bool,
+    ) -> Result<Self, Diagnostic> {
+        let (version, symbols) = JitVersion::new(ast, host_funcs, hot_reload, &BTreeMap::new())?;
+        let state = symbols
+            .state
+            .iter()
+            .map(|symbol| {
+                let addr = version
+                    .get_address(symbol)
+                    .expect("ICE: state global not found in JIT engine");
+                (symbol.clone(), addr)
+            })
+            .collect();
+        let program = JitProgram {
+            versions: vec![version],
+            call_thunks: symbols
+                .call_thunks
+                .into_iter()
+                .map(|thunk| (thunk.name.clone(), thunk))
+                .collect(),
+            hot_reload,
+            state,
+            globals: globals_of(ast),
+            adts: adts_of(ast),
+        };
+        let init = program
+            .get_function("_kvs_jit_init")
+            .expect("ICE: `_kvs_jit_init` not found in JIT engine");
+        unsafe {
+            let init_fn: extern "C" fn() = mem::transmute(init);
+            init_fn()
+        }
+        Ok(program)
+    }
+
+    /// Replace the global functions of the running program with those of `ast`, a changed
+    /// version of the program
+    ///
+    /// The new version is compiled, and the slots of the functions are swapped atomically,
+    /// so that subsequent calls, including those from other threads and from the old
+    /// versions, go to the new functions. Calls in progress finish in the old functions, which
+    /// are kept alive. The global variables keep their values, and are not initialized again.
+    ///
+    /// The reload is rejected if the program was not compiled with `hot_reload`, or if a data
+    /// type, a global variable, or the type of a function has changed, as live values would
+    /// then have the wrong layout. Functions may be added and removed.
+    pub fn reload(
+        &mut self,
+        ast: &ast::Ast,
+        host_funcs: &[(&str, *const c_void)],
+    ) -> Result<(), Diagnostic> {
+        if !self.hot_reload {
+            return Err(Diagnostic::error("The program was not compiled for hot reloading"));
+        }
+        let globals = globals_of(ast);
+        self.check_reload(&globals, &adts_of(ast))
+            .map_err(Diagnostic::error)?;
+        let (version, symbols) = JitVersion::new(ast, host_funcs, true, &self.state)?;
+        // The slots of new functions are defined by the new version
+        for symbol in &symbols.state {
+            if !self.state.contains_key(symbol) {
+                let addr = version
+                    .get_address(symbol)
+                    .expect("ICE: state global not found in JIT engine");
+                self.state.insert(symbol.clone(), addr);
+            }
+        }
+        for &(ref slot, ref func) in &symbols.slots {
+            let func_addr = version
+                .get_address(func)
+                .expect("ICE: reloaded function not found in JIT engine");
+            let slot = self.state[slot] as *const AtomicUsize;
+            unsafe { (*slot).store(func_addr, Ordering::SeqCst) }
+        }
+        self.versions.push(version);
+        self.call_thunks = symbols
+            .call_thunks
+            .into_iter()
+            .map(|thunk| (thunk.name.clone(), thunk))
+            .collect();
+        self.globals = globals;
+        Ok(())
+    }
+
+    /// Check that the globals and data types of a reloaded version agree with those of the
+    /// running program
+    fn check_reload(
+        &self,
+        globals: &BTreeMap<String, (bool, String)>,
+        adts: &BTreeMap<String, String>,
+    ) -> Result<(), String> {
+        if *adts != self.adts {
+            return Err("Can't reload the program, as its data types have changed".to_string());
+        }
+        for (name, &(is_func, ref typ)) in &self.globals {
+            match globals.get(name) {
+                Some(&(_, ref new_typ)) if new_typ != typ => {
+                    return Err(format!(
+                        "Can't reload the program, as the type of `{}` has changed from `{}` to \
+                         `{}`",
+                        name, typ, new_typ
+                    ))
+                }
+                Some(&(new_is_func, _)) if new_is_func == is_func => (),
+                None if is_func => (),
+                _ => {
+                    return Err(format!(
+                        "Can't reload the program, as the global variable `{}` has changed",
+                        name
+                    ))
+                }
+            }
+        }
+        for (name, &(is_func, _)) in globals {
+            if !is_func && !self.globals.contains_key(name) {
+                return Err(format!(
+                    "Can't reload the program, as the global variable `{}` was added, which \
+                     would not be initialized",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn latest(&self) -> &JitVersion {
+        self.versions.last().expect("ICE: JIT program without versions")
+    }
+
+    /// Run the program, and return the exit code of `main`
+    ///
+    /// `main` is given no command line arguments. I.e. `argc` is 0, and `argv` is empty.
+    pub fn run_main(&self) -> i32 {
+        let engine = self.latest().engine();
+        let main = engine
+            .find_function("main")
+            .expect("ICE: `main` not found in JIT engine");
+        let argv: [*const u8; 1] = [ptr::null()];
+        unsafe {
+            let main_fn: extern "C" fn(i32, *const *const u8) -> i32 =
+                mem::transmute(engine.get_global::<u8>(main));
+            main_fn(0, argv.as_ptr())
+        }
+    }
+
+    /// Returns the address of the compiled, global function `name`
+    ///
+    /// The global variables have been initialized when the program was loaded, so the
+    /// function may be called before `run_main`.
+    pub fn get_function(&self, name: &str) -> Option<*const c_void> {
+        self.latest()
+            .get_address(name)
+            .map(|addr| addr as *const c_void)
+    }
+
+    /// Call the global function `name` with the arguments `args`, and return the result
+    ///
+    /// Only monomorphic functions of primitive parameters and result can be called. A
+    /// function of several parameters is applied to each of `args` in turn. The global
+    /// variables have been initialized when the program was loaded, so the function may be
+    /// called before `run_main`.
+    pub fn call(&self, name: &str, args: &[JitValue]) -> Result<JitValue, String> {
+        let thunk = self.call_thunks.get(name);
+        let symbol = thunk.and_then(|thunk| thunk.symbol.as_ref()).ok_or_else(|| {
+            format!(
+                "No callable function `{}`. Only monomorphic global functions of primitive \
+                 parameters and result can be called",
+                name
+            )
+        })?;
+        let thunk = thunk.unwrap();
+        let mut ret_buf = 0u64;
+        unsafe {
+            self.call_thunk(thunk, symbol, args, &mut ret_buf as *mut u64 as *mut c_void)?;
+            Ok(JitValue::read_from(&thunk.ret, &ret_buf))
+        }
+    }
+
+    /// Call the global function `name` with the arguments `args`, and return the result as
+    /// it would be written in source code
+    ///
+    /// Like `call`, but the result may be of any type. Pairs are shown as `(cons A B)`, and
+    /// variants as `(new Variant MEMBERS...)`. Opaque values, like closures and pointers, are
+    /// shown as their type and address, e.g. `#<(-> Int64 Int64) 0x7f3a...>`. If `name` is a
+    /// global variable, `args` must be empty, and its value is shown.
+    pub fn show(&self, name: &str, args: &[JitValue]) -> Result<String, String> {
+        let thunk = self.call_thunks.get(name).ok_or_else(|| {
+            format!(
+                "No global `{}` to show. Only monomorphic global variables, and functions of \
+                 primitive parameters, can be shown",
+                name
+            )
+        })?;
+        let mut out = String::new();
+        let out_ptr = &mut out as *mut String as *mut c_void;
+        unsafe { self.call_thunk(thunk, &thunk.show_symbol, args, out_ptr)? };
+        Ok(out)
+    }
+
+    /// Check that `args` are valid arguments of the function of `thunk`, and call the thunk
+    /// `symbol` of it with `args` and `out`
+    unsafe fn call_thunk(
+        &self,
+        thunk: &CallThunk,
+        symbol: &str,
+        args: &[JitValue],
+        out: *mut c_void,
+    ) -> Result<(), String> {
+        if args.len() != thunk.params.len() {
+            return Err(format!(
+                "Function `{}` takes {} arguments, but {} were given",
+                thunk.name,
+                thunk.params.len(),
+                args.len()
+            ));
+        }
+        for (i, (arg, param)) in args.iter().zip(&thunk.params).enumerate() {
+            if arg.type_name() != param.as_str() {
+                return Err(format!(
+                    "Argument {} of `{}` has type `{}`, expected `{}`",
+                    i,
+                    thunk.name,
+                    arg.type_name(),
+                    param
+                ));
+            }
+        }
+        let addr = self.get_function(symbol)
+            .expect("ICE: call thunk not found in JIT engine");
+        let mut args_buf = vec![0u64; args.len()];
+        for (arg, slot) in args.iter().zip(&mut args_buf) {
+            arg.write_to(slot)
+        }
+        let thunk_fn: extern "C" fn(*const u64, *mut c_void) = mem::transmute(addr);
+        thunk_fn(args_buf.as_ptr(), out);
+        Ok(())
+    }
+}
+
+pub fn compile(ast: &ast::Ast, config: &BuildConfig) -> Result<(), Diagnostic> {
+    let out_filename = &config.out_filename;
+    let context = Context::new();
+    let builder = Builder::new(&context);
+    let module = Module::new("main", &context);
+    if let Some(ref target) = config.target {
+        module.set_target(target);
+    }
+
+    let mut lib_exports = config.exports.clone();
+    lib_exports.extend(
+        ast.exports
+            .iter()
+            .filter(|name| !config.exports.iter().any(|e| e == *name))
+            .map(|name| name.to_string()),
     );
+    if config.no_main && lib_exports.is_empty() {
+        lib_exports = exportable_funcs(ast)
+    }
+    let entry = match config.emission {
+        Emission::Dylib => Entry::Library(&lib_exports),
+        Emission::Exe if config.no_main => {
+            return Err(Diagnostic::error(
+                "`--no-main` can not be used to build an executable",
+            ))
+        }
+        _ if config.no_main => Entry::Library(&lib_exports),
+        _ if config.test_harness => Entry::TestHarness(&config.entry_symbol),
+        _ => Entry::Main(&config.entry_symbol),
+    };
+    gen_module(
+        &context,
+        &builder,
+        &module,
+        ast,
+        entry,
+        config.profile_heap,
+        config.debug_backtrace,
+        config.stack_probe_interval,
+        true,
+    )?;
+
+    let sanitize_address = config.sanitizers.contains(&Sanitizer::Address);
+    if sanitize_address {
+        for func in &*module {
+            if !func.is_declaration() {
+                func.add_attribute("sanitize_address")
+            }
+        }
+    }
+    let compile_options = CompileOptions {
+        opt_level: 0,
+        passes: if sanitize_address {
+            &["asan", "asan-module"]
+        } else {
+            &[]
+        },
+        cpu: config.target_cpu.as_ref().map(|s| s.as_str()),
+        features: &config.target_features,
+        file_type: FileType::Obj,
+    };
 
     let with_ext_unless_explicit = |ext| {
-        if explicit_filename {
+        if config.explicit_out_filename {
             out_filename.clone()
         } else {
             out_filename.with_extension(ext)
         }
     };
 
-    match emission {
+    match config.emission {
         Emission::LlvmAsm => {
             let ll_filename = with_ext_unless_explicit("ll");
             let mut ir_file = fs::File::create(ll_filename.path()).unwrap_or_else(|e| {
@@ -62,7 +759,7 @@ pub fn compile(
             });
             time_action(
                 || {
-                    write!(ir_file, "{:?}", codegenerator.module).unwrap_or_else(|e| {
+                    write!(ir_file, "{:?}", &*module).unwrap_or_else(|e| {
                         panic!(
                             "Failed to write IR to `{}`, {}",
                             ll_filename.path().display(),
@@ -77,8 +774,7 @@ pub fn compile(
             let bc_filename = with_ext_unless_explicit("bc");
             time_action(
                 || {
-                    codegenerator
-                        .module
+                    module
                         .write_bitcode(&bc_filename.path().to_string_lossy())
                         .unwrap_or_else(|e| {
                             panic!(
@@ -91,13 +787,29 @@ pub fn compile(
                 |t| println!("    Wrote LLVM bitcode in {}s", t),
             );
         }
+        Emission::Asm => {
+            let asm_filename = with_ext_unless_explicit("s");
+            let asm_options = CompileOptions {
+                file_type: FileType::Asm,
+                ..compile_options.clone()
+            };
+            time_action(
+                || {
+                    module
+                        .compile(asm_filename.path(), &asm_options)
+                        .expect("Failed to compile module")
+                        .wait()
+                        .expect("Failed to wait on compilation child")
+                },
+                |t| println!("    Compiled LLVM module to assembly in {}s", t),
+            );
+        }
         Emission::Obj => {
             let obj_filename = with_ext_unless_explicit("o");
             time_action(
                 || {
-                    codegenerator
-                        .module
-                        .compile(obj_filename.path(), 0)
+                    module
+                        .compile(obj_filename.path(), &compile_options)
                         .expect("Failed to compile module")
                         .wait()
                         .expect("Failed to wait on compilation child")
@@ -105,13 +817,31 @@ pub fn compile(
                 |t| println!("    Compiled LLVM module to object in {}s", t),
             );
         }
-        Emission::Exe => {
-            let obj_path = out_filename.path().with_extension("o");
+        Emission::Exe | Emission::Dylib => {
+            let shared = match config.emission {
+                Emission::Dylib => true,
+                _ => false,
+            };
+            let linked_filename = if shared {
+                with_ext_unless_explicit(DLL_EXTENSION)
+            } else {
+                out_filename.clone()
+            };
+            // With LTO, code generation is deferred to the linker, which optimizes the
+            // bitcode of the program together with the bitcode of the libraries
+            let (obj_path, exe_options) = if config.lto.is_some() {
+                let bc_options = CompileOptions {
+                    file_type: FileType::Bitcode,
+                    ..compile_options.clone()
+                };
+                (out_filename.path().with_extension("bc"), bc_options)
+            } else {
+                (out_filename.path().with_extension("o"), compile_options.clone())
+            };
             time_action(
                 || {
-                    codegenerator
-                        .module
-                        .compile(&obj_path, 0)
+                    module
+                        .compile(&obj_path, &exe_options)
                         .expect("Failed to compile module")
                         .wait()
                         .expect("Failed to wait on compilation child")
@@ -122,7 +852,10 @@ pub fn compile(
             let mut clang = Command::new("clang");
             clang
                 .arg(&obj_path)
-                .args(&["-o", &out_filename.path().to_string_lossy()]);
+                .args(&["-o", &linked_filename.path().to_string_lossy()]);
+            if shared {
+                clang.arg("-shared");
+            }
             // Add current dir to link dir paths by default
             clang.args(&[
                 "-L",
@@ -131,20 +864,37 @@ pub fn compile(
                     .to_str()
                     .expect("Path to current dir is not valid unicode"),
             ]);
-            for path in lib_paths {
-                clang.args(&["-L", path]);
+            if let Some(ref target) = config.target {
+                clang.args(&["-target", target]);
             }
-
-            let mut link_libs = user_link_libs.to_vec();
-            let link_default_libs = true;
-            if link_default_libs {
-                // Default libraries to link
-                link_libs.extend(["core", "pthread", "dl"].iter().map(|&s| s.to_string()))
+            for path in &config.lib_paths {
+                clang.args(&["-L", path]);
             }
 
-            for lib in &link_libs {
+            for lib in &config.link_libs {
                 clang.args(&["-l", lib]);
             }
+            // The runtime library, built with the compiler, and the system libraries it
+            // depends on
+            clang.arg(RUNTIME_LIB);
+            clang.args(&["-l", "pthread", "-l", "dl"]);
+            for sanitizer in &config.sanitizers {
+                clang.arg(format!("-fsanitize={}", sanitizer.name()));
+            }
+            match config.lto {
+                Some(Lto::Full) => {
+                    clang.arg("-flto=full");
+                }
+                Some(Lto::Thin) => {
+                    clang.arg("-flto=thin");
+                }
+                None => (),
+            }
+            // Code is generated by the linker plugin, which doesn't see the flags given to `llc`
+            if let (Some(_), Some(cpu)) = (config.lto, config.target_cpu.as_ref()) {
+                clang.arg(format!("-Wl,-plugin-opt=mcpu={}", cpu));
+            }
+            clang.args(&config.link_args);
 
             let output = time_action(
                 || {
@@ -154,7 +904,8 @@ pub fn compile(
                 },
                 |t| {
                     println!(
-                        "    Compiled and linked object to executable with clang in {}s",
+                        "    Compiled and linked object to {} with clang in {}s",
+                        if shared { "shared library" } else { "executable" },
                         t
                     )
                 },
@@ -171,4 +922,5 @@ pub fn compile(
             }
         }
     }
+    Ok(())
 }
@@ -0,0 +1,157 @@
+//! Interface to the host functions that write values as source code, used to show the
+//! results of a JIT compiled program, e.g. in a REPL
+
+use lib::front::ast;
+use libc::c_void;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::slice;
+use super::codegen::*;
+use super::llvm::*;
+
+pub struct Show<'ctx, 'src> {
+    ctx: &'ctx Context,
+    module: &'ctx Module,
+    builder: &'ctx Builder,
+    /// The generated show function of each type
+    pub funcs: BTreeMap<ast::Type<'src>, &'ctx Function>,
+    /// The type of a show function, `void (i8 *out, i8 *val)`
+    pub func_type: &'ctx Type,
+    show_str: &'ctx Function,
+    show_bool: &'ctx Function,
+    show_int: &'ctx Function,
+    show_uint: &'ctx Function,
+    show_float32: &'ctx Function,
+    show_float64: &'ctx Function,
+    show_addr: &'ctx Function,
+    /// The constant data of each distinct piece of text
+    strs: RefCell<BTreeMap<String, &'ctx GlobalVariable>>,
+}
+
+impl<'ctx, 'src> Show<'ctx, 'src> {
+    pub fn new(ctx: &'ctx Context, module: &'ctx Module, builder: &'ctx Builder) -> Self {
+        // extern "C" fn _kvs_show_str(out: *mut String, s: *const u8, len: usize);
+        // extern "C" fn _kvs_show_int(out: *mut String, x: i64);
+        // ...
+        let t_usize = CodeGenerator::gen_int_ptr_type(module, ctx);
+        let t_ptr_i8 = PointerType::new(Type::get::<i8>(ctx));
+        let t_void = Type::get::<()>(ctx);
+        let host_func = |name: &str, params: &[&'ctx Type]| -> &'ctx Function {
+            module.add_function(name, FunctionType::new(t_void, params))
+        };
+        Show {
+            ctx,
+            module,
+            builder,
+            funcs: BTreeMap::new(),
+            func_type: FunctionType::new(t_void, &[t_ptr_i8, t_ptr_i8]),
+            show_str: host_func("_kvs_show_str", &[t_ptr_i8, t_ptr_i8, t_usize]),
+            show_bool: host_func("_kvs_show_bool", &[t_ptr_i8, Type::get::<bool>(ctx)]),
+            show_int: host_func("_kvs_show_int", &[t_ptr_i8, Type::get::<i64>(ctx)]),
+            show_uint: host_func("_kvs_show_uint", &[t_ptr_i8, Type::get::<u64>(ctx)]),
+            show_float32: host_func("_kvs_show_float32", &[t_ptr_i8, Type::get::<f32>(ctx)]),
+            show_float64: host_func("_kvs_show_float64", &[t_ptr_i8, Type::get::<f64>(ctx)]),
+            show_addr: host_func("_kvs_show_addr", &[t_ptr_i8, t_usize]),
+            strs: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Build a write of the constant text `s` to `out`
+    pub fn build_show_str(&self, out: &Value, s: &str) {
+        let existing = self.strs.borrow().get(s).cloned();
+        let s_const = existing.unwrap_or_else(|| {
+            let s_ll = Value::new_string(self.ctx, s, true);
+            let name = format!("show_str.{:016x}", content_hash(s.as_bytes()));
+            let s_const = self.module.add_global_const_variable(&name, s_ll);
+            self.strs.borrow_mut().insert(s.to_string(), s_const);
+            s_const
+        });
+        let s_ptr = self.builder.build_gep(
+            s_const,
+            &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+        );
+        let len = s.len().compile(self.ctx);
+        self.builder.build_call(self.show_str, &[out, s_ptr, len]);
+    }
+
+    /// Build a write of the text of length `len` at `s` to `out`
+    pub fn build_show_str_dyn(&self, out: &Value, s: &Value, len: &Value) {
+        self.builder.build_call(self.show_str, &[out, s, len]);
+    }
+
+    pub fn build_show_bool(&self, out: &Value, x: &Value) {
+        self.builder.build_call(self.show_bool, &[out, x]);
+    }
+
+    /// Build a write of the signed integer `x`, extended to 64 bits, to `out`
+    pub fn build_show_int(&self, out: &Value, x: &Value) {
+        let x = self.builder.build_sext(x, Type::get::<i64>(self.ctx));
+        self.builder.build_call(self.show_int, &[out, x]);
+    }
+
+    /// Build a write of the unsigned integer `x`, extended to 64 bits, to `out`
+    pub fn build_show_uint(&self, out: &Value, x: &Value) {
+        let x = self.builder.build_zext(x, Type::get::<u64>(self.ctx));
+        self.builder.build_call(self.show_uint, &[out, x]);
+    }
+
+    pub fn build_show_float32(&self, out: &Value, x: &Value) {
+        self.builder.build_call(self.show_float32, &[out, x]);
+    }
+
+    pub fn build_show_float64(&self, out: &Value, x: &Value) {
+        self.builder.build_call(self.show_float64, &[out, x]);
+    }
+
+    /// Build a write of the pointer `p` as a hexadecimal address to `out`
+    pub fn build_show_addr(&self, out: &Value, p: &Value) {
+        let t_usize = CodeGenerator::gen_int_ptr_type(self.module, self.ctx);
+        let addr = self.builder.build_ptr_to_int(p, t_usize);
+        self.builder.build_call(self.show_addr, &[out, addr]);
+    }
+}
+
+unsafe extern "C" fn show_str(out: *mut String, s: *const u8, len: usize) {
+    (*out).push_str(&String::from_utf8_lossy(slice::from_raw_parts(s, len)))
+}
+
+unsafe extern "C" fn show_bool(out: *mut String, x: bool) {
+    (*out).push_str(if x { "true" } else { "false" })
+}
+
+unsafe extern "C" fn show_int(out: *mut String, x: i64) {
+    (*out).push_str(&x.to_string())
+}
+
+unsafe extern "C" fn show_uint(out: *mut String, x: u64) {
+    (*out).push_str(&x.to_string())
+}
+
+// `Debug` always includes the decimal point, so that the literal reads as a float
+unsafe extern "C" fn show_float32(out: *mut String, x: f32) {
+    (*out).push_str(&format!("{:?}", x))
+}
+
+unsafe extern "C" fn show_float64(out: *mut String, x: f64) {
+    (*out).push_str(&format!("{:?}", x))
+}
+
+unsafe extern "C" fn show_addr(out: *mut String, addr: usize) {
+    (*out).push_str(&format!("{:#x}", addr))
+}
+
+/// The host functions called by the generated show functions, by symbol, to be mapped into
+/// a JIT compiled program
+///
+/// Each takes the `String` being written to as first argument.
+pub fn host_funcs() -> Vec<(&'static str, *const c_void)> {
+    vec![
+        ("_kvs_show_str", show_str as *const c_void),
+        ("_kvs_show_bool", show_bool as *const c_void),
+        ("_kvs_show_int", show_int as *const c_void),
+        ("_kvs_show_uint", show_uint as *const c_void),
+        ("_kvs_show_float32", show_float32 as *const c_void),
+        ("_kvs_show_float64", show_float64 as *const c_void),
+        ("_kvs_show_addr", show_addr as *const c_void),
+    ]
+}
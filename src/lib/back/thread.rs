@@ -0,0 +1,187 @@
+//! Interface to the threads, green threads, channels, and mutexes of the runtime
+
+use super::llvm::*;
+use super::codegen::*;
+
+pub struct Threads<'ctx> {
+    ctx: &'ctx Context,
+    builder: &'ctx Builder,
+    spawn: &'ctx Function,
+    join: &'ctx Function,
+    chan_new: &'ctx Function,
+    chan_send: &'ctx Function,
+    chan_recv: &'ctx Function,
+    mutex_new: &'ctx Function,
+    mutex_lock: &'ctx Function,
+    mutex_unlock: &'ctx Function,
+    task_spawn: &'ctx Function,
+    task_await: &'ctx Function,
+    task_yield: &'ctx Function,
+    /// The type of the entry point of a thread, like
+    /// `void thread_entry(i8* func, i8* captures, i8* out)`
+    pub entry_type: &'ctx Type,
+}
+
+impl<'ctx> Threads<'ctx> {
+    pub fn new(ctx: &'ctx Context, module: &'ctx Module, builder: &'ctx Builder) -> Self {
+        // type ThreadEntry = extern "C" fn(func: *const i8, captures: *const i8, out: *mut i8);
+        // extern "C" fn _thread_spawn(entry: ThreadEntry, func: *const i8, captures: *const i8,
+        //                             result_size: usize, result_visitor: ObjVisitor)
+        //                             -> *mut KvsThread;
+        // extern "C" fn _thread_join(t: *mut KvsThread, out: *mut i8);
+        // extern "C" fn _chan_new(elem_size: usize) -> *mut KvsChan;
+        // extern "C" fn _chan_send(c: *mut KvsChan, val: *const i8);
+        // extern "C" fn _chan_recv(c: *mut KvsChan, out: *mut i8);
+        // extern "C" fn _mutex_new(size: usize, val: *const i8) -> *mut KvsMutex;
+        // extern "C" fn _mutex_lock(m: *mut KvsMutex, out: *mut i8);
+        // extern "C" fn _mutex_unlock(m: *mut KvsMutex, val: *const i8, old_out: *mut i8);
+        // extern "C" fn _task_spawn(entry: ThreadEntry, func: *const i8, captures: *const i8,
+        //                           result_size: usize) -> *mut KvsTask;
+        // extern "C" fn _task_await(t: *mut KvsTask, out: *mut i8);
+        // extern "C" fn _task_yield();
+        let t_usize = CodeGenerator::gen_int_ptr_type(module, ctx);
+        let t_ptr_i8 = PointerType::new(Type::get::<i8>(ctx));
+        let t_void = Type::get::<()>(ctx);
+        let t_obj_handler = PointerType::new(FunctionType::new(t_void, &[t_ptr_i8]));
+        let t_obj_visitor = FunctionType::new(t_void, &[t_ptr_i8, t_obj_handler]);
+        let entry_type = FunctionType::new(t_void, &[t_ptr_i8, t_ptr_i8, t_ptr_i8]);
+        let t_spawn = FunctionType::new(
+            t_ptr_i8,
+            &[
+                PointerType::new(entry_type),
+                t_ptr_i8,
+                t_ptr_i8,
+                t_usize,
+                t_obj_visitor,
+            ],
+        );
+        let t_task_spawn = FunctionType::new(
+            t_ptr_i8,
+            &[PointerType::new(entry_type), t_ptr_i8, t_ptr_i8, t_usize],
+        );
+        let spawn = module.add_function("_thread_spawn", t_spawn);
+        let join = module.add_function(
+            "_thread_join",
+            FunctionType::new(t_void, &[t_ptr_i8, t_ptr_i8]),
+        );
+        let t_in_out = FunctionType::new(t_void, &[t_ptr_i8, t_ptr_i8]);
+        let chan_new = module.add_function("_chan_new", FunctionType::new(t_ptr_i8, &[t_usize]));
+        let chan_send = module.add_function("_chan_send", t_in_out);
+        let chan_recv = module.add_function("_chan_recv", t_in_out);
+        let mutex_new = module.add_function(
+            "_mutex_new",
+            FunctionType::new(t_ptr_i8, &[t_usize, t_ptr_i8]),
+        );
+        let mutex_lock = module.add_function("_mutex_lock", t_in_out);
+        let mutex_unlock = module.add_function(
+            "_mutex_unlock",
+            FunctionType::new(t_void, &[t_ptr_i8, t_ptr_i8, t_ptr_i8]),
+        );
+        let task_spawn = module.add_function("_task_spawn", t_task_spawn);
+        let task_await = module.add_function("_task_await", t_in_out);
+        let task_yield = module.add_function("_task_yield", FunctionType::new(t_void, &[]));
+        Threads {
+            ctx,
+            builder,
+            spawn,
+            join,
+            chan_new,
+            chan_send,
+            chan_recv,
+            mutex_new,
+            mutex_lock,
+            mutex_unlock,
+            task_spawn,
+            task_await,
+            task_yield,
+            entry_type,
+        }
+    }
+
+    /// Build a spawn of a thread running `entry` with the function pointer and captures of a
+    /// closure, and return the handle of the thread
+    ///
+    /// The result is kept pinned, with `result_visitor`, for as long as the handle lives.
+    pub fn build_spawn(
+        &self,
+        entry: &'ctx Function,
+        func: &'ctx Value,
+        captures: &'ctx Value,
+        result_size: usize,
+        result_visitor: &'ctx Function,
+    ) -> &'ctx Value {
+        self.builder.build_call(
+            self.spawn,
+            &[
+                entry,
+                func,
+                captures,
+                result_size.compile(self.ctx),
+                result_visitor,
+            ],
+        )
+    }
+
+    /// Build a join of the thread of handle `thread`, writing the result to `out`
+    pub fn build_join(&self, thread: &'ctx Value, out: &'ctx Value) {
+        self.builder.build_call(self.join, &[thread, out]);
+    }
+
+    /// Build the creation of a channel of messages of `elem_size` bytes
+    pub fn build_chan_new(&self, elem_size: usize) -> &'ctx Value {
+        self.builder
+            .build_call(self.chan_new, &[elem_size.compile(self.ctx)])
+    }
+
+    /// Build a send of the message pointed to by `val` on the channel `chan`
+    pub fn build_chan_send(&self, chan: &'ctx Value, val: &'ctx Value) {
+        self.builder.build_call(self.chan_send, &[chan, val]);
+    }
+
+    /// Build a receive of a message on the channel `chan`, writing it to `out`
+    pub fn build_chan_recv(&self, chan: &'ctx Value, out: &'ctx Value) {
+        self.builder.build_call(self.chan_recv, &[chan, out]);
+    }
+
+    /// Build the creation of a mutex protecting the value of `size` bytes pointed to by `val`
+    pub fn build_mutex_new(&self, size: usize, val: &'ctx Value) -> &'ctx Value {
+        self.builder
+            .build_call(self.mutex_new, &[size.compile(self.ctx), val])
+    }
+
+    /// Build a lock of the mutex `mutex`, writing its value to `out`
+    pub fn build_mutex_lock(&self, mutex: &'ctx Value, out: &'ctx Value) {
+        self.builder.build_call(self.mutex_lock, &[mutex, out]);
+    }
+
+    /// Build an unlock of the mutex `mutex` with the new value pointed to by `val`, writing
+    /// the previous value to `old_out`
+    pub fn build_mutex_unlock(&self, mutex: &'ctx Value, val: &'ctx Value, old_out: &'ctx Value) {
+        self.builder
+            .build_call(self.mutex_unlock, &[mutex, val, old_out]);
+    }
+
+    /// Build a spawn of a green thread running `entry` with the function pointer and
+    /// captures of a closure, and return the handle of the task
+    pub fn build_task_spawn(
+        &self,
+        entry: &'ctx Function,
+        func: &'ctx Value,
+        captures: &'ctx Value,
+        result_size: usize,
+    ) -> &'ctx Value {
+        self.builder.build_call(
+            self.task_spawn,
+            &[entry, func, captures, result_size.compile(self.ctx)],
+        )
+    }
+
+    /// Build an await of the green thread of handle `task`, writing the result to `out`
+    pub fn build_task_await(&self, task: &'ctx Value, out: &'ctx Value) {
+        self.builder.build_call(self.task_await, &[task, out]);
+    }
+
+    pub fn build_task_yield(&self) {
+        self.builder.build_call(self.task_yield, &[]);
+    }
+}
@@ -0,0 +1,78 @@
+//! Interface to the shadow stack of call frames, used to print backtraces on panics
+//!
+//! Maintaining the stack costs two calls into the runtime for every call, so frames are only
+//! pushed when building with `--debug-backtrace`. Otherwise, a panic prints no backtrace.
+
+use lib::front::SrcPos;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use super::codegen::content_hash;
+use super::llvm::*;
+
+pub struct ShadowStack<'ctx> {
+    ctx: &'ctx Context,
+    module: &'ctx Module,
+    builder: &'ctx Builder,
+    push_frame: &'ctx Function,
+    pop_frame: &'ctx Function,
+    /// Whether frames are pushed and popped. If not, building them does nothing
+    enabled: bool,
+    /// The constant description of each distinct frame
+    frames: RefCell<BTreeMap<String, &'ctx GlobalVariable>>,
+}
+
+impl<'ctx> ShadowStack<'ctx> {
+    pub fn new(
+        ctx: &'ctx Context,
+        module: &'ctx Module,
+        builder: &'ctx Builder,
+        enabled: bool,
+    ) -> Self {
+        // extern "C" fn _frame_push(frame: *const c_char);
+        // extern "C" fn _frame_pop();
+        let t_ptr_i8 = PointerType::new(Type::get::<i8>(ctx));
+        let t_void = Type::get::<()>(ctx);
+        let push_frame =
+            module.add_function("_frame_push", FunctionType::new(t_void, &[t_ptr_i8]));
+        let pop_frame = module.add_function("_frame_pop", FunctionType::new(t_void, &[]));
+        ShadowStack {
+            ctx,
+            module,
+            builder,
+            push_frame,
+            pop_frame,
+            enabled,
+            frames: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Build a push of a frame for a call of the function `name` at `pos`
+    ///
+    /// The frame is described by a constant null-terminated string, like
+    /// `"fib at examples/fib.kvs:3:8"`.
+    pub fn build_push_frame(&self, name: &str, pos: &SrcPos) {
+        if !self.enabled {
+            return;
+        }
+        let descr = format!("{} at {}", name, pos.location());
+        let existing = self.frames.borrow().get(&descr).cloned();
+        let descr_const = existing.unwrap_or_else(|| {
+            let descr_ll = Value::new_string(self.ctx, &descr, false);
+            let name = format!("frame.{:016x}", content_hash(descr.as_bytes()));
+            let descr_const = self.module.add_global_const_variable(&name, descr_ll);
+            self.frames.borrow_mut().insert(descr, descr_const);
+            descr_const
+        });
+        let descr_ptr = self.builder.build_gep(
+            descr_const,
+            &[0usize.compile(self.ctx), 0usize.compile(self.ctx)],
+        );
+        self.builder.build_call(self.push_frame, &[descr_ptr]);
+    }
+
+    pub fn build_pop_frame(&self) {
+        if self.enabled {
+            self.builder.build_call(self.pop_frame, &[]);
+        }
+    }
+}
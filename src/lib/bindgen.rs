@@ -0,0 +1,296 @@
+//! Generation of Kvasir declarations of the functions and types of a C header, with libclang
+//!
+//! Each function declared in the header becomes an `extern-io` declaration, and each struct
+//! or union an opaque `extern-type`. C functions may have any side effects, so all of them
+//! are declared with IO types, to be changed to `extern` by hand where the function is pure.
+//!
+//! The parameters of a function are passed as a single `Cons` of all of them, like other
+//! externs. Functions of types without a Kvasir equivalent, e.g. structs passed by value,
+//! function pointers, and variadic functions, are skipped with a comment in the output.
+
+use clang_sys::*;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::path::Path;
+use std::ptr;
+use libc::{c_char, c_int};
+
+/// The declarations generated from a header
+struct Bindings {
+    /// The Kvasir names of the opaque types, by the USR of the declaration of the C type
+    types: BTreeMap<String, String>,
+    /// The `extern-io` declarations of the functions, and the comments of skipped functions
+    externs: Vec<String>,
+}
+
+fn string(s: CXString) -> String {
+    unsafe {
+        let c_str = clang_getCString(s);
+        let r = if c_str.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(c_str).to_string_lossy().into_owned()
+        };
+        clang_disposeString(s);
+        r
+    }
+}
+
+fn spelling(c: CXCursor) -> String {
+    string(unsafe { clang_getCursorSpelling(c) })
+}
+
+fn usr(c: CXCursor) -> String {
+    string(unsafe { clang_getCursorUSR(c) })
+}
+
+fn type_spelling(t: CXType) -> String {
+    string(unsafe { clang_getTypeSpelling(t) })
+}
+
+fn is_from_main_file(c: CXCursor) -> bool {
+    unsafe { clang_Location_isFromMainFile(clang_getCursorLocation(c)) != 0 }
+}
+
+/// The Kvasir type name of the C type name `s`, e.g. `FooBar` of `struct foo_bar`, as type
+/// names must start with an uppercase letter
+fn type_name(s: &str) -> String {
+    s.split('_')
+        .flat_map(|part| {
+            let mut cs = part.chars();
+            cs.next()
+                .into_iter()
+                .flat_map(|c| c.to_uppercase())
+                .chain(cs)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+impl Bindings {
+    fn new() -> Self {
+        Bindings {
+            types: BTreeMap::new(),
+            externs: Vec::new(),
+        }
+    }
+
+    /// Add the struct or union `decl` as an opaque type, unless it's anonymous or already
+    /// named
+    fn add_record(&mut self, decl: CXCursor) -> Option<String> {
+        let name = spelling(decl);
+        if name.is_empty() {
+            self.types.get(&usr(decl)).cloned()
+        } else {
+            Some(self.types.entry(usr(decl)).or_insert(type_name(&name)).clone())
+        }
+    }
+
+    /// The name of the opaque type of the struct or union type `t`, preferring the name of a
+    /// typedef, e.g. `FILE` over `struct _IO_FILE`
+    fn record_name(&mut self, t: CXType) -> Option<String> {
+        unsafe {
+            match t.kind {
+                CXType_Typedef => {
+                    let decl = clang_getTypeDeclaration(clang_getCanonicalType(t));
+                    let typedef_name = type_name(&spelling(clang_getTypeDeclaration(t)));
+                    Some(self.types.entry(usr(decl)).or_insert(typedef_name).clone())
+                }
+                CXType_Elaborated => self.record_name(clang_Type_getNamedType(t)),
+                CXType_Record => self.add_record(clang_getTypeDeclaration(t)),
+                _ => None,
+            }
+        }
+    }
+
+    /// The Kvasir type of the C type `t`
+    ///
+    /// Returns a description of the type if it has no Kvasir equivalent.
+    fn type_of(&mut self, t: CXType) -> Result<String, String> {
+        unsafe {
+            let canon = clang_getCanonicalType(t);
+            let int = |signed: bool| match clang_Type_getSizeOf(canon) {
+                n @ 1 | n @ 2 | n @ 4 | n @ 8 => {
+                    Ok(format!("{}Int{}", if signed { "" } else { "U" }, n * 8))
+                }
+                _ => Err(format!("integer type `{}`", type_spelling(t))),
+            };
+            match canon.kind {
+                CXType_Void => Ok("Nil".to_string()),
+                CXType_Bool => Ok("Bool".to_string()),
+                // Plain `char` is for strings, which are `(Ptr UInt8)` in Kvasir
+                CXType_Char_S | CXType_Char_U | CXType_UChar => Ok("UInt8".to_string()),
+                CXType_SChar | CXType_Short | CXType_Int | CXType_Long | CXType_LongLong => {
+                    int(true)
+                }
+                CXType_UShort | CXType_UInt | CXType_ULong | CXType_ULongLong => int(false),
+                CXType_Float => Ok("Float32".to_string()),
+                CXType_Double => Ok("Float64".to_string()),
+                CXType_Enum => {
+                    self.type_of(clang_getEnumDeclIntegerType(clang_getTypeDeclaration(canon)))
+                }
+                CXType_Pointer => {
+                    let pointee = clang_getPointeeType(t);
+                    let pointee_canon = clang_getCanonicalType(pointee);
+                    match pointee_canon.kind {
+                        CXType_Void => Ok("(Ptr UInt8)".to_string()),
+                        CXType_FunctionProto | CXType_FunctionNoProto => {
+                            Err(format!("function pointer `{}`", type_spelling(t)))
+                        }
+                        CXType_Record => {
+                            let name = self.record_name(pointee)
+                                .unwrap_or_else(|| "UInt8".to_string());
+                            Ok(format!("(Ptr {})", name))
+                        }
+                        _ => self.type_of(pointee).map(|s| format!("(Ptr {})", s)),
+                    }
+                }
+                CXType_Record => Err(format!("struct or union by value `{}`", type_spelling(t))),
+                _ => Err(format!("type `{}`", type_spelling(t))),
+            }
+        }
+    }
+
+    /// Add the `extern-io` declaration of the function `decl`, or a comment on why it's skipped
+    fn add_function(&mut self, decl: CXCursor) {
+        let name = spelling(decl);
+        match self.function_type(decl) {
+            Ok(typ) => self.externs.push(format!("(extern-io {}\n  {})", name, typ)),
+            Err(why) => self.externs.push(format!(";; Skipped `{}`, {}", name, why)),
+        }
+    }
+
+    fn function_type(&mut self, decl: CXCursor) -> Result<String, String> {
+        unsafe {
+            if clang_Cursor_getStorageClass(decl) == CX_SC_Static {
+                return Err("static functions have no symbol".to_string());
+            }
+            let t = clang_getCursorType(decl);
+            if clang_isFunctionTypeVariadic(t) != 0 {
+                return Err("variadic functions are not supported".to_string());
+            }
+            let ret = self.type_of(clang_getResultType(t))
+                .map_err(|s| format!("unsupported return {}", s))?;
+            let n_params = clang_getNumArgTypes(t).max(0) as u32;
+            let params = (0..n_params)
+                .map(|i| self.type_of(clang_getArgType(t, i)))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|s| format!("unsupported parameter {}", s))?;
+            let arg = match params.len() {
+                0 => "RealWorld".to_string(),
+                1 => format!("(Cons {} RealWorld)", params[0]),
+                _ => format!("(Cons (Cons {}) RealWorld)", params.join(" ")),
+            };
+            Ok(format!("(-> {} (Cons {} RealWorld))", arg, ret))
+        }
+    }
+
+    fn to_kvasir(&self, header: &Path) -> String {
+        let mut s = format!(";;; Generated by `kvasir bindgen` from `{}`\n", header.display());
+        if !self.types.is_empty() {
+            s.push('\n');
+        }
+        let mut type_names = self.types.values().collect::<Vec<_>>();
+        type_names.sort();
+        type_names.dedup();
+        for name in type_names {
+            s += &format!("(extern-type {})\n", name)
+        }
+        for ext in &self.externs {
+            s += &format!("\n{}\n", ext)
+        }
+        s
+    }
+}
+
+/// Visit the declarations of the types of the header, so that they're named before they're
+/// used by functions
+extern "C" fn visit_types(c: CXCursor, _: CXCursor, data: CXClientData) -> CXChildVisitResult {
+    let bindings = unsafe { &mut *(data as *mut Bindings) };
+    if is_from_main_file(c) {
+        unsafe {
+            match clang_getCursorKind(c) {
+                CXCursor_StructDecl | CXCursor_UnionDecl => {
+                    bindings.add_record(c);
+                }
+                CXCursor_TypedefDecl => {
+                    let underlying = clang_getCanonicalType(clang_getTypedefDeclUnderlyingType(c));
+                    if underlying.kind == CXType_Record {
+                        let decl = clang_getTypeDeclaration(underlying);
+                        bindings.types.insert(usr(decl), type_name(&spelling(c)));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    CXChildVisit_Continue
+}
+
+extern "C" fn visit_functions(
+    c: CXCursor,
+    _: CXCursor,
+    data: CXClientData,
+) -> CXChildVisitResult {
+    let bindings = unsafe { &mut *(data as *mut Bindings) };
+    if is_from_main_file(c) && unsafe { clang_getCursorKind(c) } == CXCursor_FunctionDecl {
+        bindings.add_function(c)
+    }
+    CXChildVisit_Continue
+}
+
+/// Generate the Kvasir declarations of the functions and types declared in the C header
+/// `header`. `clang_args` are passed on to clang, e.g. include paths and defines
+///
+/// Declarations from headers included by `header` are not generated, except for the opaque
+/// types used by the functions of `header`.
+pub fn bindgen(header: &Path, clang_args: &[String]) -> Result<String, String> {
+    let filename = CString::new(header.to_string_lossy().into_owned())
+        .map_err(|_| "Header path contains a nul byte".to_string())?;
+    let args = clang_args
+        .iter()
+        .map(|a| CString::new(a.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Clang argument contains a nul byte".to_string())?;
+    let arg_ptrs = args.iter().map(|a| a.as_ptr()).collect::<Vec<*const c_char>>();
+    unsafe {
+        let index = clang_createIndex(0, 0);
+        let tu = clang_parseTranslationUnit(
+            index,
+            filename.as_ptr(),
+            arg_ptrs.as_ptr(),
+            arg_ptrs.len() as c_int,
+            ptr::null_mut(),
+            0,
+            CXTranslationUnit_None,
+        );
+        if tu.is_null() {
+            clang_disposeIndex(index);
+            return Err(format!("Failed to parse header `{}`", header.display()));
+        }
+        let mut errors = Vec::new();
+        for i in 0..clang_getNumDiagnostics(tu) {
+            let diagnostic = clang_getDiagnostic(tu, i);
+            let severity = clang_getDiagnosticSeverity(diagnostic);
+            if severity == CXDiagnostic_Error || severity == CXDiagnostic_Fatal {
+                let opts = clang_defaultDiagnosticDisplayOptions();
+                errors.push(string(clang_formatDiagnostic(diagnostic, opts)));
+            }
+            clang_disposeDiagnostic(diagnostic);
+        }
+        let mut bindings = Bindings::new();
+        if errors.is_empty() {
+            let root = clang_getTranslationUnitCursor(tu);
+            let data = &mut bindings as *mut Bindings as CXClientData;
+            clang_visitChildren(root, visit_types, data);
+            clang_visitChildren(root, visit_functions, data);
+        }
+        clang_disposeTranslationUnit(tu);
+        clang_disposeIndex(index);
+        if errors.is_empty() {
+            Ok(bindings.to_kvasir(header))
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
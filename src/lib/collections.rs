@@ -1,12 +1,13 @@
 use std::borrow::Borrow;
 use std::collections::BTreeMap;
 use std::fmt::{self, Debug};
+use std::marker::PhantomData;
 
 // TODO: Consider using BTreeMap, possible perforance increase. Do benchmarks.
-/// A stack of scopes of something. Fast access due to hashmaps, and guaranteed to contain no
-/// duplications at any point.
+/// A stack of scopes of something. Fast access due to hashmaps.
 ///
-/// Used for ConstDef:s. Only one constant can be defined for a single name at any given moment.
+/// A key in an inner scope shadows the same key in outer scopes, like nested `let`s that
+/// bind the same name, so lookups find the innermost definition.
 #[derive(Clone)]
 pub struct ScopeStack<K, V>(Vec<BTreeMap<K, V>>);
 
@@ -15,19 +16,7 @@ impl<K: Ord + Eq, V> ScopeStack<K, V> {
         ScopeStack(Vec::new())
     }
 
-    pub fn push(&mut self, scope: BTreeMap<K, V>)
-    where
-        K: Debug,
-        V: Debug,
-    {
-        for key in scope.keys() {
-            assert!(
-                !self.contains_key(key),
-                "ICE: ScopeStack::push: Key `{:?}` already exists\n  : {:?}",
-                key,
-                self
-            );
-        }
+    pub fn push(&mut self, scope: BTreeMap<K, V>) {
         self.0.push(scope);
     }
 
@@ -56,12 +45,7 @@ impl<K: Ord + Eq, V> ScopeStack<K, V> {
         Q: Ord + Eq,
         K: Borrow<Q>,
     {
-        for (height, scope) in self.0.iter().enumerate() {
-            if scope.contains_key(key) {
-                return Some(height);
-            }
-        }
-        None
+        self.get_with_height(key).map(|(_, height)| height)
     }
 
     pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
@@ -228,4 +212,31 @@ where
             }
         }
     }
+
+    /// Iterate over the entries of the map, in order of addition
+    pub fn iter(&self) -> AddMapIter<K, V> {
+        AddMapIter {
+            next: self.next,
+            _map: PhantomData,
+        }
+    }
+}
+
+/// An iterator over the entries of an `AddMap`, in order of addition
+pub struct AddMapIter<'a, K: 'a, V: 'a> {
+    next: *mut Option<AddMapNode<K, V>>,
+    _map: PhantomData<&'a AddMap<K, V>>,
+}
+
+impl<'a, K, V> Iterator for AddMapIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            (*self.next).as_ref().map(|n| {
+                self.next = n.next;
+                (&n.key, &n.val)
+            })
+        }
+    }
 }
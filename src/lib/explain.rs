@@ -0,0 +1,419 @@
+//! Extended descriptions of diagnostic codes, for the `explain` subcommand
+
+use lib::ErrCode;
+
+/// Extended descriptions of all diagnostics with a code, as `(module, number, description)`
+static EXPLANATIONS: &[(&str, usize, &str)] = &[
+    (
+        "parse",
+        0,
+        "A form was given the wrong number of arguments.
+
+    (if true 1)       ; `if` takes three arguments
+    (cons 1 2 3)      ; `cons` takes two
+
+Check the arity of the special form or definition, and add or remove arguments.",
+    ),
+    (
+        "parse",
+        1,
+        "A form was given too few arguments, when a variable number is accepted.
+
+    (cond)            ; `cond` needs at least one clause
+    (define)          ; `define` needs a name and a value",
+    ),
+    (
+        "parse",
+        2,
+        "A syntax tree of some shape was expected, but something else was found. The error
+message says what was expected, e.g. an identifier or an S-expression.
+
+    (let (x 1) x)     ; Expected S-expression. Each binding must be a list
+    (let [[x 1]] x)   ; Ok",
+    ),
+    (
+        "parse",
+        3,
+        "An external function was declared more than once.
+
+    (extern sin (-> Float64 Float64))
+    (extern sin (-> Float64 Float64))
+
+Remove one of the declarations. Note that imports are included into the same scope, so
+the other declaration may be in an imported module.",
+    ),
+    (
+        "parse",
+        4,
+        "A type signature constrains a type variable with an unknown class. The only available
+class is `Num`.
+
+    (define: (double x)
+        (constrain ((Num a)) (-> a a))
+      (* x 2))",
+    ),
+    (
+        "parse",
+        5,
+        "A constraint in a type signature is not an identifier. Constraints are written as
+`(CLASS TYPE-VARIABLE)`, e.g. `(Num a)`.",
+    ),
+    (
+        "parse",
+        6,
+        "A type variable does not begin with a lower case letter. Names beginning with upper
+case letters are reserved for type constructors.
+
+    (constrain ((Num A)) (-> A A))   ; Error
+    (constrain ((Num a)) (-> a a))   ; Ok",
+    ),
+    (
+        "parse",
+        7,
+        "A syntax tree could not be parsed as a type. Types are either identifiers,
+e.g. `Int64` or `a`, or applications of type constructors, e.g. `(-> Int64 Bool)` or
+`(List a)`.",
+    ),
+    (
+        "parse",
+        8,
+        "The left hand side of a binding is neither a variable nor a function pattern. In
+`let`, it may also be a destructuring pattern of `cons` and data constructors, whose
+sub-patterns are themselves variables or destructuring patterns.
+
+    (define 1 2)                      ; Error
+    (define x 2)                      ; Ok, variable
+    (define (f x) x)                  ; Ok, function
+    (define (f (x : Int64)) : Int64 x) ; Ok, function with type annotations
+    (let (((cons a (Some b)) v)) b)   ; Ok, destructuring
+    (let (((cons a 1) v)) a)          ; Error, literal in destructuring pattern",
+    ),
+    (
+        "parse",
+        9,
+        "Only the following forms may appear at the top level of a module: `import`,
+`extern`, `define`, `define:`, `data`, `macro`, `allow`, and `def-test`, or macro invocations
+expanding to these. A top level `begin` splices its forms into the module, so that a macro can
+expand to several definitions. Expressions must be placed inside a definition.
+
+    (macro define-pair
+      [(name a b) (begin (define a 1) (define b 2) (define name (cons a b)))])",
+    ),
+    (
+        "parse",
+        10,
+        "The name of an algebraic data type must begin with an upper case letter.
+
+    (data (list a) Nil (Cons a (list a)))   ; Error
+    (data (List a) Nil (Cons a (List a)))   ; Ok",
+    ),
+    (
+        "parse",
+        11,
+        "The name of a data type variant constructor must begin with an upper case letter.
+
+    (data Bool' true false)   ; Error
+    (data Bool' True False)   ; Ok",
+    ),
+    (
+        "parse",
+        12,
+        "A variant in a data type definition is neither a constructor name, nor an
+S-expression of a constructor name followed by member types.
+
+    (data Foo (1 Int64))      ; Error
+    (data Foo (Bar Int64))    ; Ok",
+    ),
+    (
+        "parse",
+        13,
+        "An identifier was used as a special form, but it is not one. This is an internal
+error, and should not happen in practice.",
+    ),
+    (
+        "parse",
+        15,
+        "Two algebraic data types have the same name. Data types share a single, global
+namespace, including all imported modules. Rename one of them.",
+    ),
+    (
+        "parse",
+        17,
+        "A variable was defined more than once in the same scope, either at the top level
+or in the same `let`.
+
+    (let [[x 1] [x 2]] x)     ; Error
+
+To shadow a variable, use a nested `let` instead.",
+    ),
+    (
+        "parse",
+        18,
+        "Two variants of data types have the same constructor name. Constructors share a
+single, global namespace, even across different data types.
+
+    (data A Foo)
+    (data B Foo)              ; Error",
+    ),
+    (
+        "parse",
+        19,
+        "A data constructor that is not defined was used, either in a `new` expression or a
+pattern. Check the spelling, and that the module defining the data type is imported.",
+    ),
+    (
+        "parse",
+        20,
+        "The head of a data type definition is neither a name nor an S-expression of a name
+followed by type parameters.
+
+    (data (List a) ...)       ; Ok
+    (data (List 1) ...)       ; Error",
+    ),
+    (
+        "parse",
+        21,
+        "A type parameter of a data type was declared more than once.
+
+    (data (Pair a a) (Pair a a))   ; Error
+    (data (Pair a b) (Pair a b))   ; Ok",
+    ),
+    (
+        "parse",
+        22,
+        "The file given to `include-str` or `include-bytes` could not be read. The path is
+relative to the directory of the source file containing the form. For `include-str`, the
+file must also be valid UTF-8. Use `include-bytes` for binary data.",
+    ),
+    (
+        "parse",
+        23,
+        "An `allow` form names a lint that does not exist. Check the spelling of the lint.
+All lints known to the compiler are listed by running `explain` without a code.",
+    ),
+    (
+        "parse",
+        24,
+        "A `recur` was used outside of a `loop`. `recur` jumps back to the start of the innermost
+enclosing `loop`, which must be in the same function. A `loop` in an enclosing function can't
+be jumped to from inside a lambda, and a `loop` outside of a `defer` can't be jumped to from
+inside it, as that would skip the cleanup.
+
+    (loop [[i 0]]
+      (if (< i 10)
+          (recur (+ i 1))
+          i))",
+    ),
+    (
+        "parse",
+        25,
+        "An external function with side effects does not have an IO type. Externs declared
+with `extern-io`, and externs that take or return the `RealWorld`, must either be IO
+actions, or functions of an argument and the world, returning a result and the new world.
+
+    (extern-io flush (-> RealWorld (Cons Nil RealWorld)))               ; Ok
+    (extern-io puts (-> (Cons String RealWorld) (Cons Int32 RealWorld))) ; Ok
+    (extern-io puts (-> String Int32))                                   ; Error
+    (extern now (-> RealWorld UInt64))                                   ; Error
+
+Without the world threaded through, calls to the function could be reordered, duplicated,
+or removed as if it were pure.",
+    ),
+    (
+        "parse",
+        26,
+        "An unsafe operation was used outside of an `unsafe` form. Operations on raw pointers
+are unchecked, and may crash the program or corrupt memory if misused, as may an
+`unsafe-transmute` or a cast to a pointer, or a value escaping the arena of a `with-arena`.
+Primitive thread operations are effects outside of `IO`. All must be marked as such.
+
+    (ptr-read p)              ; Error
+    (unsafe (ptr-read p))     ; Ok
+
+Prefer the IO actions of the standard library, like `spawn`, `send`, `modify-mutex`, and
+`yield`, over the primitive thread, channel, mutex, and task operations.",
+    ),
+    (
+        "parse",
+        27,
+        "A case of a macro definition is not a list of a pattern followed by one or more
+templates.
+
+    (macro twice
+      [(x) (begin x x)])      ; Ok
+    (macro twice
+      (x))                    ; Error, no template",
+    ),
+    (
+        "parse",
+        28,
+        "A pattern of a macro case is malformed. Patterns are identifiers, which bind the
+argument they match, literal identifiers like `('else)`, lists of patterns, and repetitions
+`(... PATTERN)`, which match the rest of a list.
+
+    (macro m [(1 x) x])               ; Error, numeric literal
+    (macro m [((... a) (... b)) a])   ; Error, ambiguous where `a` ends and `b` begins
+    (macro m [(x (... ys)) x])        ; Ok",
+    ),
+    (
+        "parse",
+        29,
+        "An identifier is spliced into a macro template with `(... ID)`, but it is not a
+variable of the pattern of the case. Splicing inserts the elements of the list bound to the
+variable, so it must be bound by the pattern.
+
+    (macro my-list
+      [((... xs)) (list (... x))])    ; Error, misspelled `xs`",
+    ),
+    (
+        "parse",
+        30,
+        "A foreign type declared with `extern-type` is used other than as the pointee of a
+`Ptr`. The size and layout of a foreign type is unknown to the compiler, so values of it can
+only be handled by pointer.
+
+    (extern-type File)
+    (extern fclose (-> File Int32))          ; Error
+    (extern fclose (-> (Ptr File) Int32))    ; Ok",
+    ),
+    (
+        "parse",
+        31,
+        "A `recur` was used other than in tail position of its `loop`. `recur` jumps back to
+the start of the loop, so it doesn't return a value that the rest of the loop body could use.
+It must be the last thing evaluated in the body, like a branch of an `if` or the last
+expression of a `begin`.
+
+    (loop [[i 0]]
+      (if (< i 10)
+          (recur (+ i 1))      ; Ok
+          i))
+    (loop [[i 0]]
+      (+ 1 (recur (+ i 1))))   ; Error",
+    ),
+    (
+        "lint",
+        0,
+        "A lint set to level deny was triggered. Lints are warnings by default, but can be
+turned into errors with `--deny LINT`, or all at once with `--deny warnings`.
+
+Fix the code the lint complains about, lower the level with `--warn` or `--allow`, or
+silence the lint for a single binding by wrapping it in an `allow` form.
+
+    (allow (unused-let-binding)
+      (define (foo x)
+        (let [[y x]] x)))",
+    ),
+    (
+        "codegen",
+        0,
+        "An executable was to be built, but no binding named `main` was found in the program.
+Bindings in the main module with names similar to `main`, like `Main` or `main'`, are listed
+as likely candidates.
+
+    (define (main real-world)
+      (display \"Hello, world!\" real-world))
+
+To build a library or object file without an entry point, pass `--no-main`.",
+    ),
+    (
+        "codegen",
+        1,
+        "The `main` function has the wrong type. It must be an IO action returning `Nil`, or a
+function of the command line arguments `argc` and `argv` to an IO action returning the exit
+code.
+
+    (define: main (IO Nil) ...)
+    (define: main (-> (Cons Int32 (Ptr (Ptr UInt8))) (IO Int32)) ...)
+
+`(IO T)` is short for `(-> RealWorld (Cons T RealWorld))`.
+
+If `main` is polymorphic, add a type signature so that type-checking enforces the type.",
+    ),
+    (
+        "codegen",
+        2,
+        "A pointer operation other than `null-ptr` and `ptr-eq` was applied to a pointer to an
+opaque foreign type, declared with `extern-type`. The size and layout of a foreign type is
+unknown, so its pointee can't be read, written, offset, or operated on atomically.
+
+    (extern-type File)
+    (define (read p) (unsafe (ptr-read p)))
+    (read (: (unsafe (null-ptr)) (Ptr File)))    ; Error
+
+Type-checking reports this directly when the pointee type is known. This error is reported
+when the pointee only became a foreign type after monomorphization of a polymorphic
+function. Pass the pointer to a foreign function instead, or `unsafe-transmute` it to a
+pointer to a known type.",
+    ),
+    (
+        "RUNTIME",
+        0,
+        "No case of a `match` expression matched the value, and the program was aborted. The
+compiler warns of such matches with the lint `non-exhaustive-patterns`, which can be
+denied to make them errors.
+
+    (match (new Nothing)
+      [(Just x) x])           ; Panics
+
+Add a case for each variant, or a catch-all variable pattern last.
+
+    (match (new Nothing)
+      [(Just x) x]
+      [_ 0])",
+    ),
+    (
+        "RUNTIME",
+        1,
+        "The condition of an `assert` was false, or the operands of an `assert-eq` were not
+equal, and the program was aborted. The message includes the source code of the assertion.
+
+    (assert (< i (length xs)))
+    (assert-eq (square 3) 9)
+
+`assert-eq` compares the operands with the `=` in scope, so `std` must be imported.",
+    ),
+    (
+        "RUNTIME",
+        2,
+        "The value of a `cast-checked` was out of the range of the integer type it was cast to,
+and the program was aborted. A float is out of range if its magnitude is too large, or if it
+is NaN or negative and the target type is unsigned.
+
+    (cast-checked (: 300 Int32) UInt8)      ; Panics
+    (cast-checked (: -1 Int8) UInt64)       ; Panics
+
+Use `cast` to wrap integers, and to saturate floats at the bounds of the integer type.",
+    ),
+];
+
+/// Parse a diagnostic code, as either `MODULE.NUMBER` or `MODULENUMBER`. The module is
+/// case insensitive
+fn parse_code(s: &str) -> Option<ErrCode> {
+    let split_at = s.find(|c: char| c.is_digit(10))?;
+    let (module_s, number_s) = s.split_at(split_at);
+    let module_s = module_s.trim_right_matches('.');
+    let number = number_s.parse().ok()?;
+    EXPLANATIONS
+        .iter()
+        .find(|&&(m, _, _)| m.eq_ignore_ascii_case(module_s))
+        .map(|&(module, _, _)| ErrCode { module, number })
+}
+
+/// Returns the extended description of the diagnostic with code `code`
+pub fn explain(code: &str) -> Result<&'static str, String> {
+    let c = parse_code(code).ok_or(format!("`{}` is not a valid diagnostic code", code))?;
+    EXPLANATIONS
+        .iter()
+        .find(|&&(m, n, _)| m == c.module && n == c.number)
+        .map(|&(_, _, s)| s)
+        .ok_or(format!("No diagnostic with code `{}`", c))
+}
+
+/// Returns the codes of all diagnostics that have an extended description
+pub fn codes() -> Vec<ErrCode> {
+    EXPLANATIONS
+        .iter()
+        .map(|&(module, number, _)| ErrCode { module, number })
+        .collect()
+}
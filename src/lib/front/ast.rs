@@ -13,6 +13,12 @@ lazy_static! {
     pub static ref TYPE_FLOAT64: Type<'static> = Type::Const("Float64", None);
     pub static ref TYPE_STRING: Type<'static> = Type::Const("String", None);
     pub static ref TYPE_REALWORLD: Type<'static> = Type::Const("RealWorld", None);
+    pub static ref TYPE_SYMBOL: Type<'static> = Type::Const("Symbol", None);
+    /// A byte array as a pair of length and pointer to the data
+    pub static ref TYPE_BYTES: Type<'static> = Type::new_cons(
+        Type::Const("UIntPtr", None),
+        Type::new_ptr(Type::Const("UInt8", None))
+    );
 }
 
 fn spaces(n: usize) -> String {
@@ -26,7 +32,10 @@ fn spaces(n: usize) -> String {
 /// constraints of a polytype similarly limit what types may be passed
 /// as arguments.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Poly<'s> {
+    #[cfg_attr(feature = "serde", serde(with = "::lib::front::serialize::pairs"))]
     pub params: BTreeMap<TVar<'s>, BTreeSet<&'s str>>,
     pub body: Type<'s>,
 }
@@ -39,6 +48,21 @@ impl<'s> Poly<'s> {
     pub fn is_monomorphic(&self) -> bool {
         self.is_monomorphic_in_context(&mut BTreeSet::new())
     }
+
+    /// Render as the type signature it would be written as, for diagnostics
+    ///
+    /// Unconstrained type variables are implicit, so only constrained ones are listed.
+    pub fn pretty(&self) -> String {
+        let constraints = self.params
+            .iter()
+            .flat_map(|(tv, cs)| cs.iter().map(move |c| format!("({} {})", c, tv)))
+            .collect::<Vec<_>>();
+        if constraints.is_empty() {
+            self.body.pretty()
+        } else {
+            format!("(constrain ({}) {})", constraints.join(" "), self.body.pretty())
+        }
+    }
 }
 
 impl<'s> Display for Poly<'s> {
@@ -60,6 +84,8 @@ impl<'s> Display for Poly<'s> {
 
 /// A type function
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum TypeFunc<'s> {
     Const(&'s str),
     Poly(Poly<'s>),
@@ -77,6 +103,8 @@ impl<'s> Display for TypeFunc<'s> {
 /// A type variable. Either an explicit string name,
 /// or an implicit automatically generated unique integer id.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum TVar<'s> {
     Explicit(&'s str),
     Implicit(u64),
@@ -97,6 +125,8 @@ impl<'s> Display for TVar<'s> {
 
 /// A type
 #[derive(Clone, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum Type<'s> {
     /// A type variable uniquely identified by an integer id
     /// and constrained by a set of type classes
@@ -145,6 +175,26 @@ impl<'s> Type<'s> {
         Type::App(Box::new(TypeFunc::Const("Ptr")), vec![typ])
     }
 
+    /// The type of a handle to a thread with a result of type `typ`
+    pub fn new_thread(typ: Type<'s>) -> Self {
+        Type::App(Box::new(TypeFunc::Const("Thread")), vec![typ])
+    }
+
+    /// The type of a handle to a green thread with a result of type `typ`
+    pub fn new_task(typ: Type<'s>) -> Self {
+        Type::App(Box::new(TypeFunc::Const("Task")), vec![typ])
+    }
+
+    /// The type of a channel of messages of type `typ`
+    pub fn new_chan(typ: Type<'s>) -> Self {
+        Type::App(Box::new(TypeFunc::Const("Chan")), vec![typ])
+    }
+
+    /// The type of a mutex protecting a value of type `typ`
+    pub fn new_mutex(typ: Type<'s>) -> Self {
+        Type::App(Box::new(TypeFunc::Const("Mutex")), vec![typ])
+    }
+
     pub fn new_binop(typ: Type<'s>) -> Self {
         Type::new_func(Type::new_cons(typ.clone(), typ.clone()), typ)
     }
@@ -359,6 +409,71 @@ impl<'s> Type<'s> {
         self.get_bin("Cons")
     }
 
+    /// If a pointer type, `(Ptr T)`, return the pointee type `T`
+    pub fn get_ptr(&self) -> Option<&Type<'s>> {
+        match *self {
+            Type::App(ref f, ref ts) if **f == TypeFunc::Const("Ptr") => Some(&ts[0]),
+            _ => None,
+        }
+    }
+
+    /// If an IO action, `(-> RealWorld (Cons T RealWorld))`, return the result type `T`
+    pub fn get_io(&self) -> Option<&Type<'s>> {
+        match self.get_func().map(|(arg, ret)| (arg, ret.get_cons())) {
+            Some((arg, Some((t, world))))
+                if *arg == *TYPE_REALWORLD && *world == *TYPE_REALWORLD =>
+            {
+                Some(t)
+            }
+            _ => None,
+        }
+    }
+
+    /// Render the type as it would be written in a type signature, for diagnostics
+    ///
+    /// Applications of polytypes are canonicalized, IO actions are written as `(IO T)`,
+    /// curried functions as `(-> A B C)`, and nested pairs as `(Cons A B C)`.
+    pub fn pretty(&self) -> String {
+        self.canonicalize().pretty_canonical()
+    }
+
+    fn pretty_canonical(&self) -> String {
+        match *self {
+            Type::Var(_) | Type::Const(..) => self.to_string(),
+            Type::App(..) if self.get_io().is_some() => {
+                format!("(IO {})", self.get_io().unwrap().pretty_canonical())
+            }
+            Type::App(..) if self.get_func().is_some() => {
+                let (arg, mut ret) = self.get_func().unwrap();
+                let mut ts = vec![arg];
+                while let (None, Some((arg2, ret2))) = (ret.get_io(), ret.get_func()) {
+                    ts.push(arg2);
+                    ret = ret2;
+                }
+                ts.push(ret);
+                let ts_s = ts.iter().map(|t| t.pretty_canonical()).join(" ");
+                format!("(-> {})", ts_s)
+            }
+            Type::App(..) if self.get_cons().is_some() => {
+                let (car, mut cdr) = self.get_cons().unwrap();
+                let mut ts = vec![car];
+                while let Some((car2, cdr2)) = cdr.get_cons() {
+                    ts.push(car2);
+                    cdr = cdr2;
+                }
+                ts.push(cdr);
+                let ts_s = ts.iter().map(|t| t.pretty_canonical()).join(" ");
+                format!("(Cons {})", ts_s)
+            }
+            Type::App(ref f, ref args) => {
+                let mut ss = vec![f.to_string()];
+                ss.extend(args.iter().map(|t| t.pretty_canonical()));
+                format!("({})", ss.join(" "))
+            }
+            Type::Poly(ref p) => p.pretty(),
+        }
+    }
+
     pub fn fulfills_constraints(&self, cs: &BTreeSet<&str>) -> bool {
         use self::Type::*;
         cs.iter().all(|c| match *c {
@@ -444,6 +559,8 @@ impl<'s> Display for Type<'s> {
 
 /// An identifier
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Ident<'s> {
     pub s: &'s str,
     pub pos: SrcPos<'s>,
@@ -474,7 +591,42 @@ impl<'s> Display for Ident<'s> {
     }
 }
 
+/// The calling convention of an external function
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CallConv {
+    C,
+    Stdcall,
+    Fastcall,
+    /// The convention of the system libraries of the target. `stdcall` on 32-bit x86 Windows,
+    /// and `c` everywhere else
+    System,
+}
+
+impl CallConv {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "c" => Some(CallConv::C),
+            "stdcall" => Some(CallConv::Stdcall),
+            "fastcall" => Some(CallConv::Fastcall),
+            "system" => Some(CallConv::System),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CallConv::C => "c",
+            CallConv::Stdcall => "stdcall",
+            CallConv::Fastcall => "fastcall",
+            CallConv::System => "system",
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct ExternDecl<'s> {
     pub ident: Ident<'s>,
     /// The type of the external variable being declared.
@@ -482,16 +634,41 @@ pub struct ExternDecl<'s> {
     /// Guaranteed during parsing to be monomorphic and canonical
     /// I.e. no type variables or polytype applications
     pub typ: Type<'s>,
+    pub call_conv: CallConv,
+    /// The symbol names to link to on specific targets, as `(TARGET, SYMBOL)`, where the
+    /// symbol is used if `TARGET` is a substring of the target triple, e.g. `"apple"`
+    pub link_names: Vec<(String, String)>,
     pub pos: SrcPos<'s>,
 }
 
+impl<'s> ExternDecl<'s> {
+    /// The name of the symbol to link to on the target `triple`. The first matching link name,
+    /// or the name of the extern if none match
+    pub fn symbol(&self, triple: &str) -> &str {
+        self.link_names
+            .iter()
+            .find(|&&(ref target, _)| triple.contains(target.as_str()))
+            .map(|&(_, ref symbol)| symbol.as_str())
+            .unwrap_or(self.ident.s)
+    }
+}
+
 impl<'s> Display for ExternDecl<'s> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "(extern {} {})", self.ident, self.typ)
+        write!(f, "(extern {} {}", self.ident, self.typ)?;
+        if self.call_conv != CallConv::C {
+            write!(f, " (call-conv {})", self.call_conv.name())?;
+        }
+        for &(ref target, ref symbol) in &self.link_names {
+            write!(f, " (link-name {:?} {:?})", target, symbol)?;
+        }
+        write!(f, ")")
     }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Nil<'s> {
     pub pos: SrcPos<'s>,
 }
@@ -503,8 +680,10 @@ impl<'s> Display for Nil<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct NumLit<'s> {
-    pub lit: &'s str,
+    pub lit: borrow::Cow<'s, str>,
     pub typ: Type<'s>,
     pub pos: SrcPos<'s>,
 }
@@ -516,6 +695,8 @@ impl<'s> Display for NumLit<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct StrLit<'s> {
     pub lit: borrow::Cow<'s, str>,
     pub pos: SrcPos<'s>,
@@ -527,7 +708,42 @@ impl<'s> Display for StrLit<'s> {
     }
 }
 
+/// A symbol literal, like `'foo` or `:foo`
+///
+/// Symbols of the same name are interned to the same value, so they can be compared by
+/// identity
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct SymLit<'s> {
+    pub name: &'s str,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for SymLit<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}", self.name)
+    }
+}
+
+/// A literal array of bytes, e.g. the contents of a file embedded with `include-bytes`
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct BytesLit<'s> {
+    pub bytes: Vec<u8>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for BytesLit<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#bytes[{}]", self.bytes.len())
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Variable<'s> {
     pub ident: Ident<'s>,
     pub typ: Type<'s>,
@@ -540,6 +756,8 @@ impl<'s> Display for Variable<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Bool<'s> {
     pub val: bool,
     pub pos: SrcPos<'s>,
@@ -552,6 +770,8 @@ impl<'s> Display for Bool<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct App<'s> {
     pub func: Expr<'s>,
     pub arg: Expr<'s>,
@@ -577,6 +797,8 @@ impl<'s> App<'s> {
 
 /// if-then-else expression
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct If<'s> {
     pub predicate: Expr<'s>,
     pub consequent: Expr<'s>,
@@ -604,6 +826,8 @@ impl<'s> If<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Lambda<'s> {
     pub param_ident: Ident<'s>,
     pub body: Expr<'s>,
@@ -628,12 +852,17 @@ impl<'s> Lambda<'s> {
 
 /// A binding of a name to a value, i.e. a variable definition.
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Binding<'s> {
     pub ident: Ident<'s>,
     pub sig: Poly<'s>,
+    /// The position of the type signature, if given explicitly
+    pub sig_pos: Option<SrcPos<'s>>,
     pub val: Expr<'s>,
     /// If this binding is polymorphic, here will be mappings from
     /// application arguments to monomorphic instantiation of `val`
+    #[cfg_attr(feature = "serde", serde(with = "::lib::front::serialize::pairs"))]
     pub mono_insts: BTreeMap<Vec<Type<'s>>, Expr<'s>>,
     pub pos: SrcPos<'s>,
 }
@@ -649,6 +878,8 @@ impl<'s> Binding<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum Group<'s> {
     Circular(BTreeMap<&'s str, Binding<'s>>),
     Uncircular(&'s str, Binding<'s>),
@@ -792,6 +1023,8 @@ impl<'s> Group<'s> {
 /// `a`. Both `f` and `g` are given the same type parameters, and the result is
 /// `(: f (for (a) (-> Int a a)))` and `(: g (for (a) (-> Int a a)))`.
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct TopologicallyOrderedDependencyGroups<'s>(pub Vec<Group<'s>>);
 
 impl<'s> TopologicallyOrderedDependencyGroups<'s> {
@@ -852,6 +1085,8 @@ impl<'s> TopologicallyOrderedDependencyGroups<'s> {
 
 /// A `let` special form
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Let<'s> {
     pub bindings: TopologicallyOrderedDependencyGroups<'s>,
     pub body: Expr<'s>,
@@ -878,6 +1113,8 @@ impl<'s> Let<'s> {
 ///
 /// Ascribes a specific type to an expression
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct TypeAscript<'s> {
     pub typ: Type<'s>,
     pub expr: Expr<'s>,
@@ -897,6 +1134,8 @@ impl<'s> TypeAscript<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Cons<'s> {
     pub typ: Type<'s>,
     pub car: Expr<'s>,
@@ -920,6 +1159,8 @@ impl<'s> Cons<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Car<'s> {
     pub typ: Type<'s>,
     pub expr: Expr<'s>,
@@ -939,6 +1180,8 @@ impl<'s> Car<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Cdr<'s> {
     pub typ: Type<'s>,
     pub expr: Expr<'s>,
@@ -959,25 +1202,437 @@ impl<'s> Cdr<'s> {
 
 /// A type cast
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Cast<'s> {
     pub expr: Expr<'s>,
     pub typ: Type<'s>,
+    /// Whether the cast is a `cast-checked`, which panics if the value is out of the range of
+    /// the target type, instead of wrapping or saturating
+    pub checked: bool,
+    /// Whether the cast is inside an `unsafe` form, as is required of a cast to a pointer
+    pub in_unsafe: bool,
     pub pos: SrcPos<'s>,
 }
 
 impl<'s> Cast<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        let op = if self.checked { "cast-checked" } else { "cast" };
+        format!(
+            "({} {}\n\
+             {}{})",
+            op,
+            self.expr.to_string_indent(n + op.len() + 2),
+            spaces(n + op.len() + 2),
+            self.typ
+        )
+    }
+}
+
+/// A reinterpretation of the bits of a value as another type of the same size,
+/// `(unsafe-transmute EXPR TYPE)`. Only allowed inside an `unsafe` form
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Transmute<'s> {
+    pub expr: Expr<'s>,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Transmute<'s> {
     fn to_string_indent(&self, n: usize) -> String {
         format!(
-            "(cast {}\n\
+            "(unsafe-transmute {}\n\
              {}{})",
-            self.expr.to_string_indent(n + 6),
-            spaces(n + 6),
+            self.expr.to_string_indent(n + 18),
+            spaces(n + 18),
             self.typ
         )
     }
 }
 
+/// An assertion, `(assert EXPR)` or `(assert-eq A B)`
+///
+/// If the condition is false at runtime, the program panics with `msg` and the position of
+/// the assertion
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Assert<'s> {
+    /// The asserted condition. For `assert-eq`, an application of `=` to the operands
+    pub cond: Expr<'s>,
+    /// The message to panic with, including the source code of the asserted expression
+    pub msg: String,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Assert<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        format!("(assert {})", self.cond.to_string_indent(n + 8))
+    }
+}
+
+/// A loop, `(loop [[VAR INIT] ...] BODY)`
+///
+/// The variables are bound to the initial values, and the body is evaluated. A `recur` in the
+/// body jumps back to the start of the loop, with the variables rebound to its arguments
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Loop<'s> {
+    pub vars: Vec<Variable<'s>>,
+    pub inits: Vec<Expr<'s>>,
+    pub body: Expr<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Loop<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        let bindings = self.vars
+            .iter()
+            .zip(&self.inits)
+            .map(|(var, init)| format!("[{} {}]", var, init.to_string_indent(n + 8)))
+            .collect::<Vec<_>>()
+            .join(&format!("\n{}", spaces(n + 7)));
+        format!(
+            "(loop [{}]\n{}{})",
+            bindings,
+            spaces(n + 2),
+            self.body.to_string_indent(n + 2)
+        )
+    }
+}
+
+/// A jump back to the start of the innermost enclosing loop, `(recur ARG ...)`
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Recur<'s> {
+    pub args: Vec<Expr<'s>>,
+    /// The type of the `recur` expression. As it never returns, it may be anything
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Recur<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        format!(
+            "(recur {})",
+            self.args
+                .iter()
+                .map(|a| a.to_string_indent(n + 7))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+}
+
+/// A sequence of expressions, `(begin EXPR ... LAST)`
+///
+/// The expressions are evaluated in order, for their effects, and the value is that of the last
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Begin<'s> {
+    /// The expressions of the sequence. Never empty
+    pub exprs: Vec<Expr<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Begin<'s> {
+    pub fn last(&self) -> &Expr<'s> {
+        self.exprs.last().expect("ICE: empty begin")
+    }
+
+    fn to_string_indent(&self, n: usize) -> String {
+        format!(
+            "(begin {})",
+            self.exprs
+                .iter()
+                .map(|e| e.to_string_indent(n + 7))
+                .collect::<Vec<_>>()
+                .join(&format!("\n{}", spaces(n + 7)))
+        )
+    }
+}
+
+/// A kind of primitive operation on raw pointers
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PtrOpKind {
+    /// `(ptr-read PTR)`, load the value pointed to
+    Read,
+    /// `(ptr-write PTR VAL)`, store a value where pointed to
+    Write,
+    /// `(ptr-offset PTR N)`, the pointer `N` elements after `PTR`
+    Offset,
+    /// `(null-ptr)`, the null pointer
+    Null,
+    /// `(ptr-eq PTR1 PTR2)`, whether the pointers are equal
+    Eq,
+    /// `(ptr-read-volatile PTR)`, a load that may not be elided or reordered with other
+    /// volatile operations
+    ReadVolatile,
+    /// `(ptr-write-volatile PTR VAL)`, a store that may not be elided or reordered with other
+    /// volatile operations
+    WriteVolatile,
+    /// `(atomic-load PTR ORDERING)`
+    AtomicLoad,
+    /// `(atomic-store PTR VAL ORDERING)`
+    AtomicStore,
+    /// `(atomic-fetch-add PTR N ORDERING)`, atomically add `N` to the pointed to integer,
+    /// and return the previous value
+    AtomicFetchAdd,
+    /// `(atomic-cas PTR EXPECTED NEW ORDERING)`, atomically compare and swap. Returns the
+    /// previous value together with whether the swap succeeded. Floats are compared by
+    /// their bits, so `-0.0` and `0.0` differ, and a NaN may equal itself
+    AtomicCas,
+}
+
+impl PtrOpKind {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "ptr-read" => Some(PtrOpKind::Read),
+            "ptr-write" => Some(PtrOpKind::Write),
+            "ptr-offset" => Some(PtrOpKind::Offset),
+            "null-ptr" => Some(PtrOpKind::Null),
+            "ptr-eq" => Some(PtrOpKind::Eq),
+            "ptr-read-volatile" => Some(PtrOpKind::ReadVolatile),
+            "ptr-write-volatile" => Some(PtrOpKind::WriteVolatile),
+            "atomic-load" => Some(PtrOpKind::AtomicLoad),
+            "atomic-store" => Some(PtrOpKind::AtomicStore),
+            "atomic-fetch-add" => Some(PtrOpKind::AtomicFetchAdd),
+            "atomic-cas" => Some(PtrOpKind::AtomicCas),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PtrOpKind::Read => "ptr-read",
+            PtrOpKind::Write => "ptr-write",
+            PtrOpKind::Offset => "ptr-offset",
+            PtrOpKind::Null => "null-ptr",
+            PtrOpKind::Eq => "ptr-eq",
+            PtrOpKind::ReadVolatile => "ptr-read-volatile",
+            PtrOpKind::WriteVolatile => "ptr-write-volatile",
+            PtrOpKind::AtomicLoad => "atomic-load",
+            PtrOpKind::AtomicStore => "atomic-store",
+            PtrOpKind::AtomicFetchAdd => "atomic-fetch-add",
+            PtrOpKind::AtomicCas => "atomic-cas",
+        }
+    }
+
+    /// The number of expression arguments, not counting any atomic ordering
+    pub fn arity(self) -> usize {
+        use self::PtrOpKind::*;
+        match self {
+            Null => 0,
+            Read | ReadVolatile | AtomicLoad => 1,
+            Write | Offset | Eq | WriteVolatile | AtomicStore | AtomicFetchAdd => 2,
+            AtomicCas => 3,
+        }
+    }
+
+    /// Whether the operation is atomic, and takes an ordering as last argument
+    pub fn is_atomic(self) -> bool {
+        use self::PtrOpKind::*;
+        match self {
+            AtomicLoad | AtomicStore | AtomicFetchAdd | AtomicCas => true,
+            _ => false,
+        }
+    }
+}
+
+/// The memory ordering constraint of an atomic operation
+///
+/// Corresponds to the orderings of C11 and LLVM, where `relaxed` is LLVM's `monotonic`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AtomicOrdering {
+    Relaxed,
+    Acquire,
+    Release,
+    AcqRel,
+    SeqCst,
+}
+
+impl AtomicOrdering {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "relaxed" => Some(AtomicOrdering::Relaxed),
+            "acquire" => Some(AtomicOrdering::Acquire),
+            "release" => Some(AtomicOrdering::Release),
+            "acq-rel" => Some(AtomicOrdering::AcqRel),
+            "seq-cst" => Some(AtomicOrdering::SeqCst),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AtomicOrdering::Relaxed => "relaxed",
+            AtomicOrdering::Acquire => "acquire",
+            AtomicOrdering::Release => "release",
+            AtomicOrdering::AcqRel => "acq-rel",
+            AtomicOrdering::SeqCst => "seq-cst",
+        }
+    }
+}
+
+/// A primitive operation on raw pointers, like `(ptr-read PTR)`
+///
+/// Only allowed inside an `unsafe` form, as nothing is checked about the pointers
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct PtrOp<'s> {
+    pub kind: PtrOpKind,
+    /// The arguments of the operation. As many as the arity of `kind`
+    pub args: Vec<Expr<'s>>,
+    /// The ordering of an atomic operation. `Some` iff `kind` is atomic
+    pub ordering: Option<AtomicOrdering>,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> PtrOp<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        let indent = n + self.kind.name().len() + 2;
+        let parts = once(self.kind.name().to_string())
+            .chain(self.args.iter().map(|a| a.to_string_indent(indent)))
+            .chain(self.ordering.map(|o| o.name().to_string()))
+            .collect::<Vec<_>>();
+        format!("({})", parts.join(" "))
+    }
+}
+
+/// A kind of primitive operation on threads or green threads, or on the channels and mutexes
+/// shared between them
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ThreadOpKind {
+    /// `(thread-spawn IO)`, spawn a thread running the IO action, and return a handle
+    Spawn,
+    /// `(thread-join THREAD)`, wait for the thread to finish, and return its result
+    Join,
+    /// `(chan-new)`, a new empty channel
+    ChanNew,
+    /// `(chan-send CHAN VAL)`, send a value on the channel
+    ChanSend,
+    /// `(chan-recv CHAN)`, wait for a value to be available on the channel, and take it
+    ChanRecv,
+    /// `(mutex-new VAL)`, a new unlocked mutex protecting a value
+    MutexNew,
+    /// `(mutex-lock MUTEX)`, wait for the mutex to be unlocked, lock it, and return its value
+    MutexLock,
+    /// `(mutex-unlock MUTEX VAL)`, replace the value of the locked mutex, and unlock it
+    MutexUnlock,
+    /// `(task-spawn IO)`, spawn a green thread running the IO action, and return a handle
+    TaskSpawn,
+    /// `(task-await TASK)`, yield until the green thread has finished, and return its result
+    TaskAwait,
+    /// `(task-yield)`, give up control to the other green threads of the thread
+    TaskYield,
+}
+
+impl ThreadOpKind {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "thread-spawn" => Some(ThreadOpKind::Spawn),
+            "thread-join" => Some(ThreadOpKind::Join),
+            "chan-new" => Some(ThreadOpKind::ChanNew),
+            "chan-send" => Some(ThreadOpKind::ChanSend),
+            "chan-recv" => Some(ThreadOpKind::ChanRecv),
+            "mutex-new" => Some(ThreadOpKind::MutexNew),
+            "mutex-lock" => Some(ThreadOpKind::MutexLock),
+            "mutex-unlock" => Some(ThreadOpKind::MutexUnlock),
+            "task-spawn" => Some(ThreadOpKind::TaskSpawn),
+            "task-await" => Some(ThreadOpKind::TaskAwait),
+            "task-yield" => Some(ThreadOpKind::TaskYield),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ThreadOpKind::Spawn => "thread-spawn",
+            ThreadOpKind::Join => "thread-join",
+            ThreadOpKind::ChanNew => "chan-new",
+            ThreadOpKind::ChanSend => "chan-send",
+            ThreadOpKind::ChanRecv => "chan-recv",
+            ThreadOpKind::MutexNew => "mutex-new",
+            ThreadOpKind::MutexLock => "mutex-lock",
+            ThreadOpKind::MutexUnlock => "mutex-unlock",
+            ThreadOpKind::TaskSpawn => "task-spawn",
+            ThreadOpKind::TaskAwait => "task-await",
+            ThreadOpKind::TaskYield => "task-yield",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        use self::ThreadOpKind::*;
+        match self {
+            ChanNew | TaskYield => 0,
+            Spawn | Join | ChanRecv | MutexNew | MutexLock | TaskSpawn | TaskAwait => 1,
+            ChanSend | MutexUnlock => 2,
+        }
+    }
+}
+
+/// A primitive operation on threads, like `(thread-spawn IO)`
+///
+/// Only allowed inside an `unsafe` form, as the operations are effectful. Use the IO actions
+/// of the standard library instead, like `spawn` and `join`
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct ThreadOp<'s> {
+    pub kind: ThreadOpKind,
+    /// The arguments of the operation. As many as the arity of `kind`
+    pub args: Vec<Expr<'s>>,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> ThreadOp<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        let indent = n + self.kind.name().len() + 2;
+        let parts = once(self.kind.name().to_string())
+            .chain(self.args.iter().map(|a| a.to_string_indent(indent)))
+            .collect::<Vec<_>>();
+        format!("({})", parts.join(" "))
+    }
+}
+
+/// An arena region, `(with-arena BODY)`
+///
+/// All heap allocations during the evaluation of the body are made in a bump arena, which
+/// is freed as a whole when the body has been evaluated. The value of the body must
+/// therefore not reference any memory allocated in the arena, which is not checked, so the
+/// form is only allowed in `unsafe`
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct WithArena<'s> {
+    pub body: Expr<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> WithArena<'s> {
+    fn to_string_indent(&self, n: usize) -> String {
+        format!(
+            "(with-arena\n{}{})",
+            spaces(n + 2),
+            self.body.to_string_indent(n + 2)
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct New<'s> {
     pub constr: Ident<'s>,
     pub members: Vec<Expr<'s>>,
@@ -1005,6 +1660,8 @@ impl<'s> New<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Deconstr<'s> {
     pub constr: Ident<'s>,
     pub subpatts: Vec<Pattern<'s>>,
@@ -1026,16 +1683,48 @@ impl<'s> Display for Deconstr<'s> {
     }
 }
 
+/// A pattern of the numbers from `lo` up to and including `hi`, `(range LO HI)`
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct RangePatt<'s> {
+    pub lo: NumLit<'s>,
+    pub hi: NumLit<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for RangePatt<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(range {} {})", self.lo, self.hi)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum Pattern<'s> {
     Nil(Nil<'s>),
     NumLit(NumLit<'s>),
+    Range(RangePatt<'s>),
     StrLit(StrLit<'s>),
+    Bool(Bool<'s>),
     Variable(Variable<'s>),
     Deconstr(Box<Deconstr<'s>>),
 }
 
 impl<'s> Pattern<'s> {
+    pub fn pos(&self) -> &SrcPos<'s> {
+        match *self {
+            Pattern::Nil(ref n) => &n.pos,
+            Pattern::NumLit(ref n) => &n.pos,
+            Pattern::Range(ref r) => &r.pos,
+            Pattern::StrLit(ref s) => &s.pos,
+            Pattern::Bool(ref b) => &b.pos,
+            Pattern::Variable(ref v) => &v.ident.pos,
+            Pattern::Deconstr(ref d) => &d.pos,
+        }
+    }
+
     pub fn variables(&self) -> BTreeSet<&Variable<'s>> {
         match *self {
             Pattern::Variable(ref v) => set_of(v),
@@ -1071,7 +1760,9 @@ impl<'s> Display for Pattern<'s> {
         match *self {
             Pattern::Nil(ref n) => n.fmt(f),
             Pattern::NumLit(ref n) => n.fmt(f),
+            Pattern::Range(ref r) => r.fmt(f),
             Pattern::StrLit(ref s) => s.fmt(f),
+            Pattern::Bool(ref b) => b.fmt(f),
             Pattern::Variable(ref v) => v.fmt(f),
             Pattern::Deconstr(ref dec) => dec.fmt(f),
         }
@@ -1079,6 +1770,8 @@ impl<'s> Display for Pattern<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Case<'s> {
     pub patt: Pattern<'s>,
     pub patt_typ: Type<'s>,
@@ -1100,6 +1793,8 @@ impl<'s> Case<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Match<'s> {
     pub expr: Expr<'s>,
     pub cases: Vec<Case<'s>>,
@@ -1127,10 +1822,14 @@ impl<'s> Match<'s> {
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub enum Expr<'s> {
     Nil(Nil<'s>),
     NumLit(NumLit<'s>),
     StrLit(StrLit<'s>),
+    BytesLit(BytesLit<'s>),
+    SymLit(SymLit<'s>),
     Bool(Bool<'s>),
     Variable(Variable<'s>),
     App(Box<App<'s>>),
@@ -1142,8 +1841,16 @@ pub enum Expr<'s> {
     Car(Box<Car<'s>>),
     Cdr(Box<Cdr<'s>>),
     Cast(Box<Cast<'s>>),
+    Transmute(Box<Transmute<'s>>),
     New(Box<New<'s>>),
     Match(Box<Match<'s>>),
+    Assert(Box<Assert<'s>>),
+    WithArena(Box<WithArena<'s>>),
+    Loop(Box<Loop<'s>>),
+    Recur(Box<Recur<'s>>),
+    Begin(Box<Begin<'s>>),
+    PtrOp(Box<PtrOp<'s>>),
+    ThreadOp(Box<ThreadOp<'s>>),
 }
 
 impl<'s> Expr<'s> {
@@ -1152,6 +1859,8 @@ impl<'s> Expr<'s> {
             Expr::Nil(ref n) => &n.pos,
             Expr::NumLit(ref l) => &l.pos,
             Expr::StrLit(ref l) => &l.pos,
+            Expr::BytesLit(ref l) => &l.pos,
+            Expr::SymLit(ref l) => &l.pos,
             Expr::Bool(ref b) => &b.pos,
             Expr::Variable(ref bnd) => &bnd.ident.pos,
             Expr::App(ref app) => &app.pos,
@@ -1163,8 +1872,16 @@ impl<'s> Expr<'s> {
             Expr::Car(ref c) => &c.pos,
             Expr::Cdr(ref c) => &c.pos,
             Expr::Cast(ref c) => &c.pos,
+            Expr::Transmute(ref t) => &t.pos,
             Expr::New(ref n) => &n.pos,
             Expr::Match(ref m) => &m.pos,
+            Expr::Assert(ref a) => &a.pos,
+            Expr::WithArena(ref w) => &w.pos,
+            Expr::Loop(ref l) => &l.pos,
+            Expr::Recur(ref r) => &r.pos,
+            Expr::Begin(ref b) => &b.pos,
+            Expr::PtrOp(ref p) => &p.pos,
+            Expr::ThreadOp(ref t) => &t.pos,
         }
     }
 
@@ -1180,6 +1897,8 @@ impl<'s> Expr<'s> {
             Expr::Nil(_) => &TYPE_NIL,
             Expr::NumLit(ref l) => &l.typ,
             Expr::StrLit(_) => &TYPE_STRING,
+            Expr::BytesLit(_) => &TYPE_BYTES,
+            Expr::SymLit(_) => &TYPE_SYMBOL,
             Expr::Bool(_) => &TYPE_BOOL,
             Expr::Variable(ref bnd) => &bnd.typ,
             Expr::App(ref app) => &app.typ,
@@ -1191,8 +1910,16 @@ impl<'s> Expr<'s> {
             Expr::Car(ref c) => &c.typ,
             Expr::Cdr(ref c) => &c.typ,
             Expr::Cast(ref c) => &c.typ,
+            Expr::Transmute(ref t) => &t.typ,
             Expr::New(ref n) => &n.typ,
             Expr::Match(ref m) => &m.typ,
+            Expr::Assert(_) => &TYPE_NIL,
+            Expr::WithArena(ref w) => w.body.get_type(),
+            Expr::Loop(ref l) => l.body.get_type(),
+            Expr::Recur(ref r) => &r.typ,
+            Expr::Begin(ref b) => b.last().get_type(),
+            Expr::PtrOp(ref p) => &p.typ,
+            Expr::ThreadOp(ref t) => &t.typ,
         }
     }
 
@@ -1228,6 +1955,8 @@ impl<'s> Expr<'s> {
             Expr::Nil(ref n) => n.to_string(),
             Expr::NumLit(ref l) => l.to_string(),
             Expr::StrLit(ref l) => l.to_string(),
+            Expr::BytesLit(ref l) => l.to_string(),
+            Expr::SymLit(ref l) => l.to_string(),
             Expr::Bool(ref b) => b.to_string(),
             Expr::Variable(ref v) => v.to_string(),
             Expr::App(ref app) => app.to_string_indent(n),
@@ -1239,8 +1968,16 @@ impl<'s> Expr<'s> {
             Expr::Car(ref c) => c.to_string_indent(n),
             Expr::Cdr(ref c) => c.to_string_indent(n),
             Expr::Cast(ref c) => c.to_string_indent(n),
+            Expr::Transmute(ref t) => t.to_string_indent(n),
             Expr::New(ref new) => new.to_string_indent(n),
             Expr::Match(ref m) => m.to_string_indent(n),
+            Expr::Assert(ref a) => a.to_string_indent(n),
+            Expr::WithArena(ref w) => w.to_string_indent(n),
+            Expr::Loop(ref l) => l.to_string_indent(n),
+            Expr::Recur(ref r) => r.to_string_indent(n),
+            Expr::Begin(ref b) => b.to_string_indent(n),
+            Expr::PtrOp(ref p) => p.to_string_indent(n),
+            Expr::ThreadOp(ref t) => t.to_string_indent(n),
         }
     }
 }
@@ -1251,23 +1988,37 @@ impl<'s> Display for Expr<'s> {
     }
 }
 
+/// Whether an ADT variant is stored on the heap, behind a pointer, or inline in the ADT
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Boxing {
+    /// Decided by the code generator, from the size of the variant
+    Auto,
+    /// Annotated `boxed`. Always behind a pointer, and doesn't make the ADT recursive
+    Boxed,
+    /// Annotated `unboxed`. Always inline
+    Unboxed,
+}
+
 /// A variant of an algebraic data type
 ///
 /// An ADT variant is equivalent to a constructor and a destructor
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct AdtVariant<'s> {
     pub name: Ident<'s>,
     pub members: Vec<Type<'s>>,
+    pub boxing: Boxing,
     pub pos: SrcPos<'s>,
 }
 
 impl<'s> Display for AdtVariant<'s> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.members.is_empty() {
-            self.name.fmt(f)
+        let variant = if self.members.is_empty() {
+            self.name.to_string()
         } else {
-            write!(
-                f,
+            format!(
                 "({} {})",
                 self.name,
                 self.members
@@ -1276,12 +2027,19 @@ impl<'s> Display for AdtVariant<'s> {
                     .intersperse(" ".to_string())
                     .collect::<String>()
             )
+        };
+        match self.boxing {
+            Boxing::Auto => write!(f, "{}", variant),
+            Boxing::Boxed => write!(f, "(boxed {})", variant),
+            Boxing::Unboxed => write!(f, "(unboxed {})", variant),
         }
     }
 }
 
 /// Algebraic Data Type definition
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct AdtDef<'s> {
     pub name: Ident<'s>,
     pub params: Vec<&'s str>,
@@ -1330,6 +2088,8 @@ impl<'s> Display for AdtDef<'s> {
 
 /// Algebraic data type definitions
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Adts<'s> {
     pub defs: BTreeMap<&'s str, AdtDef<'s>>,
     /// Auxiliary map for quicker access to variants parent
@@ -1386,12 +2146,17 @@ impl<'s> Adts<'s> {
         }
     }
 
+    /// A boxed variant is behind a pointer, so it doesn't make the ADT infinitely large even if
+    /// it refers to it
     fn is_rec_adt(&self, adt: &AdtDef, origin: &str, history: &mut BTreeSet<String>) -> bool {
-        adt.variants.iter().any(|v| {
-            v.members
-                .iter()
-                .any(|t| self.is_rec_type(t, origin, history))
-        })
+        adt.variants
+            .iter()
+            .filter(|v| v.boxing != Boxing::Boxed)
+            .any(|v| {
+                v.members
+                    .iter()
+                    .any(|t| self.is_rec_type(t, origin, history))
+            })
     }
 
     // pub fn variant_is_recursive(&self, v: &str) -> bool {
@@ -1443,14 +2208,14 @@ impl<'s> Adts<'s> {
         variant: &AdtVariant<'s>,
         inst: &[Type<'s>],
     ) -> Option<Vec<Type<'s>>> {
-        use super::substitution::subst;
+        use super::substitution::subst_once;
         let adt = self.parent_adt_of_variant(variant.name.s)?;
-        let mut s = adt.params
+        let s = adt.params
             .iter()
             .map(|p| TVar::Explicit(p))
             .zip(inst.iter().cloned())
             .collect::<BTreeMap<_, _>>();
-        Some(variant.members.iter().map(|t| subst(t, &mut s)).collect())
+        Some(variant.members.iter().map(|t| subst_once(t, &s)).collect())
     }
 
     pub fn members_with_inst_of_variant_with_name(
@@ -1492,8 +2257,21 @@ impl<'s> Adts<'s> {
     }
 }
 
+/// A unit test, defined with `def-test`
+///
+/// The body of the test is a global function `(-> Nil Nil)` of the same name
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
+pub struct Test<'s> {
+    pub name: Ident<'s>,
+    pub pos: SrcPos<'s>,
+}
+
 /// A module of definitions and declarations of functions and variables
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 's")))]
 pub struct Ast<'s> {
     /// External variable declarations
     ///
@@ -1507,18 +2285,38 @@ pub struct Ast<'s> {
     pub globals: TopologicallyOrderedDependencyGroups<'s>,
     /// Algebraic Data Type definitions
     pub adts: Adts<'s>,
+    /// Unit tests, in order of definition
+    pub tests: Vec<Test<'s>>,
+    /// Opaque foreign types, declared with `extern-type`
+    pub extern_types: BTreeMap<&'s str, SrcPos<'s>>,
+    /// The names of the globals defined with `def-const`, which are evaluated at compile time
+    pub consts: BTreeSet<&'s str>,
+    /// The names of the globals defined in a `lazy` form, which are initialized on first read
+    pub lazy_globals: BTreeSet<&'s str>,
+    /// The names of the global functions defined in an `export` form, which are generated
+    /// with external linkage
+    pub exports: BTreeSet<&'s str>,
+    /// The file of the main module of the program, if parsed from a file
+    pub main_file: Option<path::PathBuf>,
 }
 
 impl<'s> Ast<'s> {
     fn to_string_indent(&self, n: usize) -> String {
         format!(
             ";;; Section Data type definitions\n\
+             {}\n\n\n\
+             ;;; Section External type declarations\n\
              {}\n\n\n\
              ;;; Section External function declarations\n\
              {}\n\n\n\
              ;;; Section Global definitions\n\
              {}\n",
             self.adts.to_string_indent(n),
+            self.extern_types
+                .keys()
+                .map(|name| format!("(extern-type {})", name))
+                .intersperse(format!("\n{}", spaces(n)))
+                .collect::<String>(),
             self.externs
                 .values()
                 .map(|e| e.to_string())
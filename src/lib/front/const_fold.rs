@@ -0,0 +1,594 @@
+//! Constant folding and propagation on the typed AST
+//!
+//! Applications of the arithmetic and relational core functions to literals are evaluated,
+//! along with numeric and boolean casts of literals, conditionals of constant predicate, and `car` and
+//! `cdr` of pairs of constants.
+//! Variables bound to constants are replaced by their values. This is done before
+//! monomorphization, so every instance of a definition gets the folded body.
+//!
+//! Literals of polymorphic numeric type are left alone, as are those of the pointer sized
+//! integer types, as the size of a pointer depends on the target.
+//!
+//! The evaluation of core binops and casts is exposed by `eval_binop` and `eval_cast`, as a
+//! reference for differential testing of the code generator.
+
+use std::collections::BTreeMap;
+use lib::grow_stack;
+use lib::front::SrcPos;
+use lib::front::ast::*;
+
+const CORE_BINOPS: &[&str] = &["add", "sub", "mul", "div", "eq", "lt"];
+
+/// The value of a numeric literal of a fixed size type
+#[derive(Clone, Copy)]
+enum Num {
+    Int(i64, u32),
+    UInt(u64, u32),
+    Float32(f32),
+    Float64(f64),
+}
+
+impl Num {
+    fn of_lit(lit: &NumLit) -> Option<Self> {
+        match lit.typ {
+            Type::Const(t, _) => Num::parse(t, &lit.lit),
+            _ => None,
+        }
+    }
+
+    /// Parse the literal `lit` of the type named `typ`
+    fn parse(typ: &str, lit: &str) -> Option<Self> {
+        let t = Type::Const(typ, None);
+        match typ {
+            "Int8" | "Int16" | "Int32" | "Int64" => {
+                lit.parse().ok().map(|n| Num::Int(n, t.int_size(0).unwrap() as u32))
+            }
+            "UInt8" | "UInt16" | "UInt32" | "UInt64" => {
+                lit.parse().ok().map(|n| Num::UInt(n, t.uint_size(0).unwrap() as u32))
+            }
+            "Float32" => lit.parse().ok().map(Num::Float32),
+            "Float64" => lit.parse().ok().map(Num::Float64),
+            _ => None,
+        }
+    }
+
+    /// Cast to the type named `typ`, as the machine would, or return `None` if `typ` is not
+    /// a fixed size numeric type
+    fn cast(self, typ: &str) -> Option<Self> {
+        use self::Num::*;
+        let r = match (self, Num::parse(typ, "0")?) {
+            (Int(n, _), Int(_, s)) => Int(n, s),
+            (Int(n, _), UInt(_, s)) => UInt(n as u64, s),
+            (Int(n, _), Float32(_)) => Float32(n as f32),
+            (Int(n, _), Float64(_)) => Float64(n as f64),
+            (UInt(n, _), Int(_, s)) => Int(n as i64, s),
+            (UInt(n, _), UInt(_, s)) => UInt(n, s),
+            (UInt(n, _), Float32(_)) => Float32(n as f32),
+            (UInt(n, _), Float64(_)) => Float64(n as f64),
+            (Float32(x), Float32(_)) => Float32(x),
+            (Float32(x), Float64(_)) => Float64(x as f64),
+            (Float64(x), Float32(_)) => Float32(x as f32),
+            (Float64(x), Float64(_)) => Float64(x),
+            (Float32(x), to) => float_to_int(x as f64, to),
+            (Float64(x), to) => float_to_int(x, to),
+        };
+        Some(r.wrap())
+    }
+
+    /// Cast the integer to a boolean of whether it's nonzero
+    fn to_bool(self) -> Option<bool> {
+        match self {
+            Num::Int(n, _) => Some(n != 0),
+            Num::UInt(n, _) => Some(n != 0),
+            _ => None,
+        }
+    }
+
+    /// Cast the boolean `b` to 1 or 0 of the integer type named `typ`
+    fn of_bool(b: bool, typ: &str) -> Option<Self> {
+        match Num::parse(typ, if b { "1" } else { "0" })? {
+            n @ Num::Int(..) | n @ Num::UInt(..) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Whether the cast of `self` to the type of `r`, giving `r`, preserved the value, as
+    /// checked by `cast-checked`
+    fn is_lossless_cast(self, r: Self) -> bool {
+        use self::Num::*;
+        match (self, r) {
+            (Int(a, _), Int(b, _)) => a == b,
+            (Int(a, _), UInt(b, _)) | (UInt(b, _), Int(a, _)) => a >= 0 && a as u64 == b,
+            (UInt(a, _), UInt(b, _)) => a == b,
+            (Float32(x), Int(..)) | (Float32(x), UInt(..)) => float_in_range(x as f64, r),
+            (Float64(x), Int(..)) | (Float64(x), UInt(..)) => float_in_range(x, r),
+            _ => true,
+        }
+    }
+
+    /// Wrap the integer to its size, as the machine would
+    fn wrap(self) -> Self {
+        match self {
+            Num::Int(n, size) => Num::Int((n << (64 - size)) >> (64 - size), size),
+            Num::UInt(n, size) if size < 64 => Num::UInt(n & ((1 << size) - 1), size),
+            _ => self,
+        }
+    }
+
+    fn to_lit(self) -> String {
+        match self {
+            Num::Int(n, _) => n.to_string(),
+            Num::UInt(n, _) => n.to_string(),
+            Num::Float32(x) => format!("{:?}", x),
+            Num::Float64(x) => format!("{:?}", x),
+        }
+    }
+}
+
+/// The bounds of the integer type of `n`, as floats, with the upper bound exclusive
+fn int_bounds(n: Num) -> (f64, f64) {
+    match n {
+        Num::Int(_, s) => (-(2f64.powi(s as i32 - 1)), 2f64.powi(s as i32 - 1)),
+        Num::UInt(_, s) => (0.0, 2f64.powi(s as i32)),
+        _ => panic!("ICE: int_bounds of float"),
+    }
+}
+
+/// Whether the float `x` is in the range of the integer type of `n`
+fn float_in_range(x: f64, n: Num) -> bool {
+    let (lo, hi) = int_bounds(n);
+    x >= lo && x < hi
+}
+
+/// Cast the float `x` to the integer type of `to`, saturating at the bounds of the type.
+/// NaN is cast to 0
+fn float_to_int(x: f64, to: Num) -> Num {
+    let (lo, hi) = int_bounds(to);
+    let t = x.trunc();
+    match to {
+        Num::Int(_, s) if t < lo => Num::Int(i64::min_value() >> (64 - s), s),
+        Num::Int(_, s) if t >= hi => Num::Int(i64::max_value() >> (64 - s), s),
+        Num::Int(_, s) if x.is_nan() => Num::Int(0, s),
+        Num::Int(_, s) => Num::Int(t as i64, s),
+        Num::UInt(_, s) if t < lo => Num::UInt(0, s),
+        Num::UInt(_, s) if t >= hi => Num::UInt(u64::max_value() >> (64 - s), s),
+        Num::UInt(_, s) if x.is_nan() => Num::UInt(0, s),
+        Num::UInt(_, s) => Num::UInt(t as u64, s),
+        _ => panic!("ICE: float_to_int to float"),
+    }
+}
+
+/// The result of applying a core binop to constant operands
+enum Folded {
+    Num(Num),
+    Bool(bool),
+}
+
+/// Apply the core binop `op` to `a` and `b`, or return `None` if the result is undefined and
+/// must be left for runtime, like for a division by zero
+fn apply_binop(op: &str, a: Num, b: Num) -> Option<Folded> {
+    use self::Num::*;
+    let r = match (op, a, b) {
+        ("add", Int(a, s), Int(b, _)) => Folded::Num(Int(a.wrapping_add(b), s)),
+        ("sub", Int(a, s), Int(b, _)) => Folded::Num(Int(a.wrapping_sub(b), s)),
+        ("mul", Int(a, s), Int(b, _)) => Folded::Num(Int(a.wrapping_mul(b), s)),
+        ("div", Int(_, _), Int(0, _)) => return None,
+        ("div", Int(a, s), Int(-1, _)) if a == (-1 << (s - 1)) => return None,
+        ("div", Int(a, s), Int(b, _)) => Folded::Num(Int(a / b, s)),
+        ("eq", Int(a, _), Int(b, _)) => Folded::Bool(a == b),
+        ("lt", Int(a, _), Int(b, _)) => Folded::Bool(a < b),
+        ("add", UInt(a, s), UInt(b, _)) => Folded::Num(UInt(a.wrapping_add(b), s)),
+        ("sub", UInt(a, s), UInt(b, _)) => Folded::Num(UInt(a.wrapping_sub(b), s)),
+        ("mul", UInt(a, s), UInt(b, _)) => Folded::Num(UInt(a.wrapping_mul(b), s)),
+        ("div", UInt(_, _), UInt(0, _)) => return None,
+        ("div", UInt(a, s), UInt(b, _)) => Folded::Num(UInt(a / b, s)),
+        ("eq", UInt(a, _), UInt(b, _)) => Folded::Bool(a == b),
+        ("lt", UInt(a, _), UInt(b, _)) => Folded::Bool(a < b),
+        ("add", Float32(a), Float32(b)) => Folded::Num(Float32(a + b)),
+        ("sub", Float32(a), Float32(b)) => Folded::Num(Float32(a - b)),
+        ("mul", Float32(a), Float32(b)) => Folded::Num(Float32(a * b)),
+        ("div", Float32(a), Float32(b)) => Folded::Num(Float32(a / b)),
+        ("eq", Float32(a), Float32(b)) => Folded::Bool(a == b),
+        ("lt", Float32(a), Float32(b)) => Folded::Bool(a < b),
+        ("add", Float64(a), Float64(b)) => Folded::Num(Float64(a + b)),
+        ("sub", Float64(a), Float64(b)) => Folded::Num(Float64(a - b)),
+        ("mul", Float64(a), Float64(b)) => Folded::Num(Float64(a * b)),
+        ("div", Float64(a), Float64(b)) => Folded::Num(Float64(a / b)),
+        ("eq", Float64(a), Float64(b)) => Folded::Bool(a == b),
+        ("lt", Float64(a), Float64(b)) => Folded::Bool(a < b),
+        _ => return None,
+    };
+    Some(match r {
+        Folded::Num(n) => Folded::Num(n.wrap()),
+        b => b,
+    })
+}
+
+/// Evaluate the core binop `op` on the literals `a` and `b` of the type named `typ`, as
+/// constant folding does, e.g. `eval_binop("add", "Int8", "127", "1")` is `Some("-128")`
+///
+/// Returns `None` if the result is undefined and left for runtime, like for a division by
+/// zero, or if literals of the type are not folded.
+pub fn eval_binop(op: &str, typ: &str, a: &str, b: &str) -> Option<String> {
+    match apply_binop(op, Num::parse(typ, a)?, Num::parse(typ, b)?)? {
+        Folded::Num(n) => Some(n.to_lit()),
+        Folded::Bool(b) => Some(b.to_string()),
+    }
+}
+
+/// Evaluate the cast of the literal `lit` of the type named `from` to the type named `to`, as
+/// constant folding does, e.g. `eval_cast("Int16", "UInt8", "-1")` is `Some("255")`
+///
+/// Returns `None` if literals of the types are not folded.
+pub fn eval_cast(from: &str, to: &str, lit: &str) -> Option<String> {
+    match (from, to) {
+        ("Bool", _) => Num::of_bool(lit.parse().ok()?, to).map(Num::to_lit),
+        (_, "Bool") => Num::parse(from, lit)?.to_bool().map(|b| b.to_string()),
+        _ => Num::parse(from, lit)?.cast(to).map(Num::to_lit),
+    }
+}
+
+/// Whether `e` is a constant, that may be freely duplicated
+fn is_const(e: &Expr) -> bool {
+    match *e {
+        Expr::Nil(_) | Expr::Bool(_) | Expr::SymLit(_) => true,
+        Expr::NumLit(ref n) => n.typ.is_monomorphic(),
+        _ => false,
+    }
+}
+
+/// If `e` is of the form `(lambda (x) (lambda (y) (OP (cons x y))))`, where `OP` is a core
+/// binop, return `OP`
+///
+/// This is how the prelude defines the operators like `+` and `<`.
+fn binop_wrapper<'s>(e: &Expr<'s>) -> Option<&'s str> {
+    let (x, inner) = match *e {
+        Expr::TypeAscript(ref a) => return binop_wrapper(&a.expr),
+        Expr::Lambda(ref l) => (l.param_ident.s, &l.body),
+        _ => return None,
+    };
+    let (y, body) = match *inner {
+        Expr::Lambda(ref l) => (l.param_ident.s, &l.body),
+        _ => return None,
+    };
+    let app = match *body {
+        Expr::App(ref app) => app,
+        _ => return None,
+    };
+    match (&app.func, &app.arg) {
+        (&Expr::Variable(ref f), &Expr::Cons(ref c)) if CORE_BINOPS.contains(&f.ident.s) => {
+            match (&c.car, &c.cdr) {
+                (&Expr::Variable(ref a), &Expr::Variable(ref b))
+                    if a.ident.s == x && b.ident.s == y && x != y =>
+                {
+                    Some(f.ident.s)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+struct Folder<'s> {
+    /// Global operators that are wrappers of core binops
+    binop_wrappers: BTreeMap<&'s str, &'s str>,
+    /// Monomorphic global variables bound to constants
+    global_consts: BTreeMap<&'s str, Expr<'s>>,
+    /// The local variables in scope, innermost scope last, with their values if constants
+    scopes: Vec<BTreeMap<&'s str, Option<Expr<'s>>>>,
+}
+
+impl<'s> Folder<'s> {
+    fn new() -> Self {
+        Folder {
+            binop_wrappers: BTreeMap::new(),
+            global_consts: BTreeMap::new(),
+            scopes: Vec::new(),
+        }
+    }
+
+    fn push_scope<I: IntoIterator<Item = &'s str>>(&mut self, names: I) {
+        self.scopes
+            .push(names.into_iter().map(|name| (name, None)).collect())
+    }
+
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(name))
+    }
+
+    /// The constant value of the variable `name`, if any
+    fn lookup_const(&self, name: &str) -> Option<&Expr<'s>> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(val) = scope.get(name) {
+                return val.as_ref();
+            }
+        }
+        self.global_consts.get(name)
+    }
+
+    /// If `func` refers to a core binop, or a global wrapper of one, return the binop
+    fn as_core_binop(&self, func: &Expr<'s>) -> Option<&'s str> {
+        match *func {
+            Expr::Variable(ref v) if !self.is_local(v.ident.s) => {
+                if CORE_BINOPS.contains(&v.ident.s) {
+                    Some(v.ident.s)
+                } else {
+                    self.binop_wrappers.get(v.ident.s).cloned()
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Fold the application of the core binop `op` to `a` and `b`
+    fn fold_binop(
+        &self,
+        op: &str,
+        a: &Expr<'s>,
+        b: &Expr<'s>,
+        pos: &SrcPos<'s>,
+    ) -> Option<Expr<'s>> {
+        let (lit_a, lit_b) = match (a, b) {
+            (&Expr::NumLit(ref a), &Expr::NumLit(ref b)) if a.typ == b.typ => (a, b),
+            _ => return None,
+        };
+        let (x, y) = match (Num::of_lit(lit_a), Num::of_lit(lit_b)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return None,
+        };
+        apply_binop(op, x, y).map(|r| match r {
+            Folded::Num(n) => Expr::NumLit(NumLit {
+                lit: n.to_lit().into(),
+                typ: lit_a.typ.clone(),
+                pos: pos.clone(),
+            }),
+            Folded::Bool(val) => Expr::Bool(Bool {
+                val,
+                pos: pos.clone(),
+            }),
+        })
+    }
+
+    /// Fold the application `app`, if it's a core binop applied to literals, either directly
+    /// as in `(add (cons 1 2))`, or via a wrapper as in `(+ 1 2)`
+    fn fold_app(&self, app: &App<'s>) -> Option<Expr<'s>> {
+        if let Some(op) = self.as_core_binop(&app.func) {
+            if let Expr::Cons(ref c) = app.arg {
+                return self.fold_binop(op, &c.car, &c.cdr, &app.pos);
+            }
+        }
+        if let Expr::App(ref inner) = app.func {
+            if let Some(op) = self.as_core_binop(&inner.func) {
+                return self.fold_binop(op, &inner.arg, &app.arg, &app.pos);
+            }
+        }
+        None
+    }
+
+    fn fold_bindings(&mut self, bindings: &mut TopologicallyOrderedDependencyGroups<'s>) {
+        let names = bindings.ids().collect::<Vec<_>>();
+        self.push_scope(names);
+        // Dependencies come after their dependents
+        for group in bindings.groups_mut().rev() {
+            for b in group.bindings_mut() {
+                self.fold_binding(b);
+            }
+            for b in group.bindings() {
+                if b.sig.is_monomorphic() && is_const(&b.val) {
+                    self.scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert(b.ident.s, Some(b.val.clone()));
+                }
+            }
+        }
+    }
+
+    fn fold_binding(&mut self, b: &mut Binding<'s>) {
+        self.fold_expr(&mut b.val);
+        for inst in b.mono_insts.values_mut() {
+            self.fold_expr(inst)
+        }
+    }
+
+    fn fold_expr(&mut self, e: &mut Expr<'s>) {
+        grow_stack(|| {
+            let folded = match *e {
+                Expr::Nil(_)
+                | Expr::NumLit(_)
+                | Expr::StrLit(_)
+                | Expr::BytesLit(_)
+                | Expr::SymLit(_)
+                | Expr::Bool(_) => None,
+                Expr::Variable(ref v) => self.lookup_const(v.ident.s).map(|c| {
+                    let mut c = c.clone();
+                    match c {
+                        Expr::Nil(ref mut n) => n.pos = v.ident.pos.clone(),
+                        Expr::NumLit(ref mut n) => n.pos = v.ident.pos.clone(),
+                        Expr::Bool(ref mut b) => b.pos = v.ident.pos.clone(),
+                        Expr::SymLit(ref mut s) => s.pos = v.ident.pos.clone(),
+                        _ => (),
+                    }
+                    c
+                }),
+                Expr::App(ref mut app) => {
+                    self.fold_expr(&mut app.func);
+                    self.fold_expr(&mut app.arg);
+                    self.fold_app(app)
+                }
+                Expr::If(ref mut cond) => {
+                    self.fold_expr(&mut cond.predicate);
+                    self.fold_expr(&mut cond.consequent);
+                    self.fold_expr(&mut cond.alternative);
+                    match cond.predicate {
+                        Expr::Bool(Bool { val: true, .. }) => Some(cond.consequent.clone()),
+                        Expr::Bool(Bool { val: false, .. }) => Some(cond.alternative.clone()),
+                        _ => None,
+                    }
+                }
+                Expr::Lambda(ref mut l) => {
+                    self.push_scope(vec![l.param_ident.s]);
+                    self.fold_expr(&mut l.body);
+                    self.pop_scope();
+                    None
+                }
+                Expr::Let(ref mut l) => {
+                    self.fold_bindings(&mut l.bindings);
+                    self.fold_expr(&mut l.body);
+                    self.pop_scope();
+                    None
+                }
+                Expr::TypeAscript(ref mut a) => {
+                    self.fold_expr(&mut a.expr);
+                    None
+                }
+                Expr::Cons(ref mut c) => {
+                    self.fold_expr(&mut c.car);
+                    self.fold_expr(&mut c.cdr);
+                    None
+                }
+                Expr::Car(ref mut c) => {
+                    self.fold_expr(&mut c.expr);
+                    match c.expr {
+                        Expr::Cons(ref pair) if is_const(&pair.car) && is_const(&pair.cdr) => {
+                            Some(pair.car.clone())
+                        }
+                        _ => None,
+                    }
+                }
+                Expr::Cdr(ref mut c) => {
+                    self.fold_expr(&mut c.expr);
+                    match c.expr {
+                        Expr::Cons(ref pair) if is_const(&pair.car) && is_const(&pair.cdr) => {
+                            Some(pair.cdr.clone())
+                        }
+                        _ => None,
+                    }
+                }
+                Expr::Cast(ref mut c) => {
+                    self.fold_expr(&mut c.expr);
+                    match (&c.expr, &c.typ) {
+                        (&Expr::Bool(ref b), &Type::Const(to, _)) => {
+                            Num::of_bool(b.val, to).map(|n| {
+                                Expr::NumLit(NumLit {
+                                    lit: n.to_lit().into(),
+                                    typ: c.typ.clone(),
+                                    pos: c.pos.clone(),
+                                })
+                            })
+                        }
+                        (&Expr::NumLit(ref lit), &Type::Const("Bool", _)) => {
+                            Num::of_lit(lit).and_then(Num::to_bool).map(|val| {
+                                Expr::Bool(Bool {
+                                    val,
+                                    pos: c.pos.clone(),
+                                })
+                            })
+                        }
+                        (&Expr::NumLit(ref lit), &Type::Const(to, _)) => Num::of_lit(lit)
+                            .and_then(|n| n.cast(to).map(|r| (n, r)))
+                            // A failing `cast-checked` is left to panic at runtime
+                            .filter(|&(n, r)| !c.checked || n.is_lossless_cast(r))
+                            .map(|(_, r)| {
+                                Expr::NumLit(NumLit {
+                                    lit: r.to_lit().into(),
+                                    typ: c.typ.clone(),
+                                    pos: c.pos.clone(),
+                                })
+                            }),
+                        _ => None,
+                    }
+                }
+                Expr::Transmute(ref mut t) => {
+                    self.fold_expr(&mut t.expr);
+                    None
+                }
+                Expr::New(ref mut n) => {
+                    for m in &mut n.members {
+                        self.fold_expr(m)
+                    }
+                    None
+                }
+                Expr::Match(ref mut m) => {
+                    self.fold_expr(&mut m.expr);
+                    for case in &mut m.cases {
+                        self.push_scope(case.patt.variable_names());
+                        self.fold_expr(&mut case.body);
+                        self.pop_scope();
+                    }
+                    None
+                }
+                Expr::Assert(ref mut a) => {
+                    self.fold_expr(&mut a.cond);
+                    None
+                }
+                Expr::WithArena(ref mut w) => {
+                    self.fold_expr(&mut w.body);
+                    None
+                }
+                Expr::Loop(ref mut l) => {
+                    for init in &mut l.inits {
+                        self.fold_expr(init)
+                    }
+                    self.push_scope(l.vars.iter().map(|v| v.ident.s).collect::<Vec<_>>());
+                    self.fold_expr(&mut l.body);
+                    self.pop_scope();
+                    None
+                }
+                Expr::Recur(ref mut r) => {
+                    for arg in &mut r.args {
+                        self.fold_expr(arg)
+                    }
+                    None
+                }
+                Expr::Begin(ref mut b) => {
+                    for e in &mut b.exprs {
+                        self.fold_expr(e)
+                    }
+                    None
+                }
+                Expr::PtrOp(ref mut p) => {
+                    for arg in &mut p.args {
+                        self.fold_expr(arg)
+                    }
+                    None
+                }
+                Expr::ThreadOp(ref mut t) => {
+                    for arg in &mut t.args {
+                        self.fold_expr(arg)
+                    }
+                    None
+                }
+            };
+            if let Some(folded) = folded {
+                *e = folded
+            }
+        })
+    }
+}
+
+/// Fold and propagate the constants in the definitions of the global bindings
+pub fn fold_constants<'s>(globals: &mut TopologicallyOrderedDependencyGroups<'s>) {
+    let mut folder = Folder::new();
+    for b in globals.bindings() {
+        if let Some(op) = binop_wrapper(&b.val) {
+            folder.binop_wrappers.insert(b.ident.s, op);
+        }
+    }
+    // Dependencies come after their dependents
+    for group in globals.groups_mut().rev() {
+        for b in group.bindings_mut() {
+            folder.fold_binding(b);
+        }
+        for b in group.bindings() {
+            if b.sig.is_monomorphic() && is_const(&b.val) {
+                folder.global_consts.insert(b.ident.s, b.val.clone());
+            }
+        }
+    }
+}
@@ -7,11 +7,12 @@
 //! topologically ordered, and we can infer the types as well as possible.
 
 use std::iter::once;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use super::ast::*;
+use super::lint::{self, GLOBAL_INIT_CYCLE};
 
 fn sibling_refs_match<'s>(m: &Match<'s>, siblings: &mut BTreeSet<&'s str>) -> BTreeSet<&'s str> {
-    let mut refs = BTreeSet::new();
+    let mut refs = sibling_refs(&m.expr, siblings);
     for case in &m.cases {
         let shadoweds = case.patt
             .variable_names()
@@ -73,52 +74,82 @@ fn sibling_refs<'src>(e: &Expr<'src>, siblings: &mut BTreeSet<&'src str>) -> BTr
         Cdr(ref c) => sibling_refs(&c.expr, siblings),
         TypeAscript(ref a) => sibling_refs(&a.expr, siblings),
         Cast(ref c) => sibling_refs(&c.expr, siblings),
+        Transmute(ref t) => sibling_refs(&t.expr, siblings),
         New(ref n) => n.members
             .iter()
             .flat_map(|e2| sibling_refs(e2, siblings))
             .collect(),
         Match(ref m) => sibling_refs_match(m, siblings),
-        Nil(_) | NumLit(_) | StrLit(_) | Bool(_) => BTreeSet::new(),
+        Assert(ref a) => sibling_refs(&a.cond, siblings),
+        WithArena(ref w) => sibling_refs(&w.body, siblings),
+        Loop(ref l) => {
+            let mut refs = l.inits
+                .iter()
+                .flat_map(|e2| sibling_refs(e2, siblings))
+                .collect::<BTreeSet<_>>();
+            let shadoweds = l.vars
+                .iter()
+                .filter(|v| siblings.remove(v.ident.s))
+                .map(|v| v.ident.s)
+                .collect::<Vec<_>>();
+            refs.extend(sibling_refs(&l.body, siblings));
+            siblings.extend(shadoweds);
+            refs
+        }
+        Recur(ref r) => r.args
+            .iter()
+            .flat_map(|e2| sibling_refs(e2, siblings))
+            .collect(),
+        Begin(ref b) => b.exprs
+            .iter()
+            .flat_map(|e2| sibling_refs(e2, siblings))
+            .collect(),
+        PtrOp(ref p) => p.args
+            .iter()
+            .flat_map(|e2| sibling_refs(e2, siblings))
+            .collect(),
+        ThreadOp(ref t) => t.args
+            .iter()
+            .flat_map(|e2| sibling_refs(e2, siblings))
+            .collect(),
+        Nil(_) | NumLit(_) | StrLit(_) | BytesLit(_) | SymLit(_) | Bool(_) => BTreeSet::new(),
     }
 }
 
-fn circular_def_members_<'src>(
-    start: &'src str,
-    current: &'src str,
+/// Returns the siblings reachable from `s` through references, excluding `s` itself unless
+/// it refers back to itself
+fn reachable<'src>(
+    s: &'src str,
     siblings_out_refs: &BTreeMap<&str, BTreeSet<&'src str>>,
-    visited: &mut BTreeSet<&'src str>,
 ) -> BTreeSet<&'src str> {
-    if current == start && visited.contains(current) {
-        once(current).collect()
-    } else if visited.contains(current) {
-        BTreeSet::new()
-    } else {
-        visited.insert(current);
-        let mut members = BTreeSet::new();
-        for next in &siblings_out_refs[current] {
-            members.extend(circular_def_members_(
-                start,
-                next,
-                siblings_out_refs,
-                visited,
-            ))
+    let mut reached = BTreeSet::new();
+    let mut to_visit = siblings_out_refs[s].iter().cloned().collect::<Vec<_>>();
+    while let Some(current) = to_visit.pop() {
+        if reached.insert(current) {
+            to_visit.extend(siblings_out_refs[current].iter().cloned())
         }
-        if !members.is_empty() {
-            members.insert(current);
-        }
-        members
     }
+    reached
 }
 
 /// Returns all members of the circular definition chain of `s`
 ///
-/// If `s` is not a circular definition, return the empty set
+/// The members are all siblings that both are reachable from `s`, and can reach `s`, so that
+/// a chain with several cycles through `s` is returned whole, no matter which member it's
+/// looked up from. If `s` is not a circular definition, return the empty set
 fn circular_def_members<'src>(
     s: &'src str,
     siblings_out_refs: &BTreeMap<&str, BTreeSet<&'src str>>,
 ) -> BTreeSet<&'src str> {
-    let mut visited = BTreeSet::new();
-    circular_def_members_(s, s, siblings_out_refs, &mut visited)
+    let from_s = reachable(s, siblings_out_refs);
+    if from_s.contains(s) {
+        from_s
+            .into_iter()
+            .filter(|&t| reachable(t, siblings_out_refs).contains(s))
+            .collect()
+    } else {
+        BTreeSet::new()
+    }
 }
 
 /// Group sets of circularly referencing bindings together, to make
@@ -235,3 +266,93 @@ pub fn flat_bindings_to_topologically_ordered<'src>(
 
     TopologicallyOrderedDependencyGroups(topo_ordered_groups)
 }
+
+/// Returns the bindings of `bindings` that are not referred to in `body`, nor in the
+/// definition of any other binding in `bindings`
+pub fn unused_bindings<'g, 'src>(
+    bindings: &'g TopologicallyOrderedDependencyGroups<'src>,
+    body: &Expr<'src>,
+) -> Vec<&'g Binding<'src>> {
+    let mut siblings: BTreeSet<_> = bindings.ids().collect();
+    let mut used = sibling_refs(body, &mut siblings);
+    for b in bindings.bindings() {
+        used.extend(
+            sibling_refs(&b.val, &mut siblings)
+                .into_iter()
+                .filter(|&s| s != b.ident.s),
+        );
+    }
+    bindings
+        .bindings()
+        .filter(|b| !used.contains(b.ident.s))
+        .collect()
+}
+
+/// The shortest cycle of references from the binding `start` back to itself, as the names of
+/// the bindings along the way
+fn ref_cycle<'src>(
+    start: &'src str,
+    refs: &BTreeMap<&'src str, BTreeSet<&'src str>>,
+) -> Vec<&'src str> {
+    // Breadth first search, remembering the predecessor of each visited binding
+    let mut preds = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(start);
+    while let Some(s) = queue.pop_front() {
+        for &r in &refs[s] {
+            if r == start {
+                let mut cycle = vec![start];
+                let mut cur = s;
+                while cur != start {
+                    cycle.push(cur);
+                    cur = preds[cur];
+                }
+                cycle.push(start);
+                cycle.reverse();
+                return cycle;
+            } else if !preds.contains_key(r) {
+                preds.insert(r, s);
+                queue.push_back(r)
+            }
+        }
+    }
+    vec![start]
+}
+
+/// Check that no global variable depends on itself
+///
+/// Global variables are initialized at runtime, in the topological order of the bindings, so
+/// a variable in a cycle of definitions may read itself or another variable of the cycle
+/// before it's initialized, and silently get an undefined value. Such variables are reported
+/// with the lint `global-init-cycle`. Whether the references are evaluated during the
+/// initialization is not analyzed, e.g. a reference in a function that is only called later
+/// is reported as well.
+pub fn check_global_init_cycles(globals: &TopologicallyOrderedDependencyGroups) {
+    for group in globals.groups() {
+        let bindings = match *group {
+            Group::Circular(ref bindings) => bindings,
+            Group::Uncircular(..) => continue,
+        };
+        let mut siblings = bindings.keys().cloned().collect::<BTreeSet<_>>();
+        let refs = bindings
+            .iter()
+            .map(|(&name, b)| (name, sibling_refs(&b.val, &mut siblings)))
+            .collect::<BTreeMap<_, _>>();
+        for (&name, b) in bindings {
+            if let Expr::Lambda(_) = b.val {
+                continue;
+            }
+            let cycle = ref_cycle(name, &refs);
+            lint::emit(
+                &GLOBAL_INIT_CYCLE,
+                &b.pos,
+                format!(
+                    "The global variable `{}` depends on itself, through `{}`, and may be \
+                     read before it's initialized",
+                    name,
+                    cycle.join(" -> ")
+                ),
+            )
+        }
+    }
+}
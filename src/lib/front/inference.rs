@@ -14,10 +14,14 @@
 //       as new info might have been gathered in second branch
 
 use self::InferenceErr::*;
-use lib::set_of;
+use lib::{grow_stack, set_of};
 use lib::front::*;
+use lib::front::linearity::check_real_world_linearity;
+use lib::front::match_check::check_matches;
+use lib::front::dependency_graph::check_global_init_cycles;
 use lib::front::ast::*;
 use lib::front::monomorphization::*;
+use lib::front::const_fold::fold_constants;
 use lib::front::substitution::*;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Display};
@@ -50,7 +54,8 @@ impl<'s> Display for InferenceErr<'s> {
             TypeMis(ref expected, ref found) => write!(
                 f,
                 "Type mismatch. Expected `{}`, found `{}`",
-                expected, found
+                expected.pretty(),
+                found.pretty()
             ),
             TypeMisSub {
                 ref expected,
@@ -61,13 +66,17 @@ impl<'s> Display for InferenceErr<'s> {
                 f,
                 "Type mismatch: Expected `{}`, found `{}`\n\
                  Cannot infer or coerce `{}` to `{}`",
-                expected, found, sub_found, sub_expected
+                expected.pretty(),
+                found.pretty(),
+                sub_found.pretty(),
+                sub_expected.pretty()
             ),
             ArmsDiffer(ref c, ref a) => write!(
                 f,
                 "Consequent and alternative have different types. Expected `{}` from \
                  alternative, found `{}`",
-                c, a
+                c.pretty(),
+                a.pretty()
             ),
             ConstrWrongNumArgs { expected, found } => write!(
                 f,
@@ -78,6 +87,28 @@ impl<'s> Display for InferenceErr<'s> {
     }
 }
 
+/// An error of unification
+enum UnifyErr<'s> {
+    /// The conflicting nodes of the types. (expected, found)
+    Mismatch(Type<'s>, Type<'s>),
+    /// A type is not defined
+    UndefType(Diagnostic),
+}
+
+impl<'s> UnifyErr<'s> {
+    /// The diagnostic of this error at `pos`, where a mismatch of the conflicting nodes is
+    /// described by `mismatch`
+    fn at<E: Display, F>(self, pos: &SrcPos<'s>, mismatch: F) -> Diagnostic
+    where
+        F: FnOnce(Type<'s>, Type<'s>) -> E,
+    {
+        match self {
+            UnifyErr::Mismatch(e, f) => pos.diagnostic(mismatch(e, f)),
+            UnifyErr::UndefType(d) => d,
+        }
+    }
+}
+
 fn type_mis<'s>(
     type_var_map: &mut BTreeMap<TVar<'s>, Type<'s>>,
     expected: &Type<'s>,
@@ -186,11 +217,44 @@ fn wrap_vars_types_in_apps_<'s>(
         Expr::Cast(ref mut c) => {
             wrap_vars_types_in_apps_(&mut c.expr, vars, app_args);
         }
+        Expr::Transmute(ref mut t) => {
+            wrap_vars_types_in_apps_(&mut t.expr, vars, app_args);
+        }
         Expr::New(ref mut n) => for member in &mut n.members {
             wrap_vars_types_in_apps_(member, vars, app_args)
         },
         Expr::Match(ref mut m) => wrap_vars_types_in_apps_match(m, vars, app_args),
-        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
+        Expr::Assert(ref mut a) => wrap_vars_types_in_apps_(&mut a.cond, vars, app_args),
+        Expr::WithArena(ref mut w) => wrap_vars_types_in_apps_(&mut w.body, vars, app_args),
+        Expr::Loop(ref mut l) => {
+            for init in &mut l.inits {
+                wrap_vars_types_in_apps_(init, vars, app_args);
+            }
+            let shadoweds = l.vars
+                .iter()
+                .filter_map(|v| vars.remove(v.ident.s).map(|p| (v.ident.s, p)))
+                .collect::<Vec<_>>();
+            wrap_vars_types_in_apps_(&mut l.body, vars, app_args);
+            vars.extend(shadoweds)
+        }
+        Expr::Recur(ref mut r) => for arg in &mut r.args {
+            wrap_vars_types_in_apps_(arg, vars, app_args)
+        },
+        Expr::Begin(ref mut b) => for e in &mut b.exprs {
+            wrap_vars_types_in_apps_(e, vars, app_args)
+        },
+        Expr::PtrOp(ref mut p) => for arg in &mut p.args {
+            wrap_vars_types_in_apps_(arg, vars, app_args)
+        },
+        Expr::ThreadOp(ref mut t) => for arg in &mut t.args {
+            wrap_vars_types_in_apps_(arg, vars, app_args)
+        },
+        Expr::Nil(_)
+        | Expr::NumLit(_)
+        | Expr::StrLit(_)
+        | Expr::BytesLit(_)
+        | Expr::SymLit(_)
+        | Expr::Bool(_) => (),
     }
 }
 
@@ -219,6 +283,8 @@ enum TypeDef {
     Core,
     /// An algebraic data type with variants and members
     Adt,
+    /// An opaque foreign type, declared with `extern-type`. Only usable behind a pointer
+    Extern,
     // TODO: Type alias
 }
 
@@ -234,6 +300,8 @@ struct Inferrer<'a, 's: 'a> {
     type_var_gen: &'a mut TypeVarGen,
     /// Defined algebraic data types
     adts: &'a Adts<'s>,
+    /// The types of the variables of each enclosing `loop`, innermost last
+    loops: Vec<Vec<Type<'s>>>,
     /// A map of core types and used defined types
     ///
     /// Numeric types, cons, (TODO) type aliases, data type definitions
@@ -244,6 +312,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     fn new(
         externs: &'a BTreeMap<&'s str, ExternDecl<'s>>,
         adts: &'a Adts<'s>,
+        extern_types: &BTreeMap<&'s str, SrcPos<'s>>,
         type_var_gen: &'a mut TypeVarGen,
     ) -> Self {
         use self::TypeDef::*;
@@ -263,8 +332,10 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 "Float64" => Core,
                 "Nil" => Core,
                 "RealWorld" => Core,
+                "Symbol" => Core,
         };
         type_defs.extend(adts.defs.iter().map(|(&k, _)| (k, TypeDef::Adt)));
+        type_defs.extend(extern_types.iter().map(|(&k, _)| (k, TypeDef::Extern)));
         Inferrer {
             var_env: BTreeMap::new(),
             externs,
@@ -272,6 +343,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             type_var_map: BTreeMap::new(),
             type_var_gen,
             adts,
+            loops: Vec::new(),
             type_defs,
         }
     }
@@ -489,7 +561,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         &mut self,
         a: &'t Type<'s>,
         b: &'t Type<'s>,
-    ) -> Result<Type<'s>, (Type<'s>, Type<'s>)> {
+    ) -> Result<Type<'s>, UnifyErr<'s>> {
         use self::Type::*;
         match (a, b) {
             (&Var(ref tv), x) | (x, &Var(ref tv)) if self.type_var_map.contains_key(tv) => {
@@ -511,11 +583,13 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 }
                 self.unify(&t, x)
             }
-            (&Var(ref t), &Var(ref u)) => self.unify_vars(t, u).map(Type::Var),
+            (&Var(ref t), &Var(ref u)) => self.unify_vars(t, u)
+                .map(Type::Var)
+                .map_err(|(e, f)| UnifyErr::Mismatch(e, f)),
             (&Var(ref tv), _) if occurs_in(tv, b, &self.type_var_map) => {
                 panic!("ICE: unify: `{}` occurs in `{}`", tv, b);
             }
-            (&Var(TVar::Explicit(_)), _) => Err((a.clone(), b.clone())),
+            (&Var(TVar::Explicit(_)), _) => Err(UnifyErr::Mismatch(a.clone(), b.clone())),
             (&Var(ref tv), _) => {
                 let fulfills_constrs = {
                     let tv_constrs = self.get_type_var_constraints(tv);
@@ -525,7 +599,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                     self.type_var_map.insert(*tv, b.clone());
                     Ok(b.clone())
                 } else {
-                    Err((a.clone(), b.clone()))
+                    Err(UnifyErr::Mismatch(a.clone(), b.clone()))
                 }
             }
             (_, &Var(_)) => self.unify(b, a),
@@ -544,31 +618,76 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             (&Const(t, ref pos), _) | (_, &Const(t, ref pos))
                 if !self.type_defs.contains_key(t) =>
             {
-                pos.as_ref()
-                    .expect("ICE: undefined type has no position")
-                    .error_exit(format!("Type `{}` not found in this scope", t))
+                let pos = pos.as_ref().expect("ICE: undefined type has no position");
+                Err(UnifyErr::UndefType(
+                    pos.diagnostic(format!("Type `{}` not found in this scope", t)),
+                ))
             }
             (_, _) if a == b => Ok(a.clone()),
-            _ => Err((a.clone(), b.clone())),
+            _ => Err(UnifyErr::Mismatch(a.clone(), b.clone())),
         }
     }
 
     /// Check that the expected type of a nil expression is unifiable with the nil type
-    fn infer_nil(&mut self, nil: &mut Nil<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_nil(
+        &mut self,
+        nil: &mut Nil<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        self.unify(expected_type, &TYPE_NIL)
+            .map_err(|err| err.at(&nil.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
+    }
+
+    /// Check that the condition of an assertion is a boolean, and that the expected type of the
+    /// assertion is unifiable with nil
+    fn infer_assert(
+        &mut self,
+        a: &mut Assert<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        self.infer_expr(&mut a.cond, &TYPE_BOOL)?;
         self.unify(expected_type, &TYPE_NIL)
-            .unwrap_or_else(|(e, f)| nil.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .map_err(|err| err.at(&a.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
     }
 
     /// Check that the expected type of a string literal is unifiable with the string type
-    fn infer_str_lit(&mut self, lit: &mut StrLit<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_str_lit(
+        &mut self,
+        lit: &mut StrLit<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
         self.unify(expected_type, &TYPE_STRING)
-            .unwrap_or_else(|(e, f)| lit.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .map_err(|err| err.at(&lit.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
+    }
+
+    /// Check that the expected type of a byte array literal is unifiable with the bytes type
+    fn infer_bytes_lit(
+        &mut self,
+        lit: &mut BytesLit<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        self.unify(expected_type, &TYPE_BYTES)
+            .map_err(|err| err.at(&lit.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
+    }
+
+    /// Check that the expected type of a symbol literal is unifiable with the symbol type
+    fn infer_sym_lit(
+        &mut self,
+        lit: &mut SymLit<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        self.unify(expected_type, &TYPE_SYMBOL)
+            .map_err(|err| err.at(&lit.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
     }
 
     /// Check that the expected type of a boolean literal is unifiable with the boolean type
-    fn infer_bool(&mut self, b: &mut Bool<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_bool(
+        &mut self,
+        b: &mut Bool<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
         self.unify(expected_type, &TYPE_BOOL)
-            .unwrap_or_else(|(e, f)| b.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .map_err(|err| err.at(&b.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))
     }
 
     /// Infer the type of a numeric literal
@@ -578,25 +697,27 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         &mut self,
         lit: &'n mut NumLit<'s>,
         expected_type: &Type<'s>,
-    ) -> &'n Type<'s> {
+    ) -> Result<&'n Type<'s>, Diagnostic> {
         if lit.lit.contains('.') {
             lit.typ = self.unify(expected_type, &TYPE_FLOAT64)
-                .unwrap_or_else(|(e, f)| {
-                    lit.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f))
-                });
-            &lit.typ
+                .map_err(|err| {
+                    err.at(&lit.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f))
+                })?;
+            Ok(&lit.typ)
         } else {
             let num_constraint = set_of("Num");
             let tv_num = self.type_var_gen.gen_tv();
             self.type_var_env.insert(tv_num, num_constraint);
             lit.typ = self.unify(expected_type, &Type::Var(tv_num))
-                .unwrap_or_else(|_| {
-                    lit.pos.error_exit(format!(
-                        "Type mismatch. Expected `{}`, found numeric literal",
-                        expected_type
-                    ))
-                });
-            &lit.typ
+                .map_err(|err| {
+                    err.at(&lit.pos, |_, _| {
+                        format!(
+                            "Type mismatch. Expected `{}`, found numeric literal",
+                            expected_type.pretty()
+                        )
+                    })
+                })?;
+            Ok(&lit.typ)
         }
     }
 
@@ -605,7 +726,11 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     /// If the variable does not refer to an extern, instantiate the variable
     /// and unify with expected type. If it does refer to an extern,
     /// unify type of extern with expected type.
-    fn infer_variable(&mut self, var: &mut Variable<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_variable(
+        &mut self,
+        var: &mut Variable<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
         if let Some(typ) = self.get_var(var.ident.s).cloned() {
             // Either not an extern, or shadowing an extern. I.e. a lambda parameter or let binding
 
@@ -614,30 +739,28 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             // they may be used in the body of the definition but not show up
             // in the resulting type of the application.
             var.typ = self.instantiate(&typ);
-            let unif = self.unify(expected_type, &var.typ).unwrap_or_else(|_| {
-                var.ident.pos.error_exit(format!(
-                    "Variable of type `{}` cannot be instantiated to expected type `{}`",
-                    typ, expected_type
-                ))
-            });
-            unif
+            self.unify(expected_type, &var.typ).map_err(|err| {
+                err.at(&var.ident.pos, |_, _| {
+                    format!(
+                        "Variable of type `{}` cannot be instantiated to expected type `{}`",
+                        typ.pretty(),
+                        expected_type.pretty()
+                    )
+                })
+            })
         } else if let Some(ext) = self.externs.get(var.ident.s) {
             // An extern. Check that type of extern is unifiable with expected type
             var.typ = self.unify(expected_type, &ext.typ)
-                .unwrap_or_else(|(e, f)| {
-                    var.ident.pos.error_exit(type_mis_sub(
-                        &mut self.type_var_map,
-                        expected_type,
-                        &ext.typ,
-                        &e,
-                        &f,
-                    ))
-                });
-            var.typ.clone()
+                .map_err(|err| {
+                    err.at(&var.ident.pos, |e, f| {
+                        type_mis_sub(&mut self.type_var_map, expected_type, &ext.typ, &e, &f)
+                    })
+                })?;
+            Ok(var.typ.clone())
         } else {
-            var.ident
+            Err(var.ident
                 .pos
-                .error_exit(format!("`{}` not found in this scope", var.ident.s))
+                .diagnostic(format!("`{}` not found in this scope", var.ident.s)))
         }
     }
 
@@ -647,51 +770,52 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     //       How to write type ascriptions for such a function?
     //       Alt. force use of PhantomData<T> like inputs?
     /// Infer types in a function application
-    fn infer_app<'c>(&mut self, app: &'c mut App<'s>, expected_type: &Type<'s>) -> &'c Type<'s> {
+    fn infer_app<'c>(
+        &mut self,
+        app: &'c mut App<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'c Type<'s>, Diagnostic> {
         let expected_func_type = Type::new_func(
             self.type_var_gen.gen_type_var(),
             self.type_var_gen.gen_type_var(),
         );
-        let func_type = self.infer_expr(&mut app.func, &expected_func_type);
+        let func_type = self.infer_expr(&mut app.func, &expected_func_type)?;
         let expected_arg_type = self.type_var_gen.gen_type_var();
-        let arg_type = self.infer_expr(&mut app.arg, &expected_arg_type);
+        let arg_type = self.infer_expr(&mut app.arg, &expected_arg_type)?;
         let (func_param_type, func_ret_type) = func_type
             .get_func()
             .expect("ICE: func_type was not func type in infer_app");
         self.unify(func_param_type, &arg_type)
-            .unwrap_or_else(|(e, f)| {
-                app.arg.pos().error_exit(type_mis_sub(
-                    &mut self.type_var_map,
-                    func_param_type,
-                    &arg_type,
-                    &e,
-                    &f,
-                ))
-            });
+            .map_err(|err| {
+                err.at(app.arg.pos(), |e, f| {
+                    type_mis_sub(&mut self.type_var_map, func_param_type, &arg_type, &e, &f)
+                })
+            })?;
         let ret_unification = self.unify(expected_type, func_ret_type)
-            .unwrap_or_else(|(e, f)| {
-                app.pos.error_exit(type_mis_sub(
-                    &mut self.type_var_map,
-                    expected_type,
-                    func_ret_type,
-                    &e,
-                    &f,
-                ))
-            });
+            .map_err(|err| {
+                err.at(&app.pos, |e, f| {
+                    type_mis_sub(&mut self.type_var_map, expected_type, func_ret_type, &e, &f)
+                })
+            })?;
         app.typ = ret_unification;
-        &app.typ
+        Ok(&app.typ)
     }
 
-    fn infer_if<'i>(&mut self, cond: &'i mut If<'s>, expected_typ: &Type<'s>) -> &'i Type<'s> {
-        self.infer_expr(&mut cond.predicate, &TYPE_BOOL);
-        let consequent_type = self.infer_expr(&mut cond.consequent, expected_typ);
-        let alternative_type = self.infer_expr(&mut cond.alternative, expected_typ);
+    fn infer_if<'i>(
+        &mut self,
+        cond: &'i mut If<'s>,
+        expected_typ: &Type<'s>,
+    ) -> Result<&'i Type<'s>, Diagnostic> {
+        self.infer_expr(&mut cond.predicate, &TYPE_BOOL)?;
+        let consequent_type = self.infer_expr(&mut cond.consequent, expected_typ)?;
+        let alternative_type = self.infer_expr(&mut cond.alternative, expected_typ)?;
         cond.typ = self.unify(&consequent_type, &alternative_type)
-            .unwrap_or_else(|_| {
-                cond.pos
-                    .error_exit(ArmsDiffer(consequent_type, alternative_type))
-            });
-        &cond.typ
+            .map_err(|err| {
+                err.at(&cond.pos, |_, _| {
+                    ArmsDiffer(consequent_type.clone(), alternative_type.clone())
+                })
+            })?;
+        Ok(&cond.typ)
     }
 
     /// Infer types for a lambda
@@ -699,7 +823,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         &mut self,
         lam: &'l mut Lambda<'s>,
         expected_type: &Type<'s>,
-    ) -> &'l Type<'s> {
+    ) -> Result<&'l Type<'s>, Diagnostic> {
         // Infer type of param by adding it to the environment and applying constraints based on
         // how it is used during inference of lambda body.
 
@@ -708,10 +832,11 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             self.type_var_gen.gen_type_var(),
         );
         let (expected_param_type, expected_body_type) = self.unify(expected_type, &lam.typ)
-            .unwrap_or_else(|_| {
-                lam.pos
-                    .error_exit(type_mis(&mut self.type_var_map, expected_type, &lam.typ))
-            })
+            .map_err(|err| {
+                err.at(&lam.pos, |_, _| {
+                    type_mis(&mut self.type_var_map, expected_type, &lam.typ)
+                })
+            })?
             .get_func()
             .map(|(p, b)| (p.clone(), b.clone()))
             .expect(
@@ -720,18 +845,173 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         let param_tvars = self.free_type_vars(&expected_param_type);
         self.extend_type_var_env_no_constrs(&param_tvars);
         self.push_var(lam.param_ident.s, expected_param_type);
-        self.infer_expr(&mut lam.body, &expected_body_type);
+        self.infer_expr(&mut lam.body, &expected_body_type)?;
         self.pop_var(lam.param_ident.s);
         self.unextend_type_var_env(param_tvars);
-        &lam.typ
+        Ok(&lam.typ)
+    }
+
+    /// Infer the types of the variables of a loop from their initial values, and the type of
+    /// the loop from its body
+    fn infer_loop(
+        &mut self,
+        l: &mut Loop<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        for (var, init) in l.vars.iter_mut().zip(&mut l.inits) {
+            var.typ = self.infer_expr(init, &var.typ)?;
+        }
+        let types = l.vars.iter().map(|v| v.typ.clone()).collect::<Vec<_>>();
+        let tvars = types
+            .iter()
+            .flat_map(|t| self.free_type_vars(t))
+            .collect::<BTreeSet<_>>();
+        self.extend_type_var_env_no_constrs(&tvars);
+        for var in &l.vars {
+            self.push_var(var.ident.s, var.typ.clone());
+        }
+        self.loops.push(types);
+        let typ = self.infer_expr(&mut l.body, expected_type)?;
+        self.loops.pop();
+        for var in &l.vars {
+            self.pop_var(var.ident.s);
+        }
+        self.unextend_type_var_env(tvars);
+        Ok(typ)
+    }
+
+    /// Check that the arguments of a `recur` match the variables of the innermost loop
+    ///
+    /// As a `recur` never returns, its type is whatever is expected
+    fn infer_recur(
+        &mut self,
+        r: &mut Recur<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        let types = self.loops
+            .last()
+            .cloned()
+            .expect("ICE: recur outside of loop in infer_recur");
+        for (arg, typ) in r.args.iter_mut().zip(&types) {
+            self.infer_expr(arg, typ)?;
+        }
+        r.typ = self.unify(expected_type, &r.typ)
+            .map_err(|err| err.at(&r.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))?;
+        Ok(r.typ.clone())
+    }
+
+    /// Infer the types of the expressions of a sequence. Only the last is constrained by the
+    /// expected type
+    fn infer_begin(
+        &mut self,
+        b: &mut Begin<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        let (last, effects) = b.exprs.split_last_mut().expect("ICE: empty begin");
+        for e in effects {
+            let t = self.type_var_gen.gen_type_var();
+            self.infer_expr(e, &t)?;
+        }
+        self.infer_expr(last, expected_type)
+    }
+
+    /// Infer the types of the arguments of a pointer operation, and the type of its result
+    fn infer_ptr_op(
+        &mut self,
+        p: &mut PtrOp<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        let elem = self.type_var_gen.gen_type_var();
+        let ptr = Type::new_ptr(elem.clone());
+        let (arg_types, result) = match p.kind {
+            PtrOpKind::Read | PtrOpKind::ReadVolatile | PtrOpKind::AtomicLoad => {
+                (vec![ptr], elem.clone())
+            }
+            PtrOpKind::Write | PtrOpKind::WriteVolatile | PtrOpKind::AtomicStore => {
+                (vec![ptr, elem.clone()], TYPE_NIL.clone())
+            }
+            PtrOpKind::AtomicFetchAdd => (vec![ptr, elem.clone()], elem.clone()),
+            PtrOpKind::AtomicCas => (
+                vec![ptr, elem.clone(), elem.clone()],
+                Type::new_cons(elem.clone(), TYPE_BOOL.clone()),
+            ),
+            PtrOpKind::Offset => (vec![ptr.clone(), Type::Const("IntPtr", None)], ptr),
+            PtrOpKind::Null => (vec![], ptr),
+            PtrOpKind::Eq => (vec![ptr.clone(), ptr], TYPE_BOOL.clone()),
+        };
+        for (arg, typ) in p.args.iter_mut().zip(&arg_types) {
+            self.infer_expr(arg, typ)?;
+        }
+        let expected = self.unify(expected_type, &result)
+            .map_err(|err| err.at(&p.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))?;
+        p.typ = self.unify(&expected, &p.typ)
+            .map_err(|err| err.at(&p.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))?;
+        // The size and layout of an opaque foreign type is unknown, so only null and equality
+        // operations are valid on pointers to one
+        if let Type::Const(name, _) = subst(&elem, &mut self.type_var_map) {
+            let is_extern = match self.type_defs.get(name) {
+                Some(&TypeDef::Extern) => true,
+                _ => false,
+            };
+            match p.kind {
+                PtrOpKind::Null | PtrOpKind::Eq => (),
+                _ if is_extern => {
+                    return Err(p.pos.diagnostic(format!(
+                        "Can't operate on the pointee of a pointer to the foreign type `{}`",
+                        name
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(p.typ.clone())
+    }
+
+    /// Infer the types of the arguments of a thread operation, and the type of its result
+    fn infer_thread_op(
+        &mut self,
+        t: &mut ThreadOp<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        let elem = self.type_var_gen.gen_type_var();
+        let thread = Type::new_thread(elem.clone());
+        let chan = Type::new_chan(elem.clone());
+        let mutex = Type::new_mutex(elem.clone());
+        let task = Type::new_task(elem.clone());
+        let (arg_types, typ) = match t.kind {
+            ThreadOpKind::Spawn => (vec![Type::new_io(elem)], thread),
+            ThreadOpKind::Join => (vec![thread], elem),
+            ThreadOpKind::ChanNew => (vec![], chan),
+            ThreadOpKind::ChanSend => (vec![chan, elem], TYPE_NIL.clone()),
+            ThreadOpKind::ChanRecv => (vec![chan], elem),
+            ThreadOpKind::MutexNew => (vec![elem], mutex),
+            ThreadOpKind::MutexLock => (vec![mutex], elem),
+            ThreadOpKind::MutexUnlock => (vec![mutex, elem], TYPE_NIL.clone()),
+            ThreadOpKind::TaskSpawn => (vec![Type::new_io(elem)], task),
+            ThreadOpKind::TaskAwait => (vec![task], elem),
+            ThreadOpKind::TaskYield => (vec![], TYPE_NIL.clone()),
+        };
+        for (arg, typ) in t.args.iter_mut().zip(&arg_types) {
+            self.infer_expr(arg, typ)?;
+        }
+        let expected = self.unify(expected_type, &typ)
+            .map_err(|err| err.at(&t.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))?;
+        t.typ = self.unify(&expected, &t.typ)
+            .map_err(|err| err.at(&t.pos, |e, f| type_mis(&mut self.type_var_map, &e, &f)))?;
+        Ok(t.typ.clone())
     }
 
-    fn infer_recursive_binding(&mut self, binding: &mut Binding<'s>, bindings_ids: &[&'s str]) {
+    fn infer_recursive_binding(
+        &mut self,
+        binding: &mut Binding<'s>,
+        bindings_ids: &[&'s str],
+    ) -> Result<(), Diagnostic> {
         let id = binding.ident.s;
         // Only allow recursion for functions. Stuff like `let a = a + 1`
         // can't be compiled without laziness.
         if binding.val.first_non_type_ascr_is_lambda() {
-            self.infer_expr(&mut binding.val, &binding.sig.body);
+            self.infer_expr(&mut binding.val, &binding.sig.body)?;
+            Ok(())
         } else {
             let refs_s = if bindings_ids.len() == 1 {
                 "itself".to_string()
@@ -744,20 +1024,20 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                     .collect::<String>();
                 format!("itself through sibling bindings {{ {} }}", siblings_s)
             };
-            binding.pos.error_exit(format!(
+            Err(binding.pos.diagnostic(format!(
                 "Non-function value `{}` defined in terms of {}",
                 id, refs_s
-            ))
+            )))
         }
     }
 
     /// Infer types for a group of mutually recursively defined bindings
-    fn infer_recursion_group(&mut self, group: &mut Group<'s>) {
+    fn infer_recursion_group(&mut self, group: &mut Group<'s>) -> Result<(), Diagnostic> {
         match *group {
             Group::Uncircular(id, ref mut binding) => {
                 let old_tv_env = self.type_var_env.clone();
                 self.extend_type_var_env(binding.sig.params.clone());
-                self.infer_expr(&mut binding.val, &binding.sig.body);
+                self.infer_expr(&mut binding.val, &binding.sig.body)?;
                 let generalized_params = self.generalize(&binding.sig.body, &old_tv_env);
                 binding.sig.params = generalized_params;
                 self.push_var(id, binding.get_type());
@@ -774,7 +1054,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 }
                 // Infer bindings
                 for (_, binding) in bindings.iter_mut() {
-                    self.infer_recursive_binding(binding, &bindings_ids)
+                    self.infer_recursive_binding(binding, &bindings_ids)?
                 }
                 for (id, _) in bindings.iter() {
                     self.pop_var(id).unwrap_or_else(|| {
@@ -807,24 +1087,33 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 self.type_var_env = old_tv_env;
             }
         }
+        Ok(())
     }
 
     /// Infer types for global bindings or bindings of a let-form
     /// and push them to the environment.
-    fn infer_bindings(&mut self, bindings: &mut TopologicallyOrderedDependencyGroups<'s>) {
+    fn infer_bindings(
+        &mut self,
+        bindings: &mut TopologicallyOrderedDependencyGroups<'s>,
+    ) -> Result<(), Diagnostic> {
         for mut recursion_group in bindings.groups_mut().rev() {
-            self.infer_recursion_group(recursion_group);
+            self.infer_recursion_group(recursion_group)?;
         }
+        Ok(())
     }
 
-    fn infer_let<'l>(&mut self, let_: &'l mut Let<'s>, expected_type: &Type<'s>) -> &'l Type<'s> {
-        self.infer_bindings(&mut let_.bindings);
-        let_.typ = self.infer_expr(&mut let_.body, expected_type).clone();
+    fn infer_let<'l>(
+        &mut self,
+        let_: &'l mut Let<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'l Type<'s>, Diagnostic> {
+        self.infer_bindings(&mut let_.bindings)?;
+        let_.typ = self.infer_expr(&mut let_.body, expected_type)?;
         for name in let_.bindings.ids() {
             self.pop_var(name)
                 .unwrap_or_else(|| panic!("ICE: binding gone from var_env in infer_let"));
         }
-        &let_.typ
+        Ok(&let_.typ)
     }
 
     /// Apply a type ascription and infer type of inner expression
@@ -832,100 +1121,152 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     /// Unify ascription type with expected type, replace the ascription
     /// with the inner expression it ascribes a type to in the AST,
     /// and infer types for the inner expression
-    fn infer_type_ascription(&mut self, expr: &mut Expr<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_type_ascription(
+        &mut self,
+        expr: &mut Expr<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
         let ascr_pos = expr.pos().clone();
         match expr.remove_type_ascription() {
             Some(ascribed) => {
-                let expected_type2 = self.unify(expected_type, &ascribed).unwrap_or_else(|_| {
-                    ascr_pos.error_exit(type_mis(&mut self.type_var_map, expected_type, &ascribed))
-                });
+                let expected_type2 = self.unify(expected_type, &ascribed).map_err(|err| {
+                    err.at(&ascr_pos, |_, _| {
+                        type_mis(&mut self.type_var_map, expected_type, &ascribed)
+                    })
+                })?;
                 self.infer_expr(expr, &expected_type2)
             }
             None => panic!("ICE: infer_type_ascript called for non-ascription expr"),
         }
     }
 
-    fn infer_cons<'c>(&mut self, cons: &'c mut Cons<'s>, expected_type: &Type<'s>) -> &'c Type<'s> {
+    fn infer_cons<'c>(
+        &mut self,
+        cons: &'c mut Cons<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'c Type<'s>, Diagnostic> {
         let arbitrary_cons_type = Type::new_cons(
             self.type_var_gen.gen_type_var(),
             self.type_var_gen.gen_type_var(),
         );
         let expected_type2 = self.unify(expected_type, &arbitrary_cons_type)
-            .unwrap_or_else(|_| {
-                cons.pos.error_exit(type_mis(
-                    &mut self.type_var_map,
-                    expected_type,
-                    &arbitrary_cons_type,
-                ))
-            });
+            .map_err(|err| {
+                err.at(&cons.pos, |_, _| {
+                    type_mis(&mut self.type_var_map, expected_type, &arbitrary_cons_type)
+                })
+            })?;
         let (expected_car_type, expected_cdr_type) = expected_type2
             .get_cons()
             .expect("ICE: expected type not cons in infer_cons ");
-        let car_type = self.infer_expr(&mut cons.car, expected_car_type);
-        let cdr_type = self.infer_expr(&mut cons.cdr, expected_cdr_type);
+        let car_type = self.infer_expr(&mut cons.car, expected_car_type)?;
+        let cdr_type = self.infer_expr(&mut cons.cdr, expected_cdr_type)?;
         cons.typ = Type::new_cons(car_type, cdr_type);
-        &cons.typ
+        Ok(&cons.typ)
     }
 
-    fn infer_car<'c>(&mut self, car: &'c mut Car<'s>, expected_type: &Type<'s>) -> &'c Type<'s> {
+    fn infer_car<'c>(
+        &mut self,
+        car: &'c mut Car<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'c Type<'s>, Diagnostic> {
         let expected_cons_type =
             Type::new_cons(expected_type.clone(), self.type_var_gen.gen_type_var());
-        let cons_type = self.infer_expr(&mut car.expr, &expected_cons_type);
+        let cons_type = self.infer_expr(&mut car.expr, &expected_cons_type)?;
         car.typ = cons_type
             .get_cons()
             .expect("ICE: inner type type not cons in infer_car")
             .0
             .clone();
-        &car.typ
+        Ok(&car.typ)
     }
 
-    fn infer_cdr<'c>(&mut self, cdr: &'c mut Cdr<'s>, expected_type: &Type<'s>) -> &'c Type<'s> {
+    fn infer_cdr<'c>(
+        &mut self,
+        cdr: &'c mut Cdr<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'c Type<'s>, Diagnostic> {
         let expected_cons_type =
             Type::new_cons(self.type_var_gen.gen_type_var(), expected_type.clone());
-        let cons_type = self.infer_expr(&mut cdr.expr, &expected_cons_type);
+        let cons_type = self.infer_expr(&mut cdr.expr, &expected_cons_type)?;
         cdr.typ = cons_type
             .get_cons()
             .expect("ICE: inner type type not cons in infer_cdr")
             .1
             .clone();
-        &cdr.typ
+        Ok(&cdr.typ)
     }
 
-    fn infer_cast<'c>(&mut self, cast: &'c mut Cast<'s>, expected_type: &Type<'s>) -> &'c Type<'s> {
+    fn infer_cast<'c>(
+        &mut self,
+        cast: &'c mut Cast<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'c Type<'s>, Diagnostic> {
         let expected_from = self.type_var_gen.gen_type_var();
-        self.infer_expr(&mut cast.expr, &expected_from);
-        cast.typ = self.unify(expected_type, &cast.typ).unwrap_or_else(|_| {
-            cast.pos
-                .error_exit(type_mis(&mut self.type_var_map, expected_type, &cast.typ))
-        });
-        &cast.typ
+        self.infer_expr(&mut cast.expr, &expected_from)?;
+        cast.typ = self.unify(expected_type, &cast.typ).map_err(|err| {
+            err.at(&cast.pos, |_, _| {
+                type_mis(&mut self.type_var_map, expected_type, &cast.typ)
+            })
+        })?;
+        Ok(&cast.typ)
     }
 
-    fn infer_new<'n>(&mut self, n: &'n mut New<'s>, expected_type: &Type<'s>) -> &'n Type<'s> {
+    fn infer_transmute<'t>(
+        &mut self,
+        t: &'t mut Transmute<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'t Type<'s>, Diagnostic> {
+        let expected_from = self.type_var_gen.gen_type_var();
+        self.infer_expr(&mut t.expr, &expected_from)?;
+        t.typ = self.unify(expected_type, &t.typ).map_err(|err| {
+            err.at(&t.pos, |_, _| {
+                type_mis(&mut self.type_var_map, expected_type, &t.typ)
+            })
+        })?;
+        Ok(&t.typ)
+    }
+
+    fn infer_new<'n>(
+        &mut self,
+        n: &'n mut New<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'n Type<'s>, Diagnostic> {
         n.typ = self.parent_type_of_variant(n.constr.s)
             .expect("ICE: No type_of_variant in infer_new");
-        n.typ = self.unify(expected_type, &n.typ).unwrap_or_else(|_| {
-            n.pos
-                .error_exit(type_mis(&mut self.type_var_map, expected_type, &n.typ))
-        });
+        n.typ = self.unify(expected_type, &n.typ).map_err(|err| {
+            err.at(&n.pos, |_, _| {
+                type_mis(&mut self.type_var_map, expected_type, &n.typ)
+            })
+        })?;
         let inst = n.typ.get_adt_inst_args().unwrap_or(&[]);
         let expected_member_types = self.adts
             .members_with_inst_of_variant_with_name(n.constr.s, inst)
             .expect("ICE: No adt_variant_of_name in infer_new");
         for (member, expected_member_type) in n.members.iter_mut().zip(expected_member_types) {
-            self.infer_expr(member, &expected_member_type);
+            self.infer_expr(member, &expected_member_type)?;
         }
-        &n.typ
+        Ok(&n.typ)
     }
 
-    fn infer_pattern(&mut self, patt: &mut Pattern<'s>, expected_type: &Type<'s>) -> Type<'s> {
+    fn infer_pattern(
+        &mut self,
+        patt: &mut Pattern<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
         match *patt {
             Pattern::Nil(ref mut nil) => self.infer_nil(nil, expected_type),
-            Pattern::NumLit(ref mut num) => self.infer_num_lit(num, expected_type).clone(),
+            Pattern::NumLit(ref mut num) => {
+                self.infer_num_lit(num, expected_type).map(Clone::clone)
+            }
+            Pattern::Range(ref mut r) => {
+                let t = self.infer_num_lit(&mut r.lo, expected_type)?.clone();
+                self.infer_num_lit(&mut r.hi, &t).map(Clone::clone)
+            }
             Pattern::StrLit(ref mut lit) => self.infer_str_lit(lit, expected_type),
+            Pattern::Bool(ref mut b) => self.infer_bool(b, expected_type),
             Pattern::Variable(ref mut var) => {
                 var.typ = expected_type.clone();
-                var.typ.clone()
+                Ok(var.typ.clone())
             }
             Pattern::Deconstr(ref mut dec) => {
                 let adt_type = self.parent_type_of_variant(dec.constr.s)
@@ -936,24 +1277,25 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                         )
                     });
                 let adt_inst = adt_type.get_adt_inst_args().unwrap_or(&[]);
-                let typ = self.unify(expected_type, &adt_type).unwrap_or_else(|_| {
-                    dec.pos
-                        .error_exit(type_mis(&mut self.type_var_map, expected_type, &adt_type))
-                });
+                let typ = self.unify(expected_type, &adt_type).map_err(|err| {
+                    err.at(&dec.pos, |_, _| {
+                        type_mis(&mut self.type_var_map, expected_type, &adt_type)
+                    })
+                })?;
                 let variant_members = self.adts
                     .members_with_inst_of_variant_with_name(dec.constr.s, adt_inst)
                     .expect("ICE: No members with inst of variant with name in infer_pattern");
                 let (n_subs, n_members) = (dec.subpatts.len(), variant_members.len());
                 if n_subs != n_members {
-                    dec.pos.error_exit(ConstrWrongNumArgs {
+                    return Err(dec.pos.diagnostic(ConstrWrongNumArgs {
                         expected: n_members,
                         found: n_subs,
-                    })
+                    }));
                 }
                 for (subpatt, member_type) in dec.subpatts.iter_mut().zip(&variant_members) {
-                    self.infer_pattern(subpatt, member_type);
+                    self.infer_pattern(subpatt, member_type)?;
                 }
-                typ
+                Ok(typ)
             }
         }
     }
@@ -963,66 +1305,129 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         case: &'c mut Case<'s>,
         expected_patt_type: &Type<'s>,
         expected_body_type: &Type<'s>,
-    ) -> (&'c Type<'s>, &'c Type<'s>) {
-        case.patt_typ = self.infer_pattern(&mut case.patt, &expected_patt_type);
+    ) -> Result<(&'c Type<'s>, &'c Type<'s>), Diagnostic> {
+        case.patt_typ = self.infer_pattern(&mut case.patt, &expected_patt_type)?;
         for var in case.patt.variables() {
             self.push_var(var.ident.s, var.typ.clone())
         }
-        self.infer_expr(&mut case.body, expected_body_type);
+        self.infer_expr(&mut case.body, expected_body_type)?;
         for var in case.patt.variables() {
             self.pop_var(var.ident.s)
                 .unwrap_or_else(|| panic!("ICE: binding gone from var_env in infer_match"));
         }
-        (&case.patt_typ, case.body.get_type())
+        Ok((&case.patt_typ, case.body.get_type()))
     }
 
-    fn infer_match<'m>(&mut self, m: &'m mut Match<'s>, expected_type: &Type<'s>) -> &'m Type<'s> {
+    fn infer_match<'m>(
+        &mut self,
+        m: &'m mut Match<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<&'m Type<'s>, Diagnostic> {
         let expected_expr_type = self.type_var_gen.gen_type_var();
-        let expr_typ = self.infer_expr(&mut m.expr, &expected_expr_type);
+        let expr_typ = self.infer_expr(&mut m.expr, &expected_expr_type)?;
         for case in &mut m.cases {
-            self.infer_case(case, &expr_typ, expected_type);
+            self.infer_case(case, &expr_typ, expected_type)?;
         }
         m.typ = expected_type.clone();
-        &m.typ
+        Ok(&m.typ)
     }
 
     // The type of an expression will only be inferred once
-    fn infer_expr(&mut self, expr: &mut Expr<'s>, expected_type: &Type<'s>) -> Type<'s> {
-        match *expr {
-            Expr::Nil(ref mut nil) => self.infer_nil(nil, expected_type),
-            Expr::StrLit(ref mut l) => self.infer_str_lit(l, expected_type),
-            Expr::Bool(ref mut b) => self.infer_bool(b, expected_type),
-            Expr::NumLit(ref mut l) => self.infer_num_lit(l, expected_type).clone(),
-            Expr::Variable(ref mut var) => self.infer_variable(var, expected_type).clone(),
-            Expr::App(ref mut app) => self.infer_app(app, expected_type).clone(),
-            Expr::If(ref mut cond) => self.infer_if(cond, expected_type).clone(),
-            Expr::Lambda(ref mut lam) => self.infer_lambda(lam, expected_type).clone(),
-            Expr::Let(ref mut l) => self.infer_let(l, expected_type).clone(),
-            Expr::TypeAscript(_) => self.infer_type_ascription(expr, expected_type),
-            Expr::Cons(ref mut cons) => self.infer_cons(cons, expected_type).clone(),
-            Expr::Car(ref mut c) => self.infer_car(c, expected_type).clone(),
-            Expr::Cdr(ref mut c) => self.infer_cdr(c, expected_type).clone(),
-            Expr::Cast(ref mut c) => self.infer_cast(c, expected_type).clone(),
-            Expr::New(ref mut n) => self.infer_new(n, expected_type).clone(),
-            Expr::Match(ref mut m) => self.infer_match(m, expected_type).clone(),
-        }
+    fn infer_expr(
+        &mut self,
+        expr: &mut Expr<'s>,
+        expected_type: &Type<'s>,
+    ) -> Result<Type<'s>, Diagnostic> {
+        grow_stack(|| {
+            match *expr {
+                Expr::Nil(ref mut nil) => self.infer_nil(nil, expected_type),
+                Expr::StrLit(ref mut l) => self.infer_str_lit(l, expected_type),
+                Expr::BytesLit(ref mut l) => self.infer_bytes_lit(l, expected_type),
+                Expr::SymLit(ref mut l) => self.infer_sym_lit(l, expected_type),
+                Expr::Bool(ref mut b) => self.infer_bool(b, expected_type),
+                Expr::NumLit(ref mut l) => {
+                    self.infer_num_lit(l, expected_type).map(Clone::clone)
+                }
+                Expr::Variable(ref mut var) => self.infer_variable(var, expected_type),
+                Expr::App(ref mut app) => self.infer_app(app, expected_type).map(Clone::clone),
+                Expr::If(ref mut cond) => self.infer_if(cond, expected_type).map(Clone::clone),
+                Expr::Lambda(ref mut lam) => {
+                    self.infer_lambda(lam, expected_type).map(Clone::clone)
+                }
+                Expr::Let(ref mut l) => self.infer_let(l, expected_type).map(Clone::clone),
+                Expr::TypeAscript(_) => self.infer_type_ascription(expr, expected_type),
+                Expr::Cons(ref mut cons) => {
+                    self.infer_cons(cons, expected_type).map(Clone::clone)
+                }
+                Expr::Car(ref mut c) => self.infer_car(c, expected_type).map(Clone::clone),
+                Expr::Cdr(ref mut c) => self.infer_cdr(c, expected_type).map(Clone::clone),
+                Expr::Cast(ref mut c) => self.infer_cast(c, expected_type).map(Clone::clone),
+                Expr::Transmute(ref mut t) => {
+                    self.infer_transmute(t, expected_type).map(Clone::clone)
+                }
+                Expr::New(ref mut n) => self.infer_new(n, expected_type).map(Clone::clone),
+                Expr::Match(ref mut m) => self.infer_match(m, expected_type).map(Clone::clone),
+                Expr::Assert(ref mut a) => self.infer_assert(a, expected_type),
+                Expr::WithArena(ref mut w) => self.infer_expr(&mut w.body, expected_type),
+                Expr::Loop(ref mut l) => self.infer_loop(l, expected_type),
+                Expr::Recur(ref mut r) => self.infer_recur(r, expected_type),
+                Expr::Begin(ref mut b) => self.infer_begin(b, expected_type),
+                Expr::PtrOp(ref mut p) => self.infer_ptr_op(p, expected_type),
+                Expr::ThreadOp(ref mut t) => self.infer_thread_op(t, expected_type),
+            }
+        })
     }
 }
 
-fn assert_externs_monomorphic(externs: &BTreeMap<&str, ExternDecl>) {
+fn assert_externs_monomorphic(externs: &BTreeMap<&str, ExternDecl>) -> Result<(), Diagnostic> {
     for ext in externs.values() {
         if !ext.typ.is_monomorphic() {
-            ext.pos
-                .error_exit("Type of external declaration must be monomorphic")
+            return Err(ext.pos
+                .diagnostic("Type of external declaration must be monomorphic"));
         }
     }
+    Ok(())
 }
 
-pub fn infer_types(ast: &mut Ast, type_var_generator: &mut TypeVarGen) {
-    assert_externs_monomorphic(&ast.externs);
-    let mut inferrer = Inferrer::new(&mut ast.externs, &mut ast.adts, type_var_generator);
+/// Check that the values of the globals defined with `def-const` have been folded to literals,
+/// so that they can be generated as constants
+fn check_consts(
+    globals: &TopologicallyOrderedDependencyGroups,
+    consts: &BTreeSet<&str>,
+) -> Result<(), Diagnostic> {
+    for b in globals.bindings().filter(|b| consts.contains(b.ident.s)) {
+        if !b.sig.is_monomorphic() {
+            return Err(b.pos.diagnostic(format!(
+                "The type of the constant `{}` must be monomorphic, but it's `{}`. Annotate \
+                 the type, e.g. `(: 1 Int64)`",
+                b.ident.s,
+                b.sig.pretty()
+            )));
+        }
+        match b.val {
+            Expr::NumLit(_) | Expr::Bool(_) => (),
+            _ => {
+                return Err(b.pos.diagnostic(format!(
+                    "The value of the constant `{}` can't be evaluated at compile time. Only \
+                     arithmetic and comparisons of numeric and boolean constants can",
+                    b.ident.s
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn infer_types(ast: &mut Ast, type_var_generator: &mut TypeVarGen) -> Result<(), Diagnostic> {
+    assert_externs_monomorphic(&ast.externs)?;
+    let mut inferrer = Inferrer::new(
+        &mut ast.externs,
+        &mut ast.adts,
+        &ast.extern_types,
+        type_var_generator,
+    );
 
-    inferrer.infer_bindings(&mut ast.globals);
+    inferrer.infer_bindings(&mut ast.globals)?;
 
     // Apply all substitutions recursively to get rid of reduntant, indirect type variables
     for binding in ast.globals.bindings_mut() {
@@ -1030,6 +1435,14 @@ pub fn infer_types(ast: &mut Ast, type_var_generator: &mut TypeVarGen) {
         subst_expr(&mut binding.val, &mut inferrer.type_var_map);
     }
 
+    fold_constants(&mut ast.globals);
+    check_consts(&ast.globals, &ast.consts)?;
+
     // Map monomorphic instantiations of variables to monomorphization of definitions
-    monomorphize_defs_of_insts(&mut ast.globals);
+    monomorphize_defs_of_insts(&mut ast.globals)?;
+
+    check_real_world_linearity(&ast.globals);
+    check_matches(&ast.globals, &ast.adts);
+    check_global_init_cycles(&ast.globals);
+    Ok(())
 }
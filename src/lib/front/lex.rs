@@ -6,14 +6,18 @@
 
 use self::LexErr::*;
 use super::cst::Cst;
-use super::SrcPos;
+use super::{Diagnostic, SrcPos};
 use lib::CanonPathBuf;
 use lib::collections::AddMap;
 use std::borrow::Cow;
+use std::cell::Cell;
 use std::path::Path;
 use std::fmt;
 use std::fs::File;
+use std::iter::once;
 use std::io::Read;
+use unicode_normalization::UnicodeNormalization;
+use unicode_xid::UnicodeXID;
 
 /// Common errors for various lexing actions
 enum LexErr {
@@ -22,11 +26,15 @@ enum LexErr {
     InvalidEscapeSeq,
     UntermStr,
     UntermRawStr,
+    UntermBlockComment,
     InvalidRawStrDelim(char),
     InvalidNum,
     InvalidIdent,
     UndelimItem,
+    MismatchedDelim(char, char),
     Unexpected(&'static str),
+    TooDeep(usize),
+    FormTooLong(usize),
 }
 
 impl fmt::Display for LexErr {
@@ -36,6 +44,7 @@ impl fmt::Display for LexErr {
             InvalidEscapeSeq => write!(f, "Invalid escape sequence"),
             UntermStr => write!(f, "Unterminated string literal"),
             UntermRawStr => write!(f, "Unterminated raw string literal"),
+            UntermBlockComment => write!(f, "Unterminated block comment"),
             InvalidRawStrDelim(c) => write!(
                 f,
                 "Invalid character found in raw string delimitation: `{}`. Only `#` is \
@@ -45,11 +54,76 @@ impl fmt::Display for LexErr {
             InvalidNum => write!(f, "Invalid numeric literal"),
             InvalidIdent => write!(f, "Invalid ident"),
             UndelimItem => write!(f, "Undelimited item"),
+            MismatchedDelim(expected, found) => write!(
+                f,
+                "Mismatched closing delimiter `{}`. Expected `{}`",
+                found,
+                expected
+            ),
             Unexpected(s) => write!(f, "Unexpected {}", s),
+            TooDeep(n) => write!(
+                f,
+                "Form nested too deeply. The maximum nesting depth is {}. \
+                 Raise it with `--max-nesting-depth`",
+                n
+            ),
+            FormTooLong(n) => write!(
+                f,
+                "Form has too many items. The maximum number of items in a form is {}. \
+                 Raise it with `--max-form-len`",
+                n
+            ),
+        }
+    }
+}
+
+/// Limits on the shape of the syntax tree, beyond which lexing fails with an error
+///
+/// Without the limits, pathological inputs may overflow the stack of the recursive passes of
+/// the frontend, or make them take unreasonably long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum depth of nested forms, including quotes
+    pub max_depth: usize,
+    /// The maximum number of items in a single form, or at the top level of a file
+    pub max_form_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 512,
+            max_form_len: 1 << 20,
         }
     }
 }
 
+thread_local! {
+    /// The limits of the current compilation. See `in_compilation`
+    static LIMITS: Cell<Limits> = Cell::new(Limits::default());
+}
+
+/// Restores the limits of the enclosing compilation when dropped
+struct LimitsGuard(Limits);
+
+impl Drop for LimitsGuard {
+    fn drop(&mut self) {
+        LIMITS.with(|l| l.set(self.0))
+    }
+}
+
+/// Run the compilation `f` with the limits `limits`, and restore the limits of any enclosing
+/// compilation afterwards
+pub fn in_compilation<R, F: FnOnce() -> R>(limits: Limits, f: F) -> R {
+    let _guard = LimitsGuard(LIMITS.with(|l| l.replace(limits)));
+    f()
+}
+
+/// The limits to use when lexing
+fn limits() -> Limits {
+    LIMITS.with(Cell::get)
+}
+
 /// Unescape the character of an escape sequence.
 /// E.g. `n` from the sequence `\n` unescapes to newline
 fn unescape_char(c: char) -> Option<char> {
@@ -72,14 +146,34 @@ fn is_delim_char(c: char) -> bool {
 }
 
 /// Returns whether `c` is a valid character of an ident
+///
+/// Any printable ASCII character that is not a delimiter may be part of an ident, to allow
+/// symbolic names like `+` and `->`. Outside of ASCII, only characters of the Unicode class
+/// `XID_Continue` are allowed
 fn is_ident_char(c: char) -> bool {
     match c {
         '"' => false,
         _ if is_delim_char(c) => false,
-        _ => true,
+        _ if c.is_ascii() => !c.is_ascii_control(),
+        _ => UnicodeXID::is_xid_continue(c),
+    }
+}
+
+/// Returns whether an ident may start with `c`
+fn is_ident_start(c: char) -> bool {
+    if c.is_ascii() {
+        is_ident_char(c) && !c.is_ascii_digit()
+    } else {
+        UnicodeXID::is_xid_start(c)
     }
 }
 
+/// Normalize source code to Unicode Normalization Form C, so that idents that look the same
+/// are the same sequence of characters, and compare equal
+pub fn normalize(src: &str) -> String {
+    src.nfc().collect()
+}
+
 /// *"A token is a structure representing a lexeme that explicitly indicates its categorization
 ///   for the purpose of parsing."*
 /// -- [Wikipedia](https://en.wikipedia.org/wiki/Lexical_analysis#Token)
@@ -89,6 +183,18 @@ enum Token<'src> {
     LParen,
     /// Right parenthesis `)`
     RParen,
+    /// Left bracket `[`
+    LBracket,
+    /// Right bracket `]`
+    RBracket,
+    /// Start of length-list literal `#[`
+    LLenList,
+    /// Start of infix arithmetic `#math(`
+    LMath,
+    /// Left brace `{`
+    LBrace,
+    /// Right brace `}`
+    RBrace,
     /// Identifier
     Ident(&'src str),
     /// Numeric literal
@@ -97,12 +203,18 @@ enum Token<'src> {
     Str(Cow<'src, str>),
     /// Quote (`'`)
     Quote,
+    /// Datum comment (`#;`), which comments out the following form
+    DatumComment,
 }
 
 /// Tokenize the string literal in `src` at `start`.
 /// Return the unescaped literal as a `Token` and it's length,
 /// including delimiting characters, in the source.
-fn tokenize_str_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Token<'s>, usize) {
+fn tokenize_str_lit<'s>(
+    filename: &'s Path,
+    src: &'s str,
+    start: usize,
+) -> Result<(Token<'s>, usize), Diagnostic> {
     let mut s = String::new();
     let mut chars = src[start + 1..].char_indices();
     while let Some((i, c)) = chars.next() {
@@ -113,32 +225,97 @@ fn tokenize_str_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Toke
                     if let Some(u) = unescape_char(e) {
                         s.push(u)
                     } else {
-                        SrcPos::new_pos(filename, src, start + 1 + j).error_exit(UnknownEscape)
+                        return Err(SrcPos::new_pos(filename, src, start + 1 + j)
+                            .diagnostic(UnknownEscape));
                     }
                 } else {
-                    SrcPos::new_pos(filename, src, start + 1 + i).error_exit(InvalidEscapeSeq)
+                    return Err(SrcPos::new_pos(filename, src, start + 1 + i)
+                        .diagnostic(InvalidEscapeSeq));
                 }
             }
-            '"' => return (Token::Str(Cow::Owned(s)), i + 2),
+            '"' => return Ok((Token::Str(Cow::Owned(s)), i + 2)),
             _ => s.push(c),
         }
     }
-    SrcPos::new_pos(filename, src, start).error_exit(UntermStr)
+    Err(SrcPos::new_pos(filename, src, start).diagnostic(UntermStr))
+}
+
+/// Tokenize the triple quoted, multi-line string literal in `src` at `start`.
+/// Return the unescaped literal as a `Token` and it's length,
+/// including delimiting characters, in the source.
+///
+/// A newline directly after the opening quotes, and a line of only whitespace before the
+/// closing quotes, are not part of the literal. The indentation common to all non-blank lines
+/// is stripped, so that the literal can be indented along with the surrounding code.
+fn tokenize_multiline_str_lit<'s>(
+    filename: &'s Path,
+    src: &'s str,
+    start: usize,
+) -> Result<(Token<'s>, usize), Diagnostic> {
+    let body_start = start + 3;
+    let body_len = src[body_start..]
+        .find("\"\"\"")
+        .ok_or_else(|| SrcPos::new_pos(filename, src, start).diagnostic(UntermStr))?;
+    let body = &src[body_start..body_start + body_len];
+    let body = if body.starts_with('\n') {
+        &body[1..]
+    } else {
+        body
+    };
+    let mut lines = body.split('\n').collect::<Vec<_>>();
+    if lines.len() > 1 && lines.last().unwrap().trim().is_empty() {
+        lines.pop();
+    }
+    let is_indent_char = |c: char| c == ' ' || c == '\t';
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_left_matches(&is_indent_char).len())
+        .min()
+        .unwrap_or(0);
+    let stripped = lines
+        .iter()
+        .map(|line| if line.trim().is_empty() {
+            ""
+        } else {
+            &line[indent..]
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let lit_pos = || SrcPos::new_interval(filename, src, start, body_start + body_len + 3);
+    let mut s = String::new();
+    let mut chars = stripped.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next().map(unescape_char) {
+                Some(Some(u)) => s.push(u),
+                Some(None) => return Err(lit_pos().diagnostic(UnknownEscape)),
+                None => return Err(lit_pos().diagnostic(InvalidEscapeSeq)),
+            }
+        } else {
+            s.push(c)
+        }
+    }
+    Ok((Token::Str(Cow::Owned(s)), body_len + 6))
 }
 
 /// Tokenize the raw string literal in `src` at `start`.
 /// Return the literal as a `Token` and it's length, including delimiting characters, in the source.
-fn tokenize_raw_str_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Token<'s>, usize) {
+fn tokenize_raw_str_lit<'s>(
+    filename: &'s Path,
+    src: &'s str,
+    start: usize,
+) -> Result<(Token<'s>, usize), Diagnostic> {
     let str_src = &src[start + 1..];
     let n_delim_octos = str_src.chars().take_while(|&c| c == '#').count();
     if let Some(first_after_octos) = str_src[n_delim_octos..].chars().next() {
         if first_after_octos != '"' {
-            SrcPos::new_pos(filename, src, start + 1 + n_delim_octos)
-                .error_exit(InvalidRawStrDelim(first_after_octos))
+            return Err(SrcPos::new_pos(filename, src, start + 1 + n_delim_octos)
+                .diagnostic(InvalidRawStrDelim(first_after_octos)));
         }
     } else {
-        SrcPos::new_interval(filename, src, start, start + 1 + n_delim_octos)
-            .error_exit(UntermRawStr)
+        return Err(SrcPos::new_interval(filename, src, start, start + 1 + n_delim_octos)
+            .diagnostic(UntermRawStr));
     }
     let delim_octos = &str_src[..n_delim_octos];
     let str_body_src = &str_src[n_delim_octos + 1..];
@@ -146,15 +323,19 @@ fn tokenize_raw_str_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (
         if c == '"' && str_body_src[i + 1..].starts_with(delim_octos) {
             // octos before and after + 'r' + open and end quotes + str len
             let literal_len = n_delim_octos * 2 + 3 + i;
-            return (Token::Str(Cow::Borrowed(&str_body_src[..i])), literal_len);
+            return Ok((Token::Str(Cow::Borrowed(&str_body_src[..i])), literal_len));
         }
     }
-    SrcPos::new_pos(filename, src, start).error_exit(UntermRawStr)
+    Err(SrcPos::new_pos(filename, src, start).diagnostic(UntermRawStr))
 }
 
 /// Tokenize the numeric literal in `src` at `start`.
 /// Return the `Token` and it's length in the source.
-fn tokenize_num_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Token<'s>, usize) {
+fn tokenize_num_lit<'s>(
+    filename: &'s Path,
+    src: &'s str,
+    start: usize,
+) -> Result<(Token<'s>, usize), Diagnostic> {
     let src_num = &src[start..];
     let mut has_decimal_pt = false;
     let mut has_e = false;
@@ -171,28 +352,32 @@ fn tokenize_num_lit<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Toke
             '-' if prev_was_e => (),
             _ if c.is_numeric() => (),
             '.' if !has_decimal_pt => has_decimal_pt = true,
-            _ if is_delim_char(c) => return (Token::Num(&src_num[..i]), i),
+            _ if is_delim_char(c) => return Ok((Token::Num(&src_num[..i]), i)),
             _ => break,
         }
         if c != 'E' {
             prev_was_e = false;
         }
     }
-    SrcPos::new_pos(filename, src, start).error_exit(InvalidNum)
+    Err(SrcPos::new_pos(filename, src, start).diagnostic(InvalidNum))
 }
 
 /// Tokenize the numeric literal in `src` at `start`.
 /// Return the literal as a `Token` and it's length in the source.
-fn tokenize_ident<'s>(filename: &'s Path, src: &'s str, start: usize) -> (Token<'s>, usize) {
+fn tokenize_ident<'s>(
+    filename: &'s Path,
+    src: &'s str,
+    start: usize,
+) -> Result<(Token<'s>, usize), Diagnostic> {
     let src_ident = &src[start..];
     for (i, c) in src_ident.char_indices() {
         if is_delim_char(c) {
-            return (Token::Ident(&src_ident[..i]), i);
+            return Ok((Token::Ident(&src_ident[..i]), i));
         } else if !is_ident_char(c) {
             break;
         }
     }
-    SrcPos::new_pos(filename, src, start).error_exit(InvalidIdent)
+    Err(SrcPos::new_pos(filename, src, start).diagnostic(InvalidIdent))
 }
 
 /// An iterator over the `Token`s, and their positions, of some source code
@@ -201,17 +386,20 @@ struct Tokens<'s> {
     filename: &'s Path,
     src: &'s str,
     pos: usize,
+    /// The number of forms enclosing the next token
+    depth: usize,
+    limits: Limits,
 }
 
 impl<'s> Iterator for Tokens<'s> {
-    type Item = (Token<'s>, SrcPos<'s>);
+    type Item = Result<(Token<'s>, SrcPos<'s>), Diagnostic>;
 
-    fn next(&mut self) -> Option<(Token<'s>, SrcPos<'s>)> {
+    fn next(&mut self) -> Option<Self::Item> {
         let pos = self.pos;
         let mut chars = self.src[pos..].char_indices().map(|(n, c)| (pos + n, c));
 
         while let Some((i, c)) = chars.next() {
-            let (token, len) = match c {
+            let token_and_len = match c {
                 _ if c.is_whitespace() => continue,
                 ';' => {
                     while let Some((_, c)) = chars.next() {
@@ -221,54 +409,146 @@ impl<'s> Iterator for Tokens<'s> {
                     }
                     continue;
                 }
-                '\'' => (Token::Quote, 1),
-                '(' | '[' => (Token::LParen, 1),
-                ')' | ']' => (Token::RParen, 1),
+                '#' if self.src[i + 1..].starts_with('|') => {
+                    chars.next();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match chars.next() {
+                            Some((j, '|')) if self.src[j + 1..].starts_with('#') => {
+                                chars.next();
+                                depth -= 1
+                            }
+                            Some((j, '#')) if self.src[j + 1..].starts_with('|') => {
+                                chars.next();
+                                depth += 1
+                            }
+                            Some(_) => (),
+                            None => {
+                                let pos = SrcPos::new_interval(self.filename, self.src, i, i + 2);
+                                return Some(Err(pos.diagnostic(UntermBlockComment)));
+                            }
+                        }
+                    }
+                    continue;
+                }
+                '#' if self.src[i + 1..].starts_with(';') => Ok((Token::DatumComment, 2)),
+                '#' if self.src[i + 1..].starts_with('[') => Ok((Token::LLenList, 2)),
+                '#' if self.src[i + 1..].starts_with("math(") => Ok((Token::LMath, 6)),
+                '{' => Ok((Token::LBrace, 1)),
+                '}' => Ok((Token::RBrace, 1)),
+                '\'' => Ok((Token::Quote, 1)),
+                '(' => Ok((Token::LParen, 1)),
+                ')' => Ok((Token::RParen, 1)),
+                '[' => Ok((Token::LBracket, 1)),
+                ']' => Ok((Token::RBracket, 1)),
+                '"' if self.src[i..].starts_with("\"\"\"") => {
+                    tokenize_multiline_str_lit(self.filename, self.src, i)
+                }
                 '"' => tokenize_str_lit(self.filename, self.src, i),
                 'r' if self.src[i + 1..].starts_with(|c: char| c == '"' || c == '#') => {
                     tokenize_raw_str_lit(self.filename, self.src, i)
                 }
-                _ if c.is_numeric() => tokenize_num_lit(self.filename, self.src, i),
-                _ if is_ident_char(c) => tokenize_ident(self.filename, self.src, i),
+                _ if c.is_ascii_digit() => tokenize_num_lit(self.filename, self.src, i),
+                _ if is_ident_start(c) => tokenize_ident(self.filename, self.src, i),
                 _ => {
-                    SrcPos::new_pos(self.filename, self.src, i).error_exit(Unexpected("character"))
+                    let pos = SrcPos::new_pos(self.filename, self.src, i);
+                    Err(pos.diagnostic(Unexpected("character")))
                 }
             };
+            let (token, len) = match token_and_len {
+                Ok(token_and_len) => token_and_len,
+                Err(e) => return Some(Err(e)),
+            };
 
             self.pos = i + len;
-            return Some((
+            return Some(Ok((
                 token,
                 SrcPos::new_interval(self.filename, self.src, i, self.pos),
-            ));
+            )));
         }
         None
     }
 }
 
+/// Enter a form opened at `pos`, checking that the maximum nesting depth is not exceeded
+fn enter_form<'s>(nexts: &mut Tokens<'s>, pos: &SrcPos<'s>) -> Result<(), Diagnostic> {
+    if nexts.depth >= nexts.limits.max_depth {
+        return Err(pos.diagnostic(TooDeep(nexts.limits.max_depth)));
+    }
+    nexts.depth += 1;
+    Ok(())
+}
+
+/// Construct the syntax tree of the form opened at `pos` and closed by `close`
+///
+/// If `head` is given, it's prepended to the items of the form. Literal syntax, like
+/// `{k v}`, is desugared this way to a special form, like `(assoc-list-literal k v)`.
+fn form_to_tree<'s>(
+    mut pos: SrcPos<'s>,
+    head: Option<&'static str>,
+    close: &Token<'s>,
+    nexts: &mut Tokens<'s>,
+) -> Result<Cst<'s>, Diagnostic> {
+    enter_form(nexts, &pos)?;
+    let (items, end) = tokens_to_trees_until(nexts, Some((pos.clone(), close)))?;
+    nexts.depth -= 1;
+    let list = match head {
+        Some(head) => once(Cst::Ident(head, pos.clone())).chain(items).collect(),
+        None => items,
+    };
+    pos.end = end;
+    Ok(Cst::Sexpr(list, pos))
+}
+
 /// Construct a new syntax tree from a token with a position, and the tokens following
-fn token_to_tree<'s>((token, mut pos): (Token<'s>, SrcPos<'s>), nexts: &mut Tokens<'s>) -> Cst<'s> {
+fn token_to_tree<'s>(
+    (token, pos): (Token<'s>, SrcPos<'s>),
+    nexts: &mut Tokens<'s>,
+) -> Result<Cst<'s>, Diagnostic> {
     match token {
-        Token::LParen => {
-            let (list, end) = tokens_to_trees_until(nexts, Some((pos.clone(), &Token::RParen)));
-            pos.end = end;
-            Cst::Sexpr(list, pos)
+        Token::LParen => form_to_tree(pos, None, &Token::RParen, nexts),
+        Token::LBracket => form_to_tree(pos, None, &Token::RBracket, nexts),
+        Token::LLenList => form_to_tree(pos, Some("len-list-literal"), &Token::RBracket, nexts),
+        Token::LBrace => form_to_tree(pos, Some("assoc-list-literal"), &Token::RBrace, nexts),
+        Token::LMath => form_to_tree(pos, Some("infix-math"), &Token::RParen, nexts),
+        Token::Ident(ident) => Ok(Cst::Ident(ident, pos)),
+        Token::Num(num) => Ok(Cst::Num(num, pos)),
+        Token::Str(s) => Ok(Cst::Str(s, pos)),
+        Token::Quote => {
+            enter_form(nexts, &pos)?;
+            let next = next_datum(nexts)?.ok_or_else(|| pos.diagnostic(Unexpected("quote")))?;
+            let quoted = token_to_tree(next, nexts)?;
+            nexts.depth -= 1;
+            Ok(Cst::Sexpr(vec![Cst::Ident("'", pos.clone()), quoted], pos))
         }
-        Token::Ident(ident) => Cst::Ident(ident, pos),
-        Token::Num(num) => Cst::Num(num, pos),
-        Token::Str(s) => Cst::Str(s, pos),
-        Token::Quote => Cst::Sexpr(
-            vec![
-                Cst::Ident("'", pos.clone()),
-                token_to_tree(
-                    nexts
-                        .next()
-                        .unwrap_or_else(|| pos.error_exit(Unexpected("quote"))),
-                    nexts,
-                ),
-            ],
-            pos,
-        ),
-        _ => pos.error_exit(Unexpected("token")),
+        _ => Err(pos.diagnostic(Unexpected("token"))),
+    }
+}
+
+/// The next token of `tokens` that is not part of a form commented out by a datum comment
+fn next_datum<'s>(
+    tokens: &mut Tokens<'s>,
+) -> Result<Option<(Token<'s>, SrcPos<'s>)>, Diagnostic> {
+    loop {
+        match tokens.next() {
+            Some(Ok((Token::DatumComment, pos))) => {
+                let commented = tokens
+                    .next()
+                    .ok_or_else(|| pos.diagnostic(Unexpected("datum comment")))??;
+                token_to_tree(commented, tokens)?;
+            }
+            next => return next.map_or(Ok(None), |r| r.map(Some)),
+        }
+    }
+}
+
+/// The character of `token`, if it's a closing delimiter
+fn closing_delim_char(token: &Token) -> Option<char> {
+    match *token {
+        Token::RParen => Some(')'),
+        Token::RBracket => Some(']'),
+        Token::RBrace => Some('}'),
+        _ => None,
     }
 }
 
@@ -278,54 +558,193 @@ fn token_to_tree<'s>((token, mut pos): (Token<'s>, SrcPos<'s>), nexts: &mut Toke
 fn tokens_to_trees_until<'s>(
     tokens: &mut Tokens<'s>,
     start_and_delim: Option<(SrcPos, &Token)>,
-) -> (Vec<Cst<'s>>, Option<usize>) {
+) -> Result<(Vec<Cst<'s>>, Option<usize>), Diagnostic> {
     let (start, delim) = start_and_delim
         .map(|(s, t)| (Some(s), Some(t)))
         .unwrap_or((None, None));
 
     let mut trees = Vec::new();
 
-    while let Some((token, token_pos)) = tokens.next() {
+    while let Some((token, token_pos)) = next_datum(tokens)? {
         if Some(&token) == delim {
-            return (trees, token_pos.end);
+            return Ok((trees, token_pos.end));
+        } else if let (Some(found), Some(expected)) =
+            (closing_delim_char(&token), delim.and_then(closing_delim_char))
+        {
+            return Err(token_pos.diagnostic(MismatchedDelim(expected, found)));
+        } else if trees.len() >= tokens.limits.max_form_len {
+            let err = FormTooLong(tokens.limits.max_form_len);
+            return Err(match start {
+                Some(ref pos) => pos.diagnostic(err),
+                None => token_pos.diagnostic(err),
+            });
         } else {
-            trees.push(token_to_tree((token, token_pos), tokens))
+            trees.push(token_to_tree((token, token_pos), tokens)?)
         }
     }
     match start {
-        None => (trees, None),
-        Some(pos) => pos.error_exit(UndelimItem),
+        None => Ok((trees, None)),
+        Some(pos) => Err(pos.diagnostic(UndelimItem)),
+    }
+}
+
+/// The length of the shebang line, like `#!/usr/bin/env kvaser`, at the start of `src`, if any
+fn shebang_len(src: &str) -> usize {
+    if src.starts_with("#!") {
+        src.find('\n').unwrap_or(src.len())
+    } else {
+        0
     }
 }
 
 /// Lex the source code as a Concrete Syntax Tree
-fn lex_src<'s>(filename: &'s Path, src: &'s str) -> Vec<Cst<'s>> {
+///
+/// A leading shebang line is ignored, so that scripts can be made executable
+fn lex_src<'s>(filename: &'s Path, src: &'s str) -> Result<Vec<Cst<'s>>, Diagnostic> {
     tokens_to_trees_until(
         &mut Tokens {
             filename,
             src,
-            pos: 0,
+            pos: shebang_len(src),
+            depth: 0,
+            limits: limits(),
         },
         None,
-    ).0
+    ).map(|(trees, _)| trees)
 }
 
 /// Lex the source code of the file `filename`
+///
+/// If the source of `filename` has already been added to `sources`, it's used instead of
+/// reading the file
 pub fn lex_file<'s>(
     filename: CanonPathBuf,
     sources: &'s AddMap<CanonPathBuf, String>,
-) -> Vec<Cst<'s>> {
+) -> Result<Vec<Cst<'s>>, Diagnostic> {
+    if let Some((filename_ref, src_ref)) = sources.entry(&filename) {
+        return lex_src(filename_ref.path(), src_ref);
+    }
     let mut src_code = String::new();
     File::open(filename.path())
-        .unwrap_or_else(|e| panic!("Failed to open file `{}`. {}", filename.path().display(), e))
+        .map_err(|e| {
+            Diagnostic::error(format!("Failed to open file `{}`. {}", filename.path().display(), e))
+        })?
         .read_to_string(&mut src_code)
-        .unwrap_or_else(|e| {
-            panic!(
+        .map_err(|e| {
+            Diagnostic::error(format!(
                 "Reading contents of `{}` failed. {}",
                 filename.path().display(),
                 e
-            )
-        });
-    let (filename_ref, src_ref) = sources.add(filename, src_code);
+            ))
+        })?;
+    let (filename_ref, src_ref) = sources.add(filename, normalize(&src_code));
     lex_src(filename_ref.path(), src_ref)
 }
+
+#[cfg(test)]
+mod test {
+    use lib::front::cst::Cst;
+    use std::path::Path;
+    use super::{tokens_to_trees_until, Limits, Tokens};
+
+    /// Lex `src` with the limits `limits`, or return the diagnostics if lexing fails
+    fn lex_with(src: &'static str, limits: Limits) -> Result<Vec<Cst<'static>>, String> {
+        let mut tokens = Tokens {
+            filename: Path::new("test.kvs"),
+            src,
+            pos: 0,
+            depth: 0,
+            limits,
+        };
+        tokens_to_trees_until(&mut tokens, None)
+            .map(|(trees, _)| trees)
+            .map_err(|e| e.0)
+    }
+
+    fn lex(src: &'static str) -> Result<Vec<Cst<'static>>, String> {
+        lex_with(src, Limits::default())
+    }
+
+    /// The forms lexed from `src`, as displayed
+    fn lex_shown(src: &'static str) -> Vec<String> {
+        lex(src)
+            .unwrap_or_else(|e| panic!("Failed to lex `{}`\n{}", src, e))
+            .iter()
+            .map(|cst| cst.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        assert!(lex_with("((a) [b])", limits).is_ok());
+        let err = lex_with("((a (b)))", limits).unwrap_err();
+        assert!(err.contains("The maximum nesting depth is 2"), "{}", err);
+        // Quotes are nested forms too
+        assert!(lex_with("('a)", limits).is_ok());
+        assert!(lex_with("(''a)", limits).is_err());
+    }
+
+    #[test]
+    fn test_max_form_len() {
+        let limits = Limits {
+            max_form_len: 3,
+            ..Limits::default()
+        };
+        assert!(lex_with("(a b c) (d e f) g\n", limits).is_ok());
+        let err = lex_with("(a b c d)", limits).unwrap_err();
+        assert!(err.contains("The maximum number of items in a form is 3"), "{}", err);
+        let err = lex_with("a b c d\n", limits).unwrap_err();
+        assert!(err.contains("too many items"), "{}", err);
+        // Literal syntax counts the items as written, not the desugared form
+        assert!(lex_with("#[a b c]", limits).is_ok());
+    }
+
+    #[test]
+    fn test_block_comments() {
+        assert_eq!(lex_shown("a #| b |# c\n"), vec!["a", "c"]);
+        assert_eq!(lex_shown("(a #| multi\nline |#)"), vec!["(a)"]);
+        // Block comments nest
+        assert_eq!(lex_shown("#| a #| b |# c |# d\n"), vec!["d"]);
+        let err = lex("#| a #| b |# c\n").unwrap_err();
+        assert!(err.contains("Unterminated block comment"), "{}", err);
+        // A comment delimiter in a string is part of the string
+        assert_eq!(lex_shown("\"#|\""), vec!["#|"]);
+    }
+
+    #[test]
+    fn test_datum_comments() {
+        assert_eq!(lex_shown("(a #;(b (c)) d)"), vec!["(a d)"]);
+        assert_eq!(lex_shown("#;a b\n"), vec!["b"]);
+        assert_eq!(lex_shown("'#;a b\n"), vec!["(' b)"]);
+        let err = lex("(a #;)").unwrap_err();
+        assert!(err.contains("Unexpected token"), "{}", err);
+        let err = lex("a #;").unwrap_err();
+        assert!(err.contains("Unexpected datum comment"), "{}", err);
+    }
+
+    #[test]
+    fn test_multiline_str_indentation() {
+        let src = r#"
+            (f """
+                first
+                  indented
+                last
+                """)"#;
+        assert_eq!(lex_shown(src), vec!["(f first\n  indented\nlast)"]);
+        // Blank lines don't count towards the common indentation
+        let src = "\"\"\"\n    a\n\n  \n    b\"\"\"";
+        assert_eq!(lex_shown(src), vec!["a\n\n\nb"]);
+        // Without a leading newline, the first line is part of the literal
+        assert_eq!(lex_shown(r#""""a
+b""""#), vec!["a\nb"]);
+        // Escapes are processed after the indentation is stripped
+        let src = "\"\"\"\n  \\t a\n  b\\n\n  \"\"\"";
+        assert_eq!(lex_shown(src), vec!["\t a\nb\n"]);
+        let err = lex("\"\"\"a\n").unwrap_err();
+        assert!(err.contains("Unterminated string literal"), "{}", err);
+    }
+}
@@ -0,0 +1,272 @@
+//! Checking that the `RealWorld` is used linearly
+//!
+//! A value of type `RealWorld` stands for the state of the world outside of the program, at
+//! some point in time. If a variable of the world is used more than once, two IO actions may
+//! act on the same state of the world, and their effects may be duplicated or reordered by
+//! the compiler. If it's not used at all, the effects of the IO actions that led up to it
+//! may be dropped. Such variables are reported with the lint `nonlinear-real-world`.
+
+use lib::grow_stack;
+use std::cmp::{max, min};
+use super::ast::*;
+use super::lint::{self, NONLINEAR_REAL_WORLD};
+
+fn is_real_world(t: &Type) -> bool {
+    *t == *TYPE_REALWORLD
+}
+
+/// The fewest and the greatest number of times that a variable may be used in a single
+/// evaluation of an expression
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Uses {
+    min: usize,
+    max: usize,
+}
+
+impl Uses {
+    fn exactly(n: usize) -> Uses {
+        Uses { min: n, max: n }
+    }
+
+    fn then(self, other: Uses) -> Uses {
+        Uses {
+            min: self.min + other.min,
+            max: self.max + other.max,
+        }
+    }
+
+    /// The uses of either `self` or `other`, depending on which branch is taken
+    fn or(self, other: Uses) -> Uses {
+        Uses {
+            min: min(self.min, other.min),
+            max: max(self.max, other.max),
+        }
+    }
+}
+
+/// The uses of the variable `name` in a single evaluation of `e`
+fn uses(name: &str, e: &Expr) -> Uses {
+    use self::Expr::*;
+    let sum = |es: &[Expr]| {
+        es.iter()
+            .fold(Uses::exactly(0), |acc, e| acc.then(uses(name, e)))
+    };
+    match *e {
+        Nil(_) | NumLit(_) | StrLit(_) | BytesLit(_) | SymLit(_) | Bool(_) => Uses::exactly(0),
+        Variable(ref v) => Uses::exactly((v.ident.s == name) as usize),
+        App(ref app) => uses(name, &app.func).then(uses(name, &app.arg)),
+        If(ref cond) => uses(name, &cond.predicate)
+            .then(uses(name, &cond.consequent).or(uses(name, &cond.alternative))),
+        Lambda(ref l) if l.param_ident.s == name => Uses::exactly(0),
+        Lambda(ref l) => uses(name, &l.body),
+        Let(ref l) if l.bindings.bindings().any(|b| b.ident.s == name) => Uses::exactly(0),
+        Let(ref l) => uses_in_let(name, l),
+        TypeAscript(ref a) => uses(name, &a.expr),
+        Cons(ref c) => uses(name, &c.car).then(uses(name, &c.cdr)),
+        Car(ref c) => uses(name, &c.expr),
+        Cdr(ref c) => uses(name, &c.expr),
+        Cast(ref c) => uses(name, &c.expr),
+        Transmute(ref t) => uses(name, &t.expr),
+        New(ref n) => sum(&n.members),
+        Match(ref m) => {
+            let mut in_cases = m.cases.iter().map(|case| {
+                if case.patt.variable_names().contains(name) {
+                    Uses::exactly(0)
+                } else {
+                    uses(name, &case.body)
+                }
+            });
+            let first = in_cases.next().unwrap_or(Uses::exactly(0));
+            let in_cases = in_cases.fold(first, Uses::or);
+            uses(name, &m.expr).then(in_cases)
+        }
+        Assert(ref a) => uses(name, &a.cond),
+        WithArena(ref w) => uses(name, &w.body),
+        Loop(ref l) if l.vars.iter().any(|v| v.ident.s == name) => sum(&l.inits),
+        Loop(ref l) => sum(&l.inits).then(uses(name, &l.body)),
+        Recur(ref r) => sum(&r.args),
+        Begin(ref b) => sum(&b.exprs),
+        PtrOp(ref p) => sum(&p.args),
+        ThreadOp(ref t) => sum(&t.args),
+    }
+}
+
+/// The uses of `name` in the bindings and body of `l`, regardless of whether `l` binds it
+fn uses_in_let(name: &str, l: &Let) -> Uses {
+    l.bindings
+        .bindings()
+        .fold(uses(name, &l.body), |acc, b| acc.then(uses(name, &b.val)))
+}
+
+fn check_var(ident: &Ident, uses: Uses) {
+    if uses.max > 1 {
+        lint::emit(
+            &NONLINEAR_REAL_WORLD,
+            &ident.pos,
+            format!(
+                "The world `{}` may be used {} times. Pass on the world returned by each IO \
+                 action instead",
+                ident.s, uses.max
+            ),
+        )
+    } else if uses.min == 0 {
+        lint::emit(
+            &NONLINEAR_REAL_WORLD,
+            &ident.pos,
+            format!(
+                "The world `{}` may be dropped without being used. Pass it on to an IO \
+                 action, or return it",
+                ident.s
+            ),
+        )
+    }
+}
+
+fn check_binding_insts(b: &Binding) {
+    if b.sig.is_monomorphic() {
+        check_expr(&b.val)
+    } else {
+        for inst in b.mono_insts.values() {
+            check_expr(inst)
+        }
+    }
+}
+
+fn check_expr(e: &Expr) {
+    grow_stack(|| {
+        use self::Expr::*;
+        match *e {
+            Nil(_) | NumLit(_) | StrLit(_) | BytesLit(_) | SymLit(_) | Bool(_) | Variable(_) => (),
+            App(ref app) => {
+                check_expr(&app.func);
+                check_expr(&app.arg)
+            }
+            If(ref cond) => {
+                check_expr(&cond.predicate);
+                check_expr(&cond.consequent);
+                check_expr(&cond.alternative)
+            }
+            Lambda(ref l) => {
+                if l.typ.get_func().map(|(p, _)| is_real_world(p)).unwrap_or(false) {
+                    check_var(&l.param_ident, uses(l.param_ident.s, &l.body))
+                }
+                check_expr(&l.body)
+            }
+            Let(ref l) => {
+                for b in l.bindings.bindings() {
+                    if is_real_world(&b.sig.body) {
+                        check_var(&b.ident, uses_in_let(b.ident.s, l))
+                    }
+                    check_binding_insts(b)
+                }
+                check_expr(&l.body)
+            }
+            TypeAscript(ref a) => check_expr(&a.expr),
+            Cons(ref c) => {
+                check_expr(&c.car);
+                check_expr(&c.cdr)
+            }
+            Car(ref c) => check_expr(&c.expr),
+            Cdr(ref c) => check_expr(&c.expr),
+            Cast(ref c) => check_expr(&c.expr),
+            Transmute(ref t) => check_expr(&t.expr),
+            New(ref n) => n.members.iter().for_each(check_expr),
+            Match(ref m) => {
+                check_expr(&m.expr);
+                for case in &m.cases {
+                    for v in case.patt.variables() {
+                        if is_real_world(&v.typ) {
+                            check_var(&v.ident, uses(v.ident.s, &case.body))
+                        }
+                    }
+                    check_expr(&case.body)
+                }
+            }
+            Assert(ref a) => check_expr(&a.cond),
+            WithArena(ref w) => check_expr(&w.body),
+            Loop(ref l) => {
+                l.inits.iter().for_each(check_expr);
+                for v in &l.vars {
+                    if is_real_world(&v.typ) {
+                        check_var(&v.ident, uses(v.ident.s, &l.body))
+                    }
+                }
+                check_expr(&l.body)
+            }
+            Recur(ref r) => r.args.iter().for_each(check_expr),
+            Begin(ref b) => b.exprs.iter().for_each(check_expr),
+            PtrOp(ref p) => p.args.iter().for_each(check_expr),
+            ThreadOp(ref t) => t.args.iter().for_each(check_expr),
+        }
+    })
+}
+
+/// Report variables of the world that are not used linearly in the monomorphic instances
+/// of the global bindings
+pub fn check_real_world_linearity(globals: &TopologicallyOrderedDependencyGroups) {
+    for b in globals.bindings() {
+        check_binding_insts(b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {check_str, Options};
+
+    const PRINT: &str =
+        "(extern-io print_int64 (-> (Cons Int64 RealWorld) (Cons Nil RealWorld)))\n";
+
+    /// Check the program `src`, with the extern `print_int64` declared
+    fn check(src: &str) -> Result<String, String> {
+        check_str(&format!("{}{}", PRINT, src), Options::default())
+            .map(|warnings| warnings.0)
+            .map_err(|errors| errors.0)
+    }
+
+    #[test]
+    fn test_world_used_twice() {
+        let errs = check(
+            "(define: (f w) (-> RealWorld (Cons Nil RealWorld))
+  (let [[r (print_int64 (cons 1 w))]]
+    (print_int64 (cons 2 w))))",
+        ).unwrap_err();
+        assert!(errs.contains("The world `w` may be used 2 times"), "{}", errs);
+    }
+
+    #[test]
+    fn test_world_dropped() {
+        let errs = check("(define: (f w) (-> RealWorld Nil) nil)").unwrap_err();
+        assert!(errs.contains("The world `w` may be dropped"), "{}", errs);
+
+        let errs = check(
+            "(define: (f w) (-> RealWorld Nil)
+  (let [[w2 (cdr (print_int64 (cons 1 w)))]]
+    nil))",
+        ).unwrap_err();
+        assert!(errs.contains("The world `w2` may be dropped"), "{}", errs);
+        assert!(!errs.contains("The world `w` "), "{}", errs);
+
+        let errs = check(
+            "(define: (f b w) (-> Bool (-> RealWorld Nil))
+  (if b
+      (car (print_int64 (cons 1 w)))
+      nil))",
+        ).unwrap_err();
+        assert!(errs.contains("The world `w` may be dropped"), "{}", errs);
+    }
+
+    #[test]
+    fn test_world_used_once() {
+        let warns = check(
+            "(define: (f w) (-> RealWorld (Cons Nil RealWorld))
+  (let [[w2 (cdr (print_int64 (cons 1 w)))]]
+    (print_int64 (cons 2 w2))))
+
+(define: (g b w) (-> Bool (-> RealWorld (Cons Nil RealWorld)))
+  (if b
+      (print_int64 (cons 1 w))
+      (print_int64 (cons 2 w))))",
+        ).unwrap();
+        assert_eq!(warns, "");
+    }
+}
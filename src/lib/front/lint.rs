@@ -0,0 +1,238 @@
+//! Named, configurable warnings
+//!
+//! Each lint has a level, which is its default unless changed by `--allow`, `--warn`, or
+//! `--deny` on the command line, or by `Options::lint_levels` for the library. A lint can
+//! also be silenced locally by wrapping a binding in an `allow` form
+//!
+//! ```scheme
+//! (allow (unused-let-binding)
+//!   (define (main real-world)
+//!     (let [[x 1]]
+//!       (cons (new Nil) real-world))))
+//! ```
+//!
+//! Lints at level deny are reported as errors, and compilation is aborted at the end of the
+//! current pass, so that all denied lints of the pass are reported together.
+//!
+//! The levels, local `allow`s, and denied lints are kept per compilation, in the thread of
+//! the compilation. See `in_compilation`.
+
+use lib::ErrCode;
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::mem;
+use std::path::PathBuf;
+use super::{Diagnostic, SrcPos};
+
+/// Pseudo-name for setting the level of all lints at once, e.g. `--deny warnings`
+pub const ALL_LINTS: &'static str = "warnings";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+pub struct Lint {
+    pub name: &'static str,
+    pub default_level: Level,
+    pub desc: &'static str,
+}
+
+pub static UNUSED_LET_BINDING: Lint = Lint {
+    name: "unused-let-binding",
+    default_level: Level::Warn,
+    desc: "a variable bound in a `let` is never used. Prefix the name with `_` to silence",
+};
+
+pub static NONLINEAR_REAL_WORLD: Lint = Lint {
+    name: "nonlinear-real-world",
+    default_level: Level::Deny,
+    desc: "a variable of the `RealWorld` may be used more than once, or not at all",
+};
+
+pub static NON_EXHAUSTIVE_PATTERNS: Lint = Lint {
+    name: "non-exhaustive-patterns",
+    default_level: Level::Warn,
+    desc: "the cases of a `match` may not match every value, and the program may abort",
+};
+
+pub static UNREACHABLE_PATTERN: Lint = Lint {
+    name: "unreachable-pattern",
+    default_level: Level::Warn,
+    desc: "a case of a `match` is never reached, as earlier cases match all of its values",
+};
+
+pub static GLOBAL_INIT_CYCLE: Lint = Lint {
+    name: "global-init-cycle",
+    default_level: Level::Deny,
+    desc: "a global variable depends on itself, and may be read before it's initialized",
+};
+
+/// All lints known to the compiler
+pub static LINTS: &[&Lint] = &[
+    &UNUSED_LET_BINDING,
+    &NONLINEAR_REAL_WORLD,
+    &NON_EXHAUSTIVE_PATTERNS,
+    &UNREACHABLE_PATTERN,
+    &GLOBAL_INIT_CYCLE,
+];
+
+/// A section of source code in which some lints are allowed
+#[derive(Clone)]
+struct AllowedSpan {
+    filename: PathBuf,
+    start: usize,
+    end: usize,
+    lints: Vec<&'static str>,
+}
+
+/// The level of each lint, for a compilation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Levels(Vec<(&'static str, Level)>);
+
+impl Levels {
+    /// Set the level of the lint named `name`, or all lints if `name` is `warnings`
+    ///
+    /// Returns an error if there is no lint named `name`
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), String> {
+        if name == ALL_LINTS {
+            for &mut (_, ref mut l) in &mut self.0 {
+                *l = level;
+            }
+            Ok(())
+        } else {
+            match self.0.iter_mut().find(|&&mut (n, _)| n == name) {
+                Some(&mut (_, ref mut l)) => {
+                    *l = level;
+                    Ok(())
+                }
+                None => Err(format!("Unknown lint `{}`", name)),
+            }
+        }
+    }
+
+    fn get(&self, lint: &Lint) -> Level {
+        self.0
+            .iter()
+            .find(|&&(n, _)| n == lint.name)
+            .map(|&(_, l)| l)
+            .unwrap_or(lint.default_level)
+    }
+}
+
+/// The default levels of all lints
+impl Default for Levels {
+    fn default() -> Self {
+        Levels(LINTS.iter().map(|l| (l.name, l.default_level)).collect())
+    }
+}
+
+#[derive(Default)]
+struct LintState {
+    levels: Levels,
+    allowed_spans: Vec<AllowedSpan>,
+    n_denied: usize,
+}
+
+thread_local! {
+    /// The lint state of the compilation running in this thread
+    static STATE: RefCell<LintState> = RefCell::new(LintState::default());
+}
+
+/// Restores the lint state of an enclosing compilation when dropped, also on unwinding
+struct StateGuard(Option<LintState>);
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        let prev = self.0.take().expect("ICE: lint state restored twice");
+        STATE.with(|s| *s.borrow_mut() = prev)
+    }
+}
+
+/// Run the compilation `f` with the lint levels `levels`, and no local `allow`s or denied
+/// lints of any previous compilation
+pub fn in_compilation<R, F: FnOnce() -> R>(levels: &Levels, f: F) -> R {
+    let state = LintState {
+        levels: levels.clone(),
+        ..LintState::default()
+    };
+    let _guard = StateGuard(Some(STATE.with(|s| mem::replace(&mut *s.borrow_mut(), state))));
+    f()
+}
+
+/// Find the lint named `name`
+pub fn lint_by_name(name: &str) -> Option<&'static Lint> {
+    LINTS.iter().cloned().find(|l| l.name == name)
+}
+
+/// Allow the lints `lints` in the section of source code `pos`
+pub fn allow_in<'s>(pos: &SrcPos<'s>, lints: Vec<&'static str>) {
+    STATE.with(|s| {
+        s.borrow_mut().allowed_spans.push(AllowedSpan {
+            filename: pos.filename.to_path_buf(),
+            start: pos.start,
+            end: pos.end.unwrap_or(pos.start + 1),
+            lints,
+        })
+    })
+}
+
+/// The sections of source code in which some lints are allowed
+#[derive(Clone, Default)]
+pub struct Allowed(Vec<AllowedSpan>);
+
+/// The local `allow`s of the current compilation so far, to be restored with `restore_allowed`
+/// in another compilation of the same sources
+pub fn allowed() -> Allowed {
+    STATE.with(|s| Allowed(s.borrow().allowed_spans.clone()))
+}
+
+/// Allow the lints of `allowed` in their sections of source code, as in the compilation
+/// they were taken from
+pub fn restore_allowed(allowed: &Allowed) {
+    STATE.with(|s| s.borrow_mut().allowed_spans.extend(allowed.0.iter().cloned()))
+}
+
+/// Report an occurence of `lint` at `pos`, according to the level of the lint
+pub fn emit<'s, S: Display>(lint: &Lint, pos: &SrcPos<'s>, msg: S) {
+    STATE.with(|s| emit_in(&mut s.borrow_mut(), lint, pos, msg))
+}
+
+fn emit_in<'s, S: Display>(state: &mut LintState, lint: &Lint, pos: &SrcPos<'s>, msg: S) {
+    let is_locally_allowed = state.allowed_spans.iter().any(|span| {
+        span.filename == pos.filename && span.start <= pos.start && pos.start < span.end
+            && span.lints.contains(&lint.name)
+    });
+    let level = state.levels.get(lint);
+    match level {
+        _ if is_locally_allowed => (),
+        Level::Allow => (),
+        Level::Warn => {
+            pos.print_warn(format!("{}\n[{}]", msg, lint.name));
+        }
+        Level::Deny => {
+            let code = ErrCode {
+                module: "lint",
+                number: 0,
+            };
+            pos.print_error(code, format!("{}\n[{}] is denied", msg, lint.name));
+            state.n_denied += 1;
+        }
+    }
+}
+
+/// Fail the compilation if any denied lints have been reported
+pub fn check_denied() -> Result<(), Diagnostic> {
+    let n = STATE.with(|s| s.borrow().n_denied);
+    if n > 0 {
+        Err(Diagnostic(format!(
+            "\nAborting due to {} denied lint{}\n",
+            n,
+            if n == 1 { "" } else { "s" }
+        )))
+    } else {
+        Ok(())
+    }
+}
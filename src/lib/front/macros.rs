@@ -1,7 +1,58 @@
-use lib::{map_of, set_of};
-use super::SrcPos;
+use lib::{grow_stack, map_of, set_of};
+use super::{with_diagnostics_out, Diagnostic, SrcPos};
 use super::cst::*;
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+
+/// Which macro expansions to print, as given by `--trace-macros`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trace {
+    /// Trace no expansions
+    Off,
+    /// Trace the expansions of all macros
+    All,
+    /// Trace the expansions of the macro of the name only
+    Only(String),
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Trace::Off
+    }
+}
+
+impl Trace {
+    fn traces(&self, name: &str) -> bool {
+        match *self {
+            Trace::Off => false,
+            Trace::All => true,
+            Trace::Only(ref n) => n == name,
+        }
+    }
+}
+
+thread_local! {
+    /// Which macro expansions to trace in the current compilation. See `in_compilation`
+    static TRACE: RefCell<Trace> = RefCell::new(Trace::Off);
+}
+
+/// Restores the trace of the enclosing compilation when dropped
+struct TraceGuard(Option<Trace>);
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let prev = self.0.take().expect("ICE: macro trace restored twice");
+        TRACE.with(|t| *t.borrow_mut() = prev)
+    }
+}
+
+/// Run the compilation `f` tracing the macro expansions of `trace`, and restore the trace of
+/// any enclosing compilation afterwards
+pub fn in_compilation<R, F: FnOnce() -> R>(trace: Trace, f: F) -> R {
+    let _guard = TraceGuard(Some(TRACE.with(|t| mem::replace(&mut *t.borrow_mut(), trace))));
+    f()
+}
 
 fn match_multi<'s>(
     ps: &[Pattern<'s>],
@@ -29,13 +80,31 @@ fn match_multi<'s>(
         }
         let bindings = binding_vs
             .into_iter()
-            .map(|(k, vs)| (k, Cst::Sexpr(vs, pos.clone())))
+            .map(|(k, vs)| {
+                let vs_pos = span_of(&vs).unwrap_or(pos.clone());
+                (k, Cst::Sexpr(vs, vs_pos))
+            })
             .collect();
         Some(bindings)
     }
 }
 
-#[derive(Debug)]
+/// The position spanning the argument syntax trees `cs`, from the first to the last
+///
+/// Used for the list of the arguments matched by a repeated pattern, so that diagnostics
+/// about the list point at the arguments, and not at the whole macro invocation. `None` if
+/// there are no arguments, or if they don't come from the same place, e.g. when some of them
+/// are from the template of another macro.
+fn span_of<'s>(cs: &[Cst<'s>]) -> Option<SrcPos<'s>> {
+    let (first, last) = (cs.first()?.pos(), cs.last()?.pos());
+    if first.filename() == last.filename() && first <= last {
+        Some(first.to(last))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Pattern<'s> {
     Lit(&'s str),
     Ident(&'s str),
@@ -51,6 +120,21 @@ impl<'s> Pattern<'s> {
         }
     }
 
+    /// Find a list pattern with more than one repeated pattern, which can never match, as it
+    /// would be ambiguous how many arguments each repetition should match
+    pub fn ambiguous_repetition(&self) -> bool {
+        match *self {
+            Pattern::Lit(_) | Pattern::Ident(_) => false,
+            Pattern::Multi(ref ps) => {
+                ps.iter().any(|p| p.is_multi() || p.ambiguous_repetition())
+            }
+            Pattern::Sexpr(ref ps) => {
+                ps.iter().filter(|p| p.is_multi()).count() > 1
+                    || ps.iter().any(|p| p.ambiguous_repetition())
+            }
+        }
+    }
+
     fn idents(&self) -> BTreeSet<&'s str> {
         match *self {
             Pattern::Lit(_) => BTreeSet::new(),
@@ -103,74 +187,156 @@ impl<'s> Pattern<'s> {
     }
 }
 
+#[derive(Clone)]
 pub struct Case<'s> {
     pub pattern: Pattern<'s>,
     pub bodies: Vec<Cst<'s>>,
 }
 
+impl<'s> Case<'s> {
+    /// Find an identifier spliced into a template with `(... ID)`, that is not a variable of
+    /// the pattern
+    ///
+    /// Only the list an argument is bound to can be spliced, so such a splice would always fail.
+    pub fn unbound_splice(&self) -> Option<(&'s str, &SrcPos<'s>)> {
+        let bound = self.pattern.idents();
+        self.bodies
+            .iter()
+            .filter_map(|body| unbound_splice_in(body, &bound))
+            .next()
+    }
+}
+
+fn unbound_splice_in<'c, 's>(
+    cst: &'c Cst<'s>,
+    bound: &BTreeSet<&'s str>,
+) -> Option<(&'s str, &'c SrcPos<'s>)> {
+    match *cst {
+        Cst::Sexpr(ref cs, _) => match cs.first() {
+            Some(&Cst::Ident("...", _)) => cs[1..]
+                .iter()
+                .filter_map(|c| match *c {
+                    Cst::Ident(id, ref pos) if !bound.contains(id) => Some((id, pos)),
+                    _ => unbound_splice_in(c, bound),
+                })
+                .next(),
+            _ => cs.iter().filter_map(|c| unbound_splice_in(c, bound)).next(),
+        },
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
 pub struct Macro<'s> {
     pub name: &'s str,
     pub cases: Vec<Case<'s>>,
 }
 
 impl<'s> Macro<'s> {
-    fn apply(&self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> Vec<Cst<'s>> {
+    /// Expand the invocation of this macro with the arguments `csts`
+    ///
+    /// Returns the index of the case that matched, and the expansion
+    fn apply(
+        &self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> Result<(usize, Vec<Cst<'s>>), Diagnostic> {
         let args = Cst::Sexpr(csts.to_vec(), pos.clone());
-        let (bindings, bodies) = self.cases
+        let (i, bindings, bodies) = self.cases
             .iter()
-            .filter_map(|case| {
+            .enumerate()
+            .filter_map(|(i, case)| {
                 case.pattern
                     .match_(&args)
-                    .map(|bindings| (bindings, &case.bodies))
+                    .map(|bindings| (i, bindings, &case.bodies))
             })
             .next()
-            .unwrap_or_else(|| pos.error_exit("No macro pattern matched token trees"));
-        bodies
-            .iter()
-            .flat_map(|body| subst(body, &bindings))
-            .collect()
+            .ok_or_else(|| pos.diagnostic("No macro pattern matched token trees"))?;
+        let mut expansion = Vec::new();
+        for body in bodies {
+            expansion.extend(subst(body, &bindings)?)
+        }
+        Ok((i, expansion))
     }
 }
 
-fn subst<'s>(cst: &Cst<'s>, s: &BTreeMap<&'s str, Cst<'s>>) -> Vec<Cst<'s>> {
+fn subst<'s>(
+    cst: &Cst<'s>,
+    s: &BTreeMap<&'s str, Cst<'s>>,
+) -> Result<Vec<Cst<'s>>, Diagnostic> {
     match *cst {
-        Cst::Ident(id, _) if s.contains_key(id) => vec![s[id].clone()],
+        Cst::Ident(id, _) if s.contains_key(id) => Ok(vec![s[id].clone()]),
         Cst::Sexpr(ref cs, ref pos) => {
+            let mut substituted = Vec::new();
             if let Some(&Cst::Ident("...", _)) = cs.first() {
-                cs[1..]
-                    .iter()
-                    .flat_map(|c| {
-                        subst(c, s).into_iter().flat_map(|c2| match c2 {
-                            Cst::Sexpr(ref cs2, _) => cs2.clone(),
-                            _ => pos.error_exit("Can't flatten non-list"),
-                        })
-                    })
-                    .collect()
+                for c in &cs[1..] {
+                    for c2 in subst(c, s)? {
+                        match c2 {
+                            Cst::Sexpr(cs2, _) => substituted.extend(cs2),
+                            _ => return Err(pos.diagnostic("Can't flatten non-list")),
+                        }
+                    }
+                }
+                Ok(substituted)
             } else {
-                vec![
-                    Cst::Sexpr(cs.iter().flat_map(|c| subst(c, s)).collect(), pos.clone()),
-                ]
+                for c in cs {
+                    substituted.extend(subst(c, s)?)
+                }
+                Ok(vec![Cst::Sexpr(substituted, pos.clone())])
             }
         }
-        _ => vec![cst.clone()],
+        _ => Ok(vec![cst.clone()]),
     }
 }
 
-pub fn expand_macros<'s>(cst: &Cst<'s>, macros: &BTreeMap<&'s str, Macro<'s>>) -> Vec<Cst<'s>> {
-    match *cst {
-        Cst::Sexpr(ref cs, ref pos) if !cs.is_empty() => match cs[0] {
-            Cst::Ident(id, _) if macros.contains_key(id) => macros[id]
-                .apply(&cs[1..], pos)
-                .iter()
-                .flat_map(|c| expand_macros(&c, macros))
-                .collect(),
-            _ => vec![
-                Cst::Sexpr(
-                    cs.iter().flat_map(|c| expand_macros(c, macros)).collect(),
-                    pos.clone(),
-                ),
-            ],
-        },
-        _ => vec![cst.clone()],
-    }
+/// Print the expansion of the invocation `cst` of the macro `name` by the case `case`, to
+/// `expansion`, indented by the depth of the expansion, with the diagnostics
+fn print_trace(name: &str, case: usize, cst: &Cst, expansion: &[Cst], depth: usize) {
+    let indent = "  ".repeat(depth);
+    let expansion_s = expansion.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    with_diagnostics_out(|w| {
+        writeln!(w, "{}{} case {}: {}", indent, name, case, cst).ok();
+        writeln!(w, "{}=> {}", indent, expansion_s.join(" ")).ok();
+    })
+}
+
+fn expand_macros_at_depth<'s>(
+    cst: &Cst<'s>,
+    macros: &BTreeMap<&'s str, Macro<'s>>,
+    depth: usize,
+) -> Result<Vec<Cst<'s>>, Diagnostic> {
+    grow_stack(|| {
+        let mut expanded = Vec::new();
+        match *cst {
+            Cst::Sexpr(ref cs, ref pos) if !cs.is_empty() => match cs[0] {
+                Cst::Ident(id, _) if macros.contains_key(id) => {
+                    let (case, expansion) = macros[id].apply(&cs[1..], pos)?;
+                    if TRACE.with(|t| t.borrow().traces(id)) {
+                        print_trace(id, case, cst, &expansion, depth)
+                    }
+                    for c in &expansion {
+                        expanded.extend(expand_macros_at_depth(c, macros, depth + 1)?)
+                    }
+                    Ok(expanded)
+                }
+                _ => {
+                    for c in cs {
+                        expanded.extend(expand_macros_at_depth(c, macros, depth)?)
+                    }
+                    Ok(vec![Cst::Sexpr(expanded, pos.clone())])
+                }
+            },
+            _ => Ok(vec![cst.clone()]),
+        }
+    })
+}
+
+/// Recursively expand all macro invocations in `cst`
+///
+/// Each expansion is printed if traced in the current compilation. See `in_compilation`.
+pub fn expand_macros<'s>(
+    cst: &Cst<'s>,
+    macros: &BTreeMap<&'s str, Macro<'s>>,
+) -> Result<Vec<Cst<'s>>, Diagnostic> {
+    expand_macros_at_depth(cst, macros, 0)
 }
@@ -0,0 +1,404 @@
+//! Checking the exhaustiveness and redundancy of the patterns of `match` expressions
+//!
+//! The cases of a match are checked with the usefulness algorithm of Maranget's
+//! "Warnings for pattern matching". A case is unreachable if its pattern is not useful with
+//! respect to the patterns of the cases before it, and a match is non-exhaustive if a
+//! wildcard pattern would be useful after all of its cases. Such matches are reported with
+//! the lints `unreachable-pattern` and `non-exhaustive-patterns`.
+
+use lib::grow_stack;
+use super::ast::*;
+use super::lint::{self, NON_EXHAUSTIVE_PATTERNS, UNREACHABLE_PATTERN};
+
+/// The head of a pattern that is not a wildcard
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Ctor<'s> {
+    Variant(&'s str),
+    Bool(bool),
+    Nil,
+    Num(&'s str),
+    Range(&'s str, &'s str),
+    Str(&'s str),
+}
+
+impl<'s> Ctor<'s> {
+    fn to_string(&self, args: &[String]) -> String {
+        let head = match *self {
+            Ctor::Variant(v) => v.to_string(),
+            Ctor::Bool(b) => b.to_string(),
+            Ctor::Nil => "nil".to_string(),
+            Ctor::Num(n) => n.to_string(),
+            Ctor::Range(lo, hi) => format!("(range {} {})", lo, hi),
+            Ctor::Str(s) => format!("{:?}", s),
+        };
+        if args.is_empty() {
+            head
+        } else {
+            format!("({} {})", head, args.join(" "))
+        }
+    }
+}
+
+/// A pattern reduced to what matters for the analysis
+#[derive(Clone, Debug)]
+enum Pat<'s> {
+    Wild,
+    Ctor(Ctor<'s>, Vec<Pat<'s>>),
+}
+
+fn pat_of<'s>(p: &'s Pattern) -> Pat<'s> {
+    match *p {
+        Pattern::Nil(_) => Pat::Ctor(Ctor::Nil, vec![]),
+        Pattern::NumLit(ref n) => Pat::Ctor(Ctor::Num(&n.lit), vec![]),
+        // Overlap of ranges with each other and with literals is not analyzed. A range only
+        // covers an identical range
+        Pattern::Range(ref r) => Pat::Ctor(Ctor::Range(&r.lo.lit, &r.hi.lit), vec![]),
+        Pattern::StrLit(ref s) => Pat::Ctor(Ctor::Str(&s.lit), vec![]),
+        Pattern::Bool(ref b) => Pat::Ctor(Ctor::Bool(b.val), vec![]),
+        Pattern::Variable(_) => Pat::Wild,
+        Pattern::Deconstr(ref d) => Pat::Ctor(
+            Ctor::Variant(d.constr.s),
+            d.subpatts.iter().map(pat_of).collect(),
+        ),
+    }
+}
+
+struct Checker<'a, 's: 'a> {
+    adts: &'a Adts<'s>,
+}
+
+impl<'a, 's: 'a> Checker<'a, 's> {
+    fn arity(&self, c: Ctor) -> usize {
+        match c {
+            Ctor::Variant(v) => self.adts
+                .adt_variant_of_name(v)
+                .map(|variant| variant.members.len())
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// All constructors of the type of `c`, if finitely many
+    fn all_ctors<'p>(&self, c: Ctor<'p>) -> Option<Vec<Ctor<'p>>>
+    where
+        's: 'p,
+    {
+        match c {
+            Ctor::Variant(v) => self.adts.parent_adt_of_variant(v).map(|adt| {
+                adt.variants
+                    .iter()
+                    .map(|variant| Ctor::Variant(variant.name.s))
+                    .collect()
+            }),
+            Ctor::Bool(_) => Some(vec![Ctor::Bool(false), Ctor::Bool(true)]),
+            Ctor::Nil => Some(vec![Ctor::Nil]),
+            Ctor::Num(_) | Ctor::Range(..) | Ctor::Str(_) => None,
+        }
+    }
+
+    /// The row `row` with a head matching `c` replaced by the subpatterns of the head,
+    /// or `None` if the head does not match `c`
+    fn specialize<'p>(&self, row: &[Pat<'p>], c: Ctor<'p>) -> Option<Vec<Pat<'p>>> {
+        let arity = self.arity(c);
+        let mut specialized = match row[0] {
+            Pat::Wild => vec![Pat::Wild; arity],
+            Pat::Ctor(c2, ref subs) if c2 == c => {
+                let mut subs = subs.clone();
+                subs.resize(arity, Pat::Wild);
+                subs
+            }
+            Pat::Ctor(..) => return None,
+        };
+        specialized.extend_from_slice(&row[1..]);
+        Some(specialized)
+    }
+
+    /// If the row `q` is useful with respect to the rows `rows`, i.e. if some value is
+    /// matched by `q` but by none of `rows`, return an example of such a value
+    fn useful<'p>(&self, rows: &[Vec<Pat<'p>>], q: &[Pat<'p>]) -> Option<Vec<String>>
+    where
+        's: 'p,
+    {
+        if q.is_empty() {
+            return if rows.is_empty() { Some(vec![]) } else { None };
+        }
+        let rebuild = |c: Ctor, mut witness: Vec<String>| {
+            let rest = witness.split_off(self.arity(c));
+            let mut rebuilt = vec![c.to_string(&witness)];
+            rebuilt.extend(rest);
+            rebuilt
+        };
+        match q[0] {
+            Pat::Ctor(c, _) => {
+                let rows = rows.iter()
+                    .filter_map(|row| self.specialize(row, c))
+                    .collect::<Vec<_>>();
+                let q = self.specialize(q, c).unwrap();
+                self.useful(&rows, &q).map(|w| rebuild(c, w))
+            }
+            Pat::Wild => {
+                let mut heads = Vec::new();
+                for row in rows {
+                    if let Pat::Ctor(c, _) = row[0] {
+                        if !heads.contains(&c) {
+                            heads.push(c)
+                        }
+                    }
+                }
+                let all = heads.first().and_then(|&c| self.all_ctors(c));
+                let missing = all.as_ref()
+                    .map(|all| all.iter().cloned().find(|c| !heads.contains(c)));
+                match (all, missing) {
+                    // The heads are all constructors of the type
+                    (Some(all), Some(None)) => all.into_iter()
+                        .filter_map(|c| {
+                            let rows = rows.iter()
+                                .filter_map(|row| self.specialize(row, c))
+                                .collect::<Vec<_>>();
+                            let q = self.specialize(q, c).unwrap();
+                            self.useful(&rows, &q).map(|w| rebuild(c, w))
+                        })
+                        .next(),
+                    (_, missing) => {
+                        let default = rows.iter()
+                            .filter(|row| match row[0] {
+                                Pat::Wild => true,
+                                _ => false,
+                            })
+                            .map(|row| row[1..].to_vec())
+                            .collect::<Vec<_>>();
+                        self.useful(&default, &q[1..]).map(|mut w| {
+                            let head = match missing {
+                                Some(Some(c)) => {
+                                    c.to_string(&vec!["_".to_string(); self.arity(c)])
+                                }
+                                _ => "_".to_string(),
+                            };
+                            w.insert(0, head);
+                            w
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_match(&self, m: &Match<'s>) {
+        let mut rows: Vec<Vec<Pat>> = Vec::new();
+        for case in &m.cases {
+            let row = vec![pat_of(&case.patt)];
+            if self.useful(&rows, &row).is_none() {
+                lint::emit(
+                    &UNREACHABLE_PATTERN,
+                    &case.pos,
+                    format!(
+                        "Unreachable case. Every value matched by `{}` is matched by an \
+                         earlier case",
+                        case.patt.pos().text()
+                    ),
+                )
+            }
+            rows.push(row);
+        }
+        if let Some(witness) = self.useful(&rows, &[Pat::Wild]) {
+            let only_num_lits = m.cases.iter().all(|case| match case.patt {
+                Pattern::NumLit(_) | Pattern::Range(_) => true,
+                _ => false,
+            });
+            let msg = if only_num_lits {
+                "Non-exhaustive patterns in match. Numeric literal and range cases are never \
+                 considered to cover every value. Add a catch-all case `_`"
+                    .to_string()
+            } else {
+                format!(
+                    "Non-exhaustive patterns in match. `{}` is not covered",
+                    witness[0]
+                )
+            };
+            lint::emit(&NON_EXHAUSTIVE_PATTERNS, &m.pos, msg)
+        }
+    }
+
+    fn check_expr(&self, e: &Expr<'s>) {
+        grow_stack(|| {
+            use self::Expr::*;
+            match *e {
+                Nil(_) | NumLit(_) | StrLit(_) | BytesLit(_) | SymLit(_) | Bool(_) => (),
+                Variable(_) => (),
+                App(ref app) => {
+                    self.check_expr(&app.func);
+                    self.check_expr(&app.arg)
+                }
+                If(ref cond) => {
+                    self.check_expr(&cond.predicate);
+                    self.check_expr(&cond.consequent);
+                    self.check_expr(&cond.alternative)
+                }
+                Lambda(ref l) => self.check_expr(&l.body),
+                Let(ref l) => {
+                    for b in l.bindings.bindings() {
+                        self.check_expr(&b.val)
+                    }
+                    self.check_expr(&l.body)
+                }
+                TypeAscript(ref a) => self.check_expr(&a.expr),
+                Cons(ref c) => {
+                    self.check_expr(&c.car);
+                    self.check_expr(&c.cdr)
+                }
+                Car(ref c) => self.check_expr(&c.expr),
+                Cdr(ref c) => self.check_expr(&c.expr),
+                Cast(ref c) => self.check_expr(&c.expr),
+                Transmute(ref t) => self.check_expr(&t.expr),
+                New(ref n) => n.members.iter().for_each(|m| self.check_expr(m)),
+                Match(ref m) => {
+                    self.check_expr(&m.expr);
+                    self.check_match(m);
+                    for case in &m.cases {
+                        self.check_expr(&case.body)
+                    }
+                }
+                Assert(ref a) => self.check_expr(&a.cond),
+                WithArena(ref w) => self.check_expr(&w.body),
+                Loop(ref l) => {
+                    l.inits.iter().for_each(|e| self.check_expr(e));
+                    self.check_expr(&l.body)
+                }
+                Recur(ref r) => r.args.iter().for_each(|e| self.check_expr(e)),
+                Begin(ref b) => b.exprs.iter().for_each(|e| self.check_expr(e)),
+                PtrOp(ref p) => p.args.iter().for_each(|e| self.check_expr(e)),
+                ThreadOp(ref t) => t.args.iter().for_each(|e| self.check_expr(e)),
+            }
+        })
+    }
+}
+
+/// Report unreachable cases and non-exhaustive matches in the global bindings
+///
+/// The analysis does not depend on types, so generic definitions are checked once, not for
+/// each monomorphic instance.
+pub fn check_matches<'s>(globals: &TopologicallyOrderedDependencyGroups<'s>, adts: &Adts<'s>) {
+    let checker = Checker { adts };
+    for b in globals.bindings() {
+        checker.check_expr(&b.val)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {check_str, Options};
+
+    const SHAPE: &str = "(data Shape (Circle Float64) (Rect Float64 Float64) Dot)\n";
+
+    /// The warnings of checking the program `src`, which is expected to check
+    fn warnings(src: &str) -> String {
+        match check_str(src, Options::default()) {
+            Ok(warnings) => warnings.0,
+            Err(errors) => panic!("Failed to check program\n{}", errors),
+        }
+    }
+
+    #[test]
+    fn test_non_exhaustive_adt_match() {
+        let src = format!(
+            "{}(define: (area s) (-> Shape Float64)
+  (match s
+    [(Circle r) r]
+    [Dot 0.0]))",
+            SHAPE
+        );
+        let warns = warnings(&src);
+        assert!(warns.contains("`(Rect _ _)` is not covered"), "{}", warns);
+    }
+
+    #[test]
+    fn test_unreachable_case() {
+        let src = format!(
+            "{}(define: (area s) (-> Shape Float64)
+  (match s
+    [_ 0.0]
+    [(Circle _) 1.0]))",
+            SHAPE
+        );
+        let warns = warnings(&src);
+        assert!(
+            warns.contains("Every value matched by `(Circle _)` is matched by an earlier case"),
+            "{}",
+            warns
+        );
+        assert!(!warns.contains("Non-exhaustive"), "{}", warns);
+    }
+
+    #[test]
+    fn test_nested_constructor_patterns() {
+        let missing = "(define: (f m) (-> (Maybe (Maybe Int64)) Int64)
+  (match m
+    [(Some (Some x)) x]
+    [None 0]))";
+        let warns = warnings(missing);
+        assert!(warns.contains("`(Some None)` is not covered"), "{}", warns);
+
+        let exhaustive = "(define: (f m) (-> (Maybe (Maybe Int64)) Int64)
+  (match m
+    [(Some (Some x)) x]
+    [(Some None) 1]
+    [None 0]))";
+        assert_eq!(warnings(exhaustive), "");
+
+        let unreachable = "(define: (f m) (-> (Maybe (Maybe Int64)) Int64)
+  (match m
+    [(Some _) 1]
+    [(Some (Some x)) x]
+    [None 0]))";
+        let warns = warnings(unreachable);
+        assert!(warns.contains("Unreachable case"), "{}", warns);
+        assert!(!warns.contains("Non-exhaustive"), "{}", warns);
+    }
+
+    #[test]
+    fn test_wildcards_and_literals() {
+        let num_lits = "(define: (f n) (-> Int64 Bool)
+  (match n
+    [0 true]
+    [1 false]))";
+        let warns = warnings(num_lits);
+        assert!(
+            warns.contains("Numeric literal and range cases are never considered"),
+            "{}",
+            warns
+        );
+
+        let num_lits_and_wildcard = "(define: (f n) (-> Int64 Bool)
+  (match n
+    [0 true]
+    [_ false]))";
+        assert_eq!(warnings(num_lits_and_wildcard), "");
+
+        let bools = "(define: (f b) (-> Bool Int64)
+  (match b
+    [true 1]
+    [false 0]))";
+        assert_eq!(warnings(bools), "");
+
+        let missing_bool = "(define: (f b) (-> Bool Int64)
+  (match b
+    [true 1]))";
+        let warns = warnings(missing_bool);
+        assert!(warns.contains("`false` is not covered"), "{}", warns);
+
+        let after_wildcard = "(define: (f b) (-> Bool Int64)
+  (match b
+    [true 1]
+    [_ 0]
+    [false 2]))";
+        let warns = warnings(after_wildcard);
+        assert!(warns.contains("Every value matched by `false`"), "{}", warns);
+
+        let str_lits = "(define: (f s) (-> String Int64)
+  (match s
+    [\"a\" 1]
+    [\"b\" 2]))";
+        let warns = warnings(str_lits);
+        assert!(warns.contains("`_` is not covered"), "{}", warns);
+    }
+}
@@ -7,11 +7,11 @@ use lib::ErrCode;
 use std::cmp::min;
 use std::fmt::{self, Debug, Display};
 use std::iter::{once, repeat};
-use std::process;
+use std::cell::{Cell, RefCell};
 use std::path::Path;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::{self, Write};
-use term::{self, color, Terminal, TerminfoTerminal};
+use term::{color, Terminal, TerminfoTerminal};
 use itertools::Itertools;
 
 pub mod cst;
@@ -22,7 +22,13 @@ pub mod parse;
 pub mod inference;
 pub mod monomorphization;
 pub mod substitution;
-mod macros;
+pub mod const_fold;
+pub mod lint;
+pub mod linearity;
+pub mod match_check;
+pub mod macros;
+#[cfg(feature = "serde")]
+pub mod serialize;
 
 /// A generator of unique type variables
 pub struct TypeVarGen(u64);
@@ -46,31 +52,124 @@ impl TypeVarGen {
     pub fn gen_type_var<'src>(&mut self) -> ast::Type<'src> {
         ast::Type::Var(self.gen_tv())
     }
+
+    /// Generates a new, unique variable name, that can't be written in source code
+    ///
+    /// Identifiers borrow their names, so the names are leaked. Each name is only allocated
+    /// once per thread, and the generator of each compilation starts over from the same names.
+    pub fn gensym(&mut self) -> &'static str {
+        thread_local! {
+            static GENSYMS: RefCell<BTreeMap<u64, &'static str>> = RefCell::new(BTreeMap::new());
+        }
+        let n = self.gen();
+        GENSYMS.with(|gensyms| {
+            *gensyms.borrow_mut().entry(n).or_insert_with(|| {
+                Box::leak(format!("gensym {}", n).into_boxed_str())
+            })
+        })
+    }
+}
+
+thread_local! {
+    /// If diagnostics are being captured, the diagnostics written so far
+    static CAPTURED_DIAGNOSTICS: RefCell<Option<Vec<u8>>> = RefCell::new(None);
+    /// Whether diagnostics are being captured. Kept separately, as it's checked while the
+    /// buffer is borrowed
+    static IS_CAPTURING: Cell<bool> = Cell::new(false);
+}
+
+/// A fatal error of the compilation
+///
+/// The error is rendered when it's created, along with any notes, as it may refer to
+/// positions in sources that don't outlive the compilation.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Diagnostic(pub String);
+
+impl Diagnostic {
+    /// An error that has no position in the source
+    pub fn error<E: Display>(msg: E) -> Self {
+        let mut v = vec![];
+        write_colored(&mut v, color::BRIGHT_RED, "Error: ");
+        writeln!(v, "{}", msg).ok();
+        Diagnostic(String::from_utf8(v).expect("ICE: Error string not valid utf8"))
+    }
+
+    /// An error with the diagnostic code `code`, that has no position in the source
+    pub fn error_code<E: Display>(code: ErrCode, msg: E) -> Self {
+        let mut v = vec![];
+        write_colored(&mut v, color::BRIGHT_RED, &format!("Error[{}]: ", code));
+        writeln!(v, "{}", msg).ok();
+        Diagnostic(String::from_utf8(v).expect("ICE: Error string not valid utf8"))
+    }
+
+    /// Append the help `msg` at the position `pos`
+    pub fn help<S: Display>(mut self, pos: &SrcPos, msg: S) -> Self {
+        let mut v = vec![];
+        pos.write_help(&mut v, msg);
+        self.0.push_str(&String::from_utf8_lossy(&v));
+        self
+    }
+
+    /// Append the note `msg`, that has no position in the source
+    pub fn note<E: Display>(mut self, msg: E) -> Self {
+        let mut v = vec![];
+        write_colored(&mut v, color::BRIGHT_GREEN, "Note: ");
+        writeln!(v, "{}", msg).ok();
+        self.0.push_str(&String::from_utf8_lossy(&v));
+        self
+    }
 }
 
-/// Exit compilation
-pub fn exit() -> ! {
-    println!("\nError occured during compilation. Exiting");
-    process::exit(0)
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Debug for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Run `f` with diagnostics written to a buffer instead of stdout
+///
+/// Returns the result of `f` along with the captured diagnostics. Fatal errors are returned
+/// by `f` as `Diagnostic`s, and are not part of the captured diagnostics.
+pub fn capture_diagnostics<R, F: FnOnce() -> R>(f: F) -> (R, String) {
+    let prev = CAPTURED_DIAGNOSTICS.with(|c| c.replace(Some(Vec::new())));
+    let was_capturing = IS_CAPTURING.with(|c| c.replace(true));
+    let r = f();
+    IS_CAPTURING.with(|c| c.set(was_capturing));
+    let captured = CAPTURED_DIAGNOSTICS
+        .with(|c| c.replace(prev))
+        .expect("ICE: Captured diagnostics dissapeared");
+    (r, String::from_utf8_lossy(&captured).into_owned())
+}
+
+fn is_capturing_diagnostics() -> bool {
+    IS_CAPTURING.with(|c| c.get())
 }
 
-/// Print an error and exit the compilation
-pub fn error_exit<E: Display>(msg: E) -> ! {
-    let mut t = term::stdout().expect("Could not acquire access to stdout");
-    t.fg(color::BRIGHT_RED).ok();
-    print!("Error: ");
-    t.reset().ok();
-    println!("{}", msg);
-    exit()
+/// Call `f` with the output for diagnostics. Stdout, unless diagnostics are being captured
+fn with_diagnostics_out<F: FnOnce(&mut Write)>(f: F) {
+    CAPTURED_DIAGNOSTICS.with(|c| match *c.borrow_mut() {
+        Some(ref mut buf) => f(buf),
+        None => f(&mut io::stdout()),
+    })
 }
 
-/// Print an error and exit the compilation
-pub fn note<E: Display>(msg: E) {
-    let mut t = term::stdout().expect("Could not acquire access to stdout");
-    t.fg(color::BRIGHT_GREEN).ok();
-    print!("Note: ");
-    t.reset().ok();
-    println!("{}", msg);
+/// Write `s` to `w` in color `color`, if `w` is a terminal that supports it
+fn write_colored<W: Write>(w: &mut W, color: color::Color, s: &str) {
+    if !is_capturing_diagnostics() {
+        if let Some(mut t) = TerminfoTerminal::new(&mut *w) {
+            t.fg(color).ok();
+            write!(t, "{}", s).ok();
+            t.reset().ok();
+            return;
+        }
+    }
+    write!(w, "{}", s).ok();
 }
 
 /// A position or interval in a string of source code
@@ -125,6 +224,11 @@ impl<'src> SrcPos<'src> {
         }
     }
 
+    /// The slice of source code covered by this position
+    fn text(&self) -> &'src str {
+        &self.src[self.start..self.end.unwrap_or(self.start)]
+    }
+
     /// [0 .. 10].after([0 .. 3]) = [4 .. 10]
     /// [0 .. 10].after([2 .. 4]) = [5 .. 10]
     fn after(&self, child: &Self) -> Self {
@@ -171,6 +275,28 @@ impl<'src> SrcPos<'src> {
         )
     }
 
+    /// The file of the position
+    pub fn filename(&self) -> &'src Path {
+        self.filename
+    }
+
+    /// The position formatted as `FILE:ROW:COL`, like in the header of diagnostics
+    pub fn location(&self) -> String {
+        let (_, _, row, col) = self.line_len_row_col();
+        format!("{}:{}:{}", self.filename.display(), row, col)
+    }
+
+    /// The position formatted as `FILE:ROW`, where `FILE` is the name of the file without
+    /// directories, for names of generated symbols
+    pub fn short_location(&self) -> String {
+        let (_, _, row, _) = self.line_len_row_col();
+        let file = self.filename
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("{}:{}", file, row)
+    }
+
     /// Prints a message along with a marked section of the source where the error occured
     ///
     /// # Examples
@@ -193,12 +319,8 @@ impl<'src> SrcPos<'src> {
         color: color::Color,
     ) {
         let (line, line_len, row, col) = self.line_len_row_col();
-        let mut t =
-            TerminfoTerminal::new(w).expect("Failed to create terminfo terminal of writer `w`");
 
-        t.fg(color).ok();
-        write!(t, "{}: ", kind).ok();
-        t.reset().ok();
+        write_colored(w, color, &format!("{}: ", kind));
         let nl_align = once('\n')
             .chain(repeat(' ').take(kind.len() + 2))
             .collect::<String>();
@@ -206,13 +328,11 @@ impl<'src> SrcPos<'src> {
             .lines()
             .intersperse(&nl_align)
             .collect::<String>();
-        writeln!(t, "{}", aligned_msg).ok();
-        writeln!(t, "  --> {}:{}:{}", self.filename.display(), row, col).ok();
-        writeln!(t, "{}: {}", row, line).ok();
-        t.fg(color).ok();
-        writeln!(
-            t,
-            "{}^{}",
+        writeln!(w, "{}", aligned_msg).ok();
+        writeln!(w, "  --> {}:{}:{}", self.filename.display(), row, col).ok();
+        writeln!(w, "{}: {}", row, line).ok();
+        let marker = format!(
+            "{}^{}\n",
             repeat(' ')
                 .take(col + (row as f32).log10() as usize + 2)
                 .collect::<String>(),
@@ -222,8 +342,8 @@ impl<'src> SrcPos<'src> {
                     line_len - col,
                 ))
                 .collect::<String>()
-        ).ok();
-        t.reset().ok();
+        );
+        write_colored(w, color, &marker);
     }
 
     /// Prints an error message along with a marked section of the source where the error occured
@@ -251,7 +371,7 @@ impl<'src> SrcPos<'src> {
     }
 
     pub fn print_error<E: Display>(&self, code: ErrCode, msg: E) {
-        self.write_error(&mut io::stdout(), code, msg)
+        with_diagnostics_out(|mut w| self.write_error(&mut w, code, msg))
     }
 
     /// Like `SrcPos::error`, but exits after message has been printed
@@ -259,10 +379,9 @@ impl<'src> SrcPos<'src> {
         self.print_error(ErrCode::undefined(), msg);
     }
 
-    /// Like `SrcPos::error`, but exits after message has been printed
-    pub fn error_exit<E: Display>(&self, msg: E) -> ! {
-        self.error(msg);
-        exit()
+    /// The error `msg` at this position, as a fatal error of the compilation
+    pub fn diagnostic<E: Display>(&self, msg: E) -> Diagnostic {
+        Diagnostic(self.error_string(ErrCode::undefined(), msg))
     }
 
     /// Like `SrcPos::error`, but text is yellow and kind is "Warning"
@@ -271,7 +390,7 @@ impl<'src> SrcPos<'src> {
     }
 
     pub fn print_warn<S: Display>(&self, msg: S) {
-        self.write_warn(&mut io::stdout(), msg);
+        with_diagnostics_out(|mut w| self.write_warn(&mut w, msg))
     }
 
     /// Like `SrcPos::error`, but text is green and kind is "Note"
@@ -280,7 +399,7 @@ impl<'src> SrcPos<'src> {
     }
 
     pub fn print_note<S: Display>(&self, msg: S) {
-        self.write_note(&mut io::stdout(), msg);
+        with_diagnostics_out(|mut w| self.write_note(&mut w, msg))
     }
 
     /// Like `SrcPos::error`, but text is cyan and kind is "Help"
@@ -289,7 +408,7 @@ impl<'src> SrcPos<'src> {
     }
 
     pub fn print_help<S: Display>(&self, msg: S) {
-        self.write_help(&mut io::stdout(), msg);
+        with_diagnostics_out(|mut w| self.write_help(&mut w, msg))
     }
 }
 impl<'src> Debug for SrcPos<'src> {
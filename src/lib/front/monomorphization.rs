@@ -1,6 +1,7 @@
 use itertools::zip;
 use std::collections::BTreeMap;
 use std::path;
+use lib::grow_stack;
 use lib::collections::*;
 use lib::front::*;
 use lib::front::ast::*;
@@ -54,90 +55,125 @@ fn monomorphize_def_of_inst<'src>(
 fn monomorphize_defs_of_insts_in_match<'src>(
     m: &mut Match<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
-) {
-    monomorphize_defs_of_insts_in_expr(&mut m.expr, env);
+) -> Result<(), Diagnostic> {
+    monomorphize_defs_of_insts_in_expr(&mut m.expr, env)?;
     // TODO: This is probably wrong. I have no idea what to put here.
     // I kinda forgot how exactly this whole process worked, and I'm
     // tired and confused.
     for case in &mut m.cases {
-        monomorphize_defs_of_insts_in_expr(&mut case.body, env)
+        monomorphize_defs_of_insts_in_expr(&mut case.body, env)?
     }
+    Ok(())
 }
 
 /// Monomorphize definitions for monomorphic instantiations of variables in `expr`
 fn monomorphize_defs_of_insts_in_expr<'src>(
     e: &mut Expr<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
-) {
-    let canon = e.get_type().canonicalize();
-    if !canon.is_monomorphic() {
-        e.pos().error(&format!(
-            "Could not deduce {} from use of expression. Type was not monomorphic at compile time.",
-            canon
-        ));
-        note("Try adding a type annotation to choose which specific instance of the type to use.");
-        exit()
-    }
-    match *e {
-        Expr::Variable(ref mut var) => {
-            if let Some((arg_ts, mut def_mono)) = monomorphize_def_of_inst(var, env) {
-                // Insert dummy monomorphization as a tag to show that monomorphization
-                // already has been done, but we still need `def_mono` to continue
-                // our recursive monomorphization
-                {
-                    let dummy_expr = Expr::Nil(Nil {
-                        pos: SrcPos::new_pos(path::Path::new(""), "", 0),
-                    });
-                    let b = env.get_mut(var.ident.s).unwrap();
-                    b.mono_insts.insert(arg_ts.clone(), dummy_expr);
-                }
+) -> Result<(), Diagnostic> {
+    grow_stack(|| {
+        let canon = e.get_type().canonicalize();
+        if !canon.is_monomorphic() {
+            let msg = format!(
+                "Could not deduce `{}` from use of expression. Type was not monomorphic at compile \
+                 time.",
+                canon.pretty()
+            );
+            return Err(e.pos().diagnostic(msg).note(
+                "Try adding a type annotation to choose which specific instance of the type \
+                 to use.",
+            ));
+        }
+        match *e {
+            Expr::Variable(ref mut var) => {
+                if let Some((arg_ts, mut def_mono)) = monomorphize_def_of_inst(var, env) {
+                    // Insert dummy monomorphization as a tag to show that monomorphization
+                    // already has been done, but we still need `def_mono` to continue
+                    // our recursive monomorphization
+                    {
+                        let dummy_expr = Expr::Nil(Nil {
+                            pos: SrcPos::new_pos(path::Path::new(""), "", 0),
+                        });
+                        let b = env.get_mut(var.ident.s).unwrap();
+                        b.mono_insts.insert(arg_ts.clone(), dummy_expr);
+                    }
 
-                // Recursively generate monomorphizations for now-monomorphic
-                // instantiations in `def_mono`
-                let h = env.get_height(var.ident.s).unwrap();
-                let above = env.split_off(h + 1);
-                monomorphize_defs_of_insts_in_expr(&mut def_mono, env);
-                env.extend(above);
+                    // Recursively generate monomorphizations for now-monomorphic
+                    // instantiations in `def_mono`
+                    let h = env.get_height(var.ident.s).unwrap();
+                    let above = env.split_off(h + 1);
+                    monomorphize_defs_of_insts_in_expr(&mut def_mono, env)?;
+                    env.extend(above);
 
-                let b = env.get_mut(var.ident.s).unwrap();
-                *b.mono_insts.get_mut(&arg_ts).unwrap() = def_mono;
+                    let b = env.get_mut(var.ident.s).unwrap();
+                    *b.mono_insts.get_mut(&arg_ts).unwrap() = def_mono;
+                }
             }
+            Expr::App(ref mut app) => {
+                monomorphize_defs_of_insts_in_expr(&mut app.func, env)?;
+                monomorphize_defs_of_insts_in_expr(&mut app.arg, env)?;
+            }
+            Expr::If(ref mut cond) => {
+                monomorphize_defs_of_insts_in_expr(&mut cond.predicate, env)?;
+                monomorphize_defs_of_insts_in_expr(&mut cond.consequent, env)?;
+                monomorphize_defs_of_insts_in_expr(&mut cond.alternative, env)?;
+            }
+            Expr::Lambda(ref mut lam) => {
+                monomorphize_defs_of_insts_in_expr(&mut lam.body, env)?;
+            }
+            Expr::Let(box ref mut l) => {
+                monomorphize_defs_of_insts_in_let(&mut l.bindings, &mut l.body, env)?
+            }
+            Expr::TypeAscript(_) => unreachable!(),
+            Expr::Cons(ref mut cons) => {
+                monomorphize_defs_of_insts_in_expr(&mut cons.car, env)?;
+                monomorphize_defs_of_insts_in_expr(&mut cons.cdr, env)?;
+            }
+            Expr::Car(ref mut c) => {
+                monomorphize_defs_of_insts_in_expr(&mut c.expr, env)?;
+            }
+            Expr::Cdr(ref mut c) => {
+                monomorphize_defs_of_insts_in_expr(&mut c.expr, env)?;
+            }
+            Expr::Cast(ref mut c) => {
+                monomorphize_defs_of_insts_in_expr(&mut c.expr, env)?;
+            }
+            Expr::Transmute(ref mut t) => {
+                monomorphize_defs_of_insts_in_expr(&mut t.expr, env)?;
+            }
+            Expr::New(ref mut n) => for member in &mut n.members {
+                monomorphize_defs_of_insts_in_expr(member, env)?
+            },
+            Expr::Match(ref mut m) => monomorphize_defs_of_insts_in_match(m, env)?,
+            Expr::Assert(ref mut a) => monomorphize_defs_of_insts_in_expr(&mut a.cond, env)?,
+            Expr::WithArena(ref mut w) => monomorphize_defs_of_insts_in_expr(&mut w.body, env)?,
+            Expr::Loop(ref mut l) => {
+                for init in &mut l.inits {
+                    monomorphize_defs_of_insts_in_expr(init, env)?
+                }
+                monomorphize_defs_of_insts_in_expr(&mut l.body, env)?
+            }
+            Expr::Recur(ref mut r) => for arg in &mut r.args {
+                monomorphize_defs_of_insts_in_expr(arg, env)?
+            },
+            Expr::Begin(ref mut b) => for e in &mut b.exprs {
+                monomorphize_defs_of_insts_in_expr(e, env)?
+            },
+            Expr::PtrOp(ref mut p) => for arg in &mut p.args {
+                monomorphize_defs_of_insts_in_expr(arg, env)?
+            },
+            Expr::ThreadOp(ref mut t) => for arg in &mut t.args {
+                monomorphize_defs_of_insts_in_expr(arg, env)?
+            },
+            Expr::Nil(_)
+            | Expr::NumLit(_)
+            | Expr::StrLit(_)
+            | Expr::BytesLit(_)
+            | Expr::SymLit(_)
+            | Expr::Bool(_) => (),
         }
-        Expr::App(ref mut app) => {
-            monomorphize_defs_of_insts_in_expr(&mut app.func, env);
-            monomorphize_defs_of_insts_in_expr(&mut app.arg, env);
-        }
-        Expr::If(ref mut cond) => {
-            monomorphize_defs_of_insts_in_expr(&mut cond.predicate, env);
-            monomorphize_defs_of_insts_in_expr(&mut cond.consequent, env);
-            monomorphize_defs_of_insts_in_expr(&mut cond.alternative, env);
-        }
-        Expr::Lambda(ref mut lam) => {
-            monomorphize_defs_of_insts_in_expr(&mut lam.body, env);
-        }
-        Expr::Let(box ref mut l) => {
-            monomorphize_defs_of_insts_in_let(&mut l.bindings, &mut l.body, env)
-        }
-        Expr::TypeAscript(_) => unreachable!(),
-        Expr::Cons(ref mut cons) => {
-            monomorphize_defs_of_insts_in_expr(&mut cons.car, env);
-            monomorphize_defs_of_insts_in_expr(&mut cons.cdr, env);
-        }
-        Expr::Car(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
-        }
-        Expr::Cdr(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
-        }
-        Expr::Cast(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
-        }
-        Expr::New(ref mut n) => for member in &mut n.members {
-            monomorphize_defs_of_insts_in_expr(member, env)
-        },
-        Expr::Match(ref mut m) => monomorphize_defs_of_insts_in_match(m, env),
-        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
-    }
+        Ok(())
+    })
 }
 
 /// Monomorphize definitions for monomorphic instantiations of variables in `bindings`
@@ -145,7 +181,7 @@ fn monomorphize_defs_of_insts_in_let<'src>(
     bindings: &mut TopologicallyOrderedDependencyGroups<'src>,
     body: &mut Expr<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
-) {
+) -> Result<(), Diagnostic> {
     let mut monos = BTreeMap::new();
     let mut bindings_flat_map = BTreeMap::new();
     for b in bindings.bindings() {
@@ -157,9 +193,9 @@ fn monomorphize_defs_of_insts_in_let<'src>(
     env.push(bindings_flat_map);
 
     for (_, mut def) in &mut monos {
-        monomorphize_defs_of_insts_in_expr(&mut def, env);
+        monomorphize_defs_of_insts_in_expr(&mut def, env)?;
     }
-    monomorphize_defs_of_insts_in_expr(body, env);
+    monomorphize_defs_of_insts_in_expr(body, env)?;
 
     for b in bindings.bindings_mut() {
         if let Some(upd_def) = monos.remove(b.ident.s) {
@@ -169,12 +205,15 @@ fn monomorphize_defs_of_insts_in_let<'src>(
         }
     }
     env.pop().unwrap();
+    Ok(())
 }
 
 /// Monomorphize definitions for monomorphic instantiations of variables in `bindings`
-pub fn monomorphize_defs_of_insts<'src>(globals: &mut TopologicallyOrderedDependencyGroups<'src>) {
+pub fn monomorphize_defs_of_insts<'src>(
+    globals: &mut TopologicallyOrderedDependencyGroups<'src>,
+) -> Result<(), Diagnostic> {
     let mut dummy_body = Expr::Nil(Nil {
         pos: SrcPos::new_pos(path::Path::new(""), "", 0),
     });
-    monomorphize_defs_of_insts_in_let(globals, &mut dummy_body, &mut ScopeStack::new());
+    monomorphize_defs_of_insts_in_let(globals, &mut dummy_body, &mut ScopeStack::new())
 }
@@ -3,12 +3,26 @@ use super::*;
 use super::ast::*;
 use super::cst::Cst;
 use super::dependency_graph::*;
+use super::lint::{self, UNUSED_LET_BINDING};
 use super::macros;
-use lib::CanonPathBuf;
+use lib::{grow_stack, CanonPathBuf};
 use lib::collections::AddMap;
-use lib::front::lex::lex_file;
-use std::collections::BTreeMap;
+use lib::front::lex::{lex_file, normalize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::iter::once;
 use std::mem;
+use std::path::PathBuf;
+
+/// The name of the virtual file of the core prelude
+const CORE_PRELUDE_NAME: &str = "<core>";
+
+/// The source of the core prelude, with the items built into the language, like the `Maybe`
+/// type, that are included in every program without an import
+const CORE_PRELUDE_SRC: &str = include_str!("core.kvs");
 
 /// Constructors for common parse errors to prevent repetition and spelling mistakes
 #[derive(PartialEq, Eq)]
@@ -69,6 +83,38 @@ enum PErr<'s> {
         name: &'s str,
         prev_pos: SrcPos<'s>,
     },
+    /// Failed to read a file to embed with `include-str` or `include-bytes`
+    IncludeFailed {
+        pos: SrcPos<'s>,
+        path: PathBuf,
+        err: String,
+    },
+    /// No lint by this name in an `allow` form
+    UnknownLint(SrcPos<'s>, &'s str),
+    /// A `recur` that is not inside a `loop` of the same function
+    RecurOutsideLoop(SrcPos<'s>),
+    /// A `recur` that is not in tail position of its `loop`
+    RecurNotInTail(SrcPos<'s>),
+    /// An external function with side effects does not have an IO type
+    ExternNotIo(SrcPos<'s>),
+    /// An unsafe operation outside of an `unsafe` form
+    UnsafeOutsideUnsafe(SrcPos<'s>, &'s str),
+    /// A case of a macro definition is not a list of a pattern and templates
+    InvalidMacroCase(SrcPos<'s>),
+    /// A pattern of a macro case is malformed
+    InvalidMacroPattern(SrcPos<'s>, &'static str),
+    /// An identifier is spliced into a macro template, but is not a variable of the pattern
+    UnboundMacroSplice(SrcPos<'s>, &'s str),
+    /// An opaque foreign type is used other than as the pointee of a `Ptr`
+    ExternTypeNotBehindPtr(SrcPos<'s>, &'s str),
+    /// An error of lexing, macro expansion, or resolving an import, which is already rendered
+    Fatal(Diagnostic),
+}
+
+impl<'s> From<Diagnostic> for PErr<'s> {
+    fn from(d: Diagnostic) -> Self {
+        Fatal(d)
+    }
 }
 
 impl<'s> PErr<'s> {
@@ -100,6 +146,17 @@ impl<'s> PErr<'s> {
             UndefDataConstr { .. } => e(19),
             InvalidDataBind(_) => e(20),
             DataDuplParam { .. } => e(21),
+            IncludeFailed { .. } => e(22),
+            UnknownLint(..) => e(23),
+            RecurOutsideLoop(..) => e(24),
+            ExternNotIo(..) => e(25),
+            UnsafeOutsideUnsafe(..) => e(26),
+            InvalidMacroCase(..) => e(27),
+            InvalidMacroPattern(..) => e(28),
+            UnboundMacroSplice(..) => e(29),
+            ExternTypeNotBehindPtr(..) => e(30),
+            RecurNotInTail(..) => e(31),
+            Fatal(_) => ErrCode::undefined(),
         }
     }
 
@@ -216,11 +273,95 @@ impl<'s> PErr<'s> {
                 );
                 prev_pos.write_note(w, "The previous declaration of the type parameter is here:")
             }
+            IncludeFailed {
+                ref pos,
+                ref path,
+                ref err,
+            } => pos.write_error(
+                w,
+                code,
+                format!("Failed to include file `{}`, {}", path.display(), err),
+            ),
+            UnknownLint(ref pos, name) => {
+                pos.write_error(w, code, format!("Unknown lint `{}`", name))
+            }
+            RecurOutsideLoop(ref pos) => {
+                pos.write_error(w, code, "`recur` outside of a `loop` in the same function")
+            }
+            RecurNotInTail(ref pos) => pos.write_error(
+                w,
+                code,
+                "`recur` not in tail position of its `loop`. The result of `recur` can't be \
+                 used, as it jumps back to the start of the loop",
+            ),
+            ExternNotIo(ref pos) => pos.write_error(
+                w,
+                code,
+                "External function with side effects must have an IO type, like \
+                 `(-> (Cons A RealWorld) (Cons B RealWorld))`",
+            ),
+            UnsafeOutsideUnsafe(ref pos, op) => pos.write_error(
+                w,
+                code,
+                format!("`{}` is unsafe, and may only be used inside an `unsafe` form", op),
+            ),
+            InvalidMacroCase(ref pos) => {
+                pos.write_error(w, code, "Invalid macro case");
+                pos.write_help(
+                    w,
+                    "Each case of a macro is a pattern followed by one or more templates, \
+                     like `(macro NAME [PATTERN TEMPLATE...]...)`",
+                )
+            }
+            InvalidMacroPattern(ref pos, reason) => {
+                pos.write_error(w, code, format!("Invalid macro pattern. {}", reason));
+                pos.write_help(
+                    w,
+                    "A pattern is an identifier, a literal identifier `('IDENT)`, a list of \
+                     patterns, or a repetition `(... PATTERN...)` in a list",
+                )
+            }
+            UnboundMacroSplice(ref pos, name) => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("`{}` is spliced, but is not a variable of the pattern", name),
+                );
+                pos.write_help(
+                    w,
+                    "Only the list of arguments bound to a pattern variable can be spliced \
+                     with `(... VAR)`. Check the spelling of the variable",
+                )
+            }
+            ExternTypeNotBehindPtr(ref pos, name) => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("The foreign type `{}` can only be used behind a `Ptr`", name),
+                );
+                pos.write_help(
+                    w,
+                    format!(
+                        "The size and layout of `{}` is unknown. Use `(Ptr {})` instead",
+                        name, name
+                    ),
+                )
+            }
+            Fatal(ref d) => {
+                write!(w, "{}", d).ok();
+            }
         }
     }
 
-    fn print(&self) {
-        self.write(&mut io::stdout())
+    fn diagnostic(&self) -> Diagnostic {
+        match *self {
+            Fatal(ref d) => d.clone(),
+            _ => {
+                let mut v = vec![];
+                self.write(&mut v);
+                Diagnostic(String::from_utf8(v).expect("ICE: Error string not valid utf8"))
+            }
+        }
     }
 }
 
@@ -232,8 +373,9 @@ type PRes<'s, T> = Result<T, PErr<'s>>;
 enum BindPattern<'s> {
     /// Just an identifier
     Var(Ident<'s>),
-    /// A function-binding pattern. E.g. `(inc x)`
-    Func(Ident<'s>, (Vec<Ident<'s>>, SrcPos<'s>)),
+    /// A function-binding pattern, with the types of the annotated parameters.
+    /// E.g. `(inc x)` or `(inc (x : Int64))`
+    Func(Ident<'s>, (Vec<(Ident<'s>, Option<Type<'s>>)>, SrcPos<'s>)),
 }
 
 // Parser combinators
@@ -275,6 +417,32 @@ fn ident<'s>(c: &Cst<'s>) -> PRes<'s, Ident<'s>> {
     }
 }
 
+/// The name of the global variable defined by `form`, if it's a `(define NAME EXPR)` or
+/// `(define: NAME TYPE EXPR)`
+fn global_var_def_name<'s>(form: &Cst<'s>) -> Option<&'s str> {
+    match *form {
+        Cst::Sexpr(ref xs, _) if xs.len() >= 3 => match (ident_s(&xs[0]), &xs[1]) {
+            (Ok("define"), &Cst::Ident(name, _)) | (Ok("define:"), &Cst::Ident(name, _)) => {
+                Some(name)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The name of the global defined by `form`, if it's a `(define (NAME PARAM) BODY)`, or a
+/// definition of a global variable, which may be a function
+fn global_func_def_name<'s>(form: &Cst<'s>) -> Option<&'s str> {
+    match *form {
+        Cst::Sexpr(ref xs, _) if xs.len() >= 3 => match (ident_s(&xs[0]), &xs[1]) {
+            (Ok("define"), &Cst::Sexpr(ref sig, _)) if !sig.is_empty() => ident_s(&sig[0]).ok(),
+            _ => global_var_def_name(form),
+        },
+        _ => None,
+    }
+}
+
 fn ident_s<'s, 'c>(c: &'c Cst<'s>) -> PRes<'s, &'s str> {
     ident(c).map(|id| id.s)
 }
@@ -316,15 +484,296 @@ fn constant<'s, T: Eq>(x: T, y: T, err: PErr<'s>) -> PRes<'s, ()> {
     }
 }
 
+/// Whether the type `t` contains the `RealWorld`
+fn mentions_real_world(t: &Type) -> bool {
+    match *t {
+        Type::Const("RealWorld", _) => true,
+        Type::App(_, ref args) => args.iter().any(mentions_real_world),
+        _ => false,
+    }
+}
+
+/// Whether `t` is the type of an IO function
+///
+/// Either an action, `(-> RealWorld (Cons B RealWorld))`, or a function of an argument,
+/// `(-> (Cons A RealWorld) (Cons B RealWorld))`, where `A` and `B` don't contain the
+/// `RealWorld`.
+fn is_io_func_type(t: &Type) -> bool {
+    let is_world = |u: &Type| *u == *TYPE_REALWORLD;
+    let with_world = |u: &Type| {
+        u.get_cons()
+            .map(|(a, w)| !mentions_real_world(a) && is_world(w))
+            .unwrap_or(false)
+    };
+    match t.get_func() {
+        Some((arg, ret)) => (is_world(arg) || with_world(arg)) && with_world(ret),
+        None => false,
+    }
+}
+
+/// If `c` is a binding statement `(VAR <- EXPR)`, as in `do-io` and `try`, return the
+/// variable and the expression
+fn arrow_binding<'c, 's>(c: &'c Cst<'s>) -> Option<(&'c Cst<'s>, &'c Cst<'s>)> {
+    match *c {
+        Cst::Sexpr(ref xs, _) if xs.len() == 3 && ident_s(&xs[1]).ok() == Some("<-") => {
+            Some((&xs[0], &xs[2]))
+        }
+        _ => None,
+    }
+}
+
+/// Split the parts of a binding into the pattern, the return type annotation if any, and the
+/// value, as `PATTERN VALUE` or `PATTERN : TYPE VALUE`
+fn split_ret_annotation<'s, 'c>(
+    cs: &'c [Cst<'s>],
+    pos: &SrcPos<'s>,
+) -> PRes<'s, (&'c Cst<'s>, Option<&'c Cst<'s>>, &'c Cst<'s>)> {
+    if cs.len() == 4 && ident_s(&cs[1]).ok() == Some(":") {
+        Ok((&cs[0], Some(&cs[2]), &cs[3]))
+    } else {
+        let (patt, val) = two(cs, pos)?;
+        Ok((patt, None, val))
+    }
+}
+
+/// The builtin binary operator and the precedence of the infix operator `op` of `#math(...)`
+fn infix_op(op: &str) -> Option<(&'static str, u8)> {
+    match op {
+        "+" => Some(("add", 1)),
+        "-" => Some(("sub", 1)),
+        "*" => Some(("mul", 2)),
+        "/" => Some(("div", 2)),
+        _ => None,
+    }
+}
+
+/// Whether `cs` is a group of infix arithmetic, i.e. an operand, maybe negated, followed by an
+/// infix operator
+fn is_infix(cs: &[Cst]) -> bool {
+    let negations = cs.iter()
+        .take_while(|c| ident_s(c).ok() == Some("-"))
+        .count();
+    let cs = &cs[negations..];
+    cs.len() >= 2 && ident_s(&cs[1]).ok().and_then(infix_op).is_some()
+}
+
+/// Convert the infix arithmetic `cs` of `#math(...)` at `pos` to applications of the builtin
+/// operators, e.g. `a + b * c` to `(add (cons a (mul (cons b c))))`
+///
+/// `*` and `/` bind tighter than `+` and `-`, and operators of the same precedence associate
+/// to the left. A `-` before an operand negates it, e.g. `- a` reads as `(sub (cons 0 a))`.
+/// A nested list that is itself infix arithmetic, like `(a + b)`, is a group. Any other
+/// operand is an ordinary expression.
+fn infix_to_prefix<'s>(cs: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Cst<'s>> {
+    fn binop<'s>(op: &'static str, op_pos: SrcPos<'s>, a: Cst<'s>, b: Cst<'s>) -> Cst<'s> {
+        let pos = a.pos().to(b.pos());
+        let pair = Cst::Sexpr(vec![Cst::Ident("cons", op_pos.clone()), a, b], pos.clone());
+        Cst::Sexpr(vec![Cst::Ident(op, op_pos), pair], pos)
+    }
+    fn apply<'s>(operands: &mut Vec<Cst<'s>>, op: &'static str, op_pos: SrcPos<'s>) {
+        let b = operands.pop().expect("ICE: missing right operand in infix_to_prefix");
+        let a = operands.pop().expect("ICE: missing left operand in infix_to_prefix");
+        operands.push(binop(op, op_pos, a, b))
+    }
+    let mut operands = Vec::new();
+    let mut operators: Vec<(&'static str, u8, SrcPos<'s>)> = Vec::new();
+    let mut rest = cs;
+    loop {
+        let mut negations = Vec::new();
+        while rest.first().and_then(|c| ident_s(c).ok()) == Some("-") {
+            negations.push(rest[0].pos().clone());
+            rest = &rest[1..];
+        }
+        let (c, after_operand) = rest.split_first().ok_or_else(|| {
+            let p = cs.last().map(|c| c.pos()).unwrap_or(pos);
+            Expected(p.clone(), "operand of infix arithmetic")
+        })?;
+        let mut operand = match *c {
+            Cst::Sexpr(ref xs, ref p) if is_infix(xs) => infix_to_prefix(xs, p)?,
+            _ => c.clone(),
+        };
+        for neg_pos in negations.into_iter().rev() {
+            let zero = Cst::Num("0", neg_pos.clone());
+            operand = binop("sub", neg_pos, zero, operand);
+        }
+        operands.push(operand);
+        let (c, after_operator) = match after_operand.split_first() {
+            Some(split) => split,
+            None => break,
+        };
+        let (op, prec) = ident_s(c).ok().and_then(infix_op).ok_or_else(|| {
+            Expected(c.pos().clone(), "infix operator `+`, `-`, `*`, or `/`")
+        })?;
+        while operators.last().map(|o| o.1 >= prec).unwrap_or(false) {
+            let (op2, _, op2_pos) = operators.pop().unwrap();
+            apply(&mut operands, op2, op2_pos)
+        }
+        operators.push((op, prec, c.pos().clone()));
+        rest = after_operator;
+    }
+    while let Some((op, _, op_pos)) = operators.pop() {
+        apply(&mut operands, op, op_pos)
+    }
+    Ok(operands.pop().expect("ICE: no operand in infix_to_prefix"))
+}
+
+/// Whether `c` is a macro definition `(macro ...)`
+fn is_macro_def(c: &Cst) -> bool {
+    match *c {
+        Cst::Sexpr(ref xs, _) => match xs.first() {
+            Some(&Cst::Ident("macro", _)) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 fn is_special_operator(op: &Cst) -> bool {
     let special_operators = [
-        "if", "lambda", "let", ":", "cons", "car", "cdr", "cast", "cond", "new", "match"
+        "if",
+        "lambda",
+        "let",
+        ":",
+        "cons",
+        "car",
+        "cdr",
+        "cast",
+        "cast-checked",
+        "cond",
+        "new",
+        "match",
+        "include-str",
+        "include-bytes",
+        "assert",
+        "assert-eq",
+        "with-arena",
+        "loop",
+        "recur",
+        "begin",
+        "do-io",
+        "when",
+        "unless",
+        "if-let",
+        "->",
+        "->>",
+        "try",
+        "defer",
+        "unsafe",
+        "ptr-read",
+        "ptr-write",
+        "ptr-offset",
+        "null-ptr",
+        "ptr-eq",
+        "ptr-read-volatile",
+        "ptr-write-volatile",
+        "atomic-load",
+        "atomic-store",
+        "atomic-fetch-add",
+        "atomic-cas",
+        "thread-spawn",
+        "thread-join",
+        "chan-new",
+        "chan-send",
+        "chan-recv",
+        "mutex-new",
+        "mutex-lock",
+        "mutex-unlock",
+        "task-spawn",
+        "task-await",
+        "task-yield",
+        "unsafe-transmute",
+        "'",
+        "quote",
+        "len-list-literal",
+        "assoc-list-literal",
+        "infix-math",
     ];
     ident_s(op)
         .map(|s| special_operators.contains(&s))
         .unwrap_or(false)
 }
 
+/// Parse the ordering of an atomic pointer operation of kind `kind`
+///
+/// Loads can't have release semantics, and stores can't have acquire semantics
+fn atomic_ordering<'s>(kind: PtrOpKind, c: &Cst<'s>) -> PRes<'s, AtomicOrdering> {
+    use self::AtomicOrdering::*;
+    let o = ident_s(c)
+        .ok()
+        .and_then(AtomicOrdering::from_name)
+        .ok_or_else(|| {
+            Expected(
+                c.pos().clone(),
+                "atomic ordering `relaxed`, `acquire`, `release`, `acq-rel`, or `seq-cst`",
+            )
+        })?;
+    match (kind, o) {
+        (PtrOpKind::AtomicLoad, Release) | (PtrOpKind::AtomicLoad, AcqRel) => Err(Expected(
+            c.pos().clone(),
+            "load ordering `relaxed`, `acquire`, or `seq-cst`",
+        )),
+        (PtrOpKind::AtomicStore, Acquire) | (PtrOpKind::AtomicStore, AcqRel) => Err(Expected(
+            c.pos().clone(),
+            "store ordering `relaxed`, `release`, or `seq-cst`",
+        )),
+        _ => Ok(o),
+    }
+}
+
+/// The position of the first `recur` in `e` that is not in tail position of its loop, where
+/// `e` is in tail position if `is_tail`
+///
+/// The bodies of nested loops and lambdas are not searched, as a `recur` in them does not
+/// jump to the same loop.
+fn recur_not_in_tail<'a, 's>(e: &'a Expr<'s>, is_tail: bool) -> Option<&'a SrcPos<'s>> {
+    grow_stack(|| {
+        use self::Expr::*;
+        let first_in = |es: &'a [Expr<'s>]| {
+            es.iter().filter_map(|e| recur_not_in_tail(e, false)).next()
+        };
+        match *e {
+            Nil(_) | NumLit(_) | StrLit(_) | BytesLit(_) | SymLit(_) | Bool(_) | Variable(_)
+            | Lambda(_) => None,
+            App(ref app) => recur_not_in_tail(&app.func, false)
+                .or_else(|| recur_not_in_tail(&app.arg, false)),
+            If(ref cond) => recur_not_in_tail(&cond.predicate, false)
+                .or_else(|| recur_not_in_tail(&cond.consequent, is_tail))
+                .or_else(|| recur_not_in_tail(&cond.alternative, is_tail)),
+            Let(ref l) => l.bindings
+                .bindings()
+                .filter_map(|b| recur_not_in_tail(&b.val, false))
+                .next()
+                .or_else(|| recur_not_in_tail(&l.body, is_tail)),
+            TypeAscript(ref a) => recur_not_in_tail(&a.expr, is_tail),
+            Cons(ref c) => {
+                recur_not_in_tail(&c.car, false).or_else(|| recur_not_in_tail(&c.cdr, false))
+            }
+            Car(ref c) => recur_not_in_tail(&c.expr, false),
+            Cdr(ref c) => recur_not_in_tail(&c.expr, false),
+            Cast(ref c) => recur_not_in_tail(&c.expr, false),
+            Transmute(ref t) => recur_not_in_tail(&t.expr, false),
+            New(ref n) => first_in(&n.members),
+            Match(ref m) => recur_not_in_tail(&m.expr, false).or_else(|| {
+                m.cases
+                    .iter()
+                    .filter_map(|case| recur_not_in_tail(&case.body, is_tail))
+                    .next()
+            }),
+            Assert(ref a) => recur_not_in_tail(&a.cond, false),
+            WithArena(ref w) => recur_not_in_tail(&w.body, is_tail),
+            Loop(ref l) => first_in(&l.inits),
+            Recur(ref r) if !is_tail => Some(&r.pos),
+            Recur(ref r) => first_in(&r.args),
+            Begin(ref b) => {
+                let (last, effects) = b.exprs.split_last()?;
+                first_in(effects).or_else(|| recur_not_in_tail(last, is_tail))
+            }
+            PtrOp(ref p) => first_in(&p.args),
+            ThreadOp(ref t) => first_in(&t.args),
+        }
+    })
+}
+
 struct Parser<'tvg, 's> {
     /// An additive-only map of module file paths to source code strings
     sources: &'s AddMap<CanonPathBuf, String>,
@@ -333,6 +782,24 @@ struct Parser<'tvg, 's> {
     /// Algebraic data type definitions
     adts: Adts<'s>,
     macros: BTreeMap<&'s str, macros::Macro<'s>>,
+    /// Directories to search for imported modules, after the current directory
+    src_roots: Vec<PathBuf>,
+    tests: Vec<Test<'s>>,
+    /// The number of variables of each enclosing `loop` in the current function, innermost
+    /// last
+    loops: Vec<usize>,
+    /// Whether currently inside an `unsafe` form
+    in_unsafe: bool,
+    /// Opaque foreign types declared with `extern-type`
+    extern_types: BTreeMap<&'s str, SrcPos<'s>>,
+    /// The names of the globals defined with `def-const`
+    consts: BTreeSet<&'s str>,
+    /// The names of the globals defined in `lazy` forms
+    lazy_globals: BTreeSet<&'s str>,
+    /// The names of the global functions defined in `export` forms
+    exports: BTreeSet<&'s str>,
+    /// The files of the modules included so far, which are not imported again
+    included: Vec<CanonPathBuf>,
 }
 
 impl<'tvg, 's> Parser<'tvg, 's> {
@@ -342,6 +809,15 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             type_var_gen,
             adts: Adts::new(),
             macros: BTreeMap::new(),
+            src_roots: Vec::new(),
+            tests: Vec::new(),
+            loops: Vec::new(),
+            in_unsafe: false,
+            extern_types: BTreeMap::new(),
+            consts: BTreeSet::new(),
+            lazy_globals: BTreeSet::new(),
+            exports: BTreeSet::new(),
+            included: Vec::new(),
         }
     }
 
@@ -358,23 +834,99 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         ident_s(one(csts, pos)?)
     }
 
+    /// Find the file of the module `module_name`
+    ///
+    /// The current directory is searched first, and then each of the source roots in order
+    fn resolve_import(&self, module_name: &str, pos: &SrcPos<'s>) -> PRes<'s, CanonPathBuf> {
+        let filename = format!("{}.kvs", module_name);
+        once(PathBuf::from(&filename))
+            .chain(self.src_roots.iter().map(|root| root.join(&filename)))
+            .filter(|path| path.is_file())
+            .filter_map(|path| CanonPathBuf::new(&path.to_string_lossy()).ok())
+            .next()
+            .ok_or_else(|| {
+                Fatal(pos.diagnostic(format!("Failed to find module `{}`", module_name)))
+            })
+    }
+
     /// Parse a list of `Cst`s as an external variable declaration
-    fn parse_extern(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ExternDecl<'s>> {
-        let (a, b) = two(csts, pos)?;
+    /// Parse an external declaration, of an `extern-io` if `is_io`
+    ///
+    /// An extern with side effects, i.e. one declared with `extern-io` or that takes the
+    /// `RealWorld`, must have an IO type. Otherwise, calls to it could be duplicated or
+    /// reordered as if it were pure.
+    ///
+    /// A declaration is `(extern NAME TYPE OPTION...)`, where the options are the calling
+    /// convention, `(call-conv CONV)`, and symbol names for specific targets,
+    /// `(link-name TARGET SYMBOL)`.
+    fn parse_extern(
+        &mut self,
+        is_io: bool,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, ExternDecl<'s>> {
+        if csts.len() < 2 {
+            return Err(ArityMisTooFew(pos.clone(), csts.len()));
+        }
+        let (a, b, options) = (&csts[0], &csts[1], &csts[2..]);
+        let typ = self.parse_type(b)?;
+        if (is_io || mentions_real_world(&typ)) && !is_io_func_type(&typ) {
+            return Err(ExternNotIo(b.pos().clone()));
+        }
+        let mut call_conv = CallConv::C;
+        let mut link_names = Vec::new();
+        for option in options {
+            let (name, args) = match *option {
+                Cst::Sexpr(ref xs, _) if !xs.is_empty() => (ident_s(&xs[0]).ok(), &xs[1..]),
+                _ => (None, &[][..]),
+            };
+            match name {
+                Some("call-conv") if args.len() == 1 => {
+                    call_conv = ident_s(&args[0])
+                        .ok()
+                        .and_then(CallConv::from_name)
+                        .ok_or_else(|| {
+                            Expected(
+                                args[0].pos().clone(),
+                                "calling convention `c`, `stdcall`, `fastcall`, or `system`",
+                            )
+                        })?
+                }
+                Some("link-name") if args.len() == 2 => match (&args[0], &args[1]) {
+                    (&Cst::Str(ref target, _), &Cst::Str(ref symbol, _)) => {
+                        link_names.push((target.to_string(), symbol.to_string()))
+                    }
+                    _ => {
+                        return Err(Expected(
+                            option.pos().clone(),
+                            "target and symbol as strings, `(link-name TARGET SYMBOL)`",
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(Expected(
+                        option.pos().clone(),
+                        "extern option `(call-conv CONV)` or `(link-name TARGET SYMBOL)`",
+                    ))
+                }
+            }
+        }
         Ok(ExternDecl {
             ident: ident(a)?,
-            typ: self.parse_type(b)?,
+            typ,
+            call_conv,
+            link_names,
             pos: pos.clone(),
         })
     }
 
     fn parse_externs(
         &mut self,
-        decls_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)],
+        decls_csts: &[(bool, Vec<Cst<'s>>, SrcPos<'s>)],
     ) -> PRes<'s, BTreeMap<&'s str, ExternDecl<'s>>> {
         let mut externs = BTreeMap::new();
-        for &(ref decl_csts, ref pos) in decls_csts {
-            let ext = self.parse_extern(decl_csts, pos)?;
+        for &(is_io, ref decl_csts, ref pos) in decls_csts {
+            let ext = self.parse_extern(is_io, decl_csts, pos)?;
             if let Some(ext) = externs.insert(ext.ident.s, ext) {
                 return Err(ExtDuplDef(ext.pos.clone(), ext.ident.s));
             }
@@ -382,6 +934,22 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Ok(externs)
     }
 
+    /// Parse a declaration of an opaque foreign type, `(extern-type NAME)`
+    fn parse_add_extern_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ()> {
+        let name = ident(one(csts, pos)?)?;
+        if !name.s.starts_with(char::is_uppercase) {
+            return Err(InvalidAdtIdent(name.pos, name.s));
+        }
+        if let Some(prev_pos) = self.extern_types.insert(name.s, pos.clone()) {
+            return Err(DataTypeDuplDef {
+                pos: pos.clone(),
+                name: name.s,
+                prev_pos,
+            });
+        }
+        Ok(())
+    }
+
     fn parse_type_var_ident(&mut self, cst: &Cst<'s>) -> PRes<'s, Ident<'s>> {
         let id = ident(cst)?;
         if id.s.starts_with(char::is_lowercase) {
@@ -464,11 +1032,20 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
+    /// Parse `(Cons A B C ...)`, with more than two members as nested pairs `(Cons A (Cons B C))`
     fn parse_cons_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
-        let (a, b) = two(csts, pos)?;
-        let car = self.parse_type(a)?;
-        let cdr = self.parse_type(b)?;
-        Ok(Type::new_cons(car, cdr))
+        if csts.len() < 2 {
+            return Err(ArityMisTooFew(pos.clone(), csts.len()));
+        }
+        let types = csts.iter()
+            .map(|c| self.parse_type(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Type::new_tuple(&types))
+    }
+
+    /// Parse `(IO T)`, the type of an IO action with result `T`
+    fn parse_io_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
+        self.parse_type(one(csts, pos)?).map(Type::new_io)
     }
 
     fn parse_type_app(&mut self, name: &'s str, args_csts: &[Cst<'s>]) -> PRes<'s, Type<'s>> {
@@ -480,7 +1057,13 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     }
 
     fn parse_ptr_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
-        self.parse_type(one(csts, pos)?).map(Type::new_ptr)
+        match *one(csts, pos)? {
+            // Opaque foreign types are only valid here, so they are not handled by `parse_type`
+            Cst::Ident(s, ref pos) if self.extern_types.contains_key(s) => {
+                Ok(Type::new_ptr(Type::Const(s, Some(pos.clone()))))
+            }
+            ref c => self.parse_type(c).map(Type::new_ptr),
+        }
     }
 
     fn parse_type_sexpr(&mut self, app: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
@@ -489,6 +1072,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         match id {
             "->" => self.parse_func_type(rest, pos),
             "Cons" => self.parse_cons_type(rest, pos),
+            "IO" => self.parse_io_type(rest, pos),
             "Ptr" => self.parse_ptr_type(rest, pos),
             _ => self.parse_type_app(id, rest),
         }
@@ -498,6 +1082,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         match id {
             "_" => Ok(self.gen_type_var()),
             "Nil" => Ok(TYPE_NIL.clone()),
+            s if self.extern_types.contains_key(s) => Err(ExternTypeNotBehindPtr(pos.clone(), s)),
             // The type identifier starts with a lowercase letter => Is a type variable
             s if s.starts_with(char::is_lowercase) => Ok(Type::Var(TVar::Explicit(s))),
             // Doesn't start with lowercase => Is a type constant e.g. Int32
@@ -525,8 +1110,21 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         let first_param = first(rest, pos)?;
         let last_param = last(rest, pos)?;
         let params_pos = first_param.pos().to(last_param.pos());
-        let params_ids = rest.iter().map(|a| ident(a)).collect::<PRes<Vec<_>>>()?;
-        Ok(BindPattern::Func(f_id, (params_ids, params_pos)))
+        let params = rest.iter()
+            .map(|a| self.parse_bind_param(a))
+            .collect::<PRes<Vec<_>>>()?;
+        Ok(BindPattern::Func(f_id, (params, params_pos)))
+    }
+
+    /// Parse a parameter of a function-binding pattern, as either `NAME`, or `(NAME : TYPE)`
+    /// with a type annotation
+    fn parse_bind_param(&mut self, cst: &Cst<'s>) -> PRes<'s, (Ident<'s>, Option<Type<'s>>)> {
+        match *cst {
+            Cst::Sexpr(ref xs, _) if xs.len() == 3 && ident_s(&xs[1]).ok() == Some(":") => {
+                Ok((ident(&xs[0])?, Some(self.parse_type(&xs[2])?)))
+            }
+            _ => Ok((ident(cst)?, None)),
+        }
     }
 
     /// Parse a syntax tree as a BindPattern
@@ -669,14 +1267,29 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             .iter()
             .map(|p| Ok((ident(p)?, self.gen_type_var())))
             .collect::<PRes<Vec<_>>>()?;
-        let body = self.parse_expr(b)?;
+        let body = self.parse_func_body(b)?;
         self.new_multary_lambda(&params, params_pos, body, pos)
     }
 
+    /// Parse the body of a function, in which a `recur` may not jump to a `loop` of an
+    /// enclosing function
+    fn parse_func_body(&mut self, body: &Cst<'s>) -> PRes<'s, Expr<'s>> {
+        let loops = mem::replace(&mut self.loops, Vec::new());
+        let r = self.parse_expr(body);
+        self.loops = loops;
+        r
+    }
+
+    /// Parse a binding of `val` to `patt`, with the type signature `maybe_sig`
+    ///
+    /// Inline type annotations, of the parameters of a function pattern and of the value or
+    /// return type `maybe_ret`, are desugared to a type ascription of the whole value. Parts
+    /// that are not annotated are left to inference.
     fn parse_binding(
         &mut self,
         patt: &Cst<'s>,
         maybe_sig: Option<&Cst<'s>>,
+        maybe_ret: Option<&Cst<'s>>,
         val: &Cst<'s>,
         pos: &SrcPos<'s>,
     ) -> PRes<'s, Binding<'s>> {
@@ -688,29 +1301,62 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                     body: self.gen_type_var(),
                 })
             })?;
+        let sig_pos = maybe_sig.map(|c| c.pos().clone());
+        let ret = match maybe_ret {
+            Some(c) => Some(self.parse_type(c)?),
+            None => None,
+        };
         Ok(match self.parse_bind_pattern(patt)? {
-            BindPattern::Var(ident) => Binding {
-                ident,
-                sig,
-                val: self.parse_expr(val)?,
-                mono_insts: BTreeMap::new(),
-                pos: pos.clone(),
-            },
-            BindPattern::Func(f_id, (params_ids, params_pos)) => {
-                let params = params_ids
+            BindPattern::Var(ident) => {
+                let val = self.parse_expr(val)?;
+                Binding {
+                    ident,
+                    sig,
+                    sig_pos,
+                    val: match ret {
+                        Some(typ) => Expr::TypeAscript(Box::new(TypeAscript {
+                            typ,
+                            expr: val,
+                            pos: pos.clone(),
+                        })),
+                        None => val,
+                    },
+                    mono_insts: BTreeMap::new(),
+                    pos: pos.clone(),
+                }
+            }
+            BindPattern::Func(f_id, (params_anns, params_pos)) => {
+                let annotated = ret.is_some() || params_anns.iter().any(|p| p.1.is_some());
+                let params = params_anns
                     .into_iter()
-                    .map(|id| (id, self.gen_type_var()))
+                    .map(|(id, ann)| (id, ann.unwrap_or_else(|| self.gen_type_var())))
                     .collect::<Vec<_>>();
-                let body = self.parse_expr(val)?;
+                let body = self.parse_func_body(val)?;
+                let lam = Expr::Lambda(Box::new(self.new_multary_lambda(
+                    &params,
+                    &params_pos,
+                    body,
+                    pos,
+                )?));
+                let val = if annotated {
+                    let ret = ret.unwrap_or_else(|| self.gen_type_var());
+                    let typ = params
+                        .iter()
+                        .rev()
+                        .fold(ret, |t, &(_, ref p)| Type::new_func(p.clone(), t));
+                    Expr::TypeAscript(Box::new(TypeAscript {
+                        typ,
+                        expr: lam,
+                        pos: pos.clone(),
+                    }))
+                } else {
+                    lam
+                };
                 Binding {
                     ident: f_id,
                     sig: sig,
-                    val: Expr::Lambda(Box::new(self.new_multary_lambda(
-                        &params,
-                        &params_pos,
-                        body,
-                        pos,
-                    )?)),
+                    sig_pos,
+                    val,
                     mono_insts: BTreeMap::new(),
                     pos: pos.clone(),
                 }
@@ -735,18 +1381,25 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     ///       ((id2        x) x))
     ///   ...)
     /// ```
+    ///
+    /// The parameters and the result may be annotated with types inline
+    /// ```
+    /// (define n : Int64 1)
+    /// (define (add (x : Int32) y) : Int64
+    ///   (+ (cast x Int64) y))
+    /// ```
     fn parse_untyped_binding(
         &mut self,
         csts: &[Cst<'s>],
         pos: &SrcPos<'s>,
     ) -> PRes<'s, Binding<'s>> {
-        let (patt, val) = two(csts, pos)?;
-        self.parse_binding(patt, None, val, pos)
+        let (patt, ret, val) = split_ret_annotation(csts, pos)?;
+        self.parse_binding(patt, None, ret, val, pos)
     }
 
     fn parse_typed_binding(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Binding<'s>> {
         let (patt, typ, val) = three(csts, pos)?;
-        self.parse_binding(patt, Some(typ), val, pos)
+        self.parse_binding(patt, Some(typ), None, val, pos)
     }
 
     fn parse_bindings_to_flat_map(
@@ -784,14 +1437,102 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         &mut self,
         csts: &[Cst<'s>],
     ) -> PRes<'s, TopologicallyOrderedDependencyGroups<'s>> {
+        let mut destructured = Vec::new();
         let mut bindings_csts = Vec::new();
         for cst in csts {
-            let binding_pair = sexpr(cst)?;
-            bindings_csts.push((false, binding_pair.clone(), cst.pos().clone()))
+            let binding_csts = sexpr(cst)?;
+            let binding_cst = match binding_csts.first().map(ident_s) {
+                Some(Ok("allow")) if binding_csts.len() == 3 => {
+                    let (_, rest) = split_first(binding_csts, cst.pos())?;
+                    let allowed = self.parse_allow(rest, cst.pos())?;
+                    one(allowed, cst.pos())?
+                }
+                _ => cst,
+            };
+            let binding = sexpr(binding_cst)?;
+            if binding.len() == 2 && self.is_destructuring_patt(&binding[0]) {
+                self.expand_destructuring_binding(&binding[0], &binding[1], &mut destructured)?
+            } else {
+                bindings_csts.push((false, binding, binding_cst.pos().clone()))
+            }
+        }
+        for cst in &destructured {
+            bindings_csts.push((false, sexpr(cst)?, cst.pos().clone()))
         }
         self.parse_bindings(&bindings_csts)
     }
 
+    /// Whether `cst` is a destructuring pattern in binding position, like `(cons a b)` or
+    /// `(Some x)`, as opposed to the pattern of a function binding, like `(f x)`
+    fn is_destructuring_patt(&self, cst: &Cst<'s>) -> bool {
+        match *cst {
+            Cst::Sexpr(ref xs, _) => match xs.first().map(ident_s) {
+                Some(Ok("cons")) => true,
+                Some(Ok(s)) => self.adts.variant_exists(s),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Expand a destructuring binding of `patt` to `val` into a binding of the whole value,
+    /// and bindings of each sub-pattern to a `car`, `cdr`, or `match` extraction from it
+    ///
+    /// `((cons a (Some b)) v)` is expanded to
+    ///
+    /// ```ignore
+    /// [whole-1 v]
+    /// [a (car whole-1)]
+    /// [whole-2 (cdr whole-1)]
+    /// [b (match whole-2 ((Some field) field))]
+    /// ```
+    /// where `whole-1`, `whole-2`, and `field` are names that can't be written in source code.
+    /// The names of the whole values are generated for each pattern. Sub-patterns of `_` are
+    /// not bound.
+    fn expand_destructuring_binding(
+        &mut self,
+        patt: &Cst<'s>,
+        val: &Cst<'s>,
+        out: &mut Vec<Cst<'s>>,
+    ) -> PRes<'s, ()> {
+        const FIELD: &str = "let field";
+        let pos = patt.pos();
+        let id = |s| Cst::Ident(s, pos.clone());
+        let list = |xs| Cst::Sexpr(xs, pos.clone());
+        match *patt {
+            Cst::Ident("_", _) => Ok(()),
+            Cst::Ident(s, _) if s != "nil" && !self.adts.variant_exists(s) => {
+                out.push(list(vec![patt.clone(), val.clone()]));
+                Ok(())
+            }
+            Cst::Sexpr(ref xs, _) if self.is_destructuring_patt(patt) => {
+                let whole = id(self.type_var_gen.gensym());
+                out.push(list(vec![whole.clone(), val.clone()]));
+                let (head, subpatts) = split_first(xs, pos)?;
+                if ident_s(head)? == "cons" {
+                    let (a, d) = two(subpatts, pos)?;
+                    let car = list(vec![id("car"), whole.clone()]);
+                    let cdr = list(vec![id("cdr"), whole]);
+                    self.expand_destructuring_binding(a, &car, out)?;
+                    self.expand_destructuring_binding(d, &cdr, out)
+                } else {
+                    for (i, subpatt) in subpatts.iter().enumerate() {
+                        let fields = (0..subpatts.len()).map(|j| if i == j { FIELD } else { "_" });
+                        let deconstr = once(head.clone()).chain(fields.map(&id)).collect();
+                        let extraction = list(vec![
+                            id("match"),
+                            whole.clone(),
+                            list(vec![list(deconstr), id(FIELD)]),
+                        ]);
+                        self.expand_destructuring_binding(subpatt, &extraction, out)?
+                    }
+                    Ok(())
+                }
+            }
+            _ => Err(InvalidBindPatt(pos.clone())),
+        }
+    }
+
     /// Parse a `let` special form and return as an invocation of a lambda
     fn parse_let(
         &mut self,
@@ -801,9 +1542,20 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     ) -> PRes<'s, Let<'s>> {
         let (a, b) = two(csts, args_pos)?;
         let binds_csts = sexpr(a)?;
+        let bindings = self.parse_let_bindings(binds_csts)?;
+        let body = self.parse_expr(b)?;
+        for binding in unused_bindings(&bindings, &body) {
+            if !binding.ident.s.starts_with('_') {
+                lint::emit(
+                    &UNUSED_LET_BINDING,
+                    &binding.ident.pos,
+                    format!("Unused variable `{}`", binding.ident.s),
+                )
+            }
+        }
         Ok(Let {
-            bindings: self.parse_let_bindings(binds_csts)?,
-            body: self.parse_expr(b)?,
+            bindings,
+            body,
             typ: self.gen_type_var(),
             pos: pos.clone(),
         })
@@ -871,14 +1623,43 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     /// Parse a type cast
     ///
     /// `(cast VAL TYPE)`, e.g. `(cast (: 1 Int32) Int64)`
+    /// Parse the arguments of a `cast`, or of a `cast-checked` if `checked`
     fn parse_cast(
         &mut self,
         csts: &[Cst<'s>],
+        checked: bool,
         pos: &SrcPos<'s>,
         args_pos: &SrcPos<'s>,
     ) -> PRes<'s, Cast<'s>> {
         let (a, b) = two(csts, args_pos)?;
+        let expr = self.parse_expr(a)?;
+        let typ = self.parse_type(b)?;
+        // The pointer could be dereferenced by the GC, so it must be valid. A target type
+        // that is only inferred to be a pointer is checked in codegen
+        if typ.get_ptr().is_some() && !self.in_unsafe {
+            let name = if checked { "cast-checked" } else { "cast" };
+            return Err(UnsafeOutsideUnsafe(pos.clone(), name));
+        }
         Ok(Cast {
+            expr,
+            typ,
+            checked,
+            in_unsafe: self.in_unsafe,
+            pos: pos.clone(),
+        })
+    }
+
+    fn parse_transmute(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Transmute<'s>> {
+        if !self.in_unsafe {
+            return Err(UnsafeOutsideUnsafe(pos.clone(), "unsafe-transmute"));
+        }
+        let (a, b) = two(csts, args_pos)?;
+        Ok(Transmute {
             expr: self.parse_expr(a)?,
             typ: self.parse_type(b)?,
             pos: pos.clone(),
@@ -933,11 +1714,53 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
+    /// Parse a range pattern, `(range LO HI)`, of the numbers from `LO` up to and including
+    /// `HI`
+    fn parse_range_pattern(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, RangePatt<'s>> {
+        let (lo_c, hi_c) = two(csts, pos)?;
+        let mut bound = |c: &Cst<'s>| match *c {
+            Cst::Num(num, ref pos) => Ok(NumLit {
+                lit: num.into(),
+                typ: self.gen_type_var(),
+                pos: pos.clone(),
+            }),
+            _ => Err(Expected(c.pos().clone(), "numeric literal")),
+        };
+        let (lo, hi) = (bound(lo_c)?, bound(hi_c)?);
+        match (lo.lit.parse::<f64>(), hi.lit.parse::<f64>()) {
+            (Ok(l), Ok(h)) if l > h => {
+                Err(Expected(pos.clone(), "lower bound not greater than upper bound"))
+            }
+            _ => Ok(RangePatt {
+                lo,
+                hi,
+                pos: pos.clone(),
+            }),
+        }
+    }
+
     fn parse_pattern(&mut self, cst: &Cst<'s>) -> PRes<'s, Pattern<'s>> {
         match *cst {
-            Cst::Sexpr(ref sexpr, ref pos) => self.parse_deconstr_pattern(sexpr, pos)
-                .map(|d| Pattern::Deconstr(box d)),
+            Cst::Sexpr(ref sexpr, ref pos) => match sexpr.split_first() {
+                Some((head, rest)) if ident_s(head).ok() == Some("range") => {
+                    self.parse_range_pattern(rest, pos).map(Pattern::Range)
+                }
+                _ => self.parse_deconstr_pattern(sexpr, pos)
+                    .map(|d| Pattern::Deconstr(box d)),
+            },
             Cst::Ident("nil", ref pos) => Ok(Pattern::Nil(Nil { pos: pos.clone() })),
+            Cst::Ident("true", ref pos) => Ok(Pattern::Bool(Bool {
+                val: true,
+                pos: pos.clone(),
+            })),
+            Cst::Ident("false", ref pos) => Ok(Pattern::Bool(Bool {
+                val: false,
+                pos: pos.clone(),
+            })),
             Cst::Ident(ident, ref pos) if self.adts.variant_exists(ident) => {
                 Ok(Pattern::Deconstr(box Deconstr {
                     constr: Ident::new(ident, pos.clone()),
@@ -950,7 +1773,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 typ: self.gen_type_var(),
             })),
             Cst::Num(num, ref pos) => Ok(Pattern::NumLit(NumLit {
-                lit: num,
+                lit: num.into(),
                 typ: self.gen_type_var(),
                 pos: pos.clone(),
             })),
@@ -990,37 +1813,680 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
-    fn parse_special_form(
+    /// Read the contents of the file at `path_cst`, relative to the directory of the
+    /// including source file
+    fn read_included_file(&mut self, path_cst: &Cst<'s>) -> PRes<'s, (PathBuf, Vec<u8>)> {
+        let (path_s, pos) = match *path_cst {
+            Cst::Str(ref s, ref pos) => (s, pos),
+            _ => return Err(Expected(path_cst.pos().clone(), "string literal path")),
+        };
+        let path = pos.filename
+            .parent()
+            .map(|dir| dir.join(&**path_s))
+            .unwrap_or(PathBuf::from(&**path_s));
+        let mut contents = Vec::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .map_err(|e| IncludeFailed {
+                pos: pos.clone(),
+                path: path.clone(),
+                err: e.to_string(),
+            })?;
+        Ok((path, contents))
+    }
+
+    /// Parse an `include-str` special form
+    ///
+    /// `(include-str "path")` is replaced at compile time by a string literal
+    /// of the contents of the file at `path`
+    fn parse_include_str(
         &mut self,
-        head: &Cst<'s>,
-        tail: &[Cst<'s>],
+        csts: &[Cst<'s>],
         pos: &SrcPos<'s>,
-    ) -> PRes<'s, Expr<'s>> {
-        let form = ident(head)?;
-        let tail_pos = pos.after(&form.pos);
-        match form.s {
-            "if" => Ok(Expr::If(Box::new(self.parse_if(tail, pos, &tail_pos)?))),
-            "lambda" => Ok(Expr::Lambda(Box::new(self.parse_lambda(
-                tail,
-                pos,
-                &tail_pos,
-            )?))),
-            "let" => Ok(Expr::Let(Box::new(self.parse_let(tail, pos, &tail_pos)?))),
-            ":" => Ok(Expr::TypeAscript(Box::new(self.parse_type_ascript(
-                tail,
-                pos,
-                &tail_pos,
-            )?))),
-            "cons" => Ok(Expr::Cons(Box::new(self.parse_cons(tail, pos, &tail_pos)?))),
-            "car" => Ok(Expr::Car(Box::new(self.parse_car(tail, pos, &tail_pos)?))),
-            "cdr" => Ok(Expr::Cdr(Box::new(self.parse_cdr(tail, pos, &tail_pos)?))),
-            "cast" => Ok(Expr::Cast(Box::new(self.parse_cast(tail, pos, &tail_pos)?))),
-            "new" => Ok(Expr::New(Box::new(self.parse_new(tail, pos, &tail_pos)?))),
-            "match" => Ok(Expr::Match(Box::new(self.parse_match(
-                tail,
-                pos,
-                &tail_pos,
-            )?))),
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, StrLit<'s>> {
+        let path_cst = one(csts, args_pos)?;
+        let (path, contents) = self.read_included_file(path_cst)?;
+        let lit = String::from_utf8(contents).map_err(|_| IncludeFailed {
+            pos: path_cst.pos().clone(),
+            path,
+            err: "file is not valid UTF-8. Try `include-bytes` instead".to_string(),
+        })?;
+        Ok(StrLit {
+            lit: Cow::Owned(lit),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse an `include-bytes` special form
+    ///
+    /// `(include-bytes "path")` is replaced at compile time by a byte array literal
+    /// of the contents of the file at `path`, of type `(Cons UIntPtr (Ptr UInt8))`
+    fn parse_include_bytes(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, BytesLit<'s>> {
+        let (_, bytes) = self.read_included_file(one(csts, args_pos)?)?;
+        Ok(BytesLit {
+            bytes,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse an `assert` special form, `(assert EXPR)`
+    fn parse_assert(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Assert<'s>> {
+        let cond_cst = one(csts, args_pos)?;
+        Ok(Assert {
+            cond: self.parse_expr(cond_cst)?,
+            msg: format!("Assertion failed: `{}`", cond_cst),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse an `assert-eq` special form, `(assert-eq A B)`, as an assertion of `(= A B)`
+    ///
+    /// Uses the `=` in scope, which is usually the one of `std`
+    fn parse_assert_eq(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Assert<'s>> {
+        let (a, b) = two(csts, args_pos)?;
+        let eq_cst = Cst::Sexpr(
+            vec![Cst::Ident("=", pos.clone()), a.clone(), b.clone()],
+            pos.clone(),
+        );
+        Ok(Assert {
+            cond: self.parse_expr(&eq_cst)?,
+            msg: format!("Assertion failed: `{}` is not equal to `{}`", a, b),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `loop` special form, `(loop [[VAR INIT] ...] BODY)`
+    fn parse_loop(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Loop<'s>> {
+        let (a, b) = two(csts, args_pos)?;
+        let mut vars = Vec::new();
+        let mut inits = Vec::new();
+        for binding in sexpr(a)? {
+            let (var, init) = pair(binding)?;
+            vars.push(Variable {
+                ident: ident(var)?,
+                typ: self.gen_type_var(),
+            });
+            inits.push(self.parse_expr(init)?);
+        }
+        self.loops.push(vars.len());
+        let body = self.parse_expr(b);
+        self.loops.pop();
+        let body = body?;
+        if let Some(recur_pos) = recur_not_in_tail(&body, true) {
+            return Err(RecurNotInTail(recur_pos.clone()));
+        }
+        Ok(Loop {
+            vars,
+            inits,
+            body,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `recur` special form, `(recur ARG ...)`, with one argument for each variable of
+    /// the innermost enclosing loop
+    fn parse_recur(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Recur<'s>> {
+        let n_vars = *self.loops
+            .last()
+            .ok_or_else(|| RecurOutsideLoop(pos.clone()))?;
+        if csts.len() != n_vars {
+            return Err(ArityMis(pos.clone(), n_vars, csts.len()));
+        }
+        Ok(Recur {
+            args: csts.iter()
+                .map(|c| self.parse_expr(c))
+                .collect::<PRes<_>>()?,
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse an `unsafe` special form, `(unsafe BODY)`, in which unsafe operations are
+    /// allowed. The form has the value of the body
+    fn parse_unsafe(&mut self, csts: &[Cst<'s>], args_pos: &SrcPos<'s>) -> PRes<'s, Expr<'s>> {
+        let body = one(csts, args_pos)?;
+        let was_unsafe = mem::replace(&mut self.in_unsafe, true);
+        let r = self.parse_expr(body);
+        self.in_unsafe = was_unsafe;
+        r
+    }
+
+    /// Construct the data constructor `constr` of a prelude type, like `List`, with `members`
+    fn new_prelude_variant(
+        &mut self,
+        constr: &'s str,
+        members: Vec<Expr<'s>>,
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        Ok(Expr::New(Box::new(New {
+            constr: self.parse_variant(&Cst::Ident(constr, pos.clone()))?,
+            members,
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })))
+    }
+
+    /// Build the quoted datum `cst` at runtime as a value of the prelude type `Sexpr`
+    fn quote_datum(&mut self, cst: &Cst<'s>) -> PRes<'s, Expr<'s>> {
+        let pos = cst.pos().clone();
+        let (constr, member) = match *cst {
+            Cst::Ident(name, _) => {
+                let name = if name == "'" { "quote" } else { name };
+                let sym = Expr::SymLit(SymLit {
+                    name,
+                    pos: pos.clone(),
+                });
+                ("SexprSym", sym)
+            }
+            Cst::Num(num, _) if num.contains('.') => ("SexprFloat", self.parse_expr(cst)?),
+            Cst::Num(..) => ("SexprInt", self.parse_expr(cst)?),
+            Cst::Str(..) => ("SexprStr", self.parse_expr(cst)?),
+            Cst::Sexpr(ref cs, _) => {
+                let mut list = self.new_prelude_variant("Empty", vec![], &pos)?;
+                for c in cs.iter().rev() {
+                    let item = self.quote_datum(c)?;
+                    list = self.new_prelude_variant("List", vec![item, list], &pos)?;
+                }
+                ("SexprList", list)
+            }
+        };
+        self.new_prelude_variant(constr, vec![member], &pos)
+    }
+
+    /// Parse a quote, `'DATUM` or `(quote DATUM)`
+    ///
+    /// A quoted identifier, like `'foo`, is a symbol literal. Any other quoted datum is built
+    /// as a value of the prelude type `Sexpr`
+    fn parse_quote(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Expr<'s>> {
+        match *one(csts, pos)? {
+            Cst::Ident(name, _) if name != "'" => Ok(Expr::SymLit(SymLit {
+                name,
+                pos: pos.clone(),
+            })),
+            ref datum => self.quote_datum(datum),
+        }
+    }
+
+    /// The syntax tree of a `List` of `items`, in prelude constructors
+    fn list_cst(&self, items: Vec<Cst<'s>>, pos: &SrcPos<'s>) -> Cst<'s> {
+        let id = |s| Cst::Ident(s, pos.clone());
+        let list = |xs| Cst::Sexpr(xs, pos.clone());
+        items
+            .into_iter()
+            .rev()
+            .fold(list(vec![id("new"), id("Empty")]), |rest, item| {
+                list(vec![id("new"), id("List"), item, rest])
+            })
+    }
+
+    /// Parse a length-list literal, `#[ITEM...]`, as a `LenList` of the items
+    fn parse_len_list_literal(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Expr<'s>> {
+        let len = Expr::NumLit(NumLit {
+            lit: csts.len().to_string().into(),
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        });
+        let items = self.list_cst(csts.to_vec(), pos);
+        let items = self.parse_expr(&items)?;
+        self.new_prelude_variant("LenList", vec![len, items], pos)
+    }
+
+    /// Parse an association list literal, `{KEY VAL...}`, as an `AssocList` of the key-value
+    /// pairs
+    fn parse_assoc_list_literal(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Expr<'s>> {
+        if csts.len() % 2 != 0 {
+            return Err(Expected(
+                csts.last().unwrap().pos().clone(),
+                "a value for each key of the association list literal",
+            ));
+        }
+        let entries = csts.chunks(2)
+            .map(|kv| Cst::Sexpr(
+                vec![Cst::Ident("cons", pos.clone()), kv[0].clone(), kv[1].clone()],
+                pos.clone(),
+            ))
+            .collect();
+        let entries = self.list_cst(entries, pos);
+        self.parse_expr(&Cst::Sexpr(
+            vec![
+                Cst::Ident("new", pos.clone()),
+                Cst::Ident("AssocList", pos.clone()),
+                entries,
+            ],
+            pos.clone(),
+        ))
+    }
+
+    /// Parse a primitive pointer operation, like `(ptr-read PTR)`, in an `unsafe` form
+    fn parse_ptr_op(
+        &mut self,
+        name: &'s str,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, PtrOp<'s>> {
+        let kind = PtrOpKind::from_name(name).expect("ICE: not a pointer operation");
+        if !self.in_unsafe {
+            return Err(UnsafeOutsideUnsafe(pos.clone(), name));
+        }
+        let (args, ordering) = if kind.is_atomic() {
+            let (ordering, args) = split_last(csts, pos)?;
+            (args, Some(atomic_ordering(kind, ordering)?))
+        } else {
+            (csts, None)
+        };
+        if args.len() != kind.arity() {
+            return Err(ArityMis(pos.clone(), kind.arity(), args.len()));
+        }
+        Ok(PtrOp {
+            kind,
+            args: args.iter()
+                .map(|c| self.parse_expr(c))
+                .collect::<PRes<_>>()?,
+            ordering,
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a primitive thread operation, like `(thread-spawn IO)`, in an `unsafe` form
+    fn parse_thread_op(
+        &mut self,
+        name: &'s str,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, ThreadOp<'s>> {
+        let kind = ThreadOpKind::from_name(name).expect("ICE: not a thread operation");
+        if !self.in_unsafe {
+            return Err(UnsafeOutsideUnsafe(pos.clone(), name));
+        }
+        if csts.len() != kind.arity() {
+            return Err(ArityMis(pos.clone(), kind.arity(), csts.len()));
+        }
+        Ok(ThreadOp {
+            kind,
+            args: csts.iter()
+                .map(|c| self.parse_expr(c))
+                .collect::<PRes<_>>()?,
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `begin` special form, `(begin EXPR ... LAST)`
+    fn parse_begin(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Begin<'s>> {
+        if csts.is_empty() {
+            return Err(ArityMisTooFew(pos.clone(), 0));
+        }
+        Ok(Begin {
+            exprs: csts.iter()
+                .map(|c| self.parse_expr(c))
+                .collect::<PRes<_>>()?,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `do-io` special form, `(do-io STMT ... RESULT)`, as an IO action that threads
+    /// the `RealWorld` through each statement, and then the result
+    ///
+    /// A statement is either an IO action, or `(VAR <- ACTION)` to bind the value of the
+    /// action. Translates to nested `let`s in a lambda of the world, e.g.
+    /// ```
+    /// (do-io (x <- read-int64)
+    ///        (print-int64 x))
+    /// ```
+    /// becomes
+    /// ```
+    /// (lambda (world)
+    ///   (let [[r (read-int64 world)]]
+    ///     (let [[world (cdr r)] [x (car r)]]
+    ///       ((print-int64 x) world))))
+    /// ```
+    /// where `world` and `r` are names that can't be written in source code.
+    fn parse_do_io(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        const WORLD: &str = "do-io world";
+        const RESULT: &str = "do-io result";
+        let id = |s| Cst::Ident(s, pos.clone());
+        let list = |xs| Cst::Sexpr(xs, pos.clone());
+        let (result, stmts) = split_last(csts, args_pos)?;
+        if arrow_binding(result).is_some() {
+            return Err(Expected(
+                result.pos().clone(),
+                "an IO action as the last form of `do-io`",
+            ));
+        }
+        let body = stmts
+            .iter()
+            .rev()
+            .fold(list(vec![result.clone(), id(WORLD)]), |rest, stmt| {
+                let (var, action) = match arrow_binding(stmt) {
+                    Some((var, action)) => (Some(var), action),
+                    None => (None, stmt),
+                };
+                let mut inner_bindings =
+                    vec![list(vec![id(WORLD), list(vec![id("cdr"), id(RESULT)])])];
+                if let Some(var) = var {
+                    inner_bindings.push(list(vec![var.clone(), list(vec![id("car"), id(RESULT)])]))
+                }
+                list(vec![
+                    id("let"),
+                    list(vec![list(vec![id(RESULT), list(vec![action.clone(), id(WORLD)])])]),
+                    list(vec![id("let"), list(inner_bindings), rest]),
+                ])
+            });
+        self.parse_expr(&list(vec![id("lambda"), list(vec![id(WORLD)]), body]))
+    }
+
+    /// Parse a threading form, `(-> X STEP...)` or `(->> X STEP...)`, by rewriting it as
+    /// nested applications
+    ///
+    /// Each step is an application, or a function to apply, that `X` is threaded through
+    /// in turn. With `->`, the value is inserted as the first argument of each step, and
+    /// with `->>` as the last. E.g. `(-> x (f a) g)` is rewritten to `(g (f x a))`, and
+    /// `(->> x (f a) g)` to `(g (f a x))`.
+    fn parse_thread(
+        &mut self,
+        csts: &[Cst<'s>],
+        args_pos: &SrcPos<'s>,
+        last: bool,
+    ) -> PRes<'s, Expr<'s>> {
+        let (x, steps) = split_first(csts, args_pos)?;
+        let threaded = steps.iter().fold(x.clone(), |acc, step| {
+            let pos = step.pos().clone();
+            match *step {
+                Cst::Sexpr(ref app, _) if !app.is_empty() => {
+                    let mut app = app.clone();
+                    if last {
+                        app.push(acc)
+                    } else {
+                        app.insert(1, acc)
+                    }
+                    Cst::Sexpr(app, pos)
+                }
+                _ => Cst::Sexpr(vec![step.clone(), acc], pos),
+            }
+        });
+        self.parse_expr(&threaded)
+    }
+
+    /// Parse a conditional binding, `(if-let (PATTERN VALUE) CONSEQUENT ALTERNATIVE)`,
+    /// as a `Match` of two cases
+    ///
+    /// If `VALUE` matches `PATTERN`, `CONSEQUENT` is evaluated with the variables of the
+    /// pattern bound. Otherwise, `ALTERNATIVE` is evaluated.
+    fn parse_if_let(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Match<'s>> {
+        let (binding, consequent, alternative) = three(csts, args_pos)?;
+        let (patt, val) = pair(binding)?;
+        let wildcard = Pattern::Variable(Variable {
+            ident: Ident::new("_", alternative.pos().clone()),
+            typ: self.gen_type_var(),
+        });
+        Ok(Match {
+            expr: self.parse_expr(val)?,
+            cases: vec![
+                Case {
+                    patt: self.parse_pattern(patt)?,
+                    patt_typ: self.gen_type_var(),
+                    body: self.parse_expr(consequent)?,
+                    pos: binding.pos().clone(),
+                },
+                Case {
+                    patt: wildcard,
+                    patt_typ: self.gen_type_var(),
+                    body: self.parse_expr(alternative)?,
+                    pos: alternative.pos().clone(),
+                },
+            ],
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `try` special form, `(try STMT ... RESULT)`, as a sequence of `Either`
+    /// computations that stops at the first `Left`
+    ///
+    /// A statement is either an `Either` expression, or `(PATTERN <- EXPR)` to bind the
+    /// `Right` value of the expression. If any statement evaluates to a `Left`, that is the
+    /// value of the whole form. Otherwise, the value is that of `RESULT`. Translates to
+    /// nested `match`es, e.g.
+    /// ```
+    /// (try (x <- (parse s))
+    ///      (check x)
+    ///      (Right (* x 2)))
+    /// ```
+    /// becomes
+    /// ```
+    /// (match (parse s)
+    ///   [(Left err) (new Left err)]
+    ///   [(Right x) (match (check x)
+    ///                [(Left err) (new Left err)]
+    ///                [(Right _) (Right (* x 2))])])
+    /// ```
+    /// where `err` is a name that can't be written in source code.
+    fn parse_try(&mut self, csts: &[Cst<'s>], args_pos: &SrcPos<'s>) -> PRes<'s, Expr<'s>> {
+        const ERR: &str = "try error";
+        let (result, stmts) = split_last(csts, args_pos)?;
+        if arrow_binding(result).is_some() {
+            return Err(Expected(
+                result.pos().clone(),
+                "an `Either` expression as the last form of `try`",
+            ));
+        }
+        let body = stmts.iter().rev().fold(result.clone(), |rest, stmt| {
+            let pos = stmt.pos().clone();
+            let id = |s| Cst::Ident(s, pos.clone());
+            let list = |xs| Cst::Sexpr(xs, pos.clone());
+            let (patt, val) = match arrow_binding(stmt) {
+                Some((patt, val)) => (patt.clone(), val),
+                None => (id("_"), stmt),
+            };
+            list(vec![
+                id("match"),
+                val.clone(),
+                list(vec![
+                    list(vec![id("Left"), id(ERR)]),
+                    list(vec![id("new"), id("Left"), id(ERR)]),
+                ]),
+                list(vec![list(vec![id("Right"), patt]), rest]),
+            ])
+        });
+        self.parse_expr(&body)
+    }
+
+    /// Parse a `defer` special form, `(defer CLEANUP BODY)`, which evaluates `CLEANUP` after
+    /// `BODY`, and has the value of `BODY`
+    ///
+    /// Translates to `(let [[result BODY]] (begin (: CLEANUP Nil) result))`, where `result` is
+    /// a name that can't be written in source code. A `recur` may not jump out of a `defer`,
+    /// as that would skip the cleanup. The cleanup must be of type `Nil`, as an IO action
+    /// would only be created, not run, and the world can't be threaded through it here. Use
+    /// `finally` of the standard library to clean up after an IO action.
+    fn parse_defer(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        const RESULT: &str = "defer result";
+        let (cleanup, body) = two(csts, args_pos)?;
+        let id = |s| Cst::Ident(s, pos.clone());
+        let list = |xs| Cst::Sexpr(xs, pos.clone());
+        let deferred = list(vec![
+            id("let"),
+            list(vec![list(vec![id(RESULT), body.clone()])]),
+            list(vec![
+                id("begin"),
+                list(vec![id(":"), cleanup.clone(), id("Nil")]),
+                id(RESULT),
+            ]),
+        ]);
+        let loops = mem::replace(&mut self.loops, Vec::new());
+        let r = self.parse_expr(&deferred);
+        self.loops = loops;
+        r
+    }
+
+    /// Parse a one-armed conditional, `(when PREDICATE BODY)` or `(unless PREDICATE BODY)`,
+    /// as an `If` of type `Nil` where the other branch is `nil`
+    ///
+    /// If `negate`, the body is evaluated when the predicate is false, as with `unless`.
+    fn parse_when(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+        negate: bool,
+    ) -> PRes<'s, If<'s>> {
+        let (p, b) = two(csts, args_pos)?;
+        let predicate = self.parse_expr(p)?;
+        let body = self.parse_expr(b)?;
+        let nil = Expr::Nil(Nil { pos: pos.clone() });
+        let (consequent, alternative) = if negate { (nil, body) } else { (body, nil) };
+        Ok(If {
+            predicate,
+            consequent,
+            alternative,
+            typ: self.gen_type_var(),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a `with-arena` special form, `(with-arena BODY)`, in an `unsafe` form
+    ///
+    /// Arena objects are not traced, so nothing keeps the body from letting one escape.
+    fn parse_with_arena(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, WithArena<'s>> {
+        if !self.in_unsafe {
+            return Err(UnsafeOutsideUnsafe(pos.clone(), "with-arena"));
+        }
+        Ok(WithArena {
+            body: self.parse_expr(one(csts, args_pos)?)?,
+            pos: pos.clone(),
+        })
+    }
+
+    fn parse_special_form(
+        &mut self,
+        head: &Cst<'s>,
+        tail: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let form = ident(head)?;
+        let tail_pos = pos.after(&form.pos);
+        match form.s {
+            "if" => Ok(Expr::If(Box::new(self.parse_if(tail, pos, &tail_pos)?))),
+            "lambda" => Ok(Expr::Lambda(Box::new(self.parse_lambda(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
+            "let" => Ok(Expr::Let(Box::new(self.parse_let(tail, pos, &tail_pos)?))),
+            ":" => Ok(Expr::TypeAscript(Box::new(self.parse_type_ascript(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
+            "cons" => Ok(Expr::Cons(Box::new(self.parse_cons(tail, pos, &tail_pos)?))),
+            "car" => Ok(Expr::Car(Box::new(self.parse_car(tail, pos, &tail_pos)?))),
+            "cdr" => Ok(Expr::Cdr(Box::new(self.parse_cdr(tail, pos, &tail_pos)?))),
+            "cast" => Ok(Expr::Cast(Box::new(self.parse_cast(tail, false, pos, &tail_pos)?))),
+            "cast-checked" => Ok(Expr::Cast(Box::new(self.parse_cast(
+                tail,
+                true,
+                pos,
+                &tail_pos,
+            )?))),
+            "unsafe-transmute" => Ok(Expr::Transmute(Box::new(self.parse_transmute(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
+            "new" => Ok(Expr::New(Box::new(self.parse_new(tail, pos, &tail_pos)?))),
+            "match" => Ok(Expr::Match(Box::new(self.parse_match(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
+            "include-str" => Ok(Expr::StrLit(self.parse_include_str(tail, pos, &tail_pos)?)),
+            "include-bytes" => Ok(Expr::BytesLit(self.parse_include_bytes(
+                tail,
+                pos,
+                &tail_pos,
+            )?)),
+            "assert" => Ok(Expr::Assert(Box::new(self.parse_assert(tail, pos, &tail_pos)?))),
+            "assert-eq" => Ok(Expr::Assert(Box::new(self.parse_assert_eq(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
+            "loop" => Ok(Expr::Loop(Box::new(self.parse_loop(tail, pos, &tail_pos)?))),
+            "recur" => Ok(Expr::Recur(Box::new(self.parse_recur(tail, pos)?))),
+            "begin" => Ok(Expr::Begin(Box::new(self.parse_begin(tail, pos)?))),
+            "do-io" => self.parse_do_io(tail, pos, &tail_pos),
+            "when" => Ok(Expr::If(Box::new(self.parse_when(tail, pos, &tail_pos, false)?))),
+            "unless" => Ok(Expr::If(Box::new(self.parse_when(tail, pos, &tail_pos, true)?))),
+            "if-let" => Ok(Expr::Match(Box::new(self.parse_if_let(tail, pos, &tail_pos)?))),
+            "->" => self.parse_thread(tail, &tail_pos, false),
+            "->>" => self.parse_thread(tail, &tail_pos, true),
+            "try" => self.parse_try(tail, &tail_pos),
+            "defer" => self.parse_defer(tail, pos, &tail_pos),
+            "unsafe" => self.parse_unsafe(tail, &tail_pos),
+            "'" | "quote" => self.parse_quote(tail, pos),
+            "len-list-literal" => self.parse_len_list_literal(tail, pos),
+            "assoc-list-literal" => self.parse_assoc_list_literal(tail, pos),
+            "infix-math" => {
+                let prefix = infix_to_prefix(tail, pos)?;
+                self.parse_expr(&prefix)
+            }
+            "ptr-read" | "ptr-write" | "ptr-offset" | "null-ptr" | "ptr-eq"
+            | "ptr-read-volatile" | "ptr-write-volatile" | "atomic-load" | "atomic-store"
+            | "atomic-fetch-add" | "atomic-cas" => {
+                Ok(Expr::PtrOp(Box::new(self.parse_ptr_op(form.s, tail, pos)?)))
+            }
+            "thread-spawn" | "thread-join" | "chan-new" | "chan-send" | "chan-recv"
+            | "mutex-new" | "mutex-lock" | "mutex-unlock" | "task-spawn" | "task-await"
+            | "task-yield" => {
+                Ok(Expr::ThreadOp(Box::new(self.parse_thread_op(form.s, tail, pos)?)))
+            }
+            "with-arena" => Ok(Expr::WithArena(Box::new(self.parse_with_arena(
+                tail,
+                pos,
+                &tail_pos,
+            )?))),
 
             // "Macros"
             "cond" => self.parse_cond(tail, &tail_pos),
@@ -1043,31 +2509,55 @@ impl<'tvg, 's> Parser<'tvg, 's> {
 
     /// Parse a `Cst` as an `Expr`
     fn parse_expr(&mut self, cst: &Cst<'s>) -> PRes<'s, Expr<'s>> {
-        match *cst {
-            Cst::Sexpr(ref sexpr, ref pos) => self.parse_sexpr_expr(sexpr, pos),
-            Cst::Ident("nil", ref pos) => Ok(Expr::Nil(Nil { pos: pos.clone() })),
-            Cst::Ident("true", ref pos) => Ok(Expr::Bool(Bool {
-                val: true,
-                pos: pos.clone(),
-            })),
-            Cst::Ident("false", ref pos) => Ok(Expr::Bool(Bool {
-                val: false,
-                pos: pos.clone(),
-            })),
-            Cst::Ident(ident, ref pos) => Ok(Expr::Variable(Variable {
-                ident: Ident::new(ident, pos.clone()),
-                typ: self.gen_type_var(),
-            })),
-            Cst::Num(num, ref pos) => Ok(Expr::NumLit(NumLit {
-                lit: num,
-                typ: self.gen_type_var(),
-                pos: pos.clone(),
-            })),
-            Cst::Str(ref s, ref pos) => Ok(Expr::StrLit(StrLit {
-                lit: s.clone(),
-                pos: pos.clone(),
-            })),
-        }
+        grow_stack(|| {
+            match *cst {
+                Cst::Sexpr(ref sexpr, ref pos) => self.parse_sexpr_expr(sexpr, pos),
+                Cst::Ident("nil", ref pos) => Ok(Expr::Nil(Nil { pos: pos.clone() })),
+                Cst::Ident("true", ref pos) => Ok(Expr::Bool(Bool {
+                    val: true,
+                    pos: pos.clone(),
+                })),
+                Cst::Ident("false", ref pos) => Ok(Expr::Bool(Bool {
+                    val: false,
+                    pos: pos.clone(),
+                })),
+                // A keyword style symbol literal, like `:foo`
+                Cst::Ident(ident, ref pos) if ident.len() > 1 && ident.starts_with(':') => {
+                    Ok(Expr::SymLit(SymLit {
+                        name: &ident[1..],
+                        pos: pos.clone(),
+                    }))
+                }
+                // A variant without members is a value. Other variants refer to their
+                // constructor functions
+                Cst::Ident(ident, ref pos)
+                    if self.adts
+                        .adt_variant_of_name(ident)
+                        .map(|v| v.members.is_empty())
+                        .unwrap_or(false) =>
+                {
+                    Ok(Expr::New(Box::new(New {
+                        constr: Ident::new(ident, pos.clone()),
+                        members: vec![],
+                        typ: self.gen_type_var(),
+                        pos: pos.clone(),
+                    })))
+                }
+                Cst::Ident(ident, ref pos) => Ok(Expr::Variable(Variable {
+                    ident: Ident::new(ident, pos.clone()),
+                    typ: self.gen_type_var(),
+                })),
+                Cst::Num(num, ref pos) => Ok(Expr::NumLit(NumLit {
+                    lit: num.into(),
+                    typ: self.gen_type_var(),
+                    pos: pos.clone(),
+                })),
+                Cst::Str(ref s, ref pos) => Ok(Expr::StrLit(StrLit {
+                    lit: s.clone(),
+                    pos: pos.clone(),
+                })),
+            }
+        })
     }
 
     fn parse_data_binding(&mut self, cst: &Cst<'s>) -> PRes<'s, (Ident<'s>, Vec<&'s str>)> {
@@ -1112,8 +2602,27 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         cs.iter().map(|c| self.parse_type(c)).collect()
     }
 
-    /// Parse a variant of a data type definition
+    /// Parse a variant of a data type definition, optionally annotated as `(boxed VARIANT)` or
+    /// `(unboxed VARIANT)`
     fn parse_data_type_variant(&mut self, c: &Cst<'s>) -> PRes<'s, AdtVariant<'s>> {
+        if let Cst::Sexpr(ref cs, ref p) = *c {
+            let boxing = match cs.first() {
+                Some(&Cst::Ident("boxed", _)) => Some(Boxing::Boxed),
+                Some(&Cst::Ident("unboxed", _)) => Some(Boxing::Unboxed),
+                _ => None,
+            };
+            if let Some(boxing) = boxing {
+                if cs.len() != 2 {
+                    return Err(InvalidAdtVariant(p.clone()));
+                }
+                let variant = self.parse_data_type_variant_unannotated(&cs[1])?;
+                return Ok(AdtVariant { boxing, ..variant });
+            }
+        }
+        self.parse_data_type_variant_unannotated(c)
+    }
+
+    fn parse_data_type_variant_unannotated(&mut self, c: &Cst<'s>) -> PRes<'s, AdtVariant<'s>> {
         match *c {
             Cst::Ident(s, ref p) => Ok(AdtVariant {
                 name: Ident {
@@ -1121,6 +2630,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                     pos: p.clone(),
                 },
                 members: vec![],
+                boxing: Boxing::Auto,
                 pos: p.clone(),
             }),
             Cst::Sexpr(ref cs, ref p) => {
@@ -1133,6 +2643,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 Ok(AdtVariant {
                     name: name,
                     members: self.parse_data_type_variant_members(members_cs, &p.after(&name_pos))?,
+                    boxing: Boxing::Auto,
                     pos: p.clone(),
                 })
             }
@@ -1163,6 +2674,13 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     fn parse_data_type_defs(&mut self, defs_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)]) -> PRes<'s, ()> {
         for &(ref def_csts, ref pos) in defs_csts {
             let def = self.parse_data_type_def(def_csts, pos)?;
+            if let Some(prev_pos) = self.extern_types.get(def.name.s) {
+                return Err(DataTypeDuplDef {
+                    pos: def.pos,
+                    name: def.name.s,
+                    prev_pos: prev_pos.clone(),
+                });
+            }
             if let Some(prev_def) = self.adts.defs.insert(def.name.s, def.clone()) {
                 return Err(DataTypeDuplDef {
                     pos: def.pos,
@@ -1190,56 +2708,189 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     }
 
     fn parse_macro_pattern(&mut self, cst: &Cst<'s>) -> PRes<'s, macros::Pattern<'s>> {
-        match *cst {
-            Cst::Ident(id, _) => Ok(macros::Pattern::Ident(id)),
+        let pattern = match *cst {
+            Cst::Ident(id, _) => macros::Pattern::Ident(id),
             Cst::Sexpr(ref cs, ref pos) if !cs.is_empty() => match cs[0] {
+                Cst::Ident("...", _) if cs.len() == 1 => {
+                    return Err(InvalidMacroPattern(
+                        pos.clone(),
+                        "Expected at least one pattern to repeat",
+                    ))
+                }
                 Cst::Ident("...", _) => {
                     let inner_patterns = cs[1..]
                         .iter()
                         .map(|p| self.parse_macro_pattern(p))
                         .collect::<PRes<Vec<_>>>()?;
-                    Ok(macros::Pattern::Multi(inner_patterns))
+                    macros::Pattern::Multi(inner_patterns)
                 }
                 Cst::Ident("'", _) => {
                     let (_, lit_cst) = two(cs, pos)?;
                     let lit = ident_s(lit_cst)?;
-                    Ok(macros::Pattern::Lit(lit))
+                    macros::Pattern::Lit(lit)
                 }
-                _ => Ok(macros::Pattern::Sexpr(cs.iter()
+                _ => macros::Pattern::Sexpr(cs.iter()
                     .map(|c| self.parse_macro_pattern(c))
-                    .collect::<PRes<Vec<_>>>()?)),
+                    .collect::<PRes<Vec<_>>>()?),
             },
-            Cst::Sexpr(..) => Ok(macros::Pattern::Sexpr(vec![])),
-            _ => cst.pos().error_exit("Invalid macro pattern"),
+            Cst::Sexpr(..) => macros::Pattern::Sexpr(vec![]),
+            Cst::Num(..) | Cst::Str(..) => {
+                return Err(InvalidMacroPattern(
+                    cst.pos().clone(),
+                    "Literals other than identifiers can't be matched",
+                ))
+            }
+        };
+        if pattern.ambiguous_repetition() {
+            Err(InvalidMacroPattern(
+                cst.pos().clone(),
+                "At most one repetition `(... PATTERN)` may occur directly in a list",
+            ))
+        } else {
+            Ok(pattern)
         }
     }
 
+    /// Parse a macro definition, `(macro NAME [PATTERN TEMPLATE...]...)`
     fn parse_add_macro(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ()> {
         let (first, rest) = split_first(csts, pos)?;
         let name = ident_s(first)?;
         let cases = rest.iter()
             .map(|c| {
-                let (pattern_cst, bodies) = split_first(sexpr(c)?, c.pos())?;
+                let (pattern_cst, bodies) = match *c {
+                    Cst::Sexpr(ref cs, _) if cs.len() >= 2 => (&cs[0], &cs[1..]),
+                    _ => return Err(InvalidMacroCase(c.pos().clone())),
+                };
                 let pattern = self.parse_macro_pattern(pattern_cst)?;
-                Ok(macros::Case {
+                let case = macros::Case {
                     pattern,
                     bodies: bodies.to_vec(),
-                })
+                };
+                if let Some((name, pos)) = case.unbound_splice() {
+                    return Err(UnboundMacroSplice(pos.clone(), name));
+                }
+                Ok(case)
             })
             .collect::<PRes<Vec<_>>>()?;
         self.macros.insert(name, macros::Macro { name, cases });
         Ok(())
     }
 
+    /// Parse the arguments of an `allow` form, `(allow (LINT ...) FORM ...)`
+    ///
+    /// The lints are allowed in the source of each of the forms, and the forms are returned
+    fn parse_allow<'c>(
+        &mut self,
+        csts: &'c [Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, &'c [Cst<'s>]> {
+        let (lints_cst, forms) = split_first(csts, pos)?;
+        let lints = sexpr(lints_cst)?
+            .iter()
+            .map(|c| {
+                let name = ident_s(c)?;
+                lint::lint_by_name(name)
+                    .map(|l| l.name)
+                    .ok_or(UnknownLint(c.pos().clone(), name))
+            })
+            .collect::<PRes<Vec<_>>>()?;
+        for form in forms {
+            lint::allow_in(form.pos(), lints.clone());
+        }
+        Ok(forms)
+    }
+
+    /// Parse the arguments of a `lazy` form, `(lazy (define NAME EXPR) ...)`
+    ///
+    /// The global variables defined by the forms are initialized on first read, instead of
+    /// before `main`, and the forms are returned
+    fn parse_lazy<'c>(&mut self, forms: &'c [Cst<'s>]) -> PRes<'s, &'c [Cst<'s>]> {
+        for form in forms {
+            let name = global_var_def_name(form).ok_or_else(|| {
+                Expected(
+                    form.pos().clone(),
+                    "definition of a global variable, `(define NAME EXPR)`",
+                )
+            })?;
+            self.lazy_globals.insert(name);
+        }
+        Ok(forms)
+    }
+
+    /// Parse the arguments of an `export` form, `(export (define (NAME PARAM) BODY) ...)`
+    ///
+    /// The global functions defined by the forms are generated with their source names as
+    /// symbols and external linkage, and the forms are returned
+    fn parse_export<'c>(&mut self, forms: &'c [Cst<'s>]) -> PRes<'s, &'c [Cst<'s>]> {
+        for form in forms {
+            let name = global_func_def_name(form).ok_or_else(|| {
+                Expected(
+                    form.pos().clone(),
+                    "definition of a global function, `(define (NAME PARAM) BODY)`",
+                )
+            })?;
+            self.exports.insert(name);
+        }
+        Ok(forms)
+    }
+
+    /// Register the test `(def-test NAME BODY)`, and return it as the typed binding
+    /// `(define: (NAME _) (-> Nil Nil) BODY)`
+    fn desugar_test(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Vec<Cst<'s>>> {
+        let (name_cst, body) = two(csts, pos)?;
+        let name = ident(name_cst)?;
+        self.tests.push(Test {
+            name: name.clone(),
+            pos: pos.clone(),
+        });
+        let nil_to_nil = Cst::Sexpr(
+            vec![
+                Cst::Ident("->", pos.clone()),
+                Cst::Ident("Nil", pos.clone()),
+                Cst::Ident("Nil", pos.clone()),
+            ],
+            pos.clone(),
+        );
+        let patt = Cst::Sexpr(
+            vec![name_cst.clone(), Cst::Ident("_", name.pos.clone())],
+            name.pos.clone(),
+        );
+        Ok(vec![patt, nil_to_nil, body.clone()])
+    }
+
+    /// Define the macros of all top level `macro` forms in `csts`, including those in `allow`
+    /// and `begin` forms, so that they may be used before their definitions in the module
+    fn add_top_level_macros(&mut self, csts: &[Cst<'s>]) -> PRes<'s, ()> {
+        for cst in csts {
+            match *cst {
+                Cst::Sexpr(ref cs, ref pos) => match cs.first() {
+                    Some(&Cst::Ident("macro", _)) => self.parse_add_macro(&cs[1..], pos)?,
+                    Some(&Cst::Ident("allow", _)) if cs.len() > 2 => {
+                        self.add_top_level_macros(&cs[2..])?
+                    }
+                    Some(&Cst::Ident("begin", _)) => self.add_top_level_macros(&cs[1..])?,
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
     fn _get_top_level_csts<'c>(
         &mut self,
         csts: &'c [Cst<'s>],
-        externs: &mut Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+        externs: &mut Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
         globals: &mut Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
         adts: &mut Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
     ) -> PRes<'s, ()> {
+        self.add_top_level_macros(csts)?;
         for cst in csts {
-            let csts_ = macros::expand_macros(cst, &self.macros);
+            if is_macro_def(cst) {
+                // Already defined by `add_top_level_macros`
+                continue;
+            }
+            let csts_ = macros::expand_macros(cst, &self.macros)?;
             for cst_ in csts_ {
                 let pos = cst_.pos().clone();
                 let (first, rest) = split_first(sexpr(&cst_)?, &pos)?;
@@ -1247,18 +2898,39 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 match first_s {
                     "import" => {
                         let module_name = self.parse_import(rest, &pos)?;
-                        let module_path = CanonPathBuf::new(&format!("{}.kvs", module_name))
-                            .expect("ICE: Failed to canonicalize module path");
-                        if !self.sources.contains_key(&module_path) {
-                            let import_csts = lex_file(module_path, &self.sources);
+                        let module_path = self.resolve_import(module_name, &pos)?;
+                        if !self.included.contains(&module_path) {
+                            self.included.push(module_path.clone());
+                            let import_csts = lex_file(module_path, &self.sources)?;
                             self._get_top_level_csts(&import_csts, externs, globals, adts)?
                         }
                     }
-                    "extern" => externs.push((rest.to_vec(), pos)),
+                    "extern" => externs.push((false, rest.to_vec(), pos)),
+                    "extern-io" => externs.push((true, rest.to_vec(), pos)),
+                    "extern-type" => self.parse_add_extern_type(rest, &pos)?,
                     "define" => globals.push((false, rest.to_vec(), pos)),
                     "define:" => globals.push((true, rest.to_vec(), pos)),
+                    "def-const" => {
+                        let (name, _) = two(rest, &pos)?;
+                        self.consts.insert(ident(name)?.s);
+                        globals.push((false, rest.to_vec(), pos))
+                    }
                     "data" => adts.push((rest.to_vec(), pos.clone())),
                     "macro" => self.parse_add_macro(rest, &pos)?,
+                    "def-test" => globals.push((true, self.desugar_test(rest, &pos)?, pos)),
+                    "allow" => {
+                        let forms = self.parse_allow(rest, &pos)?;
+                        self._get_top_level_csts(forms, externs, globals, adts)?
+                    }
+                    "begin" => self._get_top_level_csts(rest, externs, globals, adts)?,
+                    "lazy" => {
+                        let forms = self.parse_lazy(rest)?;
+                        self._get_top_level_csts(forms, externs, globals, adts)?
+                    }
+                    "export" => {
+                        let forms = self.parse_export(rest)?;
+                        self._get_top_level_csts(forms, externs, globals, adts)?
+                    }
                     _ => return Err(InvalidTopLevelItem(pos)),
                 }
             }
@@ -1275,7 +2947,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     ) -> PRes<
         's,
         (
-            Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+            Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
             Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
             Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
         ),
@@ -1285,26 +2957,201 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Ok((externs, globals, adts))
     }
 
-    fn parse_ast(&mut self, csts: &[Cst<'s>]) -> PRes<'s, Ast<'s>> {
-        let (externs_csts, globals_csts, adts_csts) = self.get_top_level_csts(csts)?;
+    /// The definitions of the constructor functions of all data type variants with members,
+    /// as `(define VARIANT (lambda (MEMBER) ...))`, except where a global of the same name is
+    /// defined explicitly in `globals`
+    ///
+    /// A constructor function takes the members one at a time, like any curried function, and
+    /// returns `(new VARIANT MEMBER...)`. The members given so far are accumulated in nested
+    /// pairs, so only two names are needed, neither of which can be written in source code:
+    ///
+    /// ```
+    /// (lambda (m)
+    ///   ((lambda (ms)
+    ///      (lambda (m)
+    ///        ((lambda (ms)
+    ///           (lambda (m) (new VARIANT (car ms) (cdr ms) m)))
+    ///         (cons ms m))))
+    ///    m))
+    /// ```
+    fn constructor_bindings_csts(
+        &self,
+        globals: &[(bool, Vec<Cst<'s>>, SrcPos<'s>)],
+    ) -> Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)> {
+        const MEMBER: &str = "constructor member";
+        const MEMBERS: &str = "constructor members";
+        let defined = globals
+            .iter()
+            .filter_map(|&(_, ref csts, _)| match csts.first() {
+                Some(&Cst::Ident(name, _)) => Some(name),
+                Some(&Cst::Sexpr(ref xs, _)) => match xs.first() {
+                    Some(&Cst::Ident(name, _)) => Some(name),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        let mut bindings = Vec::new();
+        for def in self.adts.defs.values() {
+            let variants = def.variants
+                .iter()
+                .filter(|v| !v.members.is_empty() && !defined.contains(v.name.s));
+            for variant in variants {
+                let pos = &variant.pos;
+                let id = |s| Cst::Ident(s, pos.clone());
+                let list = |xs| Cst::Sexpr(xs, pos.clone());
+                let lambda = |param, body| list(vec![id("lambda"), list(vec![id(param)]), body]);
+                let n = variant.members.len();
+                // The member `i` of the `k` members accumulated so far in `ms`
+                let accumulated = |i: usize, k: usize| {
+                    (i + 1..k).fold(id(MEMBERS), |ms, _| list(vec![id("car"), ms]))
+                };
+                let mut new = vec![id("new"), id(variant.name.s)];
+                new.extend((0..n - 1).map(|i| match (i, n - 1) {
+                    (_, 1) => id(MEMBERS),
+                    (0, k) => accumulated(0, k),
+                    (i, k) => list(vec![id("cdr"), accumulated(i, k)]),
+                }));
+                new.push(id(MEMBER));
+                let body = (0..n - 1).rev().fold(list(new), |inner, k| {
+                    let ms = if k == 0 {
+                        id(MEMBER)
+                    } else {
+                        list(vec![id("cons"), id(MEMBERS), id(MEMBER)])
+                    };
+                    list(vec![lambda(MEMBERS, lambda(MEMBER, inner)), ms])
+                });
+                let name = Cst::Ident(variant.name.s, variant.name.pos.clone());
+                bindings.push((false, vec![name, lambda(MEMBER, body)], pos.clone()));
+            }
+        }
+        bindings
+    }
+
+    fn parse_ast(
+        &mut self,
+        externs_csts: Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
+        mut globals_csts: Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
+        adts_csts: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    ) -> PRes<'s, Ast<'s>> {
+        self.parse_data_type_defs(&adts_csts)?;
+        let constructors = self.constructor_bindings_csts(&globals_csts);
+        globals_csts.extend(constructors);
         let globals_csts_slc = globals_csts
             .iter()
             .map(|&(is_typed, ref v, ref p)| (is_typed, v.as_slice(), p.clone()))
             .collect::<Vec<_>>();
-        self.parse_data_type_defs(&adts_csts)?;
         let externs = self.parse_externs(&externs_csts)?;
         let globals = self.parse_bindings(&globals_csts_slc)?;
         Ok(Ast {
             externs,
             globals,
             adts: mem::replace(&mut self.adts, Adts::new()),
+            tests: mem::replace(&mut self.tests, Vec::new()),
+            extern_types: mem::replace(&mut self.extern_types, BTreeMap::new()),
+            consts: mem::replace(&mut self.consts, BTreeSet::new()),
+            lazy_globals: mem::replace(&mut self.lazy_globals, BTreeSet::new()),
+            exports: mem::replace(&mut self.exports, BTreeSet::new()),
+            main_file: None,
+        })
+    }
+
+    /// Lex the core prelude, as the file `core_file`, and the modules `preludes`, and separate
+    /// their top level items, following imports
+    fn parse_prelude(
+        &mut self,
+        core_file: CanonPathBuf,
+        preludes: &[CanonPathBuf],
+    ) -> PRes<'s, Prelude<'s>> {
+        if !self.sources.contains_key(&core_file) {
+            self.sources
+                .add(core_file.clone(), normalize(CORE_PRELUDE_SRC));
+        }
+        let mut csts = lex_file(core_file.clone(), &self.sources)?;
+        for prelude in preludes {
+            csts.extend(lex_file(prelude.clone(), &self.sources)?)
+        }
+        self.included.push(core_file);
+        self.included.extend(preludes.iter().cloned());
+        let (externs, globals, adts) = self.get_top_level_csts(&csts)?;
+        Ok(Prelude {
+            externs,
+            globals,
+            adts,
+            macros: mem::replace(&mut self.macros, BTreeMap::new()),
+            tests: mem::replace(&mut self.tests, Vec::new()),
+            extern_types: mem::replace(&mut self.extern_types, BTreeMap::new()),
+            consts: mem::replace(&mut self.consts, BTreeSet::new()),
+            lazy_globals: mem::replace(&mut self.lazy_globals, BTreeSet::new()),
+            exports: mem::replace(&mut self.exports, BTreeSet::new()),
+            allowed: lint::allowed(),
+            files: mem::replace(&mut self.included, Vec::new()),
         })
     }
 
     /// Parse the file `filename`, and recursively parse imports as well
-    fn parse_file(&mut self, filename: CanonPathBuf) -> PRes<'s, Ast<'s>> {
-        let csts = lex_file(filename, &self.sources);
-        self.parse_ast(&csts)
+    ///
+    /// The top level items of `prelude` are included first, as if imported
+    fn parse_file(&mut self, filename: CanonPathBuf, prelude: Prelude<'s>) -> PRes<'s, Ast<'s>> {
+        let Prelude {
+            mut externs,
+            mut globals,
+            mut adts,
+            macros,
+            tests,
+            extern_types,
+            consts,
+            lazy_globals,
+            exports,
+            allowed: _,
+            files,
+        } = prelude;
+        self.macros = macros;
+        self.tests = tests;
+        self.extern_types = extern_types;
+        self.consts = consts;
+        self.lazy_globals = lazy_globals;
+        self.exports = exports;
+        self.included = files;
+        self.included.push(filename.clone());
+        let main_file = filename.path().to_path_buf();
+        let csts = lex_file(filename, &self.sources)?;
+        self._get_top_level_csts(&csts, &mut externs, &mut globals, &mut adts)?;
+        let mut ast = self.parse_ast(externs, globals, adts)?;
+        ast.main_file = Some(main_file);
+        Ok(ast)
+    }
+}
+
+/// The core prelude and the implicitly imported modules of a program, lexed and separated into
+/// top level items, along with the macros and other declarations they define
+///
+/// A prelude parsed once with `parse_prelude` can be shared by many programs with
+/// `parse_program_with_prelude`, so that the same modules are not lexed and macro expanded
+/// again for each program, e.g. in the daemon. The macros of a program do not apply to its
+/// prelude.
+#[derive(Clone)]
+pub struct Prelude<'s> {
+    externs: Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
+    globals: Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
+    adts: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    macros: BTreeMap<&'s str, macros::Macro<'s>>,
+    tests: Vec<Test<'s>>,
+    extern_types: BTreeMap<&'s str, SrcPos<'s>>,
+    consts: BTreeSet<&'s str>,
+    lazy_globals: BTreeSet<&'s str>,
+    exports: BTreeSet<&'s str>,
+    /// The local `allow`s of lints in the prelude
+    allowed: lint::Allowed,
+    /// The files of the modules of the prelude, including those they import
+    files: Vec<CanonPathBuf>,
+}
+
+impl<'s> Prelude<'s> {
+    /// The files of the modules of the prelude, including those they import. The core prelude
+    /// is a virtual file, that does not exist on disk
+    pub fn files(&self) -> &[CanonPathBuf] {
+        &self.files
     }
 }
 
@@ -1312,17 +3159,61 @@ impl<'tvg, 's> Parser<'tvg, 's> {
 ///
 /// Given the name of a file that contains the program entry point,
 /// read, lex, and parse the source, and include imported modules
-/// as needed
+/// as needed. Imports are resolved relative to the current directory, and then `src_roots`.
+/// The modules `preludes` are implicitly imported
 pub fn parse_program<'s>(
     filename: CanonPathBuf,
     sources: &'s AddMap<CanonPathBuf, String>,
     type_var_gen: &mut TypeVarGen,
-) -> Ast<'s> {
+    src_roots: &[PathBuf],
+    preludes: &[CanonPathBuf],
+) -> Result<Ast<'s>, Diagnostic> {
     let mut parser = Parser::new(sources, type_var_gen);
-    parser.parse_file(filename).unwrap_or_else(|e| {
-        e.print();
-        exit()
-    })
+    parser.src_roots = src_roots.to_vec();
+    let core_file = filename.with_file_name(CORE_PRELUDE_NAME);
+    parser
+        .parse_prelude(core_file, preludes)
+        .and_then(|prelude| parser.parse_file(filename, prelude))
+        .map_err(|e| e.diagnostic())
+}
+
+/// Returns the prelude of programs, to be parsed with `parse_program_with_prelude`
+///
+/// The core prelude is given the file name `<core>` in the directory `dir`. The modules
+/// `preludes` are implicitly imported into each program. Imports are resolved relative to the
+/// current directory, and then `src_roots`.
+pub fn parse_prelude<'s>(
+    dir: &CanonPathBuf,
+    sources: &'s AddMap<CanonPathBuf, String>,
+    src_roots: &[PathBuf],
+    preludes: &[CanonPathBuf],
+) -> Result<Prelude<'s>, Diagnostic> {
+    // No type variables are generated until the top level items are parsed
+    let mut type_var_gen = TypeVarGen::new(0);
+    let mut parser = Parser::new(sources, &mut type_var_gen);
+    parser.src_roots = src_roots.to_vec();
+    let core_file = dir.join_file_name(OsStr::new(CORE_PRELUDE_NAME));
+    parser
+        .parse_prelude(core_file, preludes)
+        .map_err(|e| e.diagnostic())
+}
+
+/// Returns the Abstract Syntax Tree of the program with entry point in `filename`, like
+/// `parse_program`, but with the prelude `prelude` instead of parsing the core prelude and any
+/// implicitly imported modules again
+pub fn parse_program_with_prelude<'s>(
+    filename: CanonPathBuf,
+    sources: &'s AddMap<CanonPathBuf, String>,
+    prelude: &Prelude<'s>,
+    type_var_gen: &mut TypeVarGen,
+    src_roots: &[PathBuf],
+) -> Result<Ast<'s>, Diagnostic> {
+    lint::restore_allowed(&prelude.allowed);
+    let mut parser = Parser::new(sources, type_var_gen);
+    parser.src_roots = src_roots.to_vec();
+    parser
+        .parse_file(filename, prelude.clone())
+        .map_err(|e| e.diagnostic())
 }
 
 // TODO: Fix all passings of `pos` to functions like `first`, `split_first`, `two`, etc.
@@ -1330,11 +3221,14 @@ pub fn parse_program<'s>(
 
 #[cfg(test)]
 mod test {
+    use lib::CanonPathBuf;
     use lib::collections::AddMap;
-    use lib::front::lex::Cst;
+    use lib::front::cst::Cst;
     use lib::front::*;
     use lib::front::ast::*;
-    use super::Parser;
+    use std::ffi::OsStr;
+    use super::{infix_to_prefix, parse_prelude, parse_program_with_prelude, PErr, Parser};
+    use {compile_str_with, Options};
 
     fn dummy_cident(s: &str) -> Cst {
         Cst::Ident(s, SrcPos::new_dummy())
@@ -1347,25 +3241,53 @@ mod test {
         }
     }
 
+    /// Parse and type check the program `src`, and pass its AST to `f`
+    fn with_checked_ast<R, F: FnOnce(&Ast) -> R>(src: &str, f: F) -> R {
+        match compile_str_with(src, &Options::default(), &[], |ast| Ok(f(ast))) {
+            (Some(r), _) => r,
+            (None, errors) => panic!("Failed to check program\n{}", errors),
+        }
+    }
+
+    /// Parse and type check the program `src`, which is expected to fail, and return the errors
+    fn check_errors(src: &str) -> String {
+        match compile_str_with(src, &Options::default(), &[], |_| Ok(())) {
+            (Some(()), warnings) => panic!("Program checked unexpectedly\n{}", warnings),
+            (None, errors) => errors.0,
+        }
+    }
+
+    /// The type of the global `name` of `ast`, as displayed
+    fn global_type(ast: &Ast, name: &str) -> String {
+        match ast.globals.bindings().find(|b| b.ident.s == name) {
+            Some(b) => b.sig.body.pretty(),
+            None => panic!("No global `{}`", name),
+        }
+    }
+
     #[test]
     fn test_parse_data_type_def() {
         let sources = AddMap::new();
         let mut tvg = TypeVarGen::new(0);
         let mut parser = Parser::new(&sources, &mut tvg);
         assert_eq!(
-            parser.parse_data_type_def(
-                &[dummy_cident("Foo"), dummy_cident("Foo"),],
-                &SrcPos::new_dummy()
-            ),
-            Ok(AdtDef {
+            parser
+                .parse_data_type_def(
+                    &[dummy_cident("Foo"), dummy_cident("Foo"),],
+                    &SrcPos::new_dummy()
+                )
+                .ok(),
+            Some(AdtDef {
                 name: Ident {
                     s: "Foo",
                     pos: SrcPos::new_dummy(),
                 },
+                params: vec![],
                 variants: vec![
                     AdtVariant {
                         name: dummy_ident("Foo"),
                         members: vec![],
+                        boxing: Boxing::Auto,
                         pos: SrcPos::new_dummy(),
                     },
                 ],
@@ -1373,4 +3295,304 @@ mod test {
             })
         )
     }
+
+    #[test]
+    fn test_parse_programs_with_prelude() {
+        let dir = CanonPathBuf::new(".").unwrap();
+        let file = |name| dir.join_file_name(OsStr::new(name));
+        let prelude_sources = AddMap::new();
+        prelude_sources.add(
+            file("twin.kvs"),
+            "(macro twice [(x) (cons x x)])\n(data Twin (Twin (Cons Int64 Int64)))\n".to_string(),
+        );
+        let prelude = parse_prelude(&dir, &prelude_sources, &[], &[file("twin.kvs")]).unwrap();
+        // Each program is parsed from a copy of the prelude, unaffected by previous programs
+        for &(name, other) in &[("a", "b"), ("b", "a")] {
+            let sources = AddMap::new();
+            let src = format!("(define {} (new Twin (twice 1)))\n", name);
+            sources.add(file("main.kvs"), src);
+            let parse = || {
+                let mut tvg = TypeVarGen::new(0);
+                parse_program_with_prelude(file("main.kvs"), &sources, &prelude, &mut tvg, &[])
+            };
+            let ast = match capture_diagnostics(parse) {
+                (Ok(ast), _) => ast,
+                (Err(e), errors) => panic!("Failed to parse program\n{}{}", errors, e),
+            };
+            let globals = ast.globals.bindings().map(|b| b.ident.s).collect::<Vec<_>>();
+            assert!(globals.contains(&name), "{:?}", globals);
+            assert!(!globals.contains(&other), "{:?}", globals);
+            assert!(ast.adts.variant_exists("Twin"));
+            assert!(ast.adts.variant_exists("Some"));
+        }
+    }
+
+    #[test]
+    fn test_constructor_functions() {
+        let src = "(data (Pair a) (Pair a a))
+(data Id (Id Int64))
+(define: (Id n) (-> Int64 Id) (new Id 0))
+(define: mk-pair (-> Int64 (-> Int64 (Pair Int64))) Pair)
+(define: pair (Pair Int64) (Pair 1 2))
+(define: id Id (Id 1))";
+        with_checked_ast(src, |ast| {
+            let bindings = |name| ast.globals.bindings().filter(|b| b.ident.s == name).count();
+            assert_eq!(bindings("Pair"), 1);
+            // The user-defined `Id` shadows the generated one
+            assert_eq!(bindings("Id"), 1);
+            let id = ast.globals.bindings().find(|b| b.ident.s == "Id").unwrap();
+            assert!(id.sig_pos.is_some());
+        })
+    }
+
+    #[test]
+    fn test_quote_without_std() {
+        let src = "(define: datum Sexpr '(foo 1 2.5 \"bar\" (baz)))";
+        with_checked_ast(src, |ast| {
+            assert!(ast.adts.variant_exists("SexprList"));
+            assert!(ast.globals.bindings().any(|b| b.ident.s == "datum"));
+        })
+    }
+
+    #[test]
+    fn test_recur_in_tail() {
+        let src = "(define: (f b) (-> Bool Bool)
+  (loop [[x b]]
+    (let [[y x]]
+      (if y (begin x (recur false)) (: y Bool)))))";
+        with_checked_ast(src, |_| ());
+    }
+
+    #[test]
+    fn test_recur_not_in_tail() {
+        for body in &["(if (recur false) x x)", "(cons (recur false) x)", "(begin (recur x) x)"] {
+            let src = format!("(define: (f b) (-> Bool Bool) (loop [[x b]] {}))", body);
+            let errors = check_errors(&src);
+            assert!(errors.contains("not in tail position"), "{}\n{}", body, errors);
+        }
+        // A `recur` in the initializers of a nested loop jumps to the outer loop
+        let src = "(define (f b) (loop [[x b]] (loop [[y (recur x)]] y)))";
+        assert!(check_errors(src).contains("not in tail position"));
+    }
+
+    #[test]
+    fn test_recur_outside_loop() {
+        for src in &[
+            "(define (f x) (recur x))",
+            "(define (f b) (loop [[x b]] (lambda (y) (recur y))))",
+        ] {
+            let errors = check_errors(src);
+            assert!(errors.contains("`recur` outside of a `loop`"), "{}\n{}", src, errors);
+        }
+    }
+
+    #[test]
+    fn test_nested_destructuring_let() {
+        let src = "(define: (f v) (-> (Cons (Cons Int64 Bool) (Maybe Float64)) Bool)
+  (let [[(cons (cons a b) (Some c)) v]]
+    (: b Bool)))";
+        with_checked_ast(src, |_| ());
+    }
+
+    #[test]
+    fn test_shadowing_destructuring_let() {
+        // The inner pattern is written the same as the outer, but binds values of other types
+        let src = "(define: (f v w) (-> (Cons Int64 Bool) (-> (Cons Bool Int64) (Cons Bool Int64)))
+  (let [[(cons a b) v]]
+    (let [[(cons a b) w]]
+      (cons (: a Bool) (: b Int64)))))";
+        with_checked_ast(src, |_| ());
+    }
+
+    #[test]
+    fn test_destructuring_let_with_equal_patterns() {
+        let src = "(define: (f p q) (-> (Maybe Int64) (-> (Maybe Bool) Bool))
+  (let [[(Some _) p] [(Some _) q]]
+    true))";
+        with_checked_ast(src, |_| ());
+    }
+
+    #[test]
+    fn test_circular_destructuring_let() {
+        // Bindings of a `let` are recursive, so the value refers to the `a` and `b` it defines
+        let errs = check_errors(
+            "(define: (f x) (-> Nil (Cons Bool Int64))
+  (let [[(cons a b) (cons b a)]]
+    (cons a b)))",
+        );
+        assert!(errs.contains("defined in terms of itself"), "{}", errs);
+    }
+
+    /// The operands and operators of infix arithmetic, separated by whitespace
+    fn infix(src: &'static str) -> Vec<Cst<'static>> {
+        src.split_whitespace()
+            .map(|s| if s.starts_with(char::is_numeric) {
+                Cst::Num(s, SrcPos::new_dummy())
+            } else {
+                dummy_cident(s)
+            })
+            .collect()
+    }
+
+    /// A parenthesized group of the infix arithmetic `src`
+    fn group(src: &'static str) -> Cst<'static> {
+        Cst::Sexpr(infix(src), SrcPos::new_dummy())
+    }
+
+    /// The infix arithmetic `cs` converted to prefix form and displayed, or what was expected
+    /// if it's malformed
+    fn math(cs: Vec<Cst<'static>>) -> Result<String, &'static str> {
+        match infix_to_prefix(&cs, &SrcPos::new_dummy()) {
+            Ok(prefix) => Ok(prefix.to_string()),
+            Err(PErr::Expected(_, expected)) => Err(expected),
+            Err(_) => panic!("Unexpected error for malformed infix arithmetic"),
+        }
+    }
+
+    #[test]
+    fn test_math_precedence() {
+        assert_eq!(math(infix("a + b * c")), Ok("(add (cons a (mul (cons b c))))".into()));
+        assert_eq!(math(infix("a * b + c")), Ok("(add (cons (mul (cons a b)) c))".into()));
+        assert_eq!(
+            math(infix("a - b / c * d")),
+            Ok("(sub (cons a (mul (cons (div (cons b c)) d))))".into())
+        );
+        let mut grouped = infix("a *");
+        grouped.push(group("b + c"));
+        assert_eq!(math(grouped), Ok("(mul (cons a (add (cons b c))))".into()));
+    }
+
+    #[test]
+    fn test_math_associativity() {
+        assert_eq!(math(infix("a - b - c")), Ok("(sub (cons (sub (cons a b)) c))".into()));
+        assert_eq!(math(infix("a / b / c")), Ok("(div (cons (div (cons a b)) c))".into()));
+        assert_eq!(math(infix("a - b + c")), Ok("(add (cons (sub (cons a b)) c))".into()));
+        let mut grouped = infix("a -");
+        grouped.push(group("b - c"));
+        assert_eq!(math(grouped), Ok("(sub (cons a (sub (cons b c))))".into()));
+    }
+
+    #[test]
+    fn test_math_unary_minus() {
+        assert_eq!(math(infix("- a")), Ok("(sub (cons 0 a))".into()));
+        assert_eq!(math(infix("- a * b")), Ok("(mul (cons (sub (cons 0 a)) b))".into()));
+        assert_eq!(math(infix("a - - b")), Ok("(sub (cons a (sub (cons 0 b))))".into()));
+        assert_eq!(math(infix("- - 1")), Ok("(sub (cons 0 (sub (cons 0 1))))".into()));
+        let mut grouped = infix("2 *");
+        grouped.push(group("- a + b"));
+        assert_eq!(math(grouped), Ok("(mul (cons 2 (add (cons (sub (cons 0 a)) b))))".into()));
+    }
+
+    #[test]
+    fn test_math_malformed() {
+        let operand = "operand of infix arithmetic";
+        let operator = "infix operator `+`, `-`, `*`, or `/`";
+        assert_eq!(math(infix("")), Err(operand));
+        assert_eq!(math(infix("a +")), Err(operand));
+        assert_eq!(math(infix("a * -")), Err(operand));
+        assert_eq!(math(infix("a b")), Err(operator));
+        assert_eq!(math(infix("a % b")), Err(operator));
+        assert_eq!(math(infix("a + b c")), Err(operator));
+    }
+
+    #[test]
+    fn test_do_io() {
+        let src = "(extern-io read_int64 (-> RealWorld (Cons Int64 RealWorld)))
+(extern-io print_int64 (-> (Cons Int64 RealWorld) (Cons Nil RealWorld)))
+(define (print-int64 n) (lambda (w) (print_int64 (cons n w))))
+(define echo-twice
+  (do-io (x <- read_int64)
+         (print-int64 x)
+         (print-int64 x)))";
+        with_checked_ast(src, |ast| {
+            assert_eq!(global_type(ast, "echo-twice"), "(IO Nil)");
+        });
+        let errors = check_errors("(define (f io) (do-io (x <- io)))");
+        assert!(errors.contains("an IO action as the last form of `do-io`"), "{}", errors);
+    }
+
+    #[test]
+    fn test_if_let() {
+        let src = "(define (first-or xs default)
+  (if-let ((List x _) xs) x (: default Int64)))";
+        with_checked_ast(src, |ast| {
+            assert_eq!(global_type(ast, "first-or"), "(-> (List Int64) Int64 Int64)");
+        });
+        let errors = check_errors("(define (f m) (if-let ((Some x) m) x))");
+        assert!(errors.contains("Arity mismatch"), "{}", errors);
+    }
+
+    #[test]
+    fn test_threading() {
+        let src = "(define: (pair-of a b) (-> Int64 (-> Bool (Cons Int64 Bool))) (cons a b))
+(define (swap p) (cons (cdr p) (car p)))
+(define (thread-first x) (-> x (pair-of true) swap))
+(define (thread-last x) (->> x (pair-of 1) swap))";
+        with_checked_ast(src, |ast| {
+            assert_eq!(global_type(ast, "thread-first"), "(-> Int64 (Cons Bool Int64))");
+            assert_eq!(global_type(ast, "thread-last"), "(-> Bool (Cons Bool Int64))");
+        });
+    }
+
+    #[test]
+    fn test_try() {
+        let src = "(data (Either a b) (Left a) (Right b))
+(define: (positive n) (-> Int64 (Either Bool Int64)) (Right n))
+(define (both m n)
+  (try (x <- (positive m))
+       (positive x)
+       (y <- (positive n))
+       (Right (cons x y))))";
+        with_checked_ast(src, |ast| {
+            assert_eq!(
+                global_type(ast, "both"),
+                "(-> Int64 Int64 (Either Bool (Cons Int64 Int64)))"
+            );
+        });
+        let errors = check_errors("(define (f e) (try (x <- e)))");
+        assert!(errors.contains("an `Either` expression as the last form of `try`"), "{}", errors);
+    }
+
+    #[test]
+    fn test_defer() {
+        let src = "(define: (log n) (-> Int64 Nil) nil)
+(define (f x)
+  (defer (log 1)
+    (defer (log 2)
+      (cons (: x Int64) true))))";
+        with_checked_ast(src, |ast| {
+            assert_eq!(global_type(ast, "f"), "(-> Int64 (Cons Int64 Bool))");
+        });
+        let errors = check_errors("(define: (f x) (-> Int64 Int64) (defer x x))");
+        assert!(errors.contains("expected type `Nil`"), "{}", errors);
+        let errors = check_errors("(define (f b) (loop [[x b]] (defer nil (recur x))))");
+        assert!(errors.contains("`recur` outside of a `loop`"), "{}", errors);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let src = "(define: (undef _) (-> Nil t) (undef nil))
+(define: (add _) (constrain [(Num t)] (-> (Cons t t) t)) (undef nil))
+(define: (mul _) (constrain [(Num t)] (-> (Cons t t) t)) (undef nil))
+(define: (lt _) (constrain [(Num t)] (-> (Cons t t) Bool)) (undef nil))
+(define: three Int64 (add (cons 1 2)))
+(define: (f x) (-> Int64 Int64)
+  (let [[k (mul (cons three 2))]]
+    (if (lt (cons k 10))
+        (add (cons x k))
+        x)))";
+        with_checked_ast(src, |ast| {
+            let val = |name| {
+                let b = ast.globals.bindings().find(|b| b.ident.s == name).unwrap();
+                b.val.to_string()
+            };
+            assert_eq!(val("three"), "(: 3 Int64)");
+            // `three` is propagated into `f`, where the `mul` and the `if` are folded
+            let f = val("f");
+            assert!(f.contains("(: 6 Int64)"), "{}", f);
+            for folded in &["three", "mul", "(if", "lt"] {
+                assert!(!f.contains(folded), "{}", f);
+            }
+        });
+    }
 }
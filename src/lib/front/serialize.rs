@@ -0,0 +1,185 @@
+//! Serialization of the typed AST with serde, with the feature `serde`
+//!
+//! A serialized AST can be deserialized in another process, so that the frontend and the
+//! backend can run separately, e.g. for build caching, or for external analysis tools.
+//!
+//! Positions in the AST refer to the sources they were parsed from, so the sources are
+//! serialized along with the AST, and positions as file and byte interval. On
+//! deserialization, the sources are loaded first, and positions are resolved into them.
+
+use lib::CanonPathBuf;
+use lib::collections::AddMap;
+use super::SrcPos;
+use super::ast::Ast;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::cell::RefCell;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    /// The sources of the AST being deserialized, which positions are resolved into
+    static LOADED_SOURCES: RefCell<Vec<(&'static Path, &'static str)>> = RefCell::new(Vec::new());
+}
+
+impl<'src> Serialize for SrcPos<'src> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.filename, self.start, self.end).serialize(serializer)
+    }
+}
+
+impl<'de, 'src> Deserialize<'de> for SrcPos<'src> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (filename, start, end) = <(PathBuf, usize, Option<usize>)>::deserialize(deserializer)?;
+        let source = LOADED_SOURCES.with(|srcs| {
+            srcs.borrow()
+                .iter()
+                .find(|&&(file, _)| file == filename)
+                .cloned()
+        });
+        match source {
+            Some((filename, src))
+                if src.is_char_boundary(start)
+                    && end.map_or(true, |end| start <= end && src.is_char_boundary(end)) =>
+            {
+                Ok(SrcPos {
+                    filename,
+                    src,
+                    start,
+                    end,
+                })
+            }
+            Some(_) => Err(de::Error::custom(format_args!(
+                "position {}..{:?} is not in the source of `{}`",
+                start,
+                end,
+                filename.display()
+            ))),
+            None => Err(de::Error::custom(format_args!(
+                "the source of `{}` is not loaded",
+                filename.display()
+            ))),
+        }
+    }
+}
+
+/// (De)serialization of maps as sequences of key-value pairs, for maps of keys that are not
+/// strings, which formats like JSON don't support
+pub mod pairs {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Vec::<(K, V)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+/// Serialize `ast` along with `sources`, the sources it was parsed from
+pub fn serialize_ast<S: Serializer>(
+    ast: &Ast,
+    sources: &AddMap<CanonPathBuf, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let sources = sources
+        .iter()
+        .map(|(file, src)| (file.path(), src.as_str()))
+        .collect::<Vec<_>>();
+    (sources, ast).serialize(serializer)
+}
+
+/// Deserialize an AST serialized with `serialize_ast`
+///
+/// Names in the AST are borrowed from the serialized data. The sources are leaked, as the
+/// positions of the AST refer to them, and they are usually needed for the rest of the
+/// compilation anyways.
+pub fn deserialize_ast<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Ast<'de>, D::Error> {
+    deserializer.deserialize_tuple(2, AstVisitor)
+}
+
+struct AstVisitor;
+
+impl<'de> Visitor<'de> for AstVisitor {
+    type Value = Ast<'de>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the sources of an AST, followed by the AST")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Ast<'de>, A::Error> {
+        let sources = seq.next_element::<Vec<(PathBuf, String)>>()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?
+            .into_iter()
+            .map(|(file, src)| -> (&'static Path, &'static str) {
+                (Box::leak(file.into_boxed_path()), Box::leak(src.into_boxed_str()))
+            })
+            .collect();
+        let prev_sources = LOADED_SOURCES.with(|srcs| srcs.replace(sources));
+        let ast = seq.next_element::<Ast<'de>>();
+        LOADED_SOURCES.with(|srcs| srcs.replace(prev_sources));
+        ast?.ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lib::CanonPathBuf;
+    use lib::collections::AddMap;
+    use lib::front::{capture_diagnostics, Diagnostic, SrcPos, TypeVarGen};
+    use lib::front::inference::infer_types;
+    use lib::front::parse::parse_program;
+    use serde_json;
+    use std::ffi::OsStr;
+    use super::{deserialize_ast, serialize_ast};
+
+    #[test]
+    fn test_serialize_typed_ast() {
+        let file = CanonPathBuf::new(".")
+            .unwrap()
+            .join_file_name(OsStr::new("<test>"));
+        let sources = AddMap::new();
+        sources.add(
+            file.clone(),
+            "(data (Pair a) (Pair a a))\n\
+             (define (twice f x) (f (f x)))\n\
+             (define (swap p) (match p [(Pair a b) (new Pair b a)]))\n\
+             (define main-pair (twice swap (new Pair 1 2)))\n"
+                .to_string(),
+        );
+        let check = || -> Result<_, Diagnostic> {
+            let mut tvg = TypeVarGen::new(0);
+            let mut ast = parse_program(file, &sources, &mut tvg, &[], &[])?;
+            infer_types(&mut ast, &mut tvg)?;
+            Ok(ast)
+        };
+        let ast = match capture_diagnostics(check) {
+            (Ok(ast), _) => ast,
+            (Err(e), errors) => panic!("Failed to check program\n{}{}", errors, e),
+        };
+        let mut json = Vec::new();
+        serialize_ast(&ast, &sources, &mut serde_json::Serializer::new(&mut json)).unwrap();
+        let deserialized = deserialize_ast(&mut serde_json::Deserializer::from_slice(&json));
+        assert_eq!(deserialized.unwrap(), ast);
+    }
+
+    #[test]
+    fn test_deserialize_position_without_source() {
+        let pos = serde_json::from_str::<SrcPos>("[\"/nonexistent.kvs\", 0, null]");
+        let err = pos.unwrap_err().to_string();
+        assert!(err.contains("the source of `/nonexistent.kvs` is not loaded"), "{}", err);
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use lib::grow_stack;
 use lib::front::ast::*;
 
 fn subst_poly<'src>(p: &Poly<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) -> Poly<'src> {
@@ -40,72 +41,144 @@ pub fn subst<'src>(t: &Type<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) ->
     }
 }
 
-/// Apply substitutions in `s` to type variables in types in `e`
-pub fn subst_expr<'src>(e: &mut Expr<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) {
-    match *e {
-        Expr::NumLit(ref mut n) => n.typ = subst(&n.typ, s),
-        Expr::Variable(ref mut bnd) => bnd.typ = subst(&bnd.typ, s),
-        Expr::App(ref mut app) => {
-            subst_expr(&mut app.func, s);
-            subst_expr(&mut app.arg, s);
-            app.typ = subst(&app.typ, s);
-        }
-        Expr::If(ref mut cond) => {
-            subst_expr(&mut cond.predicate, s);
-            subst_expr(&mut cond.consequent, s);
-            subst_expr(&mut cond.alternative, s);
-            cond.typ = subst(&cond.typ, s);
+/// Apply substitutions in `s` to free type variables in `t` once, without applying them again
+/// to the substituted types
+///
+/// Unlike with `subst`, the types in `s` may refer to the variables they substitute, like when
+/// the parameters of a data type are instantiated with type variables of the same names.
+pub fn subst_once<'src>(t: &Type<'src>, s: &BTreeMap<TVar<'src>, Type<'src>>) -> Type<'src> {
+    let subst_poly_once = |p: &Poly<'src>| {
+        let mut s = s.clone();
+        for param_v in p.params.keys() {
+            s.remove(param_v);
         }
-        Expr::Lambda(ref mut l) => {
-            subst_expr(&mut l.body, s);
-            l.typ = subst(&l.typ, s);
+        Poly {
+            params: p.params.clone(),
+            body: subst_once(&p.body, &s),
         }
-        Expr::Let(ref mut l) => {
-            for binding in l.bindings.bindings_mut() {
-                binding.sig = subst_poly(&binding.sig, s);
-                subst_expr(&mut binding.val, s);
+    };
+    match *t {
+        Type::Var(ref tv) => s.get(tv).cloned().unwrap_or(t.clone()),
+        Type::App(ref c, ref ts) => Type::App(
+            Box::new(match **c {
+                TypeFunc::Const(c) => TypeFunc::Const(c),
+                TypeFunc::Poly(ref p) => TypeFunc::Poly(subst_poly_once(p)),
+            }),
+            ts.iter().map(|t2| subst_once(t2, s)).collect(),
+        ),
+        Type::Poly(ref p) => Type::Poly(Box::new(subst_poly_once(p))),
+        _ => t.clone(),
+    }
+}
+
+/// Apply substitutions in `s` to type variables in types in `e`
+pub fn subst_expr<'src>(e: &mut Expr<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) {
+    grow_stack(|| {
+        match *e {
+            Expr::NumLit(ref mut n) => n.typ = subst(&n.typ, s),
+            Expr::Variable(ref mut bnd) => bnd.typ = subst(&bnd.typ, s),
+            Expr::App(ref mut app) => {
+                subst_expr(&mut app.func, s);
+                subst_expr(&mut app.arg, s);
+                app.typ = subst(&app.typ, s);
             }
-            subst_expr(&mut l.body, s);
-            l.typ = subst(&l.typ, s);
-        }
-        Expr::TypeAscript(ref mut a) => {
-            a.typ = subst(&a.typ, s);
-            subst_expr(&mut a.expr, s);
-        }
-        Expr::Cons(ref mut c) => {
-            c.typ = subst(&c.typ, s);
-            subst_expr(&mut c.car, s);
-            subst_expr(&mut c.cdr, s);
-        }
-        Expr::Car(ref mut c) => {
-            c.typ = subst(&c.typ, s);
-            subst_expr(&mut c.expr, s);
-        }
-        Expr::Cdr(ref mut c) => {
-            c.typ = subst(&c.typ, s);
-            subst_expr(&mut c.expr, s);
-        }
-        Expr::Cast(ref mut c) => {
-            c.typ = subst(&c.typ, s);
-            subst_expr(&mut c.expr, s);
-        }
-        Expr::New(ref mut n) => {
-            n.typ = subst(&n.typ, s);
-            for member in &mut n.members {
-                subst_expr(member, s);
+            Expr::If(ref mut cond) => {
+                subst_expr(&mut cond.predicate, s);
+                subst_expr(&mut cond.consequent, s);
+                subst_expr(&mut cond.alternative, s);
+                cond.typ = subst(&cond.typ, s);
             }
-        }
-        Expr::Match(ref mut m) => {
-            subst_expr(&mut m.expr, s);
-            m.typ = subst(&m.typ, s);
-            for case in &mut m.cases {
-                case.patt_typ = subst(&case.patt_typ, s);
-                for v in case.patt.variables_mut() {
-                    v.typ = subst(&v.typ, s);
+            Expr::Lambda(ref mut l) => {
+                subst_expr(&mut l.body, s);
+                l.typ = subst(&l.typ, s);
+            }
+            Expr::Let(ref mut l) => {
+                for binding in l.bindings.bindings_mut() {
+                    binding.sig = subst_poly(&binding.sig, s);
+                    subst_expr(&mut binding.val, s);
                 }
-                subst_expr(&mut case.body, s);
+                subst_expr(&mut l.body, s);
+                l.typ = subst(&l.typ, s);
             }
+            Expr::TypeAscript(ref mut a) => {
+                a.typ = subst(&a.typ, s);
+                subst_expr(&mut a.expr, s);
+            }
+            Expr::Cons(ref mut c) => {
+                c.typ = subst(&c.typ, s);
+                subst_expr(&mut c.car, s);
+                subst_expr(&mut c.cdr, s);
+            }
+            Expr::Car(ref mut c) => {
+                c.typ = subst(&c.typ, s);
+                subst_expr(&mut c.expr, s);
+            }
+            Expr::Cdr(ref mut c) => {
+                c.typ = subst(&c.typ, s);
+                subst_expr(&mut c.expr, s);
+            }
+            Expr::Cast(ref mut c) => {
+                c.typ = subst(&c.typ, s);
+                subst_expr(&mut c.expr, s);
+            }
+            Expr::Transmute(ref mut t) => {
+                t.typ = subst(&t.typ, s);
+                subst_expr(&mut t.expr, s);
+            }
+            Expr::New(ref mut n) => {
+                n.typ = subst(&n.typ, s);
+                for member in &mut n.members {
+                    subst_expr(member, s);
+                }
+            }
+            Expr::Match(ref mut m) => {
+                subst_expr(&mut m.expr, s);
+                m.typ = subst(&m.typ, s);
+                for case in &mut m.cases {
+                    case.patt_typ = subst(&case.patt_typ, s);
+                    for v in case.patt.variables_mut() {
+                        v.typ = subst(&v.typ, s);
+                    }
+                    subst_expr(&mut case.body, s);
+                }
+            }
+            Expr::Assert(ref mut a) => subst_expr(&mut a.cond, s),
+            Expr::WithArena(ref mut w) => subst_expr(&mut w.body, s),
+            Expr::Loop(ref mut l) => {
+                for var in &mut l.vars {
+                    var.typ = subst(&var.typ, s);
+                }
+                for init in &mut l.inits {
+                    subst_expr(init, s);
+                }
+                subst_expr(&mut l.body, s);
+            }
+            Expr::Recur(ref mut r) => {
+                for arg in &mut r.args {
+                    subst_expr(arg, s);
+                }
+                r.typ = subst(&r.typ, s);
+            }
+            Expr::Begin(ref mut b) => for e in &mut b.exprs {
+                subst_expr(e, s);
+            },
+            Expr::PtrOp(ref mut p) => {
+                for arg in &mut p.args {
+                    subst_expr(arg, s);
+                }
+                p.typ = subst(&p.typ, s);
+            }
+            Expr::ThreadOp(ref mut t) => {
+                for arg in &mut t.args {
+                    subst_expr(arg, s);
+                }
+                t.typ = subst(&t.typ, s);
+            }
+            Expr::Nil(_)
+            | Expr::StrLit(_)
+            | Expr::BytesLit(_)
+            | Expr::SymLit(_)
+            | Expr::Bool(_) => (),
         }
-        Expr::Nil(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
-    }
+    })
 }
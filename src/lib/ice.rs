@@ -0,0 +1,151 @@
+//! Handling of internal compiler errors
+//!
+//! Any panic in the compiler is a bug. Instead of a bare Rust backtrace, we print a
+//! bug-report banner with some context, and dump the AST node that was being compiled,
+//! as well as the partially generated LLVM IR, to a file that can be attached to the report.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use term::{self, color};
+
+const ICE_DUMP_FILENAME: &'static str = "kvasir-ice.txt";
+const BUG_REPORT_URL: &'static str = "https://github.com/bryal/kvasir/issues";
+
+#[derive(Default)]
+struct IceInfo {
+    /// The panic message, and location in the compiler source
+    msg: Option<(String, String)>,
+    /// The name of the binding being compiled, and a dump of its AST
+    binding: Option<(String, String)>,
+    partial_ir: Option<String>,
+}
+
+thread_local! {
+    static ICE_INFO: RefCell<IceInfo> = RefCell::new(IceInfo::default());
+    /// Whether the thread is running a pipeline in `catch`, which reports any panic itself
+    static IN_CATCH: Cell<bool> = Cell::new(false);
+}
+
+/// Install a panic hook that records the panic message for the bug report of `catch`,
+/// instead of printing it immediately
+///
+/// Panics outside of `catch`, or any panic if `RUST_BACKTRACE` is set, are passed on to the
+/// previous hook as well.
+pub fn install_hook() {
+    let prev_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !IN_CATCH.with(Cell::get) {
+            return prev_hook(info);
+        }
+        let msg = payload_msg(info.payload());
+        let loc = info.location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_else(|| "<unknown>".to_string());
+        ICE_INFO.with(|i| i.borrow_mut().msg = Some((msg, loc)));
+        if env::var_os("RUST_BACKTRACE").is_some() {
+            prev_hook(info)
+        }
+    }))
+}
+
+pub(crate) fn payload_msg(payload: &(Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+/// Run `f`, which compiles the binding `name`. If `f` panics, record the binding along with
+/// the AST dump returned by `dump_node`, and continue unwinding
+///
+/// Only the innermost binding is recorded if calls are nested.
+pub fn in_binding<R, F, D>(name: &str, dump_node: D, f: F) -> R
+where
+    F: FnOnce() -> R,
+    D: FnOnce() -> String,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        ICE_INFO.with(|i| {
+            let mut i = i.borrow_mut();
+            if i.binding.is_none() {
+                i.binding = Some((name.to_string(), dump_node()))
+            }
+        });
+        panic::resume_unwind(e)
+    })
+}
+
+/// Run `f`, which generates LLVM IR. If `f` panics, record the IR returned by `dump_ir`, and
+/// continue unwinding
+pub fn in_codegen<R, F, D>(dump_ir: D, f: F) -> R
+where
+    F: FnOnce() -> R,
+    D: FnOnce() -> String,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        // Printing the broken module might itself panic. Don't let that hide the original ICE
+        let ir = panic::catch_unwind(AssertUnwindSafe(dump_ir))
+            .unwrap_or_else(|_| "<failed to print partial LLVM IR>".to_string());
+        ICE_INFO.with(|i| i.borrow_mut().partial_ir = Some(ir));
+        panic::resume_unwind(e)
+    })
+}
+
+/// Run the compiler pipeline `f`. If it panics, report the ICE and exit
+pub fn catch<F: FnOnce()>(f: F) {
+    let was_in_catch = IN_CATCH.with(|c| c.replace(true));
+    let r = panic::catch_unwind(AssertUnwindSafe(f));
+    IN_CATCH.with(|c| c.set(was_in_catch));
+    if r.is_err() {
+        report();
+        process::exit(101)
+    }
+}
+
+fn report() {
+    let info = ICE_INFO.with(|i| i.replace(IceInfo::default()));
+    let (msg, loc) = info.msg
+        .clone()
+        .unwrap_or_else(|| ("<unknown>".to_string(), "<unknown>".to_string()));
+    let mut t = term::stdout().expect("Could not acquire access to stdout");
+    t.fg(color::BRIGHT_RED).ok();
+    print!("\nInternal compiler error: ");
+    t.reset().ok();
+    println!("{}", msg);
+    println!("  --> {}", loc);
+    println!("The compiler unexpectedly panicked. This is a bug.");
+    println!("Please submit a bug report at {}", BUG_REPORT_URL);
+    println!("kvasir {}", env!("CARGO_PKG_VERSION"));
+    if let Some((ref name, _)) = info.binding {
+        println!("While compiling binding `{}`", name);
+    }
+    if info.binding.is_some() || info.partial_ir.is_some() {
+        match write_dump(&info) {
+            Ok(()) => println!("State of the compilation was dumped to `{}`", ICE_DUMP_FILENAME),
+            Err(e) => println!("Failed to dump state to `{}`, {}", ICE_DUMP_FILENAME, e),
+        }
+    }
+}
+
+fn write_dump(info: &IceInfo) -> ::std::io::Result<()> {
+    let mut f = File::create(ICE_DUMP_FILENAME)?;
+    writeln!(f, "kvasir {}", env!("CARGO_PKG_VERSION"))?;
+    if let Some((ref msg, ref loc)) = info.msg {
+        writeln!(f, "Internal compiler error: {}\n  --> {}", msg, loc)?;
+    }
+    if let Some((ref name, ref node)) = info.binding {
+        writeln!(f, "\n;; Binding `{}`\n{}", name, node)?;
+    }
+    if let Some(ref ir) = info.partial_ir {
+        writeln!(f, "\n;; Partial LLVM IR\n{}", ir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,219 @@
+//! Mangling of the symbol names of instances of polymorphic definitions
+//!
+//! A polymorphic global is generated once for each instantiation of its type variables, and
+//! each instance gets a symbol that encodes the name of the global and the type arguments,
+//! by the following grammar:
+//!
+//! ```text
+//! symbol ::= "_KV" name "I" type* "E"
+//! name   ::= <decimal length in bytes> <UTF-8 bytes>
+//! type   ::= "C" name              ; Type constant, e.g. `Int64` is `C5Int64`
+//!          | "A" name type* "E"    ; Application of a type function, e.g. `(Ptr Int8)` is
+//!                                  ; `A3PtrC4Int8E`
+//! ```
+//!
+//! E.g. the instance of `(define (id x) x)` at `Int64` is `_KV2idIC5Int64E`, which demangles to
+//! `id[Int64]`. The length prefixes make the encoding unambiguous whatever characters the
+//! names contain. Monomorphic definitions keep their source names.
+//!
+//! Symbols may get a suffix `.N` to make them unique in the module, which is kept as is when
+//! demangling.
+
+use lib::front::ast::{Type, TypeFunc};
+
+const PREFIX: &str = "_KV";
+
+fn mangle_name(s: &mut String, name: &str) {
+    s.push_str(&name.len().to_string());
+    s.push_str(name)
+}
+
+fn mangle_type(s: &mut String, t: &Type) {
+    match *t {
+        Type::Const(c, _) => {
+            s.push('C');
+            mangle_name(s, c)
+        }
+        Type::App(ref f, ref args) => match **f {
+            TypeFunc::Const(f) => {
+                s.push('A');
+                mangle_name(s, f);
+                for arg in args {
+                    mangle_type(s, arg)
+                }
+                s.push('E')
+            }
+            TypeFunc::Poly(_) => panic!("ICE: mangle_type: polymorphic type function in `{}`", t),
+        },
+        _ => panic!("ICE: mangle_type: type `{}` is not monomorphic", t),
+    }
+}
+
+/// The mangled symbol of the instance of the global `name` at the type arguments `inst`
+pub fn mangle_inst(name: &str, inst: &[Type]) -> String {
+    let mut s = PREFIX.to_string();
+    mangle_name(&mut s, name);
+    s.push('I');
+    for t in inst {
+        mangle_type(&mut s, t)
+    }
+    s.push('E');
+    s
+}
+
+struct Demangler<'a> {
+    s: &'a str,
+    i: usize,
+}
+
+impl<'a> Demangler<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.i).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let n_digits = self.s[self.i..]
+            .bytes()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        let len = self.s[self.i..self.i + n_digits].parse::<usize>().ok()?;
+        let start = self.i + n_digits;
+        let name = self.s.get(start..start.checked_add(len)?)?;
+        self.i = start + len;
+        Some(name)
+    }
+
+    fn types(&mut self) -> Option<Vec<String>> {
+        let mut ts = Vec::new();
+        while !self.eat(b'E') {
+            ts.push(self.type_()?)
+        }
+        Some(ts)
+    }
+
+    fn type_(&mut self) -> Option<String> {
+        if self.eat(b'C') {
+            self.name().map(str::to_string)
+        } else if self.eat(b'A') {
+            let f = self.name()?;
+            let args = self.types()?;
+            if args.is_empty() {
+                Some(format!("({})", f))
+            } else {
+                Some(format!("({} {})", f, args.join(" ")))
+            }
+        } else {
+            None
+        }
+    }
+
+    fn symbol(&mut self) -> Option<String> {
+        if !self.s[self.i..].starts_with(PREFIX) {
+            return None;
+        }
+        self.i += PREFIX.len();
+        let name = self.name()?;
+        if !self.eat(b'I') {
+            return None;
+        }
+        let inst = self.types()?;
+        Some(format!("{}[{}]", name, inst.join(", ")))
+    }
+}
+
+/// Demangle the symbol `sym`, or return `None` if it's not a mangled symbol
+pub fn demangle(sym: &str) -> Option<String> {
+    let mut d = Demangler { s: sym, i: 0 };
+    let demangled = d.symbol()?;
+    let suffix = &sym[d.i..];
+    let is_unique_suffix = suffix.is_empty()
+        || (suffix.starts_with('.') && suffix[1..].bytes().all(|b| b.is_ascii_digit()));
+    if is_unique_suffix {
+        Some(demangled + suffix)
+    } else {
+        None
+    }
+}
+
+/// Demangle all mangled symbols in `text`, e.g. the output of a profiler or linker, leaving
+/// the rest of the text as is
+pub fn demangle_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = 0;
+    while let Some(offset) = text[rest..].find(PREFIX) {
+        let start = rest + offset;
+        out.push_str(&text[rest..start]);
+        let mut d = Demangler { s: text, i: start };
+        match d.symbol() {
+            Some(demangled) => {
+                out.push_str(&demangled);
+                rest = d.i
+            }
+            None => {
+                out.push_str(PREFIX);
+                rest = start + PREFIX.len()
+            }
+        }
+    }
+    out.push_str(&text[rest..]);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use lib::front::ast::Type;
+    use super::{demangle, demangle_text, mangle_inst};
+
+    fn int64() -> Type<'static> {
+        Type::Const("Int64", None)
+    }
+
+    #[test]
+    fn test_mangle_demangle() {
+        let inst = [int64(), Type::new_ptr(Type::Const("Int8", None))];
+        let sym = mangle_inst("id", &inst);
+        assert_eq!(sym, "_KV2idIC5Int64A3PtrC4Int8EE");
+        assert_eq!(demangle(&sym), Some("id[Int64, (Ptr Int8)]".to_string()));
+        // Names with digits and non-ASCII characters are length prefixed in bytes
+        let inst = [Type::new_func(int64(), Type::Const("Ä1", None))];
+        let sym = mangle_inst("map2-λ", &inst);
+        assert_eq!(demangle(&sym), Some("map2-λ[(-> Int64 Ä1)]".to_string()));
+        assert_eq!(demangle(&mangle_inst("nil", &[])), Some("nil[]".to_string()));
+    }
+
+    #[test]
+    fn test_demangle_unique_suffix() {
+        let sym = mangle_inst("id", &[int64()]);
+        assert_eq!(demangle(&(sym.clone() + ".3")), Some("id[Int64].3".to_string()));
+        assert_eq!(demangle(&(sym.clone() + ".x")), None);
+        assert_eq!(demangle(&(sym + "E")), None);
+    }
+
+    #[test]
+    fn test_demangle_invalid() {
+        assert_eq!(demangle("main"), None);
+        assert_eq!(demangle("_KV"), None);
+        assert_eq!(demangle("_KV9idIE"), None);
+        assert_eq!(demangle("_KV2idIC5Int64"), None);
+        assert_eq!(demangle("_KV2idIX5Int64E"), None);
+    }
+
+    #[test]
+    fn test_demangle_text() {
+        let text = format!(
+            "call {}\n_KV not mangled, {}.1",
+            mangle_inst("id", &[int64()]),
+            mangle_inst("f", &[])
+        );
+        assert_eq!(demangle_text(&text), "call id[Int64]\n_KV not mangled, f[].1");
+    }
+}
@@ -0,0 +1,183 @@
+//! The project manifest, `kvaser.toml`
+//!
+//! Describes how to build a multi-file project, so that `kvaser build` can be run
+//! without passing the same pile of flags on every invocation.
+//!
+//! ```toml
+//! [package]
+//! name = "foo"                 # Output is named after the package unless `out` is given
+//! main = "src/main.kvs"        # File containing the program entry point
+//! src-roots = ["src", "lib"]   # Directories searched when resolving imports
+//! out = "bin/foo"
+//! target = "x86_64-unknown-linux-gnu"
+//!
+//! [link]
+//! libs = ["m"]                 # Extern libraries, like `-l`
+//! paths = ["native"]           # Library search paths, like `-L`
+//! args = ["-static"]           # Additional arguments passed verbatim to the linker
+//! ```
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml;
+
+pub const MANIFEST_FILENAME: &'static str = "kvaser.toml";
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    /// Directory containing the manifest. Relative paths are relative to this
+    pub dir: PathBuf,
+    pub name: String,
+    pub main: PathBuf,
+    pub src_roots: Vec<PathBuf>,
+    pub out: PathBuf,
+    pub target: Option<String>,
+    pub link_libs: Vec<String>,
+    pub lib_paths: Vec<String>,
+    pub link_args: Vec<String>,
+}
+
+impl Manifest {
+    /// Find the manifest of the project that `dir` is in, looking in `dir` and then each ancestor
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|d| d.join(MANIFEST_FILENAME))
+            .find(|p| p.is_file())
+    }
+
+    /// Read and parse the manifest at `path`
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let mut src = String::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .map_err(|e| format!("Failed to read `{}`, {}", path.display(), e))?;
+        let dir = path.parent()
+            .expect("ICE: Manifest path has no parent")
+            .to_path_buf();
+        Manifest::parse(&src, dir).map_err(|e| format!("In `{}`: {}", path.display(), e))
+    }
+
+    fn parse(src: &str, dir: PathBuf) -> Result<Self, String> {
+        let root = src.parse::<toml::Value>()
+            .map_err(|e| format!("Invalid TOML, {}", e))?;
+        let empty = toml::value::Table::new();
+        let package = table(&root, "package")?.ok_or("Missing `[package]` section")?;
+        let link = table(&root, "link")?.unwrap_or(&empty);
+
+        let name = string(package, "package.name")?
+            .ok_or("Missing `name` in `[package]`")?;
+        let main = string(package, "package.main")?
+            .unwrap_or_else(|| "main.kvs".to_string());
+        let src_roots = strings(package, "package.src-roots")?;
+        let out = string(package, "package.out")?.unwrap_or_else(|| name.clone());
+        Ok(Manifest {
+            main: dir.join(main),
+            src_roots: src_roots.into_iter().map(|r| dir.join(r)).collect(),
+            out: dir.join(out),
+            target: string(package, "package.target")?,
+            link_libs: strings(link, "link.libs")?,
+            lib_paths: strings(link, "link.paths")?
+                .into_iter()
+                .map(|p| dir.join(p).to_string_lossy().into_owned())
+                .collect(),
+            link_args: strings(link, "link.args")?,
+            name,
+            dir,
+        })
+    }
+}
+
+fn table<'t>(root: &'t toml::Value, key: &str) -> Result<Option<&'t toml::value::Table>, String> {
+    match root.get(key) {
+        Some(&toml::Value::Table(ref t)) => Ok(Some(t)),
+        Some(_) => Err(format!("`{}` must be a table", key)),
+        None => Ok(None),
+    }
+}
+
+/// Get the value of `path` in `t` as a string. `path` is the full, dotted key, and only the
+/// last segment is looked up
+fn string(t: &toml::value::Table, path: &str) -> Result<Option<String>, String> {
+    let key = path.rsplit('.').next().unwrap();
+    match t.get(key) {
+        Some(&toml::Value::String(ref s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("`{}` must be a string", path)),
+        None => Ok(None),
+    }
+}
+
+fn strings(t: &toml::value::Table, path: &str) -> Result<Vec<String>, String> {
+    let key = path.rsplit('.').next().unwrap();
+    match t.get(key) {
+        Some(&toml::Value::Array(ref xs)) => xs.iter()
+            .map(|x| {
+                x.as_str()
+                    .map(str::to_string)
+                    .ok_or(format!("`{}` must be an array of strings", path))
+            })
+            .collect(),
+        Some(_) => Err(format!("`{}` must be an array of strings", path)),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use super::Manifest;
+
+    #[test]
+    fn test_parse_defaults() {
+        let dir = PathBuf::from("/proj");
+        let m = Manifest::parse("[package]\nname = \"foo\"\n", dir.clone()).unwrap();
+        assert_eq!(m.name, "foo");
+        assert_eq!(m.main, dir.join("main.kvs"));
+        assert_eq!(m.out, dir.join("foo"));
+        assert_eq!(m.target, None);
+        assert!(m.src_roots.is_empty());
+        assert!(m.link_libs.is_empty() && m.lib_paths.is_empty() && m.link_args.is_empty());
+    }
+
+    #[test]
+    fn test_parse_paths_relative_to_dir() {
+        let src = "[package]
+name = \"foo\"
+main = \"src/main.kvs\"
+src-roots = [\"src\", \"lib\"]
+out = \"bin/foo\"
+target = \"x86_64-unknown-linux-gnu\"
+
+[link]
+libs = [\"m\"]
+paths = [\"native\"]
+args = [\"-static\"]
+";
+        let dir = PathBuf::from("/proj");
+        let m = Manifest::parse(src, dir.clone()).unwrap();
+        assert_eq!(m.main, dir.join("src/main.kvs"));
+        assert_eq!(m.src_roots, vec![dir.join("src"), dir.join("lib")]);
+        assert_eq!(m.out, dir.join("bin/foo"));
+        assert_eq!(m.target, Some("x86_64-unknown-linux-gnu".to_string()));
+        assert_eq!(m.link_libs, vec!["m"]);
+        assert_eq!(m.lib_paths, vec![dir.join("native").to_string_lossy().into_owned()]);
+        assert_eq!(m.link_args, vec!["-static"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |src| Manifest::parse(src, PathBuf::from("/proj")).unwrap_err();
+        assert_eq!(parse("[link]\n"), "Missing `[package]` section");
+        assert_eq!(parse("[package]\n"), "Missing `name` in `[package]`");
+        assert_eq!(parse("package = 1\n"), "`package` must be a table");
+        assert_eq!(
+            parse("[package]\nname = \"foo\"\nmain = 1\n"),
+            "`package.main` must be a string"
+        );
+        assert_eq!(
+            parse("[package]\nname = \"foo\"\nsrc-roots = [1]\n"),
+            "`package.src-roots` must be an array of strings"
+        );
+        assert!(parse("[package").starts_with("Invalid TOML"));
+    }
+}
@@ -2,12 +2,36 @@ pub use self::collections::ScopeStack;
 use std::collections::{BTreeMap, BTreeSet};
 use std::iter::once;
 use std::{cmp, fmt, io, time};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
 #[macro_use]
 pub mod front;
+#[cfg(feature = "backend-llvm")]
 pub mod back;
+#[cfg(feature = "bindgen")]
+pub mod bindgen;
 pub mod collections;
+pub mod explain;
+pub mod ice;
+pub mod manifest;
+pub mod mangle;
+
+/// The amount of stack that must remain for `grow_stack` to not allocate a new segment
+const STACK_RED_ZONE: usize = 128 * 1024;
+
+/// The size of each new segment of stack allocated by `grow_stack`
+const STACK_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Run `f`, on a newly allocated segment of stack if little of the current one remains
+///
+/// The passes over the AST recurse as deep as expressions nest, so deeply nested programs, like
+/// big macro expansions and long chains of `let`, would overflow any fixed size stack. The
+/// recursive function of each pass that all nesting goes through, like `parse_expr` or
+/// `gen_expr`, runs its body in this.
+pub fn grow_stack<R, F: FnOnce() -> R>(f: F) -> R {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, f)
+}
 
 /// Returns the unit set of the single element `x`
 fn set_of<T: cmp::Ord>(x: T) -> BTreeSet<T> {
@@ -37,6 +61,16 @@ impl CanonPathBuf {
     pub fn with_extension(&self, ext: &str) -> Self {
         CanonPathBuf(self.0.with_extension(ext))
     }
+
+    /// Replace the file name of this path. `name` must not contain any `.` or `..` components
+    pub fn with_file_name(&self, name: &str) -> Self {
+        CanonPathBuf(self.0.with_file_name(name))
+    }
+
+    /// Join a file name to this path. `name` must not contain any `.` or `..` components
+    pub fn join_file_name(&self, name: &OsStr) -> Self {
+        CanonPathBuf(self.0.join(name))
+    }
 }
 
 impl AsRef<Path> for CanonPathBuf {
@@ -78,100 +78,262 @@
 // TODO: Base macro system on pure functions that has syntax trees as input and output.
 //       This would require some kind of interpretation in order to execute code at compile time
 
-#![feature(non_ascii_idents, box_syntax, box_patterns, duration_extras, inner_deref)]
+#![feature(duration_extras)]
 
-extern crate bitflags;
-extern crate cbox;
 extern crate cpuprofiler;
 extern crate getopts;
-extern crate itertools;
-#[macro_use]
-extern crate lazy_static;
-extern crate libc;
-extern crate llvm_sys;
-#[macro_use]
-extern crate maplit;
-extern crate term;
-
-use getopts::Options;
-use lib::{time_action, CanonPathBuf};
+extern crate kvasir;
+
+use getopts::{Matches, Options};
+use kvasir::{lib, BuildConfig, Emission, Lto, Sanitizer};
+use lib::{ice, mangle, time_action, CanonPathBuf};
 use lib::collections::AddMap;
-use lib::back::compile;
+use lib::back::{compile, default_stack_probe_interval};
+use lib::front::{capture_diagnostics, Diagnostic};
 use lib::front::inference::infer_types;
-use lib::front::parse::parse_program;
-use std::{env, fmt, time};
-
-mod lib;
-
-/// Enum of the different output formats of the compiler
-pub enum Emission {
-    /// Human readable LLVM assembly language code
-    LlvmAsm,
-    /// LLVM bitcode
-    LlvmBc,
-    /// Linkable object code
-    Obj,
-    /// An executable binary
-    Exe,
-}
-impl<S: AsRef<str> + fmt::Display> From<S> for Emission {
-    fn from(s: S) -> Emission {
-        match s.as_ref() {
-            "llvm-ir" => Emission::LlvmAsm,
-            "llvm-bc" => Emission::LlvmBc,
-            "obj" => Emission::Obj,
-            "exe" => Emission::Exe,
-            _ => panic!("Unknown emission type `{}`", s),
-        }
-    }
-}
+use lib::front::lex;
+use lib::front::lint::{self, Level};
+use lib::front::macros;
+use lib::front::parse::{parse_prelude, parse_program, parse_program_with_prelude, Prelude};
+use lib::manifest::{Manifest, MANIFEST_FILENAME};
+use std::{env, process, time};
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::Command;
+use std::path::PathBuf;
 
 #[cfg(windows)]
 const BIN_EXT: &'static str = "exe";
 #[cfg(not(windows))]
 const BIN_EXT: &'static str = "bin";
 
+/// Print the fatal error `err`, and exit the compilation
+fn exit_with(err: Diagnostic) -> ! {
+    print!("{}", err);
+    println!("\nError occured during compilation. Exiting");
+    process::exit(0)
+}
+
+/// Print an error that has no position in the source, and exit the compilation
+fn error_exit<E: Display>(msg: E) -> ! {
+    exit_with(Diagnostic::error(msg))
+}
+
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options] SOURCE-FILE", program);
+    let brief = format!(
+        "Usage: {0} [options] SOURCE-FILE\n       {0} build [options]\n       \
+         {0} test [options] SOURCE-FILE\n       {0} explain [CODE]\n       \
+         {0} demangle [SYMBOL...]\n       {0} daemon SOCKET [PRELUDE...]\n       \
+         {0} bindgen HEADER [CLANG-ARG...]\n\n\
+         A SOURCE-FILE of `-` reads the source from stdin",
+        program
+    );
     print!("{}", opts.usage(&brief));
 }
 
-fn main() {
-    //use cpuprofiler::PROFILER;
-    //PROFILER.lock().unwrap().start("./prof.profile").unwrap();
+fn add_lint_opts(opts: &mut Options) {
+    opts.optmulti("A", "allow", "Set lint <LINT> to allowed", "LINT")
+        .optmulti("W", "warn", "Set lint <LINT> to warn", "LINT")
+        .optmulti(
+            "D",
+            "deny",
+            "Set lint <LINT> to denied. Use `warnings` to deny all lints",
+            "LINT",
+        );
+}
 
-    let t_start = time::Instant::now();
-    let args: Vec<_> = env::args().collect();
-    let bin_name = args[0].clone();
-    let mut opts = Options::new();
-    opts.optopt("o", "out-file", "Write output to <FILENAME>", "FILENAME")
+fn add_limit_opts(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "max-nesting-depth",
+        "Reject source with forms nested deeper than <N>. Defaults to 512",
+        "N",
+    ).optopt(
+        "",
+        "max-form-len",
+        "Reject source with forms of more than <N> items. Defaults to 1048576",
+        "N",
+    );
+}
+
+fn add_macro_opts(opts: &mut Options) {
+    opts.optflagopt(
+        "",
+        "trace-macros",
+        "Print each expansion of the macro <NAME>, or of all macros if no name is given",
+        "NAME",
+    );
+}
+
+fn add_codegen_opts(opts: &mut Options) {
+    opts.optopt(
+        "",
+        "sanitize",
+        "Instrument the program with the comma separated sanitizers <SANITIZERS>",
+        "address,undefined",
+    ).optflagopt(
+        "",
+        "lto",
+        "Perform link time optimization of the kind <KIND>. Defaults to full",
+        "full|thin",
+    )
         .optopt(
+        "",
+        "target-cpu",
+        "Generate code for the CPU <CPU>, e.g. `native`",
+        "CPU",
+    )
+        .optmulti(
             "",
-            "emit",
-            "Specify the type of output for the compiler to emit",
-            "llvm-ir|llvm-bc|obj|exe",
+            "target-feature",
+            "Enable or disable the target feature <FEATURE>, e.g. `+avx2`",
+            "FEATURE",
         )
-        .optmulti("l", "", "Link with <LIBRARY>", "LIBRARY")
-        .optmulti("L", "", "Add <PATH> to the library search path", "PATH")
-        .optflag("h", "help", "Display this help menu");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(e) => panic!(e),
-    };
-    if matches.opt_present("h") {
-        print_usage(&bin_name, opts);
-        return;
+        .optmulti(
+            "",
+            "export",
+            "Export the global function <NAME> from the shared library, with `--emit=dylib`, \
+             or from the library, with `--no-main`",
+            "NAME",
+        )
+        .optopt(
+            "",
+            "entry-symbol",
+            "Name the generated C entry point <NAME> instead of `main`",
+            "NAME",
+        )
+        .optflag(
+            "",
+            "no-main",
+            "Compile a library without `main`, exporting its global functions. \
+             Requires an `--emit` other than `exe`",
+        )
+        .optflag(
+            "",
+            "profile-heap",
+            "Instrument heap allocations, and print the bytes allocated per site at exit",
+        )
+        .optflag(
+            "",
+            "debug-backtrace",
+            "Track the calls in progress, and print a backtrace of them on panics",
+        )
+        .optopt(
+            "",
+            "stack-probe-interval",
+            "Probe stack frames of at least <BYTES> bytes every <BYTES> bytes. \
+             Defaults to the guard page size of the target. 0 disables probing",
+            "BYTES",
+        );
+}
+
+/// Parse the stack probe interval given by the `--stack-probe-interval` flag, or get the
+/// default of the target `target`
+fn stack_probe_interval(matches: &Matches, target: Option<&str>) -> Option<u64> {
+    match matches.opt_str("stack-probe-interval") {
+        Some(s) => match s.parse() {
+            Ok(0) => None,
+            Ok(n) => Some(n),
+            Err(e) => error_exit(format!("Invalid stack probe interval `{}`, {}", s, e)),
+        },
+        None => default_stack_probe_interval(target),
     }
-    let inp_filename = if !matches.free.is_empty() {
-        CanonPathBuf::new(&matches.free[0]).expect("Failed to canonicalize input filename")
+}
+
+/// Parse the kind of LTO given by the `--lto` flag
+fn lto(matches: &Matches) -> Option<Lto> {
+    if matches.opt_present("lto") {
+        Some(
+            matches
+                .opt_str("lto")
+                .map(|s| s.parse().unwrap_or_else(|e| error_exit(e)))
+                .unwrap_or(Lto::Full),
+        )
     } else {
-        print_usage(&bin_name, opts);
-        return;
+        None
+    }
+}
+
+/// Parse the sanitizers given by the `--sanitize` flag
+fn sanitizers(matches: &Matches) -> Vec<Sanitizer> {
+    matches
+        .opt_str("sanitize")
+        .map(|s| {
+            s.split(',')
+                .map(|name| name.trim().parse().unwrap_or_else(|e| error_exit(e)))
+                .collect()
+        })
+        .unwrap_or(Vec::new())
+}
+
+/// The limits on source given by the `--max-nesting-depth` and `--max-form-len` flags
+fn lex_limits(matches: &Matches) -> lex::Limits {
+    let parse_limit = |flag: &str, default: usize| {
+        matches
+            .opt_str(flag)
+            .map(|s| {
+                s.parse().unwrap_or_else(|_| {
+                    error_exit(format!("Invalid value `{}` for `--{}`", s, flag))
+                })
+            })
+            .unwrap_or(default)
     };
-    let out_filename = matches
-        .opt_str("o")
-        .map(|p| CanonPathBuf::new(&p).expect("Failed to canonicalize output filename"))
-        .unwrap_or(inp_filename.with_extension(BIN_EXT));
+    let default = lex::Limits::default();
+    lex::Limits {
+        max_depth: parse_limit("max-nesting-depth", default.max_depth),
+        max_form_len: parse_limit("max-form-len", default.max_form_len),
+    }
+}
+
+/// Which macro expansions to trace, as given by the `--trace-macros` flag
+fn macro_trace(matches: &Matches) -> macros::Trace {
+    if matches.opt_present("trace-macros") {
+        matches
+            .opt_str("trace-macros")
+            .map(macros::Trace::Only)
+            .unwrap_or(macros::Trace::All)
+    } else {
+        macros::Trace::Off
+    }
+}
+
+/// The levels of lints given by the `--allow`, `--warn`, and `--deny` flags
+///
+/// The flags are applied in the order given on the command line, so if a lint is given to
+/// multiple flags, the last one takes precedence
+fn lint_levels(matches: &Matches) -> lint::Levels {
+    let mut flags = Vec::new();
+    for &(flag, level) in &[("A", Level::Allow), ("W", Level::Warn), ("D", Level::Deny)] {
+        let positions = matches.opt_positions(flag);
+        flags.extend(positions.into_iter().zip(matches.opt_strs(flag)).map(|(i, name)| {
+            (i, name, level)
+        }));
+    }
+    flags.sort_by_key(|&(i, _, _)| i);
+    let mut levels = lint::Levels::default();
+    for (_, name, level) in flags {
+        levels.set(&name, level).unwrap_or_else(|e| error_exit(e))
+    }
+    levels
+}
+
+/// Parse, typecheck, and compile the program with entry point in `inp_filename`
+///
+/// If `inp_src` is given, it's used as the source of `inp_filename` instead of reading the file
+fn compile_program(
+    inp_filename: CanonPathBuf,
+    inp_src: Option<String>,
+    src_roots: &[PathBuf],
+    lint_levels: &lint::Levels,
+    lex_limits: lex::Limits,
+    macro_trace: macros::Trace,
+    config: &BuildConfig,
+) {
     {
         let inp_file_dir = inp_filename
             .path()
@@ -180,39 +342,437 @@ fn main() {
         env::set_current_dir(inp_file_dir).expect("Failed to change dir to dir of input file")
     }
 
-    let explicit_out_filename = matches.opt_str("o").is_some();
+    println!("    Compiling {}", inp_filename.path().display());
+
+    let build = || {
+        let mut type_var_generator = lib::front::TypeVarGen::new(0);
+        let sources = AddMap::new();
+        if let Some(src) = inp_src {
+            sources.add(inp_filename.clone(), lex::normalize(&src));
+        }
+
+        let mut ast = time_action(
+            || parse_program(inp_filename, &sources, &mut type_var_generator, src_roots, &[]),
+            |t| println!("    Parsed source in {}s", t),
+        )?;
+        lint::check_denied()?;
+        //println!("parsed:\n\n{}", ast);
+
+        time_action(
+            || infer_types(&mut ast, &mut type_var_generator),
+            |t| println!("    Infered types and monomorphization in {}s", t),
+        )?;
+        lint::check_denied()?;
+        //println!("inferred:\n\n{}", ast);
+
+        compile(&ast, config)
+    };
+    ice::catch(|| {
+        let r = lint::in_compilation(lint_levels, || {
+            lex::in_compilation(lex_limits, || macros::in_compilation(macro_trace, build))
+        });
+        if let Err(e) = r {
+            exit_with(e)
+        }
+    })
+}
+
+/// The `build` subcommand. Build the project described by the manifest `kvaser.toml`
+/// in the current directory, or the closest ancestor directory that has one
+fn build(bin_name: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt(
+        "",
+        "emit",
+        "Specify the type of output for the compiler to emit",
+        "llvm-ir|llvm-bc|asm|obj|exe|dylib",
+    ).optflag("h", "help", "Display this help menu");
+    add_lint_opts(&mut opts);
+    add_limit_opts(&mut opts);
+    add_macro_opts(&mut opts);
+    add_codegen_opts(&mut opts);
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => panic!(e),
+    };
+    if matches.opt_present("h") {
+        print_usage(bin_name, opts);
+        return;
+    }
+    let lint_levels = lint_levels(&matches);
+    let lex_limits = lex_limits(&matches);
+    let macro_trace = macro_trace(&matches);
+    let cwd = env::current_dir().expect("Invalid current working directory");
+    let manifest_path = Manifest::find(&cwd).unwrap_or_else(|| {
+        error_exit(format!(
+            "Could not find `{}` in `{}` or any parent directory",
+            MANIFEST_FILENAME,
+            cwd.display()
+        ))
+    });
+    let manifest = Manifest::read(&manifest_path).unwrap_or_else(|e| error_exit(e));
+    let inp_filename = CanonPathBuf::new(&manifest.main.to_string_lossy())
+        .unwrap_or_else(|e| {
+            error_exit(format!(
+                "Failed to find main file `{}`, {}",
+                manifest.main.display(),
+                e
+            ))
+        });
+    let src_roots = manifest
+        .src_roots
+        .iter()
+        .map(|root| {
+            root.canonicalize().unwrap_or_else(|e| {
+                error_exit(format!(
+                    "Failed to find source root `{}`, {}",
+                    root.display(),
+                    e
+                ))
+            })
+        })
+        .collect::<Vec<_>>();
+    let out_dir = manifest.out.parent().expect("ICE: Output path has no parent");
+    let out_filename = CanonPathBuf::new(&out_dir.to_string_lossy())
+        .map(|dir| dir.join_file_name(manifest.out.file_name().expect("Invalid output path")))
+        .unwrap_or_else(|e| {
+            error_exit(format!(
+                "Failed to find output directory `{}`, {}",
+                out_dir.display(),
+                e
+            ))
+        });
     let emission = matches
         .opt_str("emit")
         .map(|s| s.into())
         .unwrap_or(Emission::Exe);
-    let link_libs = matches.opt_strs("l");
-    let lib_paths = matches.opt_strs("L");
+    let config = BuildConfig {
+        out_filename,
+        explicit_out_filename: false,
+        emission,
+        link_libs: manifest.link_libs,
+        lib_paths: manifest.lib_paths,
+        link_args: manifest.link_args,
+        stack_probe_interval: stack_probe_interval(
+            &matches,
+            manifest.target.as_ref().map(|s| s.as_str()),
+        ),
+        target: manifest.target,
+        test_harness: false,
+        target_cpu: matches.opt_str("target-cpu"),
+        target_features: matches.opt_strs("target-feature"),
+        sanitizers: sanitizers(&matches),
+        lto: lto(&matches),
+        exports: matches.opt_strs("export"),
+        entry_symbol: matches.opt_str("entry-symbol").unwrap_or("main".to_string()),
+        no_main: matches.opt_present("no-main"),
+        profile_heap: matches.opt_present("profile-heap"),
+        debug_backtrace: matches.opt_present("debug-backtrace"),
+    };
+    println!("    Building {}", manifest.name);
+    compile_program(
+        inp_filename,
+        None,
+        &src_roots,
+        &lint_levels,
+        lex_limits,
+        macro_trace,
+        &config,
+    )
+}
 
-    println!("    Compiling {}", inp_filename.path().display());
+/// The `explain` subcommand. Print the extended description of the diagnostic `code`, or list
+/// all diagnostic codes and lints if no code is given
+fn explain(code: Option<&str>) {
+    match code {
+        Some(code) => match lib::explain::explain(code) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error_exit(e),
+        },
+        None => {
+            println!("Diagnostic codes:");
+            for code in lib::explain::codes() {
+                println!("    {}", code)
+            }
+            println!("\nLints:");
+            for l in lint::LINTS {
+                println!("    {:<24}{:?} by default, {}", l.name, l.default_level, l.desc)
+            }
+        }
+    }
+}
 
-    let mut type_var_generator = lib::front::TypeVarGen::new(0);
-    let sources = AddMap::new();
+/// The `demangle` subcommand. Print the demangled form of each of the mangled `symbols`, or if
+/// none are given, copy stdin to stdout with all mangled symbols demangled, e.g. to read the
+/// output of a profiler or linker
+fn demangle(symbols: &[String]) {
+    if symbols.is_empty() {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .unwrap_or_else(|e| error_exit(format!("Failed to read stdin, {}", e)));
+        print!("{}", mangle::demangle_text(&text))
+    } else {
+        for sym in symbols {
+            println!("{}", mangle::demangle(sym).unwrap_or(sym.clone()))
+        }
+    }
+}
 
-    let mut ast = time_action(
-        || parse_program(inp_filename, &sources, &mut type_var_generator),
-        |t| println!("    Parsed source in {}s", t),
-    );
-    //println!("parsed:\n\n{}", ast);
+/// The `daemon` subcommand. Keep the compiler running, and check or compile the programs of the
+/// requests of clients on the Unix socket `SOCKET`, so that each request skips the startup of a
+/// new process, e.g. in editor integrations
+///
+/// The core prelude, and the modules of the files `PRELUDE...`, are lexed and have their macros
+/// collected once, and are kept in memory between requests. The `PRELUDE` modules are implicitly
+/// imported into every program. The prelude is loaded again if any of its files change.
+///
+/// Only the frontend is kept warm. `compile` creates a new LLVM context and target machine
+/// for every request, like `kvasir SOURCE-FILE` does, so code generation and linking take as
+/// long as in a new process.
+///
+/// A request is a line `check SOURCE-FILE` or `compile SOURCE-FILE`, answered with a line
+/// `ok LEN` or `error LEN`, followed by `LEN` bytes of the diagnostics. `compile` builds an
+/// executable next to the source file, like `kvasir SOURCE-FILE`. The compiler has global
+/// state, so requests are handled one at a time.
+#[cfg(unix)]
+fn daemon(bin_name: &str, args: &[String]) {
+    let socket = match args.first() {
+        Some(socket) => socket,
+        None => error_exit(format!("Usage: {} daemon SOCKET [PRELUDE...]", bin_name)),
+    };
+    let prelude_files = args[1..]
+        .iter()
+        .map(|path| {
+            CanonPathBuf::new(path).unwrap_or_else(|e| {
+                error_exit(format!("Invalid prelude module `{}`, {}", path, e))
+            })
+        })
+        .collect();
+    let mut daemon = Daemon {
+        dir: CanonPathBuf::new(".").expect("Invalid current working directory"),
+        prelude_files,
+        prelude: None,
+    };
+    if let Err(diagnostics) = daemon.load_prelude() {
+        print!("{}", diagnostics);
+        error_exit("Failed to load the prelude")
+    }
+    let listener = UnixListener::bind(socket)
+        .unwrap_or_else(|e| error_exit(format!("Failed to bind socket `{}`, {}", socket, e)));
+    println!("    Listening on {}", socket);
+    for stream in listener.incoming() {
+        let r = stream.and_then(|stream| daemon.serve_client(stream));
+        if let Err(e) = r {
+            println!("    Connection closed, {}", e)
+        }
+    }
+}
 
-    time_action(
-        || infer_types(&mut ast, &mut type_var_generator),
-        |t| println!("    Infered types and monomorphization in {}s", t),
-    );
-    //println!("inferred:\n\n{}", ast);
+#[cfg(not(unix))]
+fn daemon(_: &str, _: &[String]) {
+    error_exit("The daemon is only supported on Unix")
+}
 
-    compile(
-        &ast,
-        out_filename,
-        explicit_out_filename,
-        emission,
-        &link_libs,
-        &lib_paths,
-    );
+/// The state kept by the daemon between requests
+#[cfg(unix)]
+struct Daemon {
+    /// The directory the daemon was started in, which imports of the prelude are resolved
+    /// relative to
+    dir: CanonPathBuf,
+    /// The files of the modules implicitly imported into every program
+    prelude_files: Vec<CanonPathBuf>,
+    /// The loaded prelude, and the modification times of its files when it was loaded
+    prelude: Option<(Prelude<'static>, Vec<Option<time::SystemTime>>)>,
+}
+
+#[cfg(unix)]
+impl Daemon {
+    /// Load the prelude, unless it's already loaded and its files are unchanged
+    ///
+    /// Returns the diagnostics if the prelude fails to load.
+    fn load_prelude(&mut self) -> Result<(), String> {
+        let is_fresh = match self.prelude {
+            Some((ref prelude, ref mtimes)) => modification_times(prelude.files()) == *mtimes,
+            None => false,
+        };
+        if is_fresh {
+            return Ok(());
+        }
+        self.prelude = None;
+        env::set_current_dir(self.dir.path())
+            .map_err(|e| format!("Error: Failed to enter `{}`, {}\n", self.dir.path().display(), e))?;
+        // The prelude borrows its sources for as long as it's loaded, so they're leaked. Only
+        // changes to the prelude modules leak a previous version
+        let sources: &'static AddMap<CanonPathBuf, String> = Box::leak(Box::new(AddMap::new()));
+        let (dir, prelude_files) = (&self.dir, &self.prelude_files);
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            capture_diagnostics(|| {
+                lint::in_compilation(&lint::Levels::default(), || {
+                    parse_prelude(dir, sources, &[], prelude_files)
+                })
+            })
+        }));
+        match r {
+            Ok((Ok(prelude), _)) => {
+                let mtimes = modification_times(prelude.files());
+                self.prelude = Some((prelude, mtimes));
+                Ok(())
+            }
+            Ok((Err(e), errors)) => Err(errors + &e.0),
+            Err(_) => Err("Error: Internal compiler error\n".to_string()),
+        }
+    }
+
+    /// Answer the requests of a client, until it disconnects
+    fn serve_client(&mut self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let (ok, diagnostics) = {
+                let mut words = line.trim().splitn(2, ' ');
+                match (words.next(), words.next()) {
+                    (Some("check"), Some(path)) => self.build(path.trim(), false),
+                    (Some("compile"), Some(path)) => self.build(path.trim(), true),
+                    _ => (
+                        false,
+                        format!(
+                            "Error: Invalid request `{}`. Expected `check SOURCE-FILE` or \
+                             `compile SOURCE-FILE`\n",
+                            line.trim()
+                        ),
+                    ),
+                }
+            };
+            let status = if ok { "ok" } else { "error" };
+            write!(writer, "{} {}\n{}", status, diagnostics.len(), diagnostics)?;
+            writer.flush()?;
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Parse and typecheck the program of the source file `path`, and compile it to an
+    /// executable if `exe`, for a request
+    ///
+    /// Returns whether the request succeeded, and the diagnostics. An internal compiler error
+    /// fails the request, instead of bringing down the daemon.
+    fn build(&mut self, path: &str, exe: bool) -> (bool, String) {
+        let path = match CanonPathBuf::new(path) {
+            Ok(path) => path,
+            Err(e) => return (false, format!("Error: Invalid source file `{}`, {}\n", path, e)),
+        };
+        if let Err(errors) = self.load_prelude() {
+            return (false, errors);
+        }
+        let prelude = &self.prelude.as_ref().expect("ICE: Prelude not loaded").0;
+        // Imports are resolved relative to the directory of the source file, as when compiling
+        // the file from the command line
+        let dir = path.path().parent().expect("Failed to get parent dir of source file");
+        if let Err(e) = env::set_current_dir(dir) {
+            return (false, format!("Error: Failed to enter `{}`, {}\n", dir.display(), e));
+        }
+        let config = if exe {
+            Some(BuildConfig {
+                out_filename: path.with_extension(BIN_EXT),
+                explicit_out_filename: false,
+                emission: Emission::Exe,
+                link_libs: Vec::new(),
+                lib_paths: Vec::new(),
+                link_args: Vec::new(),
+                target: None,
+                target_cpu: None,
+                target_features: Vec::new(),
+                test_harness: false,
+                sanitizers: Vec::new(),
+                lto: None,
+                exports: Vec::new(),
+                entry_symbol: "main".to_string(),
+                no_main: false,
+                profile_heap: false,
+                debug_backtrace: false,
+                stack_probe_interval: default_stack_probe_interval(None),
+            })
+        } else {
+            None
+        };
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            capture_diagnostics(|| {
+                lint::in_compilation(&lint::Levels::default(), || {
+                    let mut type_var_generator = lib::front::TypeVarGen::new(0);
+                    let sources = AddMap::new();
+                    let mut ast = parse_program_with_prelude(
+                        path,
+                        &sources,
+                        prelude,
+                        &mut type_var_generator,
+                        &[],
+                    )?;
+                    lint::check_denied()?;
+                    infer_types(&mut ast, &mut type_var_generator)?;
+                    lint::check_denied()?;
+                    match config {
+                        Some(ref config) => compile(&ast, config),
+                        None => Ok(()),
+                    }
+                })
+            })
+        }));
+        match r {
+            Ok((Ok(()), warnings)) => (true, warnings),
+            Ok((Err(e), errors)) => (false, errors + &e.0),
+            Err(_) => (false, "Error: Internal compiler error\n".to_string()),
+        }
+    }
+}
+
+/// The modification time of each of the files `files`, or `None` for a file that doesn't exist
+#[cfg(unix)]
+fn modification_times(files: &[CanonPathBuf]) -> Vec<Option<time::SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file.path()).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// The `bindgen` subcommand. Print the Kvasir declarations of the functions and types of the
+/// C header `HEADER`. The rest of the arguments are passed on to clang
+#[cfg(feature = "bindgen")]
+fn bindgen(bin_name: &str, args: &[String]) {
+    let header = match args.first() {
+        Some(header) => PathBuf::from(header),
+        None => error_exit(format!("Usage: {} bindgen HEADER [CLANG-ARG...]", bin_name)),
+    };
+    match lib::bindgen::bindgen(&header, &args[1..]) {
+        Ok(decls) => print!("{}", decls),
+        Err(e) => error_exit(e),
+    }
+}
+
+#[cfg(not(feature = "bindgen"))]
+fn bindgen(_: &str, _: &[String]) {
+    error_exit("Kvasir was built without the `bindgen` feature")
+}
+
+fn main() {
+    //use cpuprofiler::PROFILER;
+    //PROFILER.lock().unwrap().start("./prof.profile").unwrap();
+
+    let t_start = time::Instant::now();
+    let args: Vec<_> = env::args().collect();
+    ice::install_hook();
+    let bin_name = args[0].clone();
+
+    match args.get(1).map(|s| s.as_str()) {
+        Some("build") => build(&bin_name, &args[2..]),
+        Some("explain") => return explain(args.get(2).map(|s| s.as_str())),
+        Some("demangle") => return demangle(&args[2..]),
+        Some("daemon") => return daemon(&bin_name, &args[2..]),
+        Some("bindgen") => return bindgen(&bin_name, &args[2..]),
+        Some("test") => compile_file(&bin_name, &args[2..], true),
+        _ => compile_file(&bin_name, &args[1..], false),
+    }
 
     let t = t_start.elapsed();
     println!(
@@ -223,3 +783,117 @@ fn main() {
 
     //PROFILER.lock().unwrap().stop().unwrap();
 }
+
+/// Compile a single source file, with all configuration given as command line flags
+///
+/// If `test_harness`, compile the tests of the file into a test harness and run it,
+/// for the `test` subcommand
+fn compile_file(bin_name: &str, args: &[String], test_harness: bool) {
+    let mut opts = Options::new();
+    opts.optopt("o", "out-file", "Write output to <FILENAME>", "FILENAME")
+        .optopt(
+            "",
+            "emit",
+            "Specify the type of output for the compiler to emit",
+            "llvm-ir|llvm-bc|asm|obj|exe|dylib",
+        )
+        .optmulti("l", "", "Link with <LIBRARY>", "LIBRARY")
+        .optmulti("L", "", "Add <PATH> to the library search path", "PATH")
+        .optopt("", "target", "Compile for the target <TRIPLE>", "TRIPLE")
+        .optflag("h", "help", "Display this help menu");
+    add_lint_opts(&mut opts);
+    add_limit_opts(&mut opts);
+    add_macro_opts(&mut opts);
+    add_codegen_opts(&mut opts);
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => panic!(e),
+    };
+    if matches.opt_present("h") {
+        print_usage(bin_name, opts);
+        return;
+    }
+    let lint_levels = lint_levels(&matches);
+    let lex_limits = lex_limits(&matches);
+    let macro_trace = macro_trace(&matches);
+    let (inp_filename, inp_src) = match matches.free.first().map(|s| s.as_str()) {
+        Some("-") => {
+            let mut src = String::new();
+            io::stdin()
+                .read_to_string(&mut src)
+                .unwrap_or_else(|e| error_exit(format!("Failed to read stdin, {}", e)));
+            let cwd = CanonPathBuf::new(".").expect("Invalid current working directory");
+            (cwd.join_file_name(OsStr::new("<stdin>")), Some(src))
+        }
+        Some(path) => (
+            CanonPathBuf::new(path).expect("Failed to canonicalize input filename"),
+            None,
+        ),
+        None => {
+            print_usage(bin_name, opts);
+            return;
+        }
+    };
+    // The output of a program read from stdin is named as if the source file was `stdin`
+    let out_stem = if inp_src.is_some() {
+        inp_filename.with_file_name("stdin")
+    } else {
+        inp_filename.clone()
+    };
+    let out_filename = matches
+        .opt_str("o")
+        .map(|p| CanonPathBuf::new(&p).expect("Failed to canonicalize output filename"))
+        .unwrap_or_else(|| if test_harness {
+            out_stem.with_extension(&format!("test.{}", BIN_EXT))
+        } else {
+            out_stem.with_extension(BIN_EXT)
+        });
+    let config = BuildConfig {
+        out_filename,
+        explicit_out_filename: matches.opt_str("o").is_some(),
+        emission: match matches.opt_str("emit") {
+            Some(_) if test_harness => error_exit("`--emit` is not supported by `test`"),
+            Some(s) => s.into(),
+            None => Emission::Exe,
+        },
+        link_libs: matches.opt_strs("l"),
+        lib_paths: matches.opt_strs("L"),
+        link_args: Vec::new(),
+        target: matches.opt_str("target"),
+        stack_probe_interval: stack_probe_interval(
+            &matches,
+            matches.opt_str("target").as_ref().map(|s| s.as_str()),
+        ),
+        test_harness,
+        target_cpu: matches.opt_str("target-cpu"),
+        target_features: matches.opt_strs("target-feature"),
+        sanitizers: sanitizers(&matches),
+        lto: lto(&matches),
+        exports: matches.opt_strs("export"),
+        entry_symbol: matches.opt_str("entry-symbol").unwrap_or("main".to_string()),
+        no_main: matches.opt_present("no-main"),
+        profile_heap: matches.opt_present("profile-heap"),
+        debug_backtrace: matches.opt_present("debug-backtrace"),
+    };
+    compile_program(
+        inp_filename,
+        inp_src,
+        &[],
+        &lint_levels,
+        lex_limits,
+        macro_trace,
+        &config,
+    );
+    if test_harness {
+        run_test_harness(&config.out_filename)
+    }
+}
+
+/// Run the compiled test harness `harness`, and exit with its exit code
+fn run_test_harness(harness: &CanonPathBuf) -> ! {
+    println!("     Running {}\n", harness.path().display());
+    let status = Command::new(harness.path())
+        .status()
+        .unwrap_or_else(|e| error_exit(format!("Failed to run test harness, {}", e)));
+    process::exit(status.code().unwrap_or(101))
+}
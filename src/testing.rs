@@ -0,0 +1,231 @@
+//! Differential testing of the code generator against constant folding
+//!
+//! The core binops and numeric casts are evaluated both by the constant folder of the
+//! frontend, `lib::front::const_fold`, and by a JIT compiled function, and the results are
+//! compared. A disagreement is a bug in either `gen_core_funcs` or `gen_cast` of the code
+//! generator, or in the folder, so the checks can be run over many operands to find them.
+//!
+//! ```ignore
+//! use kvasir::JitValue;
+//! use kvasir::testing::{check_binop, check_cast};
+//!
+//! check_binop("div", JitValue::Int8(-128), JitValue::Int8(3))?;
+//! check_cast(JitValue::Float64(-1.5), "Int32")?;
+//! ```
+//!
+//! Only operands of the fixed size numeric types, and booleans in casts, are supported, as
+//! the pointer sized types are not folded.
+
+use lib::front::const_fold::{eval_binop, eval_cast};
+use {jit_str, HostFunctions, JitValue, Options};
+
+/// The outcome of a differential check that found no disagreement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Both evaluations gave the value
+    Agree(JitValue),
+    /// The result is undefined, like for a division by zero, so the compiled code was not
+    /// run
+    Undefined,
+}
+
+/// The types that the constant folder evaluates
+const FOLDED_TYPES: &[&str] = &[
+    "Int8", "Int16", "Int32", "Int64", "UInt8", "UInt16", "UInt32", "UInt64", "Float32",
+    "Float64",
+];
+
+/// Parse the literal `lit` of the type named `typ`, as printed by the constant folder
+fn parse_value(typ: &str, lit: &str) -> Option<JitValue> {
+    match typ {
+        "Bool" => lit.parse().ok().map(JitValue::Bool),
+        "Int8" => lit.parse().ok().map(JitValue::Int8),
+        "Int16" => lit.parse().ok().map(JitValue::Int16),
+        "Int32" => lit.parse().ok().map(JitValue::Int32),
+        "Int64" => lit.parse().ok().map(JitValue::Int64),
+        "UInt8" => lit.parse().ok().map(JitValue::UInt8),
+        "UInt16" => lit.parse().ok().map(JitValue::UInt16),
+        "UInt32" => lit.parse().ok().map(JitValue::UInt32),
+        "UInt64" => lit.parse().ok().map(JitValue::UInt64),
+        "Float32" => lit.parse().ok().map(JitValue::Float32),
+        "Float64" => lit.parse().ok().map(JitValue::Float64),
+        _ => None,
+    }
+}
+
+/// Whether `a` and `b` are the same value. Floats are compared bitwise, so that `0.0` and
+/// `-0.0` differ, except that all NaNs are the same
+fn same_value(a: JitValue, b: JitValue) -> bool {
+    match (a, b) {
+        (JitValue::Float32(x), JitValue::Float32(y)) => {
+            x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan())
+        }
+        (JitValue::Float64(x), JitValue::Float64(y)) => {
+            x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan())
+        }
+        _ => a == b,
+    }
+}
+
+/// JIT compile the global function `under-test` of source code `src`, call it with `args`,
+/// and compare the result to `folded`
+fn check_against_jit(
+    expr: &str,
+    src: &str,
+    args: &[JitValue],
+    folded: JitValue,
+) -> Result<Outcome, String> {
+    let m = jit_str(src, Options::default(), &HostFunctions::new())
+        .map_err(|e| format!("Failed to compile `{}`\n{}", expr, e))?;
+    let compiled = m.call("under-test", args)?;
+    if same_value(folded, compiled) {
+        Ok(Outcome::Agree(compiled))
+    } else {
+        Err(format!(
+            "`{}` evaluates to `{}` when folded, but to `{}` when compiled",
+            expr, folded, compiled
+        ))
+    }
+}
+
+/// Check that the core binop `op`, e.g. `add` or `lt`, of the operands `a` and `b` evaluates
+/// to the same value when folded as when compiled
+///
+/// Returns an error describing the disagreement if the values differ.
+pub fn check_binop(op: &str, a: JitValue, b: JitValue) -> Result<Outcome, String> {
+    let typ = a.type_name();
+    if !FOLDED_TYPES.contains(&typ) || b.type_name() != typ {
+        return Err(format!(
+            "Operands of types `{}` and `{}` are not supported. Both must be of the same \
+             fixed size numeric type",
+            typ,
+            b.type_name()
+        ));
+    }
+    let (ret, decl_ret) = match op {
+        "eq" | "lt" => ("Bool", "Bool"),
+        _ => (typ, "t"),
+    };
+    let expr = format!("({} (cons (: {} {}) (: {} {})))", op, a, typ, b, typ);
+    let folded = match eval_binop(op, typ, &a.to_string(), &b.to_string()) {
+        Some(lit) => parse_value(ret, &lit)
+            .unwrap_or_else(|| panic!("ICE: invalid folded literal `{}` of `{}`", lit, expr)),
+        None => return Ok(Outcome::Undefined),
+    };
+    // The binop is declared like in std, and its definition is generated by the code generator
+    let src = format!(
+        "(define: (undef _) (-> Nil t) (undef nil))\n\
+         (define: ({} _) (constrain [(Num t)] (-> (Cons t t) {})) (undef nil))\n\
+         (define: (under-test a b) (-> {} (-> {} {})) ({} (cons a b)))",
+        op, decl_ret, typ, typ, ret, op
+    );
+    check_against_jit(&expr, &src, &[a, b], folded)
+}
+
+/// Check that the cast of `x` to the type named `to` evaluates to the same value when folded
+/// as when compiled
+///
+/// Returns an error describing the disagreement if the values differ.
+pub fn check_cast(x: JitValue, to: &str) -> Result<Outcome, String> {
+    let from = x.type_name();
+    let is_supported = |t: &str| FOLDED_TYPES.contains(&t) || t == "Bool";
+    if !is_supported(from) || !is_supported(to) {
+        return Err(format!(
+            "Cast from `{}` to `{}` is not supported. Both must be fixed size numeric types, \
+             or `Bool`",
+            from, to
+        ));
+    }
+    let expr = format!("(cast (: {} {}) {})", x, from, to);
+    let folded = match eval_cast(from, to, &x.to_string()) {
+        Some(lit) => parse_value(to, &lit)
+            .unwrap_or_else(|| panic!("ICE: invalid folded literal `{}` of `{}`", lit, expr)),
+        None => return Ok(Outcome::Undefined),
+    };
+    let src = format!("(define: (under-test x) (-> {} {}) (cast x {}))", from, to, to);
+    check_against_jit(&expr, &src, &[x], folded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{f32, f64};
+
+    /// The values at the edges of the type named `typ`, where the code generator and the
+    /// constant folder are most likely to disagree
+    fn edge_values(typ: &str) -> Vec<JitValue> {
+        macro_rules! ints {
+            ($variant:ident, $t:ident) => {
+                vec![
+                    JitValue::$variant(0),
+                    JitValue::$variant(1),
+                    JitValue::$variant((0 as $t).wrapping_sub(1)),
+                    JitValue::$variant($t::min_value()),
+                    JitValue::$variant($t::max_value()),
+                ]
+            };
+        }
+        macro_rules! floats {
+            ($variant:ident, $t:ident) => {
+                vec![
+                    JitValue::$variant(0.0),
+                    JitValue::$variant(-0.0),
+                    JitValue::$variant(-1.0),
+                    JitValue::$variant($t::MIN),
+                    JitValue::$variant($t::MAX),
+                    JitValue::$variant($t::NAN),
+                    JitValue::$variant($t::INFINITY),
+                    JitValue::$variant($t::NEG_INFINITY),
+                ]
+            };
+        }
+        match typ {
+            "Bool" => vec![JitValue::Bool(false), JitValue::Bool(true)],
+            "Int8" => ints!(Int8, i8),
+            "Int16" => ints!(Int16, i16),
+            "Int32" => ints!(Int32, i32),
+            "Int64" => ints!(Int64, i64),
+            "UInt8" => ints!(UInt8, u8),
+            "UInt16" => ints!(UInt16, u16),
+            "UInt32" => ints!(UInt32, u32),
+            "UInt64" => ints!(UInt64, u64),
+            "Float32" => floats!(Float32, f32),
+            "Float64" => floats!(Float64, f64),
+            _ => panic!("No edge values of `{}`", typ),
+        }
+    }
+
+    #[test]
+    fn test_binops_at_edges() {
+        let mut disagreements = Vec::new();
+        for typ in FOLDED_TYPES {
+            let xs = edge_values(typ);
+            for op in &["add", "sub", "mul", "div", "eq", "lt"] {
+                for &a in &xs {
+                    for &b in &xs {
+                        if let Err(e) = check_binop(op, a, b) {
+                            disagreements.push(e)
+                        }
+                    }
+                }
+            }
+        }
+        assert!(disagreements.is_empty(), "{}", disagreements.join("\n"));
+    }
+
+    #[test]
+    fn test_casts_at_edges() {
+        let types = FOLDED_TYPES.iter().chain(&["Bool"]).collect::<Vec<_>>();
+        let mut disagreements = Vec::new();
+        for from in &types {
+            for to in &types {
+                for x in edge_values(from) {
+                    if let Err(e) = check_cast(x, to) {
+                        disagreements.push(e)
+                    }
+                }
+            }
+        }
+        assert!(disagreements.is_empty(), "{}", disagreements.join("\n"));
+    }
+}